@@ -0,0 +1,44 @@
+//! Measures the effect of the `cache-friendly` feature on a free-then-reuse workload.
+//!
+//! ```text
+//! cargo bench --bench cache_friendly --features std
+//! cargo bench --bench cache_friendly --features std,cache-friendly
+//! ```
+//!
+//! Every round frees a batch of objects and immediately reallocates and reads them, which is
+//! the pattern where leaving the first cache line of freed objects untouched pays off.
+use std::{hint::black_box, time::Instant};
+
+#[global_allocator]
+static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
+
+const OBJECTS: usize = 4096;
+const ROUNDS: usize = 2000;
+
+fn run<const SIZE: usize>() {
+    let mut objects: Vec<Option<Box<[u8; SIZE]>>> = (0..OBJECTS).map(|_| Some(Box::new([1; SIZE]))).collect();
+    let start = Instant::now();
+    for round in 0..ROUNDS {
+        for object in objects.iter_mut().skip(round % 2).step_by(2) {
+            *object = None;
+        }
+        for object in objects.iter_mut().skip(round % 2).step_by(2) {
+            *object = Some(Box::new([round as u8; SIZE]));
+        }
+        black_box(objects.iter().flatten().map(|object| object[0] as usize).sum::<usize>());
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:>5} B objects: {:>8.2} ns per reallocation",
+        SIZE,
+        elapsed.as_nanos() as f64 / (ROUNDS * OBJECTS / 2) as f64
+    );
+}
+
+fn main() {
+    println!("CACHE_FRIENDLY_OFFSET = {}", snmalloc_rs::cache_friendly_offset());
+    run::<64>();
+    run::<128>();
+    run::<256>();
+    run::<1024>();
+}
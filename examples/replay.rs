@@ -0,0 +1,33 @@
+//! Replays an allocation log recorded by `snmalloc_rs::replay::record_to_file`, and reports how
+//! the heap of a fresh allocator evolved.
+//!
+//! ```text
+//! cargo run --release --example replay --features replay -- allocations.log
+//! ```
+use std::{fs::File, io::BufReader, process::ExitCode};
+
+use snmalloc_rs::{replay, stats};
+
+fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: replay <log>");
+        return ExitCode::FAILURE;
+    };
+    let records = match File::open(&path).and_then(|file| replay::read_log(BufReader::new(file))) {
+        Ok(records) => records,
+        Err(error) => {
+            eprintln!("{}: {}", path, error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let before = stats::memory_usage();
+    let report = replay::replay(&records);
+    let after = stats::memory_usage();
+    println!("operations:      {}", report.operations);
+    println!("unmatched:       {}", report.unmatched);
+    println!("failed:          {}", report.failed);
+    println!("leaked blocks:   {}", report.leaked);
+    println!("peak live bytes: {}", report.peak_live_bytes);
+    println!("peak committed:  {}", after.peak.saturating_sub(before.current));
+    ExitCode::SUCCESS
+}
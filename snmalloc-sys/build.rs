@@ -3,6 +3,18 @@
 use std::env;
 use std::fs;
 
+#[path = "src/abi.rs"]
+mod abi;
+
+/// Default template of the symbol prefix of the `prefix-symbols` feature, see
+/// `BuildConfig::symbol_prefix`.
+const SYMBOL_PREFIX_TEMPLATE: &str = "snmalloc_sys_{version}_";
+
+/// The randomisation of the layout of the heap among snmalloc's mitigations, enabled in the fast
+/// shim by the `randomize` feature: shuffled free lists, randomly kept-back objects and slabs,
+/// and random thresholds for the reuse of slabs.
+const RANDOMIZE_MITIGATIONS: &str = "random_initial+random_preserve+random_extra_slab+random_larger_thresholds";
+
 #[derive(Debug, PartialEq)]
 enum Compiler {
     Clang,
@@ -13,6 +25,8 @@ enum Compiler {
 
 struct BuildConfig {
     debug: bool,
+    debug_info: bool,
+    debug_assertions: bool,
     optim_level: String, 
     target_os: String,
     target_env: String,
@@ -23,6 +37,8 @@ struct BuildConfig {
     msystem: Option<String>,
     cmake_cxx_standard: String,  
     target_lib: String,  
+    include_dir: String,
+    shim_source: String,
     features: BuildFeatures,
     #[cfg(feature = "build_cc")]
     builder: cc::Build,
@@ -35,6 +51,8 @@ impl std::fmt::Debug for BuildConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("BuildConfig")
             .field("debug", &self.debug)
+            .field("debug_info", &self.debug_info)
+            .field("debug_assertions", &self.debug_assertions)
             .field("optim_level", &self.optim_level)
             .field("target_os", &self.target_os)
             .field("target_env", &self.target_env)
@@ -45,6 +63,8 @@ impl std::fmt::Debug for BuildConfig {
             .field("msystem", &self.msystem)
             .field("cmake_cxx_standard", &self.cmake_cxx_standard)
             .field("target_lib", &self.target_lib)
+            .field("include_dir", &self.include_dir)
+            .field("shim_source", &self.shim_source)
             .field("features", &self.features)
             .finish()
     }
@@ -58,29 +78,58 @@ struct BuildFeatures {
     lto: bool,
     notls: bool,
     win8compat: bool,
+    no_crt: bool,
     stats: bool,
     android_lld: bool,
     local_dynamic_tls: bool,
+    cxx_new: bool,
+    no_unwind: bool,
+    dynamic_loading: bool,
+    reproducible: bool,
+    control_flow_guard: bool,
+    cet_compat: bool,
+    cache_friendly: bool,
+    checked_handles: bool,
+    single_threaded: bool,
+    audit_dealloc: bool,
+    lock_memory: bool,
+    early_init: bool,
+    critical_section: bool,
+    prefix_symbols: bool,
+    randomize: bool,
+    handle_api: bool,
+    stats_api: bool,
+    guard_api: bool,
+    universal_macos: bool,
+    dylib: bool,
 }
 
 impl BuildConfig {
     fn new() -> Self {
-        let debug = cfg!(feature = "debug");
+        let profile = CargoProfile::from_env();
+        let debug = profile.opt_level == "0";
         #[cfg(feature = "build_cc")]
         let builder = cc::Build::new();
         
         #[cfg(not(feature = "build_cc"))]
-        let builder = Config::new("snmalloc");
+        let builder = Config::new("shim");
+
+        #[cfg(feature = "system-snmalloc")]
+        let (include_dir, shim_source) = locate_system_snmalloc();
+        #[cfg(not(feature = "system-snmalloc"))]
+        let (include_dir, shim_source) = locate_vendored_snmalloc();
 
         let mut config = Self {
             debug,
-            optim_level: (if debug { "-O0" } else { "-O3" }).to_string(),
+            debug_info: profile.debug_info,
+            debug_assertions: profile.debug_assertions,
+            optim_level: String::new(),
             target_os: env::var("CARGO_CFG_TARGET_OS").expect("target_os not defined!"),
             target_env: env::var("CARGO_CFG_TARGET_ENV").expect("target_env not defined!"),
             target_family: env::var("CARGO_CFG_TARGET_FAMILY").expect("target family not set"),
             target: env::var("TARGET").expect("TARGET not set"),
             out_dir: env::var("OUT_DIR").unwrap(),
-            build_type: (if debug { "Debug" } else { "Release" }).to_string(),
+            build_type: profile.build_type().to_string(),
             msystem: env::var("MSYSTEM").ok(),
             cmake_cxx_standard: (if cfg!(feature = "usecxx17") { "17" } else { "20" }).to_string(),
             target_lib: (if cfg!(feature = "check") {
@@ -88,11 +137,14 @@ impl BuildConfig {
             } else {
                 "snmallocshim-rust"
             }).to_string(),
+            include_dir,
+            shim_source,
             features: BuildFeatures::new(),
             builder,
             compiler: Compiler::Unknown,
         };
         config.compiler = config.detect_compiler();
+        config.optim_level = profile.optim_flag(&config.compiler).to_string();
         config.embed_build_info();
         config
     }
@@ -148,6 +200,8 @@ impl BuildConfig {
             ("BUILD_CC", &format!("{:#?}", self.compiler)),
             ("BUILD_TYPE", &self.build_type),
             ("BUILD_DEBUG", &self.debug.to_string()),
+            ("BUILD_DEBUG_INFO", &self.debug_info.to_string()),
+            ("BUILD_DEBUG_ASSERTIONS", &self.debug_assertions.to_string()),
             ("BUILD_OPTIM_LEVEL", &self.optim_level),
             ("BUILD_CXX_STANDARD", &self.cmake_cxx_standard),
         ];
@@ -173,6 +227,30 @@ impl BuildConfig {
         self.target_env == "msvc"
     }
 
+    fn is_arm64ec(&self) -> bool {
+        self.target.starts_with("arm64ec")
+    }
+
+    fn target_arch(&self) -> String {
+        env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default()
+    }
+
+    fn is_x86(&self) -> bool {
+        matches!(self.target_arch().as_str(), "x86" | "x86_64")
+    }
+
+    fn is_ppc64(&self) -> bool {
+        self.target_arch() == "powerpc64"
+    }
+
+    fn is_s390x(&self) -> bool {
+        self.target_arch() == "s390x"
+    }
+
+    fn is_32bit(&self) -> bool {
+        env::var("CARGO_CFG_TARGET_POINTER_WIDTH").is_ok_and(|width| width == "32")
+    }
+
     fn is_gnu(&self) -> bool {
         self.target_env == "gnu"
     }
@@ -196,6 +274,225 @@ impl BuildConfig {
     fn is_ucrt64(&self) -> bool {
         self.msystem.as_deref() == Some("UCRT64")
     }
+
+    fn is_haiku(&self) -> bool {
+        self.target_os == "haiku"
+    }
+
+    fn is_solarish(&self) -> bool {
+        matches!(self.target_os.as_str(), "illumos" | "solaris")
+    }
+
+    fn is_emscripten(&self) -> bool {
+        self.target_os == "emscripten"
+    }
+
+    fn is_android(&self) -> bool {
+        self.target_os == "android"
+    }
+
+    /// OpenHarmony, whose targets (`*-linux-ohos`) are Linux with a musl-based libc and LLVM's libc++.
+    fn is_ohos(&self) -> bool {
+        self.target_env == "ohos"
+    }
+
+    /// Android API level targeted, from `ANDROID_PLATFORM` (`android-29` or `29`, as the NDK
+    /// accepts it) or `ANDROID_API_LEVEL`. `None` leaves the NDK to its minimum.
+    fn android_api_level(&self) -> Option<u32> {
+        let platform = target_env_var("ANDROID_PLATFORM").or_else(|| target_env_var("ANDROID_API_LEVEL"))?;
+        let level = platform.strip_prefix("android-").unwrap_or(&platform);
+        match level.parse() {
+            Ok(level) => Some(level),
+            Err(_) if level == "latest" => None,
+            Err(_) => panic!("ANDROID_PLATFORM must be an API level such as `android-29`, not {}", platform),
+        }
+    }
+
+    /// Bionic only supports ELF TLS from Android 10 (API level 29); below, or when the level is
+    /// left to the NDK, thread-locals go through emutls.
+    fn android_elf_tls(&self) -> bool {
+        self.android_api_level().is_some_and(|level| level >= 29)
+    }
+
+    /// Whether to build one library for both macOS architectures; ignored for other targets.
+    fn is_universal_macos(&self) -> bool {
+        self.features.universal_macos && self.target_os == "macos"
+    }
+
+    /// Initial-exec TLS is fastest, but a module using it cannot be `dlopen`ed.
+    fn tls_model(&self) -> &'static str {
+        if self.features.local_dynamic_tls || self.features.dynamic_loading {
+            "-ftls-model=local-dynamic"
+        } else {
+            "-ftls-model=initial-exec"
+        }
+    }
+
+    /// Page size to compile snmalloc for, when it differs from upstream's 4KiB: the one set in
+    /// `SNMALLOC_SYS_PAGE_SIZE`, 16KiB on Apple Silicon and aarch64 Android, or the page size of
+    /// the build machine for native aarch64 Linux builds, whose kernels may use 16KiB or 64KiB.
+    fn page_size(&self) -> Option<usize> {
+        if let Some(value) = target_env_var("SNMALLOC_SYS_PAGE_SIZE") {
+            return match value.parse::<usize>() {
+                Ok(size) if size.is_power_of_two() && size >= 4096 => Some(size),
+                _ => panic!("SNMALLOC_SYS_PAGE_SIZE must be a power of two of at least 4096, not {}", value),
+            };
+        }
+        match (self.target_arch().as_str(), self.target_os.as_str()) {
+            ("aarch64", "macos" | "ios" | "tvos" | "watchos" | "visionos" | "android") => Some(16384),
+            ("aarch64", "linux") if env::var("HOST").is_ok_and(|host| host == self.target) => {
+                let output = std::process::Command::new("getconf").arg("PAGESIZE").output().ok()?;
+                String::from_utf8(output.stdout).ok()?.trim().parse().ok().filter(|size| *size > 4096)
+            }
+            _ => None,
+        }
+    }
+
+    /// Directory of the shared library built with the `dylib` feature, outside of the link search
+    /// paths so that the Rust build keeps linking the static archive.
+    fn dylib_dir(&self) -> String {
+        format!("{}/dylib", self.out_dir).replace('\\', "/")
+    }
+
+    /// Prefix of every symbol of the shim with the `prefix-symbols` feature, rendered from
+    /// `SNMALLOC_SYS_SYMBOL_PREFIX` or [`SYMBOL_PREFIX_TEMPLATE`], whose `{version}` stands for the
+    /// semver-compatible version of this crate (`0_3` for 0.3.x), so that each major links its
+    /// own shim.
+    fn symbol_prefix(&self) -> Option<String> {
+        if !self.features.prefix_symbols {
+            return None;
+        }
+        let major = env::var("CARGO_PKG_VERSION_MAJOR").expect("CARGO_PKG_VERSION_MAJOR not set");
+        let version = match major.as_str() {
+            "0" => format!("0_{}", env::var("CARGO_PKG_VERSION_MINOR").expect("CARGO_PKG_VERSION_MINOR not set")),
+            _ => major,
+        };
+        let template = target_env_var("SNMALLOC_SYS_SYMBOL_PREFIX").unwrap_or_else(|| SYMBOL_PREFIX_TEMPLATE.to_string());
+        let prefix = template.replace("{version}", &version);
+        let is_identifier = prefix.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_identifier {
+            panic!("SNMALLOC_SYS_SYMBOL_PREFIX must render to the start of a C identifier, not {:?}", prefix);
+        }
+        Some(prefix)
+    }
+
+    fn has_target_feature(&self, feature: &str) -> bool {
+        env::var("CARGO_CFG_TARGET_FEATURE")
+            .is_ok_and(|features| features.split(',').any(|f| f == feature))
+    }
+
+    /// The optional sections of `rust_ext.cc`: the define compiling each in, whether the features
+    /// enable it, and the prefixes of the functions it defines.
+    fn shim_apis(&self) -> [(&'static str, bool, &'static [&'static str]); 3] {
+        [
+            ("SNMALLOC_RUST_HANDLE_API", self.features.handle_api, &["sn_rust_allocator_"]),
+            (
+                "SNMALLOC_RUST_STATS_API",
+                self.features.stats_api,
+                &[
+                    "sn_rust_memory_usage",
+                    "sn_rust_address_space",
+                    "sn_rust_slab_info",
+                    "sn_rust_large_cache_stats",
+                    "sn_rust_owned_ranges",
+                ],
+            ),
+            ("SNMALLOC_RUST_GUARD_API", self.features.guard_api, &["sn_rust_guarded_", "sn_rust_redzone_"]),
+        ]
+    }
+
+    /// Whether the shim is built with `function`, i.e. it is not in a section left out.
+    fn defines_shim_function(&self, function: &str) -> bool {
+        self.shim_apis()
+            .iter()
+            .all(|(_, enabled, prefixes)| *enabled || !prefixes.iter().any(|prefix| function.starts_with(prefix)))
+    }
+
+    /// The CRT the Rust side links against: mixing `/MT` and `/MD` objects breaks the MSVC link.
+    fn static_crt(&self) -> bool {
+        self.has_target_feature("crt-static")
+    }
+
+    /// Compiler flags enabling the instruction set extensions the Rust side is compiled for
+    /// (`-C target-feature`, `-C target-cpu`), so that both halves assume the same baseline.
+    fn target_feature_flags(&self) -> Vec<&'static str> {
+        let enabled = |flags: &[(&str, &'static str)]| -> Vec<&'static str> {
+            flags
+                .iter()
+                .filter(|(feature, _)| self.has_target_feature(feature))
+                .map(|(_, flag)| *flag)
+                .collect()
+        };
+        if self.is_s390x() {
+            // z10 is the Rust baseline, while older GCCs still default to z900; vector registers
+            // came with z13, below which GCC rejects `-mvx`.
+            return match self.has_target_feature("vector") {
+                true => vec!["-march=z13", "-mvx"],
+                false => vec!["-march=z10"],
+            };
+        }
+        if self.is_ppc64() {
+            return enabled(&[("altivec", "-maltivec"), ("vsx", "-mvsx"), ("power8-vector", "-mpower8-vector")]);
+        }
+        if !self.is_x86() {
+            return Vec::new();
+        }
+        if self.is_msvc() {
+            // MSVC only selects a baseline, the highest one enabled wins.
+            let arch = [("avx512f", "/arch:AVX512"), ("avx2", "/arch:AVX2"), ("avx", "/arch:AVX")]
+                .into_iter()
+                .find(|(feature, _)| self.has_target_feature(feature));
+            return arch.map(|(_, flag)| flag).into_iter().collect();
+        }
+        [
+            ("sse3", "-msse3"),
+            ("ssse3", "-mssse3"),
+            ("sse4.1", "-msse4.1"),
+            ("sse4.2", "-msse4.2"),
+            ("popcnt", "-mpopcnt"),
+            ("cmpxchg16b", "-mcx16"),
+            ("lzcnt", "-mlzcnt"),
+            ("bmi1", "-mbmi"),
+            ("bmi2", "-mbmi2"),
+            ("avx", "-mavx"),
+            ("avx2", "-mavx2"),
+            ("fma", "-mfma"),
+            ("avx512f", "-mavx512f"),
+        ]
+        .into_iter()
+        .filter(|(feature, _)| self.has_target_feature(feature))
+        .map(|(_, flag)| flag)
+        .collect()
+    }
+
+    /// The `/arch` baseline standing in for `-march=native` with MSVC, which has no equivalent:
+    /// the highest of AVX-512, AVX2 and AVX supported by the CPU of the build machine, which
+    /// `native-cpu` requires to be the target.
+    fn msvc_native_arch(&self) -> Option<&'static str> {
+        if !self.is_msvc() || !self.is_x86() {
+            return None;
+        }
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            // `/arch:AVX512` assumes the subsets of AVX-512 common to every CPU implementing it.
+            if is_x86_feature_detected!("avx512f")
+                && is_x86_feature_detected!("avx512cd")
+                && is_x86_feature_detected!("avx512bw")
+                && is_x86_feature_detected!("avx512dq")
+                && is_x86_feature_detected!("avx512vl")
+            {
+                return Some("/arch:AVX512");
+            }
+            if is_x86_feature_detected!("avx2") {
+                return Some("/arch:AVX2");
+            }
+            if is_x86_feature_detected!("avx") {
+                return Some("/arch:AVX");
+            }
+        }
+        None
+    }
 }
 
 trait BuilderDefine {
@@ -203,7 +500,7 @@ trait BuilderDefine {
     fn flag_if_supported(&mut self, flag: &str) -> &mut Self;
     fn build_lib(&mut self, target_lib: &str) -> std::path::PathBuf;
     fn configure_output_dir(&mut self, out_dir: &str) -> &mut Self;
-    fn configure_cpp(&mut self, debug: bool) -> &mut Self;
+    fn configure_cpp(&mut self, debug_info: bool, static_crt: bool, include_dir: &str, shim_source: &str) -> &mut Self;
 }
 
 #[cfg(feature = "build_cc")]
@@ -225,19 +522,27 @@ impl BuilderDefine for cc::Build {
         self.out_dir(out_dir)
     }
 
-    fn configure_cpp(&mut self, debug: bool) -> &mut Self {
-        self.include("snmalloc/src")
-            .file("snmalloc/src/snmalloc/override/rust.cc")
+    // The sources are added by `add_sources`, once the features chose them.
+    fn configure_cpp(&mut self, debug_info: bool, static_crt: bool, include_dir: &str, _shim_source: &str) -> &mut Self {
+        self.include(include_dir)
             .cpp(true)
-            .debug(debug)
-            .static_crt(true)
+            .debug(debug_info)
+            .static_crt(static_crt)
     }
 }
 
 #[cfg(not(feature = "build_cc"))]
 impl BuilderDefine for cmake::Config {
     fn define(&mut self, key: &str, value: &str) -> &mut Self {
-        self.define(key, value)
+        // A toolchain file from the environment decides the system and the compilers itself.
+        let toolchain_keys = ["CMAKE_TOOLCHAIN_FILE", "CMAKE_SYSTEM_NAME", "CMAKE_C_COMPILER", "CMAKE_CXX_COMPILER"];
+        if toolchain_keys.contains(&key) && user_toolchain_file().is_some() {
+            return self;
+        }
+        match user_flags(key) {
+            Some(flags) => self.define(key, format!("{} {}", value, flags)),
+            None => self.define(key, value),
+        }
     }
     
     fn flag_if_supported(&mut self, _flag: &str) -> &mut Self {
@@ -252,12 +557,14 @@ impl BuilderDefine for cmake::Config {
         self.out_dir(out_dir)
     }
 
-    fn configure_cpp(&mut self, _debug: bool) -> &mut Self {
-        self.define("SNMALLOC_RUST_SUPPORT", "ON")
+    fn configure_cpp(&mut self, _debug_info: bool, static_crt: bool, include_dir: &str, _shim_source: &str) -> &mut Self {
+        // The checkout to build is the parent of the include directory.
+        self.define("SNMALLOC_RUST_SOURCE_DIR", include_dir.strip_suffix("/src").unwrap_or(include_dir))
+            .define("SNMALLOC_RUST_SUPPORT", "ON")
             .very_verbose(true)
             .define("CMAKE_SH", "CMAKE_SH-NOTFOUND")
             .always_configure(true)
-            .static_crt(true)
+            .static_crt(static_crt)
     }
 }
 
@@ -266,33 +573,251 @@ fn apply_defines<T: BuilderDefine>(builder: &mut T, defines: &[(&str, &str)]) {
         builder.define(key, value);
     }
 }
+
+/// Reads a variable the way cc and cmake-rs do: for the target first (`NAME_<target>`, with dashes
+/// or underscores), then `TARGET_NAME`, then `NAME`.
+fn target_env_var(name: &str) -> Option<String> {
+    let target = env::var("TARGET").unwrap_or_default();
+    [
+        format!("{}_{}", name, target),
+        format!("{}_{}", name, target.replace('-', "_")),
+        format!("TARGET_{}", name),
+        name.to_string(),
+    ]
+    .into_iter()
+    .find_map(|var| env::var(var).ok().filter(|value| !value.is_empty()))
+}
+
+/// Toolchain file set in the environment (e.g. by Yocto or Buildroot), which cmake-rs passes on
+/// unless the build script defines its own.
+fn user_toolchain_file() -> Option<String> {
+    target_env_var("CMAKE_TOOLCHAIN_FILE")
+}
+
+/// Flags from the environment for a CMake flag variable. cmake-rs only fills these variables from
+/// `CFLAGS`, `CXXFLAGS` and `LDFLAGS` when they are not defined, so the flags must be appended to
+/// every definition the build script makes.
+fn user_flags(key: &str) -> Option<String> {
+    let var = match key {
+        "CMAKE_C_FLAGS" => "CFLAGS",
+        "CMAKE_CXX_FLAGS" => "CXXFLAGS",
+        "CMAKE_EXE_LINKER_FLAGS" | "CMAKE_SHARED_LINKER_FLAGS" => "LDFLAGS",
+        _ => return None,
+    };
+    target_env_var(var)
+}
+
+/// Applies `SNMALLOC_SYS_CMAKE_ARGS`, whitespace-separated arguments for the CMake configure step
+/// given last, so that they override the definitions of the build script: `-DNAME=VALUE` (or
+/// `-DNAME:TYPE=VALUE`) defines a variable, anything else is passed as is.
+#[cfg(not(feature = "build_cc"))]
+fn apply_user_cmake_args(config: &mut BuildConfig) {
+    let Ok(args) = env::var("SNMALLOC_SYS_CMAKE_ARGS") else {
+        return;
+    };
+    for arg in args.split_whitespace() {
+        let define = arg.strip_prefix("-D").and_then(|define| define.split_once('='));
+        match define {
+            Some((name, value)) => config.builder.define(name.split(':').next().unwrap_or(name), value),
+            None => config.builder.configure_arg(arg),
+        };
+    }
+}
 impl BuildFeatures {
     fn new() -> Self {
         Self {
             native_cpu: cfg!(feature = "native-cpu"),
             qemu: cfg!(feature = "qemu"),
-            wait_on_address: cfg!(feature = "usewait-on-address"),
+            // Nothing can be woken up on a single core without an OS, nor without a second thread:
+            // snmalloc's locks must spin (and never wait).
+            wait_on_address: cfg!(feature = "usewait-on-address")
+                && !cfg!(feature = "critical-section")
+                && !cfg!(feature = "single-threaded"),
             lto: cfg!(feature = "lto"),
             notls: cfg!(feature = "notls"),
             win8compat: cfg!(feature = "win8compat"),
+            no_crt: cfg!(feature = "win-no-crt"),
             stats: cfg!(feature = "stats"),
             android_lld: cfg!(feature = "android-lld"),
             local_dynamic_tls: cfg!(feature = "local_dynamic_tls"),
+            cxx_new: cfg!(feature = "cxx-new"),
+            no_unwind: cfg!(feature = "no-unwind"),
+            // Build scripts cannot see the crate type of the final artifact, so crates building a
+            // cdylib opt in through the feature or by exporting `SNMALLOC_DYNAMIC_LOADING=1`.
+            dynamic_loading: cfg!(feature = "dynamic-loading")
+                || env::var("SNMALLOC_DYNAMIC_LOADING").is_ok_and(|v| v == "1"),
+            reproducible: cfg!(feature = "reproducible"),
+            control_flow_guard: cfg!(feature = "control-flow-guard"),
+            cet_compat: cfg!(feature = "cet-compat"),
+            cache_friendly: cfg!(feature = "cache-friendly"),
+            checked_handles: cfg!(feature = "checked-handles"),
+            single_threaded: cfg!(feature = "single-threaded"),
+            audit_dealloc: cfg!(feature = "no-alloc-on-free"),
+            lock_memory: cfg!(feature = "lock-memory"),
+            early_init: cfg!(feature = "early-init"),
+            critical_section: cfg!(feature = "critical-section"),
+            prefix_symbols: cfg!(feature = "prefix-symbols"),
+            randomize: cfg!(feature = "randomize"),
+            handle_api: cfg!(feature = "handle-api"),
+            stats_api: cfg!(feature = "stats-api"),
+            guard_api: cfg!(feature = "guard-api"),
+            universal_macos: cfg!(feature = "universal-macos"),
+            dylib: cfg!(feature = "dylib"),
         }
     }
 }
 
+/// The settings of the cargo profile the shim is built for (`opt-level`, `debug`,
+/// `debug-assertions`), so that `cargo build --release` yields an optimised allocator and
+/// `opt-level = "z"` a small one. The `debug` feature overrides them when it is set.
+#[derive(Debug)]
+struct CargoProfile {
+    opt_level: String,
+    debug_info: bool,
+    debug_assertions: bool,
+}
+
+impl CargoProfile {
+    fn from_env() -> Self {
+        if cfg!(feature = "debug") {
+            return Self { opt_level: "0".to_string(), debug_info: true, debug_assertions: true };
+        }
+        // `OPT_LEVEL` and `DEBUG` are always set by cargo; `PROFILE` only tells debug from release.
+        let release = env::var("PROFILE").is_ok_and(|profile| profile == "release");
+        let opt_level = env::var("OPT_LEVEL").unwrap_or_else(|_| (if release { "3" } else { "0" }).to_string());
+        let debug_info = env::var("DEBUG").map_or(!release, |debug| !matches!(debug.as_str(), "false" | "0" | "none"));
+        let debug_assertions = env::var_os("CARGO_CFG_DEBUG_ASSERTIONS").is_some();
+        Self { opt_level, debug_info, debug_assertions }
+    }
+
+    /// The CMake build type closest to the profile.
+    fn build_type(&self) -> &'static str {
+        match self.opt_level.as_str() {
+            "0" => "Debug",
+            "s" | "z" => "MinSizeRel",
+            _ if self.debug_info => "RelWithDebInfo",
+            _ => "Release",
+        }
+    }
+
+    /// The optimisation flag of the compiler for the profile. GCC only learnt `-Oz` in version 12.
+    fn optim_flag(&self, compiler: &Compiler) -> &'static str {
+        match (compiler, self.opt_level.as_str()) {
+            (Compiler::Msvc, "0") => "/Od",
+            (Compiler::Msvc, "1" | "s" | "z") => "/O1",
+            (Compiler::Msvc, _) => "/O2",
+            (_, "0") => "-O0",
+            (_, "1") => "-O1",
+            (_, "2") => "-O2",
+            (_, "s") => "-Os",
+            (Compiler::Clang, "z") => "-Oz",
+            (_, "z") => "-Os",
+            _ => "-O3",
+        }
+    }
+}
+
+/// Rejects feature combinations that would otherwise fail deep inside the C++ build, or silently
+/// do something else than asked, before anything is built.
+fn check_features(config: &BuildConfig) {
+    let mut errors = Vec::new();
+    if config.features.checked_handles && cfg!(feature = "check") {
+        errors.push("`checked-handles` and `check`: `check` hardens every allocator, drop it to select the shim per handle");
+    }
+    if config.features.dylib && cfg!(feature = "build_cc") {
+        errors.push("`dylib` and `build_cc`: the cc build only produces static archives, build with cmake");
+    }
+    if config.features.dylib && cfg!(feature = "check") {
+        errors.push("`dylib` and `check`: the shared library is built from the fast shim, drop `check`");
+    }
+    if config.features.checked_handles && cfg!(feature = "build_cc") {
+        errors.push("`checked-handles` and `build_cc`: both shims can only be built with cmake, drop `build_cc` (or `system-snmalloc`, which implies it)");
+    }
+    if config.features.cxx_new && config.features.no_unwind {
+        errors.push("`cxx-new` and `no-unwind`: the replaced `operator new` throws `std::bad_alloc`, which needs the unwinder; drop one of them");
+    }
+    if config.features.notls && config.features.local_dynamic_tls {
+        errors.push("`notls` and `local_dynamic_tls`: `notls` does not use TLS at all, drop `local_dynamic_tls`");
+    }
+    if config.features.no_crt && config.features.win8compat {
+        errors.push("`win-no-crt` and `win8compat`: without the CRT the shim relies on `VirtualAlloc2` and `WaitOnAddress`, drop `win8compat`");
+    }
+    if config.features.no_crt && config.features.cxx_new {
+        errors.push("`win-no-crt` and `cxx-new`: the replaced `operator new` throws `std::bad_alloc`, which needs the CRT; drop `cxx-new`");
+    }
+    if config.features.critical_section && config.features.cxx_new {
+        errors.push("`critical-section` and `cxx-new`: the replaced `operator new` comes from upstream and never enters the critical section; drop `cxx-new`");
+    }
+    if config.features.critical_section && config.features.dylib {
+        errors.push("`critical-section` and `dylib`: the shared library cannot link the critical section of the Rust side, drop `dylib`");
+    }
+    // `-march=native` describes the machine running the build, not the target.
+    if config.features.native_cpu && env::var("HOST").is_ok_and(|host| host != config.target) {
+        errors.push("`native-cpu` on a cross build: the host CPU says nothing about the target, drop it and pass `-C target-cpu=<cpu>` in RUSTFLAGS instead");
+    }
+    if config.is_android() && config.android_api_level().is_some_and(|level| level < 21) {
+        errors.push("ANDROID_PLATFORM: current NDKs and snmalloc need API level 21 (Android 5.0) or later");
+    }
+    if config.features.native_cpu && config.is_universal_macos() {
+        errors.push("`native-cpu` and `universal-macos`: the host CPU only describes one of the two slices, drop `native-cpu`");
+    }
+    if !errors.is_empty() {
+        panic!("incompatible snmalloc-sys features:\n  - {}", errors.join("\n  - "));
+    }
+
+    if config.is_android() && !config.android_elf_tls() && (config.features.local_dynamic_tls || config.features.dynamic_loading) {
+        println!("cargo:warning=snmalloc-sys: the TLS model is fixed by emutls below API level 29, `local_dynamic_tls` and `dynamic-loading` do not change it");
+    }
+    if config.features.no_crt && !config.is_msvc() {
+        println!("cargo:warning=snmalloc-sys: `win-no-crt` only applies to windows-msvc targets, it is ignored for {}", config.target);
+    }
+    if config.features.stats && cfg!(feature = "build_cc") {
+        println!("cargo:warning=snmalloc-sys: `stats` only enables snmalloc's own counters in the cmake build, the cc build ignores it");
+    }
+}
+
 fn configure_platform(config: &mut BuildConfig) {
     // Basic optimization and compiler flags
     config.builder
         .flag_if_supported(&config.optim_level)
         .flag_if_supported("-fomit-frame-pointer");
 
+    // The build type of CMake decides `NDEBUG`, and with it snmalloc's assertions; the cc build
+    // follows `debug-assertions` directly.
+    #[cfg(feature = "build_cc")]
+    if !config.debug_assertions {
+        config.builder.flag_if_supported(if config.is_msvc() { "/DNDEBUG" } else { "-DNDEBUG" });
+    }
+    #[cfg(not(feature = "build_cc"))]
+    {
+        config.builder.profile(&config.build_type);
+        if config.debug_assertions && !config.debug {
+            config.builder.define("SNMALLOC_RUST_DEBUG_ASSERTIONS", "ON");
+        }
+    }
+
     // C++ standard flags
     for std in config.get_cpp_flags() {
         config.builder.flag_if_supported(std);
     }
 
+    // Rust target features, e.g. from `-C target-cpu=x86-64-v3`, apply to the shim too. With
+    // MSVC, `native-cpu` raises the single `/arch` baseline to the one of the build machine.
+    let mut target_flags = config.target_feature_flags();
+    if config.features.native_cpu {
+        if let Some(arch) = config.msvc_native_arch() {
+            target_flags.retain(|flag| !flag.starts_with("/arch:"));
+            target_flags.push(arch);
+        }
+    }
+    for flag in &target_flags {
+        config.builder.flag_if_supported(flag);
+    }
+    #[cfg(not(feature = "build_cc"))]
+    if !target_flags.is_empty() {
+        config.builder.define("SNMALLOC_RUST_TARGET_FLAGS", &*target_flags.join(";"));
+    }
+
     // Common feature configurations
     if config.features.native_cpu {
         config.builder.define("SNMALLOC_OPTIMISE_FOR_CURRENT_MACHINE", "ON");
@@ -300,6 +825,155 @@ fn configure_platform(config: &mut BuildConfig) {
         config.builder.flag_if_supported("-march=native");
     }
 
+    if config.features.dylib {
+        let dir = config.dylib_dir();
+        config.builder
+            .define("SNMALLOC_RUST_DYLIB", "ON")
+            .define("SNMALLOC_RUST_DYLIB_DIR", &*dir);
+    }
+
+    if config.features.cxx_new {
+        config.builder.define("SNMALLOC_RUST_NEW_OVERRIDE", "ON");
+    }
+
+    // Without unwind tables the shim references neither a personality routine nor the unwinder,
+    // so it links into `panic = "abort"` and `-Z build-std` builds.
+    if config.features.no_unwind {
+        config.builder
+            .define("SNMALLOC_RUST_NO_UNWIND", "ON")
+            .flag_if_supported("-fno-asynchronous-unwind-tables")
+            .flag_if_supported("-fno-unwind-tables");
+    }
+
+    // Absolute paths of the checkout and of the build directory end up in `__FILE__` and in debug
+    // info. Archives are written without timestamps and `__DATE__` is fixed through the environment
+    // of every tool, see `main`; cmake is also told by the shim.
+    if config.features.reproducible {
+        let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+        let prefix_maps = [format!("{}=/snmalloc-sys", manifest_dir), format!("{}=/out", config.out_dir)];
+        for map in &prefix_maps {
+            let flag = format!("-ffile-prefix-map={}", map);
+            config.builder.flag_if_supported(flag.as_str());
+        }
+        config.builder
+            .flag_if_supported("/Brepro")
+            .define("SNMALLOC_RUST_REPRODUCIBLE", "ON")
+            .define("SNMALLOC_RUST_PREFIX_MAPS", &*prefix_maps.join(";"));
+    }
+
+    // Control Flow Guard and CET shadow stacks are enforced for the final image: the shim only has
+    // to be compiled for them, the binary itself must still be linked with `/guard:cf` or `/CETCOMPAT`.
+    if config.features.control_flow_guard && config.is_windows() {
+        config.builder
+            .flag_if_supported(if config.is_msvc() { "/guard:cf" } else { "-mguard=cf" })
+            .define("SNMALLOC_RUST_CONTROL_FLOW_GUARD", "ON");
+    }
+    if config.features.cet_compat {
+        config.builder
+            .flag_if_supported("-fcf-protection=full")
+            .define("SNMALLOC_RUST_CET", "ON");
+    }
+
+    // Leaves the first cache line of every object untouched by the free lists, so that a freed
+    // object does not evict the line its next user starts with. cmake gets it through the shim.
+    if config.features.cache_friendly {
+        config.builder.define("SNMALLOC_RUST_CACHE_FRIENDLY_OFFSET", "64");
+        #[cfg(feature = "build_cc")]
+        config.builder.define("CACHE_FRIENDLY_OFFSET", "64");
+    }
+
+    // Compiled for pages smaller than the kernel's, snmalloc would decommit and protect partial
+    // pages. The shim rounds its own page-granular calls to the kernel's page size at run time.
+    if let Some(page_size) = config.page_size() {
+        let page_size = page_size.to_string();
+        config.builder.define("SNMALLOC_RUST_PAGE_SIZE", page_size.as_str());
+        #[cfg(feature = "build_cc")]
+        config.builder.define("SNMALLOC_PAGESIZE", page_size.as_str());
+    }
+
+    // A 4GiB address space cannot afford the 64-bit reservation granularity: use the smaller
+    // chunks upstream provides for constrained address spaces. Emscripten is already configured.
+    if config.is_32bit() && !config.is_emscripten() {
+        config.builder.define("SNMALLOC_RUST_SMALL_ADDRESS_SPACE", "ON");
+        #[cfg(feature = "build_cc")]
+        config.builder.define("SNMALLOC_USE_SMALL_CHUNKS", "1");
+    }
+
+    // Sections of the shim only some features call are left out of minimal builds. cmake has them
+    // on by default for builds outside of cargo.
+    for (api, enabled, _) in config.shim_apis() {
+        #[cfg(not(feature = "build_cc"))]
+        config.builder.define(api, if enabled { "ON" } else { "OFF" });
+        #[cfg(feature = "build_cc")]
+        if enabled {
+            config.builder.define(api, "1");
+        }
+    }
+    // The statistics count the address space, the audit of frees watches it and the locking of
+    // memory locks it through the PAL of `sn_rust_pal.h`, which has to be seen before the headers
+    // of snmalloc pick theirs; cmake force-includes it likewise.
+    #[cfg(feature = "build_cc")]
+    if config.features.stats_api || config.features.audit_dealloc || config.features.lock_memory {
+        let path = format!("{}/shim/sn_rust_pal.h", env::var("CARGO_MANIFEST_DIR").unwrap_or_default()).replace('\\', "/");
+        if config.is_msvc() {
+            config.builder.flag(format!("/FI{}", path));
+        } else {
+            config.builder.flag("-include").flag(&path);
+        }
+    }
+
+    // The hardened shim is built as a second library, whose symbols are renamed by the shim.
+    if config.features.checked_handles {
+        config.builder.define("SNMALLOC_RUST_CHECKED_HANDLES", "ON");
+    }
+
+    // upstream has no configuration without per-thread allocators: drop what only exists for
+    // concurrent callers, the guards around the initialisation of function-local statics.
+    if config.features.single_threaded {
+        config.builder
+            .flag_if_supported(if config.is_msvc() { "/Zc:threadSafeInit-" } else { "-fno-threadsafe-statics" })
+            .define("SNMALLOC_RUST_SINGLE_THREADED", "ON");
+    }
+
+    // cmake turns the option into the define checked by the shim, which cc passes as is.
+    if config.features.audit_dealloc {
+        config.builder.define("SNMALLOC_RUST_AUDIT_DEALLOC", "ON");
+    }
+    if config.features.lock_memory {
+        config.builder.define("SNMALLOC_RUST_LOCK_MEMORY", "ON");
+    }
+    if config.features.early_init {
+        config.builder.define("SNMALLOC_RUST_EARLY_INIT", "ON");
+    }
+
+    // The shim enters the critical section through the hooks of `src/critical.rs`, likewise.
+    if config.features.critical_section {
+        config.builder.define("SNMALLOC_RUST_CRITICAL_SECTION", "ON");
+    }
+
+    // The hardened shim of the `check` feature already randomises the heap, along with every
+    // other mitigation: only the fast shim is configured.
+    if config.features.randomize && !cfg!(feature = "check") {
+        config.builder.define("SNMALLOC_RUST_MITIGATIONS", RANDOMIZE_MITIGATIONS);
+        #[cfg(feature = "build_cc")]
+        config.builder.define("SNMALLOC_CHECK_CLIENT_MITIGATIONS", RANDOMIZE_MITIGATIONS);
+    }
+
+    // cmake builds both slices of a universal library natively; cc builds them one by one, see
+    // `build_universal_macos`.
+    #[cfg(not(feature = "build_cc"))]
+    if config.is_universal_macos() {
+        config.builder.define("CMAKE_OSX_ARCHITECTURES", "arm64;x86_64");
+    }
+
+    // cc passes `-arm64EC` itself; cmake-rs has no Visual Studio platform for ARM64EC, so build
+    // with Ninja and let the shim add the flags.
+    if config.is_msvc() && config.is_arm64ec() {
+        config.builder.define("SNMALLOC_RUST_ARM64EC", "ON");
+        #[cfg(not(feature = "build_cc"))]
+        config.builder.generator("Ninja");
+    }
+
     // Platform-specific configurations
     match () {
         _ if config.is_windows() => {
@@ -341,7 +1015,7 @@ fn configure_platform(config: &mut BuildConfig) {
         _ if config.is_msvc() => {
             let msvc_flags = vec![
                 "/nologo", "/W4", "/WX", "/wd4127", "/wd4324", "/wd4201",
-                "/Ob2", "/DNDEBUG", "/EHsc", "/Gd", "/TP", "/Gm-", "/GS",
+                "/Ob2", "/EHsc", "/Gd", "/TP", "/Gm-", "/GS",
                 "/fp:precise", "/Zc:wchar_t", "/Zc:forScope", "/Zc:inline"
             ];
             for flag in msvc_flags {
@@ -360,16 +1034,52 @@ fn configure_platform(config: &mut BuildConfig) {
                 .define("CMAKE_CXX_FLAGS_RELEASE", "/O2 /Ob2 /DNDEBUG /EHsc")
                 .define("CMAKE_C_FLAGS_RELEASE", "/O2 /Ob2 /DNDEBUG /EHsc");
         }
+        _ if config.is_emscripten() => {
+            let emscripten_flags = vec!["-fno-exceptions", "-fno-rtti", "-Wno-unused-parameter"];
+            for flag in emscripten_flags {
+                config.builder.flag_if_supported(flag);
+            }
+
+            // Rust only enables shared memory for wasm when the `atomics` target feature is set;
+            // otherwise the module is single-threaded and must not be built against pthreads.
+            if config.has_target_feature("atomics") {
+                config.builder.flag_if_supported("-pthread");
+                #[cfg(not(feature = "build_cc"))]
+                config.builder.define("SNMALLOC_RUST_PLATFORM_FLAGS", "-pthread");
+                println!("cargo:rustc-link-arg=-pthread");
+            }
+        }
+        _ if config.is_haiku() => {
+            // Haiku's runtime loader does not support the static TLS models, and threads live in libroot.
+            let haiku_flags = vec!["-fPIC", "-fno-exceptions", "-fno-rtti", "-Wno-unused-parameter"];
+            for flag in haiku_flags {
+                config.builder.flag_if_supported(flag);
+            }
+        }
+        _ if config.is_solarish() => {
+            // `__EXTENSIONS__` exposes `madvise` and friends, which the strict C++ standard modes hide.
+            let solarish_flags = vec!["-fPIC", "-pthread", "-fno-exceptions", "-fno-rtti", "-Wno-unused-parameter", "-D__EXTENSIONS__"];
+            for flag in solarish_flags {
+                config.builder.flag_if_supported(flag);
+            }
+            // The flags above only reach cc: cmake defines it on the shims through the option.
+            #[cfg(not(feature = "build_cc"))]
+            config.builder.define("SNMALLOC_RUST_SOLARIS_EXTENSIONS", "ON");
+
+            config.builder.flag_if_supported(config.tls_model());
+        }
         _ if config.is_unix() => {
-            let unix_flags = vec!["-fPIC", "-pthread", "-fno-exceptions", "-fno-rtti", "-mcx16", "-Wno-unused-parameter"];
+            let unix_flags = vec!["-fPIC", "-pthread", "-fno-exceptions", "-fno-rtti", "-Wno-unused-parameter"];
             for flag in unix_flags {
                 config.builder.flag_if_supported(flag);
             }
-
-            if config.target_os != "haiku" {
-                let tls_model = if config.features.local_dynamic_tls { "-ftls-model=local-dynamic" } else { "-ftls-model=initial-exec" };
-                config.builder.flag_if_supported(tls_model);
+            // Only x86 has a separate switch for 16-byte compare-and-swap; elsewhere (e.g. s390x and
+            // big-endian ppc64) GCC calls into libatomic, which is linked below.
+            if config.is_x86() {
+                config.builder.flag_if_supported("-mcx16");
             }
+
+            config.builder.flag_if_supported(config.tls_model());
         }
         _ => {}
     }
@@ -377,16 +1087,57 @@ fn configure_platform(config: &mut BuildConfig) {
     // Feature configurations
     config.builder
         .define("SNMALLOC_QEMU_WORKAROUND", if config.features.qemu { "ON" } else { "OFF" })
-        .define("SNMALLOC_ENABLE_DYNAMIC_LOADING", if config.features.notls { "ON" } else { "OFF" })
+        .define("SNMALLOC_ENABLE_DYNAMIC_LOADING", if config.features.notls || config.features.dynamic_loading { "ON" } else { "OFF" })
         .define("SNMALLOC_USE_WAIT_ON_ADDRESS", if config.features.wait_on_address { "1" } else { "0" })
         .define("USE_SNMALLOC_STATS", if config.features.stats { "ON" } else { "OFF" });
 
-    // Android configuration
-    if config.target.contains("android") {
-        let ndk = env::var("ANDROID_NDK").expect("ANDROID_NDK environment variable not set");
+    // no_std binaries linked with `/NODEFAULTLIB`: the shim must not reference the CRT, whose few
+    // remaining pieces (static TLS, thread-local destructors, fatal error reports) it provides.
+    if config.features.no_crt && config.is_msvc() {
         config.builder
-            .define("CMAKE_TOOLCHAIN_FILE", &*format!("{}/build/cmake/android.toolchain.cmake", ndk))
-            .define("ANDROID_PLATFORM", &*env::var("ANDROID_PLATFORM").unwrap_or_default());
+            .flag_if_supported("/Zl")
+            .flag_if_supported("/GS-")
+            .flag_if_supported("/EHs-c-")
+            .flag_if_supported("/Zc:threadSafeInit-")
+            .flag_if_supported("/Zc:tlsGuards-")
+            .define("SNMALLOC_RUST_NO_CRT", "ON");
+        #[cfg(feature = "build_cc")]
+        config.builder.define("_HAS_EXCEPTIONS", "0");
+    }
+
+    // Emscripten configuration
+    #[cfg(not(feature = "build_cc"))]
+    if config.is_emscripten() && user_toolchain_file().is_none() {
+        let toolchain = match (env::var("EMSDK"), env::var("EMSCRIPTEN")) {
+            (_, Ok(emscripten)) => format!("{}/cmake/Modules/Platform/Emscripten.cmake", emscripten),
+            (Ok(emsdk), _) => format!("{}/upstream/emscripten/cmake/Modules/Platform/Emscripten.cmake", emsdk),
+            _ => panic!("EMSDK or EMSCRIPTEN environment variable must be set to build for emscripten"),
+        };
+        config.builder.define("CMAKE_TOOLCHAIN_FILE", &*toolchain);
+    }
+
+}
+
+
+/// Toolchain, ABI and TLS set-up of the mobile targets: Android through the NDK and OpenHarmony
+/// (`*-linux-ohos`) through its native SDK, unless a toolchain file is set.
+fn configure_mobile(config: &mut BuildConfig) {
+    for var in ["ANDROID_NDK", "ANDROID_PLATFORM", "ANDROID_API_LEVEL", "OHOS_NDK_HOME", "OHOS_SDK_NATIVE"] {
+        println!("cargo:rerun-if-env-changed={}", var);
+    }
+    if config.is_android() {
+        if user_toolchain_file().is_none() {
+            let ndk = env::var("ANDROID_NDK").expect("ANDROID_NDK environment variable not set");
+            let toolchain = format!("{}/build/cmake/android.toolchain.cmake", ndk);
+            #[cfg(not(feature = "build_cc"))]
+            if !std::path::Path::new(&toolchain).exists() {
+                panic!("ANDROID_NDK does not point to an NDK: {} is missing", toolchain);
+            }
+            config.builder.define("CMAKE_TOOLCHAIN_FILE", &*toolchain);
+        }
+        if let Some(level) = config.android_api_level() {
+            config.builder.define("ANDROID_PLATFORM", &*format!("android-{}", level));
+        }
 
         if cfg!(feature = "android-lld") {
             config.builder.define("ANDROID_LD", "lld");
@@ -405,62 +1156,310 @@ fn configure_platform(config: &mut BuildConfig) {
         if let Some(mode) = arm_mode {
             config.builder.define("ANDROID_ARM_MODE", mode);
         }
+
+        // Chosen explicitly, so that the shim agrees with the API level whatever the default of
+        // the compiler: emutls needs no loader support, but ignores the TLS model.
+        let tls = if config.android_elf_tls() { "-fno-emulated-tls" } else { "-femulated-tls" };
+        config.builder.flag_if_supported(tls);
+        #[cfg(not(feature = "build_cc"))]
+        config.builder.define("SNMALLOC_RUST_PLATFORM_FLAGS", tls);
+    }
+
+    if config.is_ohos() {
+        if user_toolchain_file().is_none() {
+            // `OHOS_NDK_HOME` is the SDK holding `native/`, `OHOS_SDK_NATIVE` the `native/` directory itself.
+            let native = match (env::var("OHOS_SDK_NATIVE"), env::var("OHOS_NDK_HOME")) {
+                (Ok(native), _) => native,
+                (_, Ok(home)) => format!("{}/native", home),
+                _ => panic!("OHOS_NDK_HOME or OHOS_SDK_NATIVE environment variable must be set to build for OpenHarmony"),
+            };
+            config.builder.define("CMAKE_TOOLCHAIN_FILE", &*format!("{}/build/cmake/ohos.toolchain.cmake", native));
+        }
+        let arch = match config.target_arch().as_str() {
+            "aarch64" => "arm64-v8a",
+            "arm" => "armeabi-v7a",
+            "x86_64" => "x86_64",
+            _ => panic!("Unsupported OpenHarmony architecture: {}", config.target),
+        };
+        config.builder
+            .define("OHOS_ARCH", arch)
+            .define("OHOS_PLATFORM", "OHOS")
+            .define("OHOS_STL", "c++_static");
+    }
+}
+
+/// Exports the symbol prefix to the Rust declarations, and with the `prefix-symbols` feature
+/// writes `sn_rust_prefix.h`, mapping each function of `sn_rust.h`, and the hooks of
+/// `sn_rust_critical.h`, to its prefixed name, and has the shim renamed with it.
+fn configure_symbol_prefix(config: &mut BuildConfig) {
+    let prefix = config.symbol_prefix();
+    println!("cargo:rustc-env=SNMALLOC_SYS_SYMBOL_PREFIX={}", prefix.as_deref().unwrap_or_default());
+    let Some(prefix) = prefix else {
+        return;
+    };
+    let header = fs::read_to_string("shim/sn_rust.h").expect("cannot read shim/sn_rust.h");
+    let mut defines = String::from("// Generated by the build script of snmalloc-sys, see sn_rust.h.\n#pragma once\n\n");
+    for sig in abi::header_signatures(&header) {
+        defines += &format!("#define {} {}{}\n", sig.name, prefix, sig.name);
+    }
+    // Defined by `src/critical.rs` under the same prefix, so that two copies do not clash.
+    for hook in ["sn_rust_critical_enter", "sn_rust_critical_leave"] {
+        defines += &format!("#define {} {}{}\n", hook, prefix, hook);
+    }
+    let path = std::path::Path::new(&config.out_dir).join("sn_rust_prefix.h");
+    fs::write(&path, defines).expect("cannot write sn_rust_prefix.h");
+    let path = path.display().to_string().replace('\\', "/");
+
+    #[cfg(not(feature = "build_cc"))]
+    config.builder
+        .define("SNMALLOC_RUST_SYMBOL_PREFIX", &prefix)
+        .define("SNMALLOC_RUST_PREFIX_HEADER", &path);
+    #[cfg(feature = "build_cc")]
+    {
+        if config.is_msvc() {
+            config.builder.flag(format!("/FI{}", path));
+        } else {
+            config.builder.flag("-include").flag(&path);
+        }
+        config.builder.define("snmalloc", format!("{}snmalloc", prefix).as_str());
     }
 }
 
+/// Writes `size_classes.rs`, the table of small size classes of the shim for the Rust-side
+/// rounding of `size_classes`. It follows snmalloc's `sizeclass_to_size`: two intermediate bits
+/// between powers of two, a 16-byte minimum and classes up to 64KiB. The table is left empty on
+/// 32-bit targets, whose minimum differs, so that they always ask the shim. The limits of the
+/// shim follow: `MIN_ALLOC_SIZE` (two pointers) and the address bits of snmalloc's AAL (48 on
+/// 64-bit architectures, all 32 otherwise).
+fn write_size_classes(config: &BuildConfig) {
+    const INTERMEDIATE_BITS: u32 = 2;
+    const MIN_ALLOC_BITS: u32 = 4;
+    const MAX_SMALL_SIZECLASS_BITS: u32 = 16;
+    let mut classes = Vec::new();
+    if !config.is_32bit() {
+        for m_e in 1usize.. {
+            let mantissa = m_e & ((1 << INTERMEDIATE_BITS) - 1);
+            let exponent = m_e >> INTERMEDIATE_BITS;
+            let leading = usize::from(exponent != 0);
+            let size = (mantissa + (leading << INTERMEDIATE_BITS)) << (exponent - leading + MIN_ALLOC_BITS as usize);
+            if size > 1 << MAX_SMALL_SIZECLASS_BITS {
+                break;
+            }
+            classes.push(size.to_string());
+        }
+    }
+    let (min_alloc_size, address_bits) = if config.is_32bit() { (8, 32) } else { (16, 48) };
+    let table = format!(
+        "// Generated by the build script of snmalloc-sys.\npub const SIZE_CLASSES: [usize; {}] = [{}];\n\
+         pub const MIN_ALLOC_SIZE: usize = {};\npub const ADDRESS_BITS: u32 = {};\n",
+        classes.len(),
+        classes.join(", "),
+        min_alloc_size,
+        address_bits
+    );
+    let path = std::path::Path::new(&config.out_dir).join("size_classes.rs");
+    fs::write(path, table).expect("cannot write size_classes.rs");
+}
 
-fn configure_linking(config: &BuildConfig) {
+/// Looks for the archive of `lib` under the output directory, wherever the builder put it.
+fn find_archive(config: &BuildConfig, lib: &str) -> Option<std::path::PathBuf> {
+    fn find(dir: &std::path::Path, names: &[String]) -> Option<std::path::PathBuf> {
+        for entry in fs::read_dir(dir).ok()?.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                if let Some(found) = find(&path, names) {
+                    return Some(found);
+                }
+            } else if names.iter().any(|name| path.file_name().is_some_and(|f| f == name.as_str())) {
+                return Some(path);
+            }
+        }
+        None
+    }
+
+    find(std::path::Path::new(&config.out_dir), &[format!("{}.lib", lib), format!("lib{}.a", lib)])
+}
+
+/// Checks that the archive carries the Control Flow Guard tables (`.gfids` sections), which
+/// a CFG-enforcing loader needs to accept indirect calls into the shim.
+fn verify_control_flow_guard(config: &BuildConfig) {
+    let archive = find_archive(config, &config.target_lib)
+        .unwrap_or_else(|| panic!("control-flow-guard: cannot find the {} archive in {}", config.target_lib, config.out_dir));
+    let bytes = fs::read(&archive).expect("control-flow-guard: cannot read the archive");
+    if !bytes.windows(6).any(|w| w == b".gfids") {
+        panic!(
+            "control-flow-guard: {} has no Control Flow Guard tables; check that the C++ compiler supports /guard:cf",
+            archive.display()
+        );
+    }
+}
+
+/// Checks that the archives define every function of `sn_rust.h`, so that a function the shim
+/// lost or renamed fails the build of this crate instead of the link of a downstream binary.
+fn verify_shim_symbols(config: &BuildConfig) {
+    // LTO archives hold bitcode, whose symbol tables this cannot read.
+    if config.features.lto {
+        return;
+    }
+    let header = fs::read_to_string("shim/sn_rust.h").expect("cannot read shim/sn_rust.h");
+    let mut expected: Vec<(&str, String)> = Vec::new();
+    for sig in abi::header_signatures(&header) {
+        let lib = if sig.name.starts_with("snc_rust_") {
+            if !config.features.checked_handles {
+                continue;
+            }
+            "snmallocshim-checks-rust"
+        } else if (sig.name == "sn_rust_operator_new_is_snmalloc" && !config.features.cxx_new)
+            || !config.defines_shim_function(&sig.name)
+        {
+            continue;
+        } else {
+            config.target_lib.as_str()
+        };
+        expected.push((lib, format!("{}{}", config.symbol_prefix().unwrap_or_default(), sig.name)));
+    }
+    for lib in [config.target_lib.as_str(), "snmallocshim-checks-rust"] {
+        let Some(archive) = expected.iter().any(|(l, _)| *l == lib).then(|| find_archive(config, lib)).flatten() else {
+            continue;
+        };
+        let bytes = fs::read(&archive).expect("cannot read the shim archive");
+        // Symbol names end with a NUL in the string tables of ELF, Mach-O and COFF archives.
+        let symbols: std::collections::HashSet<&[u8]> = bytes
+            .split(|b| *b == 0)
+            .map(|name| {
+                let start = name.iter().rposition(|b| !(b.is_ascii_alphanumeric() || *b == b'_')).map_or(0, |i| i + 1);
+                &name[start..]
+            })
+            .collect();
+        // Mach-O and 32-bit Windows symbols carry a leading underscore.
+        let missing: Vec<&str> = expected
+            .iter()
+            .filter(|(l, name)| {
+                *l == lib && !symbols.contains(name.as_bytes()) && !symbols.contains(format!("_{}", name).as_bytes())
+            })
+            .map(|(_, name)| name.as_str())
+            .collect();
+        if !missing.is_empty() {
+            panic!(
+                "{} does not define {}, declared by shim/sn_rust.h: the shim and its declarations are out of sync",
+                archive.display(),
+                missing.join(", ")
+            );
+        }
+    }
+}
+
+/// Builds the shim for both macOS architectures and merges the archives with `lipo` into the
+/// library cargo links, for universal binaries.
+#[cfg(feature = "build_cc")]
+fn build_universal_macos(config: &BuildConfig) -> std::path::PathBuf {
+    let mut slices = Vec::new();
+    for triple in ["aarch64-apple-darwin", "x86_64-apple-darwin"] {
+        let dir = format!("{}/{}", config.out_dir, triple);
+        fs::create_dir_all(&dir).expect("universal-macos: cannot create the slice directory");
+        config.builder.clone()
+            .target(triple)
+            .out_dir(&dir)
+            .cargo_metadata(false)
+            .compile(&config.target_lib);
+        slices.push(format!("{}/lib{}.a", dir, config.target_lib));
+    }
+    let output = format!("{}/lib{}.a", config.out_dir, config.target_lib);
+    let status = std::process::Command::new("lipo")
+        .arg("-create")
+        .arg("-output")
+        .arg(&output)
+        .args(&slices)
+        .status()
+        .unwrap_or_else(|err| panic!("universal-macos: cannot run lipo ({}), install the Xcode command line tools", err));
+    if !status.success() {
+        panic!("universal-macos: lipo failed to merge {}", slices.join(" "));
+    }
+    std::path::PathBuf::from(&config.out_dir)
+}
 
+/// Links the system libraries the shim depends on, returning them for the package files.
+fn configure_linking(config: &BuildConfig) -> Vec<&'static str> {
+    let mut libs = Vec::new();
     match () {
+        _ if config.is_emscripten() => {
+            // em++ links its own libc++ and libc as part of the final wasm link.
+        }
         _ if config.is_msvc() => {
             // Windows MSVC specific libraries
             if !config.features.win8compat {
-                println!("cargo:rustc-link-lib=mincore");
+                libs.push("mincore");
+            }
+            // Without the CRT, only what the Windows PAL calls: `VirtualAlloc2` lives in mincore,
+            // `WaitOnAddress` in synchronization and the entropy source in bcrypt.
+            if config.features.no_crt {
+                libs.extend(["kernel32", "synchronization", "bcrypt"]);
+            } else {
+                // Essential Windows libraries
+                libs.push("kernel32");
+                libs.push("user32");
+                libs.push("advapi32");
+                libs.push("ws2_32");
+                libs.push("userenv");
+                libs.push("bcrypt");
+                libs.push("msvcrt");
             }
-            // Essential Windows libraries
-            println!("cargo:rustc-link-lib=kernel32");
-            println!("cargo:rustc-link-lib=user32");
-            println!("cargo:rustc-link-lib=advapi32");
-            println!("cargo:rustc-link-lib=ws2_32");
-            println!("cargo:rustc-link-lib=userenv");
-            println!("cargo:rustc-link-lib=bcrypt");
-            println!("cargo:rustc-link-lib=msvcrt");
         }
         _ if config.is_windows() && config.is_gnu() => {
-            println!("cargo:rustc-link-lib=kernel32");
-            println!("cargo:rustc-link-lib=bcrypt");
-            println!("cargo:rustc-link-lib=winpthread");
+            libs.push("kernel32");
+            libs.push("bcrypt");
+            libs.push("winpthread");
 
             if config.is_clang_msys() {
-                println!("cargo:rustc-link-lib=c++");
+                libs.push("c++");
             } else if config.is_ucrt64() {
-                println!("cargo:rustc-link-lib=stdc++");
+                libs.push("stdc++");
             } else {
-                println!("cargo:rustc-link-lib=stdc++");
-                println!("cargo:rustc-link-lib=atomic");
+                libs.push("stdc++");
+                libs.push("atomic");
             }
         }
         _ if cfg!(target_os = "freebsd") => {
-            println!("cargo:rustc-link-lib=c++");
+            libs.push("c++");
+        }
+        // LLVM's libc++, linked statically like the NDK does, and a musl libc holding pthreads,
+        // librt, libdl and libm.
+        _ if config.is_ohos() => {
+            libs.push("c++_static");
+            libs.push("c++abi");
         }
         _ if config.is_linux() => {
-            println!("cargo:rustc-link-lib=atomic");
-            println!("cargo:rustc-link-lib=stdc++");
-            println!("cargo:rustc-link-lib=pthread");
-            println!("cargo:rustc-link-lib=c");
-            println!("cargo:rustc-link-lib=gcc_s");
-            println!("cargo:rustc-link-lib=util");
-            println!("cargo:rustc-link-lib=rt");
-            println!("cargo:rustc-link-lib=dl");
-            println!("cargo:rustc-link-lib=m");
+            libs.push("atomic");
+            libs.push("stdc++");
+            libs.push("pthread");
+            libs.push("c");
+            if !config.features.no_unwind {
+                libs.push("gcc_s");
+            }
+            libs.push("util");
+            libs.push("rt");
+            libs.push("dl");
+            libs.push("m");
             
             if cfg!(feature = "usecxx17") && !config.is_clang_msys() {
-                println!("cargo:rustc-link-lib=gcc");
+                libs.push("gcc");
             }
         }
+        _ if config.is_haiku() => {
+            // pthreads, librt and libm are all part of libroot.
+            libs.push("stdc++");
+        }
+        _ if config.is_solarish() => {
+            libs.push("stdc++");
+            // 16-byte atomics are not inlined by GCC, and `rt`/`socket` are still separate libraries on Solaris.
+            libs.push("atomic");
+            libs.push("rt");
+            libs.push("socket");
+            libs.push("pthread");
+        }
         _ if config.is_unix() && !cfg!(any(target_os = "macos", target_os = "freebsd")) => {
             if config.is_gnu() {
-                println!("cargo:rustc-link-lib=c_nonshared");
+                libs.push("c_nonshared");
             }
         }
         _ if !config.is_windows() => {
@@ -469,10 +1468,274 @@ fn configure_linking(config: &BuildConfig) {
             } else {
                 "stdc++"
             };
-            println!("cargo:rustc-link-lib={}", cxxlib);
+            libs.push(cxxlib);
         }
         _ => {}
     }
+    for lib in &libs {
+        println!("cargo:rustc-link-lib={}", lib);
+    }
+    libs
+}
+
+/// Writes `snmalloc-rust.pc` and `snmalloc-rust-config.cmake` to the output directory, describing
+/// the archives just built, so that C and C++ builds can link the exact same artifacts. Dependent
+/// build scripts find the directory in `DEP_SNMALLOC_PACKAGE_DIR`.
+fn export_package(config: &BuildConfig, system_libs: &[&str]) {
+    let mut libs = vec![config.target_lib.as_str()];
+    if config.features.checked_handles {
+        libs.push("snmallocshim-checks-rust");
+    }
+    let Some(archives) = libs.iter().map(|lib| find_archive(config, lib)).collect::<Option<Vec<_>>>() else {
+        println!("cargo:warning=snmalloc-sys: cannot find the shim archives, no package files written");
+        return;
+    };
+    let libdir = archives[0].parent().unwrap().display().to_string().replace('\\', "/");
+    let includedir = format!("{}/shim", env::var("CARGO_MANIFEST_DIR").unwrap_or_default()).replace('\\', "/");
+    let version = env::var("CARGO_PKG_VERSION").unwrap_or_default();
+
+    // The generated `sn_rust_prefix.h` sits in the output directory.
+    let (cflags, include_dirs, definitions) = match config.features.prefix_symbols {
+        true => {
+            let out_dir = config.out_dir.replace('\\', "/");
+            (format!("-I${{includedir}} -I{} -DSN_RUST_PREFIXED", out_dir), format!("{};{}", includedir, out_dir), "SN_RUST_PREFIXED")
+        }
+        false => ("-I${includedir}".to_string(), includedir.clone(), ""),
+    };
+
+    let link_flags: Vec<_> = libs.iter().chain(system_libs).map(|lib| format!("-l{}", lib)).collect();
+    let pc = format!(
+        "libdir={}\nincludedir={}\n\nName: snmalloc-rust\nDescription: snmalloc with the snmalloc-rs shim, as built by snmalloc-sys\nVersion: {}\nLibs: -L${{libdir}} {}\nCflags: {}\n",
+        libdir, includedir, version, link_flags.join(" "), cflags
+    );
+
+    let mut cmake = format!("# Generated by snmalloc-sys {}.\n", version);
+    for (target, archive) in ["snmalloc-rust::shim", "snmalloc-rust::checks"].iter().zip(&archives) {
+        cmake += &format!(
+            "if(NOT TARGET {target})\n  add_library({target} STATIC IMPORTED)\n  set_target_properties({target} PROPERTIES\n    IMPORTED_LOCATION \"{}\"\n    INTERFACE_INCLUDE_DIRECTORIES \"{}\"\n    INTERFACE_COMPILE_DEFINITIONS \"{}\"\n    INTERFACE_LINK_LIBRARIES \"{}\")\nendif()\n",
+            archive.display().to_string().replace('\\', "/"),
+            include_dirs,
+            definitions,
+            system_libs.join(";"),
+        );
+    }
+    cmake += &format!("set(snmalloc-rust_VERSION {})\n", version);
+
+    fs::write(format!("{}/snmalloc-rust.pc", config.out_dir), pc).expect("cannot write snmalloc-rust.pc");
+    fs::write(format!("{}/snmalloc-rust-config.cmake", config.out_dir), cmake)
+        .expect("cannot write snmalloc-rust-config.cmake");
+    println!("cargo:package_dir={}", config.out_dir);
+}
+
+/// Exposes the shared library built with the `dylib` feature to dependent build scripts in
+/// `DEP_SNMALLOC_DYLIB`, e.g. to package it or to preload it in tests.
+fn export_dylib(config: &BuildConfig) {
+    let names = ["libsnmallocshim-rust.so", "libsnmallocshim-rust.dylib", "snmallocshim-rust.dll", "libsnmallocshim-rust.dll"];
+    let dir = std::path::PathBuf::from(config.dylib_dir());
+    match names.iter().map(|name| dir.join(name)).find(|path| path.exists()) {
+        Some(path) => println!("cargo:dylib={}", path.display()),
+        None => println!("cargo:warning=snmalloc-sys: cannot find the shared library in {}", dir.display()),
+    }
+}
+
+/// Minimum version of snmalloc the shim is known to build against, checked out or installed.
+const MIN_SNMALLOC_VERSION: &str = "0.7.0";
+
+/// Returns the snmalloc checkout to build, and whether it was overridden: `SNMALLOC_SRC_DIR`, e.g.
+/// a local mirror for offline builds, or else the submodule.
+fn snmalloc_source_dir() -> (String, bool) {
+    println!("cargo:rerun-if-env-changed=SNMALLOC_SRC_DIR");
+    match env::var("SNMALLOC_SRC_DIR") {
+        Ok(dir) if !dir.is_empty() => (dir.trim_end_matches(['/', '\\']).to_string(), true),
+        _ => {
+            let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+            (format!("{}/snmalloc", manifest_dir), false)
+        }
+    }
+}
+
+/// Returns the version declared by the `project()` of the top-level `CMakeLists.txt` of an
+/// snmalloc checkout, if any.
+fn snmalloc_version(source_dir: &str) -> Option<(u32, u32, u32)> {
+    let cmake = fs::read_to_string(std::path::Path::new(source_dir).join("CMakeLists.txt")).ok()?;
+    let project = &cmake[cmake.find("project(")?..];
+    let project = &project[..project.find(')')?];
+    let mut words = project.split_whitespace();
+    words.find(|word| *word == "VERSION")?;
+    parse_version(words.next()?)
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = version.split('.').map(|part| part.parse().ok());
+    Some((parts.next()??, parts.next().flatten().unwrap_or(0), parts.next().flatten().unwrap_or(0)))
+}
+
+/// Locates the snmalloc checkout to build and validates it, returning the include directory and
+/// the shim source.
+#[cfg(not(feature = "system-snmalloc"))]
+fn locate_vendored_snmalloc() -> (String, String) {
+    let (source_dir, overridden) = snmalloc_source_dir();
+    let hint = match overridden {
+        true => "SNMALLOC_SRC_DIR must point to a checkout of https://github.com/microsoft/snmalloc",
+        false => "check out the submodule with `git submodule update --init`, or point SNMALLOC_SRC_DIR to a \
+                  checkout of https://github.com/microsoft/snmalloc",
+    };
+    let source = std::path::Path::new(&source_dir);
+    if !source.join("src/snmalloc/snmalloc.h").exists() {
+        panic!("snmalloc-sys: no snmalloc sources in {} ({}/src/snmalloc/snmalloc.h is missing): {}.", source_dir, source_dir, hint);
+    }
+    let shim_source = format!("{}/src/snmalloc/override/rust.cc", source_dir);
+    let too_old = |found: &str| panic!(
+        "snmalloc-sys: the snmalloc checkout in {} is {}, the shim needs snmalloc >= {}.",
+        source_dir, found, MIN_SNMALLOC_VERSION
+    );
+    match snmalloc_version(&source_dir) {
+        Some(version) if version < parse_version(MIN_SNMALLOC_VERSION).unwrap() => {
+            too_old(&format!("version {}.{}.{}", version.0, version.1, version.2))
+        }
+        Some(_) => {}
+        None => println!(
+            "cargo:warning=snmalloc-sys: cannot read the version of the snmalloc checkout in {}, assuming >= {}",
+            source_dir, MIN_SNMALLOC_VERSION
+        ),
+    }
+    if !std::path::Path::new(&shim_source).exists() {
+        too_old("missing src/snmalloc/override/rust.cc");
+    }
+    (format!("{}/src", source_dir), shim_source)
+}
+
+/// Locates the headers of a system-installed snmalloc, either under `SNMALLOC_ROOT` or through
+/// pkg-config, and the `rust.cc` shim to compile against them.
+/// Returns the include directory and the shim source.
+#[cfg(feature = "system-snmalloc")]
+fn locate_system_snmalloc() -> (String, String) {
+    let include_dir = match env::var("SNMALLOC_ROOT") {
+        Ok(root) => {
+            let root = root.trim_end_matches(['/', '\\']);
+            match installed_snmalloc_version(root) {
+                Some(version) if version >= parse_version(MIN_SNMALLOC_VERSION).unwrap() => {}
+                Some(version) => panic!(
+                    "system-snmalloc: the snmalloc installed in {} is version {}.{}.{}, the shim needs snmalloc >= {}. \
+                     Install a newer snmalloc, or point SNMALLOC_ROOT to another installation prefix.",
+                    root, version.0, version.1, version.2, MIN_SNMALLOC_VERSION
+                ),
+                None => panic!(
+                    "system-snmalloc: cannot read the version of the snmalloc installed in {} (neither \
+                     lib/pkgconfig/snmalloc.pc nor lib/cmake/snmalloc/snmalloc-config-version.cmake exists). \
+                     Install snmalloc >= {} with its package files, or unset SNMALLOC_ROOT to locate it with pkg-config.",
+                    root, MIN_SNMALLOC_VERSION
+                ),
+            }
+            format!("{}/include", root)
+        }
+        Err(_) => {
+            let library = pkg_config::Config::new()
+                .atleast_version(MIN_SNMALLOC_VERSION)
+                .cargo_metadata(false)
+                .probe("snmalloc")
+                .unwrap_or_else(|err| panic!(
+                    "system-snmalloc: could not find snmalloc >= {} with pkg-config ({}). \
+                     Install snmalloc, add its `snmalloc.pc` to PKG_CONFIG_PATH, or set SNMALLOC_ROOT \
+                     to its installation prefix.",
+                    MIN_SNMALLOC_VERSION, err
+                ));
+            library.include_paths.first()
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|| "/usr/include".to_string())
+        }
+    };
+    println!("cargo:rerun-if-env-changed=SNMALLOC_ROOT");
+
+    if !std::path::Path::new(&include_dir).join("snmalloc/snmalloc.h").exists() {
+        panic!(
+            "system-snmalloc: {}/snmalloc/snmalloc.h does not exist. \
+             SNMALLOC_ROOT must point to the installation prefix of snmalloc >= {}.",
+            include_dir, MIN_SNMALLOC_VERSION
+        );
+    }
+
+    // The shim must come with the installed headers: the vendored one may not match them.
+    let shim_source = format!("{}/snmalloc/override/rust.cc", include_dir);
+    if !std::path::Path::new(&shim_source).exists() {
+        panic!(
+            "system-snmalloc: {} is not installed, so the shim cannot be built against these headers. \
+             Reinstall snmalloc >= {} with its override sources, or drop the \
+             `system-snmalloc` feature to build the vendored sources.",
+            shim_source, MIN_SNMALLOC_VERSION
+        );
+    }
+    (include_dir, shim_source)
+}
+
+/// Returns the version of the snmalloc installed under the prefix `root`, from its pkg-config or
+/// CMake package files.
+#[cfg(feature = "system-snmalloc")]
+fn installed_snmalloc_version(root: &str) -> Option<(u32, u32, u32)> {
+    let root = std::path::Path::new(root);
+    for lib in ["lib", "lib64", "share"] {
+        if let Ok(pc) = fs::read_to_string(root.join(lib).join("pkgconfig/snmalloc.pc")) {
+            let version = pc.lines().find_map(|line| line.strip_prefix("Version:"));
+            if let Some(version) = version.and_then(|version| parse_version(version.trim())) {
+                return Some(version);
+            }
+        }
+        for file in ["snmalloc-config-version.cmake", "snmallocConfigVersion.cmake"] {
+            if let Ok(cmake) = fs::read_to_string(root.join(lib).join("cmake/snmalloc").join(file)) {
+                let version = cmake.lines().find_map(|line| line.trim().strip_prefix("set(PACKAGE_VERSION "));
+                if let Some(version) = version.and_then(|version| parse_version(version.trim_end_matches(')').trim_matches('"'))) {
+                    return Some(version);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Links the generated declarations under the symbol prefix of the shim.
+#[cfg(feature = "bindgen")]
+#[derive(Debug)]
+struct PrefixLinkNames(String);
+
+#[cfg(feature = "bindgen")]
+impl bindgen::callbacks::ParseCallbacks for PrefixLinkNames {
+    fn generated_link_name_override(&self, item: bindgen::callbacks::ItemInfo<'_>) -> Option<String> {
+        abi::is_shim(item.name).then(|| format!("{}{}", self.0, item.name))
+    }
+}
+
+#[cfg(feature = "bindgen")]
+fn generate_bindings(config: &BuildConfig) {
+    let prefix = config.symbol_prefix().unwrap_or_default();
+    // bindgen panics instead of returning an error when libclang cannot be loaded.
+    let bindings = std::panic::catch_unwind(|| {
+        bindgen::Builder::default()
+            .header("shim/sn_rust.h")
+            .parse_callbacks(Box::new(PrefixLinkNames(prefix.clone())))
+            .allowlist_function("sn_rust_.*")
+            .allowlist_type("sn_rust_.*")
+            .allowlist_function("snc_rust_.*")
+            .allowlist_type("snc_rust_.*")
+            .use_core()
+            // The crate builds the out-parameters of the shim with `Default`, as with the hand-written
+            // declarations.
+            .derive_default(true)
+            .layout_tests(false)
+            .generate()
+            .map_err(|err| err.to_string())
+    })
+    .unwrap_or_else(|_| Err("libclang is not available".to_string()));
+
+    match bindings {
+        Ok(bindings) => {
+            let path = std::path::Path::new(&config.out_dir).join("bindings.rs");
+            bindings.write_to_file(path).expect("failed to write bindings");
+            println!("cargo:rustc-cfg=snmalloc_sys_bindgen");
+        }
+        Err(err) => {
+            println!("cargo:warning=failed to generate bindings from shim/sn_rust.h ({}), using the checked-in ones", err);
+        }
+    }
 }
 
 #[cfg(feature = "build_cc")]
@@ -480,15 +1743,69 @@ use cc;
 #[cfg(not(feature = "build_cc"))]
 use cmake::Config;
 
+#[cfg(not(any(feature = "build_cc", feature = "build_cmake")))]
+compile_error!("snmalloc-sys needs a builder: enable `build_cmake` (the default) or `build_cc`");
+
+/// Adds the sources of the shim the features chose to the cc build, sorted by file name: the
+/// archive lists the objects in the order of the files, which must not depend on where the
+/// checkout is nor on the order the features are configured in.
+#[cfg(feature = "build_cc")]
+fn add_sources(config: &mut BuildConfig) {
+    let mut sources = vec![config.shim_source.clone(), "shim/rust_ext.cc".to_string()];
+    if config.features.cxx_new {
+        sources.push("shim/rust_new.cc".to_string());
+    }
+    if config.features.no_crt && config.is_msvc() {
+        sources.push("shim/rust_nocrt.cc".to_string());
+    }
+    sources.sort_by(|a, b| std::path::Path::new(a).file_name().cmp(&std::path::Path::new(b).file_name()));
+    config.builder.files(&sources);
+}
+
 fn main() {
+    // Set before any tool runs, so that the compilers, `ar` and cmake all inherit them: archive
+    // members without timestamps on Apple, and a fixed `__DATE__`/`__TIME__` unless the caller
+    // picked one.
+    if cfg!(feature = "reproducible") {
+        env::set_var("ZERO_AR_DATE", "1");
+        if env::var_os("SOURCE_DATE_EPOCH").is_none() {
+            env::set_var("SOURCE_DATE_EPOCH", "0");
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
     let mut config = BuildConfig::new();
+    check_features(&config);
+
+    println!("cargo:rustc-check-cfg=cfg(snmalloc_sys_bindgen)");
+    println!("cargo:rustc-check-cfg=cfg(snmalloc_sys_dynamic_loading)");
+    println!("cargo:rerun-if-env-changed=SNMALLOC_DYNAMIC_LOADING");
+    if config.features.dynamic_loading {
+        println!("cargo:rustc-cfg=snmalloc_sys_dynamic_loading");
+    }
+    #[cfg(feature = "bindgen")]
+    generate_bindings(&config);
     
     config.builder
-        .configure_cpp(config.debug)
+        .configure_cpp(config.debug_info, config.static_crt(), &config.include_dir, &config.shim_source)
         .configure_output_dir(&config.out_dir);
 
     // Apply all configurations
     configure_platform(&mut config);
+    configure_mobile(&mut config);
+    configure_symbol_prefix(&mut config);
+    write_size_classes(&config);
+    for var in ["SNMALLOC_SYS_CMAKE_ARGS", "SNMALLOC_SYS_PAGE_SIZE", "SNMALLOC_SYS_SYMBOL_PREFIX", "CMAKE_TOOLCHAIN_FILE", "CFLAGS", "CXXFLAGS", "LDFLAGS"] {
+        println!("cargo:rerun-if-env-changed={}", var);
+    }
+    #[cfg(not(feature = "build_cc"))]
+    apply_user_cmake_args(&mut config);
+    #[cfg(feature = "build_cc")]
+    if env::var_os("SNMALLOC_SYS_CMAKE_ARGS").is_some() {
+        println!("cargo:warning=snmalloc-sys: SNMALLOC_SYS_CMAKE_ARGS only applies to the cmake build, the cc build ignores it");
+    }
+
+    #[cfg(feature = "build_cc")]
+    add_sources(&mut config);
 
     // Build and configure output
     println!("cargo:rustc-link-search=/usr/local/lib");
@@ -496,7 +1813,30 @@ fn main() {
     println!("cargo:rustc-link-search={}/build", config.out_dir);
     println!("cargo:rustc-link-search={}/build/Debug", config.out_dir);
     println!("cargo:rustc-link-search={}/build/Release", config.out_dir);
+    println!("cargo:rustc-link-search={}/build/snmalloc", config.out_dir);
+    println!("cargo:rustc-link-search={}/build/snmalloc/Debug", config.out_dir);
+    println!("cargo:rustc-link-search={}/build/snmalloc/Release", config.out_dir);
+    #[cfg(feature = "build_cc")]
+    let mut dst = if config.is_universal_macos() {
+        build_universal_macos(&config)
+    } else {
+        config.builder.build_lib(&config.target_lib)
+    };
+    #[cfg(not(feature = "build_cc"))]
     let mut dst = config.builder.build_lib(&config.target_lib);
     println!("cargo:rustc-link-lib={}", config.target_lib);
-    configure_linking(&config);
+    if config.features.checked_handles {
+        config.builder.build_lib("snmallocshim-checks-rust");
+        println!("cargo:rustc-link-lib=snmallocshim-checks-rust");
+    }
+    if config.features.dylib {
+        config.builder.build_lib("snmallocshim-rust-dylib");
+        export_dylib(&config);
+    }
+    if config.features.control_flow_guard && config.is_windows() {
+        verify_control_flow_guard(&config);
+    }
+    verify_shim_symbols(&config);
+    let system_libs = configure_linking(&config);
+    export_package(&config, &system_libs);
 }
@@ -0,0 +1,183 @@
+// Allocator handles of the hardened shim, built with the `checked-handles`
+// feature next to the fast shim.
+//
+// This file is only compiled into `snmallocshim-checks-rust`, where the
+// `snmalloc` namespace is renamed to `snmalloc_checked` and the upstream
+// functions are prefixed with `snc_`, so that both shims can be linked into the
+// same binary without sharing any symbol. Every function mirrors its `sn_rust_`
+// counterpart in `rust_ext.cc` and is declared in `sn_rust.h`.
+#include "sn_rust.h"
+#include "sn_rust_critical.h"
+
+#include "snmalloc/snmalloc.h"
+
+#include <algorithm>
+#include <cstring>
+#include <functional>
+#include <new>
+
+using namespace snmalloc;
+
+namespace
+{
+  /// Allocates `size` bytes from `alloc` with every byte set to `byte`, in the
+  /// single pass of the allocation: zero goes through snmalloc's zeroing path,
+  /// which skips the fresh pages of the OS, and any other byte is written over
+  /// an object taken without zeroing, while the free-list read has just brought
+  /// its first line into cache. snmalloc has no hook to write another byte from
+  /// within its own fast path.
+  template<typename A>
+  void* alloc_filled(A& alloc, size_t alignment, size_t size, uint8_t byte)
+  {
+    if (byte == 0)
+      return alloc.template alloc<YesZero>(aligned_size(alignment, size));
+    void* p = alloc.template alloc<NoZero>(aligned_size(alignment, size));
+    if (p != nullptr)
+      std::memset(p, byte, size);
+    return p;
+  }
+}
+
+/// A dedicated allocator of the hardened configuration.
+struct snc_rust_allocator
+{
+  Alloc alloc;
+  /// Label for diagnostics, set by `snc_rust_allocator_set_name`.
+  char name[32] = {};
+  size_t name_len = 0;
+  /// Largest request served, from `sn_rust_allocator_config_t`.
+  size_t max_alloc_size = SIZE_MAX;
+};
+
+extern "C" SNMALLOC_EXPORT snc_rust_allocator* snc_rust_allocator_new()
+{
+  SN_RUST_CRITICAL_SECTION();
+  void* mem = ThreadAlloc::get().alloc(
+    aligned_size(alignof(snc_rust_allocator), sizeof(snc_rust_allocator)));
+  if (mem == nullptr)
+    return nullptr;
+  auto* handle = new (mem) snc_rust_allocator();
+  handle->alloc.init();
+  return handle;
+}
+
+extern "C" SNMALLOC_EXPORT snc_rust_allocator* snc_rust_allocator_new_with_config(
+  const sn_rust_allocator_config_t* config)
+{
+  snc_rust_allocator* handle = snc_rust_allocator_new();
+  if (handle == nullptr)
+    return nullptr;
+  if (config->name != nullptr)
+    snc_rust_allocator_set_name(handle, config->name, config->name_len);
+  if (config->max_alloc_size != 0)
+    handle->max_alloc_size = config->max_alloc_size;
+  return handle;
+}
+
+extern "C" SNMALLOC_EXPORT void
+snc_rust_allocator_free(snc_rust_allocator* handle)
+{
+  SN_RUST_CRITICAL_SECTION();
+  handle->alloc.teardown();
+  handle->~snc_rust_allocator();
+  ThreadAlloc::get().dealloc(handle);
+}
+
+extern "C" SNMALLOC_EXPORT void* snc_rust_allocator_allocate(
+  snc_rust_allocator* handle, size_t alignment, size_t size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  if (size > handle->max_alloc_size)
+    return nullptr;
+  return handle->alloc.alloc(aligned_size(alignment, size));
+}
+
+extern "C" SNMALLOC_EXPORT void* snc_rust_allocator_allocate_zeroed(
+  snc_rust_allocator* handle, size_t alignment, size_t size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  if (size > handle->max_alloc_size)
+    return nullptr;
+  return handle->alloc.alloc<YesZero>(aligned_size(alignment, size));
+}
+
+extern "C" SNMALLOC_EXPORT void* snc_rust_allocator_allocate_filled(
+  snc_rust_allocator* handle, size_t alignment, size_t size, uint8_t byte)
+{
+  SN_RUST_CRITICAL_SECTION();
+  if (size > handle->max_alloc_size)
+    return nullptr;
+  return alloc_filled(handle->alloc, alignment, size, byte);
+}
+
+extern "C" SNMALLOC_EXPORT void snc_rust_allocator_deallocate(
+  snc_rust_allocator* handle, void* ptr, size_t alignment, size_t size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  handle->alloc.dealloc(ptr, aligned_size(alignment, size));
+}
+
+extern "C" SNMALLOC_EXPORT void snc_rust_allocator_deallocate_many(
+  snc_rust_allocator* handle, sn_rust_block_t* blocks, size_t count)
+{
+  SN_RUST_CRITICAL_SECTION();
+  std::sort(
+    blocks,
+    blocks + count,
+    [](const sn_rust_block_t& a, const sn_rust_block_t& b) {
+      return std::less<void*>()(a.ptr, b.ptr);
+    });
+  for (size_t i = 0; i < count; i++)
+    handle->alloc.dealloc(
+      blocks[i].ptr, aligned_size(blocks[i].alignment, blocks[i].size));
+}
+
+extern "C" SNMALLOC_EXPORT void* snc_rust_allocator_reallocate(
+  snc_rust_allocator* handle,
+  void* ptr,
+  size_t alignment,
+  size_t old_size,
+  size_t new_size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  if (new_size > handle->max_alloc_size)
+    return nullptr;
+  size_t aligned_old_size = aligned_size(alignment, old_size),
+         aligned_new_size = aligned_size(alignment, new_size);
+  if (
+    size_to_sizeclass_full(aligned_old_size).raw() ==
+    size_to_sizeclass_full(aligned_new_size).raw())
+    return ptr;
+  // On failure the original block must be left untouched and valid: callers
+  // rely on it to stay exception-safe when running out of memory.
+  void* p = handle->alloc.alloc(aligned_new_size);
+  if (p)
+  {
+    std::memcpy(p, ptr, old_size < new_size ? old_size : new_size);
+    handle->alloc.dealloc(ptr, aligned_old_size);
+  }
+  return p;
+}
+
+extern "C" SNMALLOC_EXPORT void
+snc_rust_allocator_flush(snc_rust_allocator* handle)
+{
+  SN_RUST_CRITICAL_SECTION();
+  handle->alloc.flush();
+}
+
+extern "C" SNMALLOC_EXPORT void snc_rust_allocator_set_name(
+  snc_rust_allocator* handle, const char* name, size_t len)
+{
+  len = bits::min(len, sizeof(handle->name) - 1);
+  std::memcpy(handle->name, name, len);
+  handle->name[len] = '\0';
+  handle->name_len = len;
+}
+
+extern "C" SNMALLOC_EXPORT const char*
+snc_rust_allocator_name(const snc_rust_allocator* handle, size_t* len)
+{
+  *len = handle->name_len;
+  return handle->name_len == 0 ? nullptr : handle->name;
+}
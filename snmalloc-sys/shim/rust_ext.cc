@@ -0,0 +1,1453 @@
+// Extensions to the upstream Rust shim (`snmalloc/override/rust.cc`).
+//
+// Everything here is linked into the same static library as the upstream
+// shim and follows its conventions: `sn_rust_` prefixed, C ABI, and sizes are
+// always passed together with the alignment of the original request.
+// Every function defined here must be declared in `sn_rust.h`. The sections
+// only some features call are compiled in by their `SNMALLOC_RUST_*_API`
+// define, see `sn_rust.h`. Functions that reach an allocator or a lock start
+// with `SN_RUST_CRITICAL_SECTION`, see `sn_rust_critical.h`.
+#include "sn_rust.h"
+#include "sn_rust_critical.h"
+#include "sn_rust_lock.h"
+
+#include "snmalloc/snmalloc.h"
+
+#include <algorithm>
+#include <atomic>
+#include <cerrno>
+#include <cstddef>
+#include <cstdio>
+#include <cstdlib>
+#include <cstring>
+#include <functional>
+#include <new>
+#include <type_traits>
+#include <utility>
+
+#if defined(_WIN32)
+#  define WIN32_LEAN_AND_MEAN
+#  include <windows.h>
+#else
+#  include <pthread.h>
+#  include <sys/mman.h>
+#  include <sys/resource.h>
+#  include <unistd.h>
+#endif
+
+#if defined(__linux__) || defined(__APPLE__) || defined(__FreeBSD__) || \
+  defined(__NetBSD__) || defined(__sun)
+#  define SN_RUST_HAS_MINCORE
+#endif
+
+#if !defined(_WIN32) && defined(__has_include)
+#  if __has_include(<unwind.h>)
+#    define SN_RUST_HAS_UNWIND
+#    include <unwind.h>
+#  endif
+#endif
+
+#if defined(__APPLE__)
+#  include <dlfcn.h>
+#  include <mach-o/dyld.h>
+#elif defined(__linux__) || defined(__FreeBSD__) || defined(__NetBSD__) || \
+  defined(__OpenBSD__) || defined(__sun)
+#  define SN_RUST_HAS_DL_ITERATE_PHDR
+#  include <link.h>
+#endif
+
+using namespace snmalloc;
+
+namespace
+{
+  /// Allocates `size` bytes from `alloc` with every byte set to `byte`, in the
+  /// single pass of the allocation: zero goes through snmalloc's zeroing path,
+  /// which skips the fresh pages of the OS, and any other byte is written over
+  /// an object taken without zeroing, while the free-list read has just brought
+  /// its first line into cache. snmalloc has no hook to write another byte from
+  /// within its own fast path.
+  template<typename A>
+  void* alloc_filled(A& alloc, size_t alignment, size_t size, uint8_t byte)
+  {
+    if (byte == 0)
+      return alloc.template alloc<YesZero>(aligned_size(alignment, size));
+    void* p = alloc.template alloc<NoZero>(aligned_size(alignment, size));
+    if (p != nullptr)
+      std::memset(p, byte, size);
+    return p;
+  }
+
+  /// Frees `count` blocks through `alloc`, sorted by address first: the blocks
+  /// of a slab then follow each other, so that its metadata is brought into
+  /// cache once, and frees owned by the same remote allocator are buffered
+  /// together.
+  template<typename A>
+  void dealloc_sorted(A& alloc, sn_rust_block_t* blocks, size_t count)
+  {
+    std::sort(
+      blocks,
+      blocks + count,
+      [](const sn_rust_block_t& a, const sn_rust_block_t& b) {
+        return std::less<void*>()(a.ptr, b.ptr);
+      });
+    for (size_t i = 0; i < count; i++)
+      alloc.dealloc(
+        blocks[i].ptr, aligned_size(blocks[i].alignment, blocks[i].size));
+  }
+
+  /// Page size of the running kernel, which may be larger than the one
+  /// snmalloc was compiled for, e.g. 64KiB on some aarch64 Linux kernels.
+  size_t kernel_page_size()
+  {
+#if defined(_WIN32)
+    SYSTEM_INFO info;
+    GetSystemInfo(&info);
+    return info.dwPageSize;
+#else
+    long size = sysconf(_SC_PAGESIZE);
+    return size > 0 ? static_cast<size_t>(size) : OS_PAGE_SIZE;
+#endif
+  }
+
+  /// Granularity of the protection, locking and residency calls of the shim:
+  /// whole pages of both the kernel and snmalloc.
+  size_t page_size()
+  {
+    static const size_t size = bits::max(OS_PAGE_SIZE, kernel_page_size());
+    return size;
+  }
+}
+
+#if defined(SNMALLOC_RUST_HANDLE_API)
+// Allocator handles (`handle-api` feature).
+/// A dedicated allocator, independent from the thread-local one.
+struct sn_rust_allocator
+{
+  Alloc alloc;
+  /// Label for diagnostics, set by `sn_rust_allocator_set_name`.
+  char name[32] = {};
+  size_t name_len = 0;
+  /// Largest request served, from `sn_rust_allocator_config_t`.
+  size_t max_alloc_size = SIZE_MAX;
+};
+
+extern "C" SNMALLOC_EXPORT sn_rust_allocator* sn_rust_allocator_new()
+{
+  SN_RUST_CRITICAL_SECTION();
+  void* mem = ThreadAlloc::get().alloc(
+    aligned_size(alignof(sn_rust_allocator), sizeof(sn_rust_allocator)));
+  if (mem == nullptr)
+    return nullptr;
+  auto* handle = new (mem) sn_rust_allocator();
+  handle->alloc.init();
+  return handle;
+}
+
+extern "C" SNMALLOC_EXPORT sn_rust_allocator* sn_rust_allocator_new_with_config(
+  const sn_rust_allocator_config_t* config)
+{
+  sn_rust_allocator* handle = sn_rust_allocator_new();
+  if (handle == nullptr)
+    return nullptr;
+  if (config->name != nullptr)
+    sn_rust_allocator_set_name(handle, config->name, config->name_len);
+  if (config->max_alloc_size != 0)
+    handle->max_alloc_size = config->max_alloc_size;
+  return handle;
+}
+
+extern "C" SNMALLOC_EXPORT void
+sn_rust_allocator_free(sn_rust_allocator* handle)
+{
+  SN_RUST_CRITICAL_SECTION();
+  handle->alloc.teardown();
+  handle->~sn_rust_allocator();
+  ThreadAlloc::get().dealloc(handle);
+}
+
+extern "C" SNMALLOC_EXPORT void* sn_rust_allocator_allocate(
+  sn_rust_allocator* handle, size_t alignment, size_t size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  if (size > handle->max_alloc_size)
+    return nullptr;
+  return handle->alloc.alloc(aligned_size(alignment, size));
+}
+
+extern "C" SNMALLOC_EXPORT void* sn_rust_allocator_allocate_zeroed(
+  sn_rust_allocator* handle, size_t alignment, size_t size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  if (size > handle->max_alloc_size)
+    return nullptr;
+  return handle->alloc.alloc<YesZero>(aligned_size(alignment, size));
+}
+
+extern "C" SNMALLOC_EXPORT void* sn_rust_allocator_allocate_filled(
+  sn_rust_allocator* handle, size_t alignment, size_t size, uint8_t byte)
+{
+  SN_RUST_CRITICAL_SECTION();
+  if (size > handle->max_alloc_size)
+    return nullptr;
+  return alloc_filled(handle->alloc, alignment, size, byte);
+}
+
+// Memory owned by another allocator, including other handles and thread-local
+// allocators, is recognised by `dealloc` and sent back as a remote free.
+extern "C" SNMALLOC_EXPORT void sn_rust_allocator_deallocate(
+  sn_rust_allocator* handle, void* ptr, size_t alignment, size_t size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  handle->alloc.dealloc(ptr, aligned_size(alignment, size));
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_allocator_deallocate_many(
+  sn_rust_allocator* handle, sn_rust_block_t* blocks, size_t count)
+{
+  SN_RUST_CRITICAL_SECTION();
+  dealloc_sorted(handle->alloc, blocks, count);
+}
+
+extern "C" SNMALLOC_EXPORT void* sn_rust_allocator_reallocate(
+  sn_rust_allocator* handle,
+  void* ptr,
+  size_t alignment,
+  size_t old_size,
+  size_t new_size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  if (new_size > handle->max_alloc_size)
+    return nullptr;
+  size_t aligned_old_size = aligned_size(alignment, old_size),
+         aligned_new_size = aligned_size(alignment, new_size);
+  if (
+    size_to_sizeclass_full(aligned_old_size).raw() ==
+    size_to_sizeclass_full(aligned_new_size).raw())
+    return ptr;
+  // On failure the original block must be left untouched and valid: callers
+  // rely on it to stay exception-safe when running out of memory.
+  void* p = handle->alloc.alloc(aligned_new_size);
+  if (p)
+  {
+    std::memcpy(p, ptr, old_size < new_size ? old_size : new_size);
+    handle->alloc.dealloc(ptr, aligned_old_size);
+  }
+  return p;
+}
+
+extern "C" SNMALLOC_EXPORT void
+sn_rust_allocator_flush(sn_rust_allocator* handle)
+{
+  SN_RUST_CRITICAL_SECTION();
+  handle->alloc.flush();
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_allocator_set_name(
+  sn_rust_allocator* handle, const char* name, size_t len)
+{
+  len = bits::min(len, sizeof(handle->name) - 1);
+  std::memcpy(handle->name, name, len);
+  handle->name[len] = '\0';
+  handle->name_len = len;
+}
+
+extern "C" SNMALLOC_EXPORT const char*
+sn_rust_allocator_name(const sn_rust_allocator* handle, size_t* len)
+{
+  *len = handle->name_len;
+  return handle->name_len == 0 ? nullptr : handle->name;
+}
+#endif
+
+#if defined(SNMALLOC_RUST_GUARD_API)
+// Guard pages (`guard-api` feature).
+namespace
+{
+  void set_accessible(void* p, size_t len, bool accessible)
+  {
+#if defined(_WIN32)
+    DWORD old;
+    VirtualProtect(p, len, accessible ? PAGE_READWRITE : PAGE_NOACCESS, &old);
+#else
+    mprotect(p, len, accessible ? PROT_READ | PROT_WRITE : PROT_NONE);
+#endif
+  }
+
+  /// Layout of a guarded allocation:
+  ///   [lead (first page optionally protected)][body][trailing guard page]
+  /// The user region is placed at the end of `body`, so that it ends exactly
+  /// where the trailing guard page starts.
+  struct GuardedLayout
+  {
+    size_t lead;
+    size_t body;
+    size_t offset;
+    size_t total;
+
+    GuardedLayout(size_t alignment, size_t size)
+    {
+      size_t user = bits::align_up(size, alignment);
+      lead = bits::max(page_size(), alignment);
+      body = bits::align_up(user, page_size());
+      offset = lead + (body - user);
+      total = lead + body + page_size();
+    }
+  };
+}
+
+extern "C" SNMALLOC_EXPORT void* sn_rust_guarded_alloc(
+  size_t alignment, size_t size, bool zero, bool leading_guard)
+{
+  SN_RUST_CRITICAL_SECTION();
+  GuardedLayout layout(alignment, size);
+  size_t request = aligned_size(layout.lead, layout.total);
+  void* base = zero ? ThreadAlloc::get().alloc<YesZero>(request) :
+                      ThreadAlloc::get().alloc(request);
+  if (base == nullptr)
+    return nullptr;
+  char* bytes = static_cast<char*>(base);
+  if (leading_guard)
+    set_accessible(bytes, page_size(), false);
+  set_accessible(bytes + layout.lead + layout.body, page_size(), false);
+  return bytes + layout.offset;
+}
+
+extern "C" SNMALLOC_EXPORT void
+sn_rust_guarded_dealloc(void* ptr, size_t alignment, size_t size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  auto& alloc = ThreadAlloc::get();
+  void* base = alloc.external_pointer<Start>(ptr);
+  // Guarded allocations never start at the beginning of their object.
+  if (base == ptr)
+  {
+    alloc.dealloc(ptr, aligned_size(alignment, size));
+    return;
+  }
+  GuardedLayout layout(alignment, size);
+  char* bytes = static_cast<char*>(base);
+  set_accessible(bytes, page_size(), true);
+  set_accessible(bytes + layout.lead + layout.body, page_size(), true);
+  alloc.dealloc(base, aligned_size(layout.lead, layout.total));
+}
+#endif
+
+extern "C" SNMALLOC_EXPORT void*
+sn_rust_alloc_usable(size_t alignment, size_t size, size_t* usable)
+{
+  SN_RUST_CRITICAL_SECTION();
+  // The block is the size class of the aligned size, as for `sn_rust_alloc`;
+  // the rounding is handed to the caller instead of being wasted.
+  void* p = ThreadAlloc::get().alloc(aligned_size(alignment, size));
+  *usable = p == nullptr ? 0 : ThreadAlloc::get().alloc_size(p);
+  return p;
+}
+
+extern "C" SNMALLOC_EXPORT void
+sn_rust_dealloc_many(sn_rust_block_t* blocks, size_t count)
+{
+  SN_RUST_CRITICAL_SECTION();
+  dealloc_sorted(ThreadAlloc::get(), blocks, count);
+}
+
+namespace
+{
+  /// Clamps the bytes of remote frees a thread buffers. Remote frees are posted
+  /// once the `capacity` of the `RemoteDeallocCache` is exhausted, which then
+  /// refills to the compile-time default: clamping it sends them out sooner.
+  ///
+  /// `capacity` is an internal of snmalloc (0.7), not part of its API, so the
+  /// clamp is only compiled in while the cache has a signed integral field of
+  /// that name; otherwise batches keep the default size, which
+  /// `sn_rust_remote_batch_supported` reports.
+  template<typename Cache, typename = void>
+  struct RemoteBatch
+  {
+    static constexpr bool supported = false;
+
+    static void clamp(Cache&, size_t) {}
+  };
+
+  template<typename Cache>
+  struct RemoteBatch<
+    Cache,
+    std::enable_if_t<std::is_signed_v<
+      std::remove_reference_t<decltype(std::declval<Cache&>().capacity)>>>>
+  {
+    static constexpr bool supported = true;
+
+    static void clamp(Cache& cache, size_t batch)
+    {
+      using Capacity =
+        std::remove_reference_t<decltype(std::declval<Cache&>().capacity)>;
+      if (cache.capacity > static_cast<Capacity>(batch))
+        cache.capacity = static_cast<Capacity>(batch);
+    }
+  };
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_dealloc_batched(
+  void* ptr, size_t alignment, size_t size, size_t batch)
+{
+  SN_RUST_CRITICAL_SECTION();
+  auto& alloc = ThreadAlloc::get();
+  alloc.dealloc(ptr, aligned_size(alignment, size));
+  RemoteBatch<decltype(alloc.get_local_cache().remote_dealloc_cache)>::clamp(
+    alloc.get_local_cache().remote_dealloc_cache, batch);
+}
+
+extern "C" SNMALLOC_EXPORT bool sn_rust_remote_batch_supported()
+{
+  return RemoteBatch<decltype(ThreadAlloc::get()
+                                .get_local_cache()
+                                .remote_dealloc_cache)>::supported;
+}
+
+extern "C" SNMALLOC_EXPORT size_t sn_rust_remaining_bytes(const void* ptr)
+{
+  SN_RUST_CRITICAL_SECTION();
+  return ThreadAlloc::get().remaining_bytes(address_cast(ptr));
+}
+
+extern "C" SNMALLOC_EXPORT void*
+sn_rust_alloc_filled(size_t alignment, size_t size, uint8_t byte)
+{
+  SN_RUST_CRITICAL_SECTION();
+  return alloc_filled(ThreadAlloc::get(), alignment, size, byte);
+}
+
+#if defined(SNMALLOC_RUST_STATS_API)
+extern "C" SNMALLOC_EXPORT void
+sn_rust_memory_usage(size_t* current_memory_usage, size_t* peak_memory_usage)
+{
+  *current_memory_usage = Alloc::Config::Backend::get_current_usage();
+  *peak_memory_usage = Alloc::Config::Backend::get_peak_usage();
+}
+
+extern "C" SNMALLOC_EXPORT void
+sn_rust_address_space(size_t* reserved, size_t* committed)
+{
+#  if defined(SN_RUST_COUNTING_PAL)
+  *reserved = SnRustPal::reserved.load(std::memory_order_relaxed);
+  *committed = SnRustPal::committed.load(std::memory_order_relaxed);
+#  else
+  // Not counted on this platform: the backend only knows what it handed out.
+  *reserved = Alloc::Config::Backend::get_peak_usage();
+  *committed = Alloc::Config::Backend::get_current_usage();
+#  endif
+}
+#endif
+
+#if defined(SN_RUST_HAS_DL_ITERATE_PHDR)
+namespace
+{
+  /// Checks whether `data` (an address) lies in the first object, which is
+  /// always the main program.
+  int in_main_program(dl_phdr_info* info, size_t, void* data)
+  {
+    auto* probe = static_cast<uintptr_t*>(data);
+    for (size_t i = 0; i < info->dlpi_phnum; i++)
+    {
+      const auto& phdr = info->dlpi_phdr[i];
+      uintptr_t start = info->dlpi_addr + phdr.p_vaddr;
+      if (phdr.p_type == PT_LOAD && *probe >= start && *probe < start + phdr.p_memsz)
+        *probe = 0;
+    }
+    return 1;
+  }
+}
+#endif
+
+#ifdef SNMALLOC_RUST_EARLY_INIT
+namespace
+{
+  /// Brings snmalloc up from a C++ static initializer, ahead of the
+  /// constructors of user code where the toolchain allows it. Every path into
+  /// snmalloc still initialises it lazily on first use, so constructors that
+  /// run earlier (or in another order) only pay for that initialisation.
+  ///
+  /// Skipped when `SNMALLOC_DISABLE=1` hands the process to the system
+  /// allocator, which must then not find snmalloc initialised.
+  bool disabled_by_env()
+  {
+#  if defined(_WIN32)
+    // Read without the C runtime, which `win-no-crt` builds do not link.
+    char value[2];
+    return GetEnvironmentVariableA("SNMALLOC_DISABLE", value, sizeof(value)) ==
+      1 &&
+      value[0] == '1';
+#  else
+    const char* value = getenv("SNMALLOC_DISABLE");
+    return value != nullptr && strcmp(value, "1") == 0;
+#  endif
+  }
+
+  struct EarlyInit
+  {
+    bool done = false;
+
+    EarlyInit()
+    {
+      if (disabled_by_env())
+        return;
+      SN_RUST_CRITICAL_SECTION();
+      auto& alloc = ThreadAlloc::get();
+      void* p = alloc.alloc(1);
+      done = p != nullptr;
+      alloc.dealloc(p);
+    }
+  };
+
+#  if defined(_MSC_VER)
+#    pragma warning(push)
+#    pragma warning(disable : 4073)
+#    pragma init_seg(lib)
+#    pragma warning(pop)
+  EarlyInit early_init;
+#  elif defined(__GNUC__) && !defined(__APPLE__)
+  EarlyInit early_init __attribute__((init_priority(101)));
+#  else
+  EarlyInit early_init;
+#  endif
+}
+#endif
+
+extern "C" SNMALLOC_EXPORT bool sn_rust_initialized_before_main()
+{
+#ifdef SNMALLOC_RUST_EARLY_INIT
+  return early_init.done;
+#else
+  return false;
+#endif
+}
+
+extern "C" SNMALLOC_EXPORT bool sn_rust_is_shared_object()
+{
+#if defined(_WIN32)
+  HMODULE self;
+  if (!GetModuleHandleExA(
+        GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS |
+          GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+        reinterpret_cast<LPCSTR>(&sn_rust_is_shared_object),
+        &self))
+    return false;
+  return self != GetModuleHandleA(nullptr);
+#elif defined(__APPLE__)
+  Dl_info self;
+  if (!dladdr(reinterpret_cast<void*>(&sn_rust_is_shared_object), &self))
+    return false;
+  return self.dli_fbase != _dyld_get_image_header(0);
+#elif defined(SN_RUST_HAS_DL_ITERATE_PHDR)
+  auto probe = reinterpret_cast<uintptr_t>(&sn_rust_is_shared_object);
+  dl_iterate_phdr(in_main_program, &probe);
+  return probe != 0;
+#else
+  return false;
+#endif
+}
+
+#if defined(SN_RUST_HAS_UNWIND)
+namespace
+{
+  /// Return addresses collected by `sn_rust_return_addresses`.
+  struct Frames
+  {
+    void** frames;
+    size_t count;
+    size_t skip;
+    size_t len;
+  };
+
+  _Unwind_Reason_Code collect_frame(_Unwind_Context* context, void* data)
+  {
+    auto* walk = static_cast<Frames*>(data);
+    if (walk->skip > 0)
+    {
+      walk->skip--;
+      return _URC_NO_REASON;
+    }
+    if (walk->len == walk->count)
+      return _URC_END_OF_STACK;
+    walk->frames[walk->len++] = reinterpret_cast<void*>(_Unwind_GetIP(context));
+    return _URC_NO_REASON;
+  }
+}
+#endif
+
+extern "C" SNMALLOC_EXPORT size_t
+sn_rust_return_addresses(void** frames, size_t count)
+{
+  // Both walks read the unwind tables in place, without allocating.
+#if defined(_WIN32)
+  return CaptureStackBackTrace(
+    1, static_cast<DWORD>(std::min<size_t>(count, 62)), frames, nullptr);
+#elif defined(SN_RUST_HAS_UNWIND)
+  Frames walk{frames, count, 1, 0};
+  _Unwind_Backtrace(collect_frame, &walk);
+  return walk.len;
+#else
+  UNUSED(frames, count);
+  return 0;
+#endif
+}
+
+namespace
+{
+  enum class Access
+  {
+    None,
+    Read,
+    ReadWrite
+  };
+
+  bool protect(void* ptr, size_t size, Access access)
+  {
+    size_t len = bits::align_up(size, page_size());
+    // Only whole pages of the allocation itself may change protection.
+    if (
+      (address_cast(ptr) & (page_size() - 1)) != 0 ||
+      ThreadAlloc::get().remaining_bytes(address_cast(ptr)) < len)
+      return false;
+#if defined(_WIN32)
+    DWORD old;
+    DWORD flags = access == Access::None ? PAGE_NOACCESS :
+      access == Access::Read             ? PAGE_READONLY :
+                                           PAGE_READWRITE;
+    return VirtualProtect(ptr, len, flags, &old) != 0;
+#else
+    int flags = access == Access::None ? PROT_NONE :
+      access == Access::Read           ? PROT_READ :
+                                         PROT_READ | PROT_WRITE;
+    return mprotect(ptr, len, flags) == 0;
+#endif
+  }
+}
+
+extern "C" SNMALLOC_EXPORT bool
+sn_rust_protect_read_only(void* ptr, size_t size, bool read_only)
+{
+  SN_RUST_CRITICAL_SECTION();
+  return protect(ptr, size, read_only ? Access::Read : Access::ReadWrite);
+}
+
+extern "C" SNMALLOC_EXPORT bool
+sn_rust_protect_no_access(void* ptr, size_t size, bool no_access)
+{
+  SN_RUST_CRITICAL_SECTION();
+  return protect(ptr, size, no_access ? Access::None : Access::ReadWrite);
+}
+
+#if defined(SNMALLOC_RUST_STATS_API)
+extern "C" SNMALLOC_EXPORT bool
+sn_rust_slab_info(const void* ptr, sn_rust_slab_info_t* info)
+{
+  const auto& entry = Config::Backend::get_metaentry(address_cast(ptr));
+  auto sizeclass = entry.get_sizeclass();
+  // Unowned memory has no remote, and large objects do not live in slabs.
+  if (entry.get_remote() == nullptr || !sizeclass.is_small())
+    return false;
+  smallsizeclass_t small = sizeclass.as_small();
+  size_t slab_size = sizeclass_to_slab_size(small);
+  info->sizeclass = small;
+  info->object_size = sizeclass_to_size(small);
+  info->slab_base = pointer_align_down(const_cast<void*>(ptr), slab_size);
+  info->slab_size = slab_size;
+  info->objects_per_slab = sizeclass_to_slab_object_count(small);
+  return true;
+}
+#endif
+
+extern "C" SNMALLOC_EXPORT void* sn_rust_realloc_zeroed(
+  void* ptr, size_t alignment, size_t old_size, size_t new_size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  size_t aligned_old_size = aligned_size(alignment, old_size),
+         aligned_new_size = aligned_size(alignment, new_size);
+  void* p = ptr;
+  if (
+    size_to_sizeclass_full(aligned_old_size).raw() !=
+    size_to_sizeclass_full(aligned_new_size).raw())
+  {
+    auto& alloc = ThreadAlloc::get();
+    p = alloc.alloc(aligned_new_size);
+    if (p == nullptr)
+      return nullptr;
+    std::memcpy(p, ptr, old_size < new_size ? old_size : new_size);
+    alloc.dealloc(ptr, aligned_old_size);
+  }
+  // Only the newly exposed bytes are written.
+  if (new_size > old_size)
+    std::memset(static_cast<char*>(p) + old_size, 0, new_size - old_size);
+  return p;
+}
+
+namespace
+{
+  /// Replaces the whole pages at `ptr` with fresh ones of the OS, which read as
+  /// zero and only become resident when written. Returns false, leaving the
+  /// pages untouched, if they cannot be replaced.
+  bool fresh_pages(void* ptr, size_t len)
+  {
+    size_t page = page_size();
+    if ((address_cast(ptr) & (page - 1)) != 0 || (len & (page - 1)) != 0)
+      return false;
+#if defined(_WIN32)
+    return VirtualFree(ptr, len, MEM_DECOMMIT) != 0 &&
+      VirtualAlloc(ptr, len, MEM_COMMIT, PAGE_READWRITE) == ptr;
+#elif defined(__linux__)
+    // Private anonymous pages are dropped, and read as zero afterwards.
+    return madvise(ptr, len, MADV_DONTNEED) == 0;
+#else
+    // Mapping over the reservation of the backend would replace memory the
+    // PAL owns, and `MADV_FREE` elsewhere does not promise zeroes: leave the
+    // zeroing to the caller.
+    UNUSED(ptr, len);
+    return false;
+#endif
+  }
+}
+
+extern "C" SNMALLOC_EXPORT void*
+sn_rust_alloc_zeroed_fresh(size_t alignment, size_t size, bool strict)
+{
+  SN_RUST_CRITICAL_SECTION();
+  size_t rounded = round_size(aligned_size(alignment, size));
+  auto& alloc = ThreadAlloc::get();
+  // Small objects share their pages with other objects.
+  if (size_to_sizeclass_full(rounded).is_small())
+    return alloc.alloc<YesZero>(rounded);
+  void* p = alloc.alloc(rounded);
+  if (p == nullptr || fresh_pages(p, rounded))
+    return p;
+  if (strict)
+  {
+    alloc.dealloc(p, rounded);
+    return nullptr;
+  }
+  std::memset(p, 0, size);
+  return p;
+}
+
+namespace
+{
+  /// OS error recorded by the last `sn_rust_record_os_error` of the thread.
+  thread_local int last_os_error = 0;
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_clear_os_error()
+{
+#if defined(_WIN32)
+  SetLastError(0);
+#else
+  errno = 0;
+#endif
+}
+
+extern "C" SNMALLOC_EXPORT int sn_rust_record_os_error()
+{
+  // The PAL reports nothing beyond the null pointer, but the failed mmap or
+  // VirtualAlloc left its reason in the thread's error slot.
+#if defined(_WIN32)
+  last_os_error = static_cast<int>(GetLastError());
+#else
+  last_os_error = errno;
+#endif
+  return last_os_error;
+}
+
+extern "C" SNMALLOC_EXPORT int sn_rust_last_os_error()
+{
+  return last_os_error;
+}
+
+/// Posts the frees of `sn_rust_remote_dealloc` gathered by the thread.
+static void post_remote_frees();
+
+extern "C" SNMALLOC_EXPORT void sn_rust_flush_thread_cache()
+{
+  SN_RUST_CRITICAL_SECTION();
+  post_remote_frees();
+  ThreadAlloc::get().flush();
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_thread_init()
+{
+  SN_RUST_CRITICAL_SECTION();
+  // The allocator of a thread only attaches to a core allocator, and registers
+  // its teardown, on its first slow path: take it now.
+  auto& alloc = ThreadAlloc::get();
+  alloc.dealloc(alloc.alloc(1));
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_thread_teardown()
+{
+  SN_RUST_CRITICAL_SECTION();
+  post_remote_frees();
+  // The allocator goes back to the pool with its caches, to be reused by the
+  // next thread; an allocation of this thread afterwards takes a new one.
+  ThreadAlloc::get().teardown();
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_shutdown()
+{
+  SN_RUST_CRITICAL_SECTION();
+  post_remote_frees();
+  ThreadAlloc::get().flush();
+  // Allocators of exited threads are parked in the pool with their caches.
+  cleanup_unused<Config>();
+}
+
+extern "C" SNMALLOC_EXPORT void*
+sn_rust_alloc_chunk(size_t size, size_t* chunk_size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  // Large objects are whole, naturally aligned chunks registered in the
+  // pagemap, which is exactly what sub-allocators need. Smaller sizes would be
+  // served from slabs, so they are raised to the smallest large object, and
+  // sizes above the largest power of two cannot be rounded.
+  if (size > bits::one_at_bit(bits::BITS - 1))
+    return nullptr;
+  size_t rounded = bits::next_pow2(
+    bits::max(size, bits::max(MAX_SMALL_SIZECLASS_SIZE + 1, MIN_CHUNK_SIZE)));
+  void* p = ThreadAlloc::get().alloc(rounded);
+  if (p != nullptr)
+    *chunk_size = rounded;
+  return p;
+}
+
+extern "C" SNMALLOC_EXPORT void
+sn_rust_dealloc_chunk(void* ptr, size_t chunk_size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  ThreadAlloc::get().dealloc(ptr, chunk_size);
+}
+
+extern "C" SNMALLOC_EXPORT size_t sn_rust_cache_friendly_offset()
+{
+#ifdef CACHE_FRIENDLY_OFFSET
+  return CACHE_FRIENDLY_OFFSET;
+#else
+  return 0;
+#endif
+}
+
+extern "C" SNMALLOC_EXPORT size_t
+sn_rust_round_size(size_t alignment, size_t size)
+{
+  return round_size(aligned_size(alignment, size));
+}
+
+extern "C" SNMALLOC_EXPORT void* sn_rust_malloc(size_t size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  // Same guarantee as `malloc`: aligned for any fundamental type.
+  return ThreadAlloc::get().alloc(
+    aligned_size(alignof(std::max_align_t), size == 0 ? 1 : size));
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_free(void* ptr)
+{
+  SN_RUST_CRITICAL_SECTION();
+  // The size class is recovered from the pagemap, whatever the alignment and
+  // size the block was allocated with.
+  ThreadAlloc::get().dealloc(ptr);
+}
+
+namespace
+{
+  /// Process-wide cache of freed large objects, so that workloads cycling
+  /// through big buffers reuse them instead of having the backend decommit
+  /// and recommit the pages every time.
+  struct LargeCache
+  {
+    /// Objects kept per size.
+    static constexpr size_t PER_SIZE = 4;
+
+    FlagWord lock{};
+    /// Large objects are powers of two: index `i` holds objects of `2^i` bytes.
+    void* objects[bits::BITS][PER_SIZE]{};
+    size_t counts[bits::BITS]{};
+    size_t cached_bytes = 0;
+    size_t cached_objects = 0;
+    size_t limit = 0;
+    size_t hits = 0;
+    size_t misses = 0;
+
+    /// Takes the largest cached object out of the cache, into `ptr` and
+    /// `size`, if more than `limit` bytes are cached. Must be called with the
+    /// lock held; the object is freed by the caller once it is released.
+    bool evict(void*& ptr, size_t& size)
+    {
+      if (cached_bytes <= limit)
+        return false;
+      for (size_t i = bits::BITS; i-- > 0;)
+      {
+        if (counts[i] > 0)
+        {
+          ptr = objects[i][--counts[i]];
+          size = bits::one_at_bit(i);
+          cached_bytes -= size;
+          cached_objects--;
+          return true;
+        }
+      }
+      return false;
+    }
+  };
+
+  LargeCache large_cache;
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_set_large_cache(size_t limit)
+{
+  SN_RUST_CRITICAL_SECTION();
+  {
+    FlagLock guard(large_cache.lock);
+    large_cache.limit = limit;
+  }
+  // Freeing a large object goes through the backend: do it without the lock,
+  // which every allocation and free of a large object takes.
+  while (true)
+  {
+    void* ptr;
+    size_t size;
+    {
+      FlagLock guard(large_cache.lock);
+      if (!large_cache.evict(ptr, size))
+        return;
+    }
+    ThreadAlloc::get().dealloc(ptr, size);
+  }
+}
+
+extern "C" SNMALLOC_EXPORT void*
+sn_rust_alloc_large_cached(size_t alignment, size_t size, bool zero)
+{
+  SN_RUST_CRITICAL_SECTION();
+  size_t rounded = round_size(aligned_size(alignment, size));
+  if (!size_to_sizeclass_full(rounded).is_small())
+  {
+    void* p = nullptr;
+    {
+      FlagLock guard(large_cache.lock);
+      size_t i = bits::ctz(rounded);
+      if (large_cache.counts[i] > 0)
+      {
+        p = large_cache.objects[i][--large_cache.counts[i]];
+        large_cache.cached_bytes -= rounded;
+        large_cache.cached_objects--;
+        large_cache.hits++;
+      }
+      else
+      {
+        large_cache.misses++;
+      }
+    }
+    if (p != nullptr)
+    {
+      if (zero)
+        std::memset(p, 0, size);
+      return p;
+    }
+  }
+  auto& alloc = ThreadAlloc::get();
+  return zero ? alloc.alloc<YesZero>(rounded) : alloc.alloc(rounded);
+}
+
+extern "C" SNMALLOC_EXPORT void
+sn_rust_dealloc_large_cached(void* ptr, size_t alignment, size_t size)
+{
+  SN_RUST_CRITICAL_SECTION();
+  size_t rounded = round_size(aligned_size(alignment, size));
+  if (!size_to_sizeclass_full(rounded).is_small())
+  {
+    FlagLock guard(large_cache.lock);
+    size_t i = bits::ctz(rounded);
+    if (
+      large_cache.counts[i] < LargeCache::PER_SIZE &&
+      large_cache.cached_bytes + rounded <= large_cache.limit)
+    {
+      large_cache.objects[i][large_cache.counts[i]++] = ptr;
+      large_cache.cached_bytes += rounded;
+      large_cache.cached_objects++;
+      return;
+    }
+  }
+  // Not cached: freed after the lock is released, at the end of its scope.
+  ThreadAlloc::get().dealloc(ptr, rounded);
+}
+
+namespace
+{
+  /// Process-wide allocator of the blocks expected to be long-lived, so that
+  /// they fill slabs and chunks of their own instead of pinning those the
+  /// short-lived blocks of the thread-local allocators cycle through.
+  struct ColdAlloc
+  {
+    FlagWord lock{};
+    Alloc* alloc = nullptr;
+    /// Never destroyed: cold blocks may be freed until the process exits.
+    alignas(Alloc) unsigned char storage[sizeof(Alloc)];
+  };
+
+  ColdAlloc cold_alloc;
+}
+
+extern "C" SNMALLOC_EXPORT void*
+sn_rust_alloc_hint_cold(size_t alignment, size_t size, bool zero)
+{
+  SN_RUST_CRITICAL_SECTION();
+  size_t rounded = aligned_size(alignment, size);
+  FlagLock guard(cold_alloc.lock);
+  if (cold_alloc.alloc == nullptr)
+  {
+    cold_alloc.alloc = new (cold_alloc.storage) Alloc();
+    cold_alloc.alloc->init();
+  }
+  return zero ? cold_alloc.alloc->alloc<YesZero>(rounded) :
+                cold_alloc.alloc->alloc(rounded);
+}
+
+namespace
+{
+  /// Process-wide allocator forwarding the frees of `sn_rust_remote_dealloc`
+  /// to the owners of the blocks. It never allocates, so every free it sees is
+  /// a remote one, posted to the message queue of the owner.
+  struct RemoteFreeAlloc
+  {
+    FlagWord lock{};
+    Alloc* alloc = nullptr;
+    /// Never destroyed: threads may free through it until the process exits.
+    alignas(Alloc) unsigned char storage[sizeof(Alloc)];
+  };
+
+  RemoteFreeAlloc remote_free_alloc;
+
+  /// Creates the allocator of `remote_free_alloc` if needed, mapping its
+  /// metadata the first time. The caller holds its lock.
+  void init_remote_free_alloc()
+  {
+    if (remote_free_alloc.alloc == nullptr)
+    {
+      remote_free_alloc.alloc = new (remote_free_alloc.storage) Alloc();
+      remote_free_alloc.alloc->init();
+    }
+  }
+
+#if defined(SNMALLOC_RUST_AUDIT_DEALLOC)
+  /// Built to audit frees, the allocator is created before `main`, so that no
+  /// `sn_rust_remote_dealloc` maps its metadata.
+  struct RemoteFreeAllocInit
+  {
+    RemoteFreeAllocInit()
+    {
+      FlagLock guard(remote_free_alloc.lock);
+      init_remote_free_alloc();
+    }
+  };
+
+  RemoteFreeAllocInit remote_free_alloc_init;
+#endif
+
+  /// Frees of `sn_rust_remote_dealloc` gathered by a thread, so that the lock
+  /// of `remote_free_alloc` is taken, and its remote cache flushed, once per
+  /// `capacity` frees rather than on every free.
+  struct RemoteFreeBatch
+  {
+    static constexpr size_t capacity = 64;
+    void* blocks[capacity];
+    size_t count = 0;
+
+    /// Posts the gathered frees to the owners of the blocks.
+    void post()
+    {
+      if (count == 0)
+        return;
+      FlagLock guard(remote_free_alloc.lock);
+      init_remote_free_alloc();
+      for (size_t i = 0; i < count; i++)
+        remote_free_alloc.alloc->dealloc(blocks[i]);
+      // Post the frees now rather than when the remote cache fills up, which
+      // would hold the blocks back from their owners for an unbounded time.
+      remote_free_alloc.alloc->flush();
+      count = 0;
+    }
+
+    /// The frees still gathered when the thread exits are posted then.
+    ~RemoteFreeBatch()
+    {
+      post();
+    }
+  };
+
+  thread_local RemoteFreeBatch remote_free_batch;
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_remote_dealloc(void* ptr)
+{
+  SN_RUST_CRITICAL_SECTION();
+  // The thread-local allocator is not touched, so that a thread that only
+  // frees never takes one from the pool.
+  auto& batch = remote_free_batch;
+  batch.blocks[batch.count++] = ptr;
+  if (batch.count == RemoteFreeBatch::capacity)
+    batch.post();
+}
+
+static void post_remote_frees()
+{
+  remote_free_batch.post();
+}
+
+#if defined(SNMALLOC_RUST_STATS_API)
+// Statistics and the walk of the pagemap (`stats-api` feature).
+extern "C" SNMALLOC_EXPORT void
+sn_rust_large_cache_stats(sn_rust_large_cache_stats_t* stats)
+{
+  SN_RUST_CRITICAL_SECTION();
+  FlagLock guard(large_cache.lock);
+  stats->hits = large_cache.hits;
+  stats->misses = large_cache.misses;
+  stats->cached_bytes = large_cache.cached_bytes;
+  stats->cached_objects = large_cache.cached_objects;
+}
+
+namespace
+{
+  using PagemapEntry =
+    std::remove_reference_t<decltype(Config::Backend::get_metaentry(0))>;
+
+  /// Number of pagemap entries, one per chunk of the address space.
+  constexpr size_t pagemap_entries = size_t(1)
+    << (bits::ADDRESS_BITS - MIN_CHUNK_BITS);
+
+  /// Coalesces owned chunks into ranges, reported once they end.
+  struct RangeWalk
+  {
+    sn_rust_range_callback callback;
+    void* context;
+    size_t start = 0;
+    size_t end = 0;
+
+    /// Visits the chunks whose pagemap entries are in [first, last).
+    void visit(size_t first, size_t last)
+    {
+      for (size_t i = first; i < last; i++)
+      {
+        // Like `sn_rust_slab_info`: only chunks handed to a frontend
+        // allocator have a remote.
+        if (Config::Backend::get_metaentry(i << MIN_CHUNK_BITS).get_remote() ==
+            nullptr)
+          continue;
+        if (i != end)
+        {
+          finish();
+          start = i;
+        }
+        end = i + 1;
+      }
+    }
+
+    void finish()
+    {
+      if (end != start)
+        callback(
+          context,
+          reinterpret_cast<void*>(start << MIN_CHUNK_BITS),
+          (end - start) << MIN_CHUNK_BITS);
+      start = end;
+    }
+  };
+
+  /// Index of the first pagemap entry at or after `address` in the pagemap.
+  size_t entry_index(const char* body, uintptr_t address)
+  {
+    uintptr_t base = address_cast(body);
+    if (address <= base)
+      return 0;
+    size_t index = (address - base + sizeof(PagemapEntry) - 1) /
+      sizeof(PagemapEntry);
+    return bits::min(index, pagemap_entries);
+  }
+}
+
+extern "C" SNMALLOC_EXPORT bool
+sn_rust_owned_ranges(sn_rust_range_callback callback, void* context)
+{
+  const char* body =
+    reinterpret_cast<const char*>(&Config::Backend::get_metaentry(0));
+  uintptr_t body_end =
+    address_cast(body) + pagemap_entries * sizeof(PagemapEntry);
+  RangeWalk walk{callback, context};
+  // Scanning the entries of the whole address space would take minutes, but
+  // an entry is only ever set after its pagemap page was committed and
+  // written, so the stretches of the pagemap that are not backed are skipped.
+#if defined(_WIN32)
+  for (uintptr_t page = address_cast(body); page < body_end;)
+  {
+    MEMORY_BASIC_INFORMATION info;
+    if (
+      VirtualQuery(reinterpret_cast<void*>(page), &info, sizeof(info)) == 0)
+      return false;
+    uintptr_t region_end = address_cast(info.BaseAddress) + info.RegionSize;
+    if (info.State == MEM_COMMIT)
+      walk.visit(entry_index(body, page), entry_index(body, region_end));
+    page = region_end;
+  }
+#elif defined(SN_RUST_HAS_MINCORE)
+#  if defined(__linux__)
+  using residency_t = unsigned char;
+#  else
+  using residency_t = char;
+#  endif
+  // A page that was never written is not resident. A pagemap page swapped out
+  // since is skipped too, which only loses precision under heavy swapping.
+  constexpr size_t batch = 4096;
+  residency_t resident[batch];
+  // mincore reports one entry per page of the kernel.
+  size_t page_bytes = kernel_page_size();
+  uintptr_t first = bits::align_down(address_cast(body), page_bytes);
+  for (uintptr_t pages = first; pages < body_end; pages += batch * page_bytes)
+  {
+    size_t length = bits::min(batch * page_bytes, body_end - pages);
+    if (mincore(reinterpret_cast<void*>(pages), length, resident) != 0)
+      return false;
+    for (size_t i = 0; i * page_bytes < length; i++)
+    {
+      if ((resident[i] & 1) == 0)
+        continue;
+      uintptr_t page = pages + i * page_bytes;
+      walk.visit(
+        entry_index(body, page), entry_index(body, page + page_bytes));
+    }
+  }
+#else
+  UNUSED(body_end);
+  return false;
+#endif
+  walk.finish();
+  return true;
+}
+#endif
+
+#if defined(SNMALLOC_RUST_GUARD_API)
+// Redzones (`guard-api` feature).
+namespace
+{
+  /// Bytes of canary on each side of an allocation with redzones.
+  constexpr size_t REDZONE = 16;
+
+  /// Canary word stored at `address`. Mixing in the address makes a block
+  /// copied over another one, redzones included, still show up as corrupted.
+  uint64_t canary(uintptr_t address)
+  {
+    return 0xA5C3'5A3C'96E1'1E69 ^
+      (static_cast<uint64_t>(address) * 0x9E37'79B9);
+  }
+
+  void write_canaries(char* zone)
+  {
+    for (size_t i = 0; i < REDZONE; i += sizeof(uint64_t))
+    {
+      uint64_t word = canary(address_cast(zone + i));
+      std::memcpy(zone + i, &word, sizeof(word));
+    }
+  }
+
+  /// Checks the canaries of `zone`, describing the first overwritten word in
+  /// `report`.
+  bool check_canaries(const char* zone, sn_rust_redzone_report_t* report)
+  {
+    for (size_t i = 0; i < REDZONE; i += sizeof(uint64_t))
+    {
+      uint64_t found;
+      std::memcpy(&found, zone + i, sizeof(found));
+      uint64_t expected = canary(address_cast(zone + i));
+      if (found != expected)
+      {
+        report->expected = expected;
+        report->found = found;
+        report->offset = static_cast<ptrdiff_t>(
+          address_cast(zone + i) - address_cast(report->ptr));
+        return false;
+      }
+    }
+    return true;
+  }
+
+  /// Bytes before the allocation: the leading redzone, padded to keep the
+  /// allocation aligned.
+  size_t redzone_lead(size_t alignment)
+  {
+    return bits::max(alignment, REDZONE);
+  }
+}
+
+extern "C" SNMALLOC_EXPORT void*
+sn_rust_redzone_alloc(size_t alignment, size_t size, bool zero)
+{
+  SN_RUST_CRITICAL_SECTION();
+  size_t lead = redzone_lead(alignment);
+  size_t total = lead + size + REDZONE;
+  if (total < size)
+    return nullptr;
+  size_t request = aligned_size(alignment, total);
+  void* base = zero ? ThreadAlloc::get().alloc<YesZero>(request) :
+                      ThreadAlloc::get().alloc(request);
+  if (base == nullptr)
+    return nullptr;
+  char* ptr = static_cast<char*>(base) + lead;
+  write_canaries(ptr - REDZONE);
+  write_canaries(ptr + size);
+  return ptr;
+}
+
+extern "C" SNMALLOC_EXPORT bool sn_rust_redzone_dealloc(
+  void* ptr, size_t alignment, size_t size, sn_rust_redzone_report_t* report)
+{
+  SN_RUST_CRITICAL_SECTION();
+  char* bytes = static_cast<char*>(ptr);
+  report->ptr = ptr;
+  report->size = size;
+  // Underflows are reported first, as they usually come from the same bug
+  // as an overflow of the previous block.
+  bool intact = check_canaries(bytes - REDZONE, report) &&
+    check_canaries(bytes + size, report);
+  size_t lead = redzone_lead(alignment);
+  ThreadAlloc::get().dealloc(
+    bytes - lead, aligned_size(alignment, lead + size + REDZONE));
+  return intact;
+}
+#endif
+
+extern "C" SNMALLOC_EXPORT bool sn_rust_lock_pages(void* ptr, size_t size)
+{
+  if (size == 0)
+    return true;
+  uintptr_t first = bits::align_down(address_cast(ptr), page_size());
+  uintptr_t end = bits::align_up(address_cast(ptr) + size, page_size());
+  void* base = reinterpret_cast<void*>(first);
+  size_t len = end - first;
+  return sn_rust_lock_range(base, len);
+}
+
+extern "C" SNMALLOC_EXPORT size_t sn_rust_locked_commits(size_t* failures)
+{
+#if defined(SNMALLOC_RUST_LOCK_MEMORY) && defined(SN_RUST_COUNTING_PAL)
+  *failures = SnRustPal::lock_failures.load(std::memory_order_relaxed);
+  return SnRustPal::locked.load(std::memory_order_relaxed);
+#else
+  *failures = 0;
+  return 0;
+#endif
+}
+
+extern "C" SNMALLOC_EXPORT size_t sn_rust_os_page_size(void)
+{
+  return kernel_page_size();
+}
+
+namespace
+{
+  // Callbacks registered by `sn_rust_at_thread_exit`. A C++ thread-local
+  // destructor is registered with the C runtime, which does not allocate
+  // through Rust, unlike a Rust thread-local with `Drop`.
+  struct ThreadExit
+  {
+    static constexpr size_t capacity = 8;
+    sn_rust_thread_exit_callback callbacks[capacity];
+    void* contexts[capacity];
+    size_t count = 0;
+
+    ~ThreadExit()
+    {
+      while (count > 0)
+      {
+        count--;
+        callbacks[count](contexts[count]);
+      }
+    }
+  };
+
+  thread_local ThreadExit thread_exit;
+}
+
+extern "C" SNMALLOC_EXPORT bool
+sn_rust_at_thread_exit(sn_rust_thread_exit_callback callback, void* context)
+{
+  if (thread_exit.count == ThreadExit::capacity)
+    return false;
+  thread_exit.callbacks[thread_exit.count] = callback;
+  thread_exit.contexts[thread_exit.count] = context;
+  thread_exit.count++;
+  return true;
+}
+
+extern "C" SNMALLOC_EXPORT size_t sn_rust_thread_name(char* buffer, size_t size)
+{
+  if (size == 0)
+    return 0;
+#if defined(_WIN32)
+  using GetThreadDescriptionFn = HRESULT(WINAPI*)(HANDLE, PWSTR*);
+  // Only available from Windows 10 1607.
+  static auto get_description = reinterpret_cast<GetThreadDescriptionFn>(
+    GetProcAddress(GetModuleHandleW(L"kernel32.dll"), "GetThreadDescription"));
+  PWSTR description;
+  if (
+    get_description == nullptr ||
+    FAILED(get_description(GetCurrentThread(), &description)))
+    return 0;
+  int len = WideCharToMultiByte(
+    CP_UTF8, 0, description, -1, buffer, static_cast<int>(size), nullptr, nullptr);
+  LocalFree(description);
+  return len > 0 ? static_cast<size_t>(len) - 1 : 0;
+#elif defined(__GLIBC__) || defined(__APPLE__)
+  if (pthread_getname_np(pthread_self(), buffer, size) != 0)
+    return 0;
+  return strnlen(buffer, size);
+#else
+  UNUSED(buffer);
+  return 0;
+#endif
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_audit_dealloc_enter(void)
+{
+  // The PAL of `sn_rust_pal.h` aborts if the free reserves or commits memory,
+  // e.g. to initialise the allocator of a thread that never allocated.
+#if defined(SNMALLOC_RUST_AUDIT_DEALLOC) && defined(SN_RUST_COUNTING_PAL)
+  sn_rust_freeing = true;
+#endif
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_audit_dealloc_leave(void)
+{
+#if defined(SNMALLOC_RUST_AUDIT_DEALLOC) && defined(SN_RUST_COUNTING_PAL)
+  sn_rust_freeing = false;
+#endif
+}
+
+extern "C" SNMALLOC_EXPORT uint64_t sn_rust_entropy(void)
+{
+  if constexpr (pal_supports<Entropy, DefaultPal>)
+    return DefaultPal::get_entropy64();
+  else
+    return 0;
+}
+
+extern "C" SNMALLOC_EXPORT void sn_rust_zero(void* ptr, size_t size)
+{
+  // Only pages of both the kernel and snmalloc can be zeroed by the platform:
+  // the edges of the range are cleared in place.
+  uintptr_t start = address_cast(ptr);
+  uintptr_t first = bits::align_up(start, page_size());
+  uintptr_t last = bits::align_down(start + size, page_size());
+  if (first >= last)
+  {
+    memset(ptr, 0, size);
+    return;
+  }
+  memset(ptr, 0, first - start);
+  DefaultPal::zero<true>(pointer_offset(ptr, first - start), last - first);
+  memset(pointer_offset(ptr, last - start), 0, start + size - last);
+}
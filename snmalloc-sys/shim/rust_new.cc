@@ -0,0 +1,22 @@
+// Replaces the global C++ allocation operators, including the sized and
+// aligned variants, with snmalloc (`cxx-new` feature).
+//
+// The definitions come from upstream; they live in this translation unit so
+// that referencing `sn_rust_operator_new_is_snmalloc` is enough to pull them
+// out of the static library.
+#include "sn_rust.h"
+
+#include "snmalloc/override/new.cc"
+
+extern "C" SNMALLOC_EXPORT bool sn_rust_operator_new_is_snmalloc()
+{
+  // Call through a volatile pointer, so that the compiler cannot assume that
+  // the definition above is the one picked by the linker.
+  void* (*volatile new_fn)(size_t) =
+    static_cast<void* (*)(size_t)>(&::operator new);
+  void* p = new_fn(1);
+  // Memory that snmalloc does not own has no size class.
+  bool owned = snmalloc::ThreadAlloc::get().alloc_size(p) != 0;
+  ::operator delete(p);
+  return owned;
+}
@@ -0,0 +1,151 @@
+// The parts of the MSVC C runtime the shims rely on, for binaries linked
+// without it (`win-no-crt` feature, `/NODEFAULTLIB`).
+//
+// snmalloc only talks to the OS through the Windows PAL (VirtualAlloc2,
+// WaitOnAddress, BCryptGenRandom). What is left of the CRT is:
+//  - the directory of static TLS (`_tls_used`), which the CRT provides;
+//  - the initialisers and destructors of thread-locals, which the CRT runs from
+//    its TLS callback;
+//  - the report of fatal errors, through stdio and `abort`.
+// The shims are compiled with `/Zl /GS- /EHs-c- /Zc:threadSafeInit-
+// /Zc:tlsGuards-`, so that they reference nothing else. `memcpy`, `memmove`,
+// `memset` and `memcmp` are left to the binary, e.g. to the `mem` feature of
+// compiler_builtins. Linked into the fast shim only: the hardened one shares
+// these symbols.
+#if defined(SNMALLOC_RUST_NO_CRT) && defined(_MSC_VER)
+
+#  define WIN32_LEAN_AND_MEAN
+#  include <intrin.h>
+#  include <windows.h>
+
+namespace
+{
+  using Function = void(__cdecl*)();
+
+  /// Destructors of the thread-locals of the thread, in construction order.
+  constexpr unsigned thread_destructors_capacity = 32;
+  __declspec(thread) Function thread_destructors[thread_destructors_capacity];
+  __declspec(thread) unsigned thread_destructors_count = 0;
+
+  /// Sentinels standing for stdin, stdout and stderr.
+  void* streams[3];
+
+  void write_stderr(const char* str, DWORD len)
+  {
+    DWORD written;
+    WriteFile(GetStdHandle(STD_ERROR_HANDLE), str, len, &written, nullptr);
+  }
+}
+
+extern "C"
+{
+  // Static TLS, laid out like the CRT's `tlssup.obj`.
+  ULONG _tls_index = 0;
+
+#  pragma data_seg(".tls")
+  char _tls_start = 0;
+#  pragma data_seg(".tls$ZZZ")
+  char _tls_end = 0;
+#  pragma data_seg()
+
+#  pragma section(".CRT$XLA", long, read)
+#  pragma section(".CRT$XLC", long, read)
+#  pragma section(".CRT$XLZ", long, read)
+#  pragma section(".CRT$XDA", long, read)
+#  pragma section(".CRT$XDZ", long, read)
+
+  __declspec(allocate(".CRT$XLA")) PIMAGE_TLS_CALLBACK __xl_a = nullptr;
+  __declspec(allocate(".CRT$XLZ")) PIMAGE_TLS_CALLBACK __xl_z = nullptr;
+  // Dynamic initialisers of thread-locals, emitted between these two with
+  // `/Zc:tlsGuards-`.
+  __declspec(allocate(".CRT$XDA")) Function __xd_a = nullptr;
+  __declspec(allocate(".CRT$XDZ")) Function __xd_z = nullptr;
+
+  extern const IMAGE_TLS_DIRECTORY _tls_used = {
+    reinterpret_cast<ULONG_PTR>(&_tls_start),
+    reinterpret_cast<ULONG_PTR>(&_tls_end),
+    reinterpret_cast<ULONG_PTR>(&_tls_index),
+    reinterpret_cast<ULONG_PTR>(&__xl_a + 1),
+    0,
+    0};
+
+  /// Registers the destructor of a thread-local of the calling thread.
+  int __cdecl __tlregdtor(Function destructor)
+  {
+    if (thread_destructors_count == thread_destructors_capacity)
+      return -1;
+    thread_destructors[thread_destructors_count++] = destructor;
+    return 0;
+  }
+
+  int __cdecl atexit(Function)
+  {
+    // Without the CRT the process ends with ExitProcess, which runs no static
+    // destructors: report success and never run it.
+    return 0;
+  }
+
+  void* __cdecl __acrt_iob_func(unsigned index)
+  {
+    return &streams[index < 3 ? index : 2];
+  }
+
+  int __cdecl fputs(const char* str, void*)
+  {
+    write_stderr(str, static_cast<DWORD>(lstrlenA(str)));
+    return 0;
+  }
+
+  int __cdecl fputc(int c, void*)
+  {
+    char ch = static_cast<char>(c);
+    write_stderr(&ch, 1);
+    return c;
+  }
+
+  int __cdecl puts(const char* str)
+  {
+    fputs(str, nullptr);
+    return fputc('\n', nullptr) < 0 ? -1 : 0;
+  }
+
+  int __cdecl fflush(void*)
+  {
+    return 0;
+  }
+
+  __declspec(noreturn) void __cdecl abort()
+  {
+    __fastfail(FAST_FAIL_FATAL_APP_EXIT);
+  }
+}
+
+namespace
+{
+  void NTAPI tls_callback(PVOID, DWORD reason, PVOID)
+  {
+    switch (reason)
+    {
+      case DLL_PROCESS_ATTACH:
+      case DLL_THREAD_ATTACH:
+        for (Function* init = &__xd_a + 1; init != &__xd_z; init++)
+        {
+          if (*init != nullptr)
+            (*init)();
+        }
+        break;
+      case DLL_PROCESS_DETACH:
+      case DLL_THREAD_DETACH:
+        while (thread_destructors_count > 0)
+          thread_destructors[--thread_destructors_count]();
+        break;
+      default:
+        break;
+    }
+  }
+}
+
+extern "C" __declspec(allocate(".CRT$XLC"))
+  PIMAGE_TLS_CALLBACK sn_rust_nocrt_tls_callback = tls_callback;
+
+#endif
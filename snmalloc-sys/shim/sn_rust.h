@@ -0,0 +1,428 @@
+// C declarations of the Rust shim: the upstream `snmalloc/override/rust.cc`
+// and the snmalloc-rs extensions in `rust_ext.cc`.
+//
+// `snmalloc-sys` declares exactly these functions; with the `bindgen` feature
+// its bindings are generated from this header.
+//
+// Some functions are only compiled into the shim with the feature of
+// `snmalloc-sys` that calls them, which defines the matching
+// `SNMALLOC_RUST_*_API`:
+//  - `handle-api`: the `sn_rust_allocator_` functions;
+//  - `stats-api`: `sn_rust_memory_usage`, `sn_rust_address_space`,
+//    `sn_rust_slab_info`, `sn_rust_large_cache_stats` and
+//    `sn_rust_owned_ranges`;
+//  - `guard-api`: the `sn_rust_guarded_` and `sn_rust_redzone_` functions.
+#pragma once
+
+// With the `prefix-symbols` feature, every function is exported under a
+// prefix, and `sn_rust_prefix.h`, generated next to the archives, maps the
+// names below to the exported ones. The package files of snmalloc-sys define
+// `SN_RUST_PREFIXED` and add its directory to the include path.
+#ifdef SN_RUST_PREFIXED
+#  include "sn_rust_prefix.h"
+#endif
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C"
+{
+#endif
+
+  /// Allocate the memory with the given alignment and size.
+  void* sn_rust_alloc(size_t alignment, size_t size);
+
+  /// Behaves like `sn_rust_alloc`, but also ensures that the contents are set
+  /// to zero before being returned.
+  void* sn_rust_alloc_zeroed(size_t alignment, size_t size);
+
+  /// De-allocate the memory at the given address with the given alignment and
+  /// size.
+  void sn_rust_dealloc(void* ptr, size_t alignment, size_t size);
+
+  /// Re-allocate the memory at the given address with the given alignment and
+  /// size.
+  void* sn_rust_realloc(
+    void* ptr, size_t alignment, size_t old_size, size_t new_size);
+
+  /// Return the available bytes in a memory block.
+  size_t sn_rust_usable_size(const void* ptr);
+
+  /// Opaque handle to a dedicated snmalloc allocator (`handle-api`).
+  typedef struct sn_rust_allocator sn_rust_allocator;
+
+  /// Create a dedicated allocator, independent from the thread-local one.
+  sn_rust_allocator* sn_rust_allocator_new(void);
+
+  /// Options of `sn_rust_allocator_new_with_config`; zeroed fields keep the
+  /// defaults of `sn_rust_allocator_new`. The pre-allocated pool and the
+  /// locking of `snmalloc-rs` are applied on the Rust side and have no field.
+  typedef struct sn_rust_allocator_config_t
+  {
+    /// Label of the handle, `name_len` bytes long, see
+    /// `sn_rust_allocator_set_name`. May be null.
+    const char* name;
+    size_t name_len;
+    /// Largest size the handle allocates or reallocates to: larger requests
+    /// return null. 0 for no limit.
+    size_t max_alloc_size;
+  } sn_rust_allocator_config_t;
+
+  /// Create a dedicated allocator configured by `config`, which is only read
+  /// during the call.
+  sn_rust_allocator* sn_rust_allocator_new_with_config(
+    const sn_rust_allocator_config_t* config);
+
+  /// Release a dedicated allocator.
+  void sn_rust_allocator_free(sn_rust_allocator* handle);
+
+  /// Same as `sn_rust_alloc`, but allocates from the given handle.
+  void* sn_rust_allocator_allocate(
+    sn_rust_allocator* handle, size_t alignment, size_t size);
+
+  /// Same as `sn_rust_alloc_zeroed`, but allocates from the given handle.
+  void* sn_rust_allocator_allocate_zeroed(
+    sn_rust_allocator* handle, size_t alignment, size_t size);
+
+  /// Same as `sn_rust_alloc_filled`, but allocates from the given handle.
+  void* sn_rust_allocator_allocate_filled(
+    sn_rust_allocator* handle, size_t alignment, size_t size, uint8_t byte);
+
+  /// Same as `sn_rust_dealloc`, but deallocates through the given handle.
+  /// `ptr` may come from any handle of the same shim, or from `sn_rust_alloc`:
+  /// memory owned by another allocator is sent back to it as a remote free.
+  void sn_rust_allocator_deallocate(
+    sn_rust_allocator* handle, void* ptr, size_t alignment, size_t size);
+
+  /// Same as `sn_rust_dealloc_many`, but deallocates through the given handle.
+  void sn_rust_allocator_deallocate_many(
+    sn_rust_allocator* handle, sn_rust_block_t* blocks, size_t count);
+
+  /// Same as `sn_rust_realloc`, but reallocates through the given handle.
+  void* sn_rust_allocator_reallocate(
+    sn_rust_allocator* handle,
+    void* ptr,
+    size_t alignment,
+    size_t old_size,
+    size_t new_size);
+
+  /// Send the remote frees buffered by the handle to their owners, process the
+  /// frees sent to it, and return its cached memory to the global pool.
+  void sn_rust_allocator_flush(sn_rust_allocator* handle);
+
+  /// Label the handle for diagnostics with the `len` bytes at `name`, truncated
+  /// to 31 bytes. An empty name removes the label.
+  void sn_rust_allocator_set_name(
+    sn_rust_allocator* handle, const char* name, size_t len);
+
+  /// Return the NUL-terminated label of the handle, storing its length in
+  /// `len`, or null if it has none. The label lives until the next call of
+  /// `sn_rust_allocator_set_name` on the handle.
+  const char* sn_rust_allocator_name(const sn_rust_allocator* handle, size_t* len);
+
+  /// Allocate memory followed by an inaccessible guard page.
+  void* sn_rust_guarded_alloc(
+    size_t alignment, size_t size, bool zero, bool leading_guard);
+
+  /// De-allocate memory returned by either `sn_rust_guarded_alloc` or
+  /// `sn_rust_alloc`.
+  void sn_rust_guarded_dealloc(void* ptr, size_t alignment, size_t size);
+
+  /// Return the number of bytes from `ptr` to the end of its allocation.
+  size_t sn_rust_remaining_bytes(const void* ptr);
+
+  /// Behaves like `sn_rust_alloc`, but sets every byte of the allocation to
+  /// `byte`.
+  void* sn_rust_alloc_filled(size_t alignment, size_t size, uint8_t byte);
+
+  /// Report the memory currently, and at most, used by the allocator.
+  void sn_rust_memory_usage(
+    size_t* current_memory_usage, size_t* peak_memory_usage);
+
+  /// Report the address space reserved from the OS, and the part of it
+  /// committed, in bytes. Platforms whose PAL is not counted report the peak
+  /// and current usage instead.
+  void sn_rust_address_space(size_t* reserved, size_t* committed);
+
+  /// Like `sn_rust_alloc`, but also stores the usable size of the block,
+  /// which may be freed with any size between `size` and `*usable`.
+  void* sn_rust_alloc_usable(size_t alignment, size_t size, size_t* usable);
+
+  /// A block to free with `sn_rust_dealloc_many`, with the alignment and size
+  /// it was allocated with.
+  typedef struct sn_rust_block_t
+  {
+    void* ptr;
+    size_t alignment;
+    size_t size;
+  } sn_rust_block_t;
+
+  /// Free the `count` blocks of `blocks`, like `sn_rust_dealloc` on each of
+  /// them. The blocks are sorted by address first, in place, so that the
+  /// blocks of a slab are freed together.
+  void sn_rust_dealloc_many(sn_rust_block_t* blocks, size_t count);
+
+  /// Free the block at `ptr`, allocated by any allocator of the shim, without
+  /// initialising the allocator of the calling thread: the free is posted to
+  /// the message queue of the owner of the block through a process-wide
+  /// allocator, under a lock. The thread gathers 64 frees before posting
+  /// them together; the rest are posted when it exits or calls
+  /// `sn_rust_flush_thread_cache` or `sn_rust_shutdown`.
+  void sn_rust_remote_dealloc(void* ptr);
+
+  /// Like `sn_rust_dealloc`, but posts pending frees of memory owned by other
+  /// threads once more than `batch` bytes are buffered.
+  void sn_rust_dealloc_batched(
+    void* ptr, size_t alignment, size_t size, size_t batch);
+
+  /// Report whether `sn_rust_dealloc_batched` can clamp the batches of the
+  /// snmalloc the shim was compiled against; it frees like `sn_rust_dealloc`
+  /// otherwise.
+  bool sn_rust_remote_batch_supported(void);
+
+  /// Report whether the static initializer of the shim allocated from
+  /// snmalloc successfully, before `main`. Always false unless the shim was
+  /// built with `SNMALLOC_RUST_EARLY_INIT`.
+  bool sn_rust_initialized_before_main(void);
+
+  /// Report whether the allocator lives in a shared object rather than in the
+  /// main program.
+  bool sn_rust_is_shared_object(void);
+
+  /// Store up to `count` return addresses of the callers of this function in
+  /// `frames`, innermost first, without allocating. Returns how many were
+  /// stored: 0 where the stack cannot be walked.
+  size_t sn_rust_return_addresses(void** frames, size_t count);
+
+  /// Make the whole pages of the allocation at `ptr` read-only, or writable
+  /// again. Returns false if the protection could not be changed.
+  bool sn_rust_protect_read_only(void* ptr, size_t size, bool read_only);
+
+  /// Make the whole pages of the allocation at `ptr` inaccessible, or
+  /// accessible again. Returns false if the protection could not be changed.
+  bool sn_rust_protect_no_access(void* ptr, size_t size, bool no_access);
+
+  /// Geometry of the slab holding a small object.
+  typedef struct sn_rust_slab_info_t
+  {
+    size_t sizeclass;
+    size_t object_size;
+    void* slab_base;
+    size_t slab_size;
+    size_t objects_per_slab;
+  } sn_rust_slab_info_t;
+
+  /// Describe the slab holding `ptr`. Returns false if `ptr` is not part of a
+  /// small object owned by snmalloc.
+  bool sn_rust_slab_info(const void* ptr, sn_rust_slab_info_t* info);
+
+  /// Behaves like `sn_rust_realloc`, but also sets the bytes between
+  /// `old_size` and `new_size` to zero.
+  void* sn_rust_realloc_zeroed(
+    void* ptr, size_t alignment, size_t old_size, size_t new_size);
+
+  /// Behaves like `sn_rust_alloc_zeroed`, but replaces the pages of large
+  /// objects with fresh ones of the OS instead of writing zeros, so that they
+  /// only become resident when written. If the pages cannot be replaced, the
+  /// object is zeroed by `memset`, or freed and null is returned if `strict`.
+  void* sn_rust_alloc_zeroed_fresh(size_t alignment, size_t size, bool strict);
+
+  /// Clear the OS error of the calling thread (`errno`, or `GetLastError()` on
+  /// Windows) ahead of an allocation whose failure is recorded.
+  void sn_rust_clear_os_error(void);
+
+  /// Record the OS error of the calling thread (`errno`, or `GetLastError()` on
+  /// Windows) after an allocation failed, and return it; 0 if the allocation
+  /// left it cleared.
+  int sn_rust_record_os_error(void);
+
+  /// Return the OS error recorded by the last `sn_rust_record_os_error` of the
+  /// calling thread, 0 if none.
+  int sn_rust_last_os_error(void);
+
+  /// Return the memory cached by the calling thread to the global pool.
+  void sn_rust_flush_thread_cache(void);
+
+  /// Initialise the allocator of the calling thread ahead of its first
+  /// allocation.
+  void sn_rust_thread_init(void);
+
+  /// Return the allocator of the calling thread, with its caches, to the
+  /// global pool ahead of the thread exit.
+  void sn_rust_thread_teardown(void);
+
+  /// Return the memory cached by the calling thread and by the allocators of
+  /// exited threads to the global pool.
+  void sn_rust_shutdown(void);
+
+  /// Allocate a naturally aligned chunk of at least `size` bytes, storing its
+  /// actual size in `chunk_size`.
+  void* sn_rust_alloc_chunk(size_t size, size_t* chunk_size);
+
+  /// De-allocate a chunk returned by `sn_rust_alloc_chunk`.
+  void sn_rust_dealloc_chunk(void* ptr, size_t chunk_size);
+
+  /// Return the `CACHE_FRIENDLY_OFFSET` snmalloc was built with, 0 if unset.
+  size_t sn_rust_cache_friendly_offset(void);
+
+  /// Return the size of the block serving a request of `size` bytes aligned to
+  /// `alignment`.
+  size_t sn_rust_round_size(size_t alignment, size_t size);
+
+  /// Allocate `size` bytes aligned like `malloc`, to be freed with
+  /// `sn_rust_free`. Returns a unique pointer for a size of 0.
+  void* sn_rust_malloc(size_t size);
+
+  /// De-allocate any block allocated by snmalloc through the thread-local
+  /// allocator, without its size or alignment. Null is ignored.
+  void sn_rust_free(void* ptr);
+
+  /// Cap the bytes held by the large-object cache, freeing cached objects
+  /// above the new cap. 0, the default, disables the cache.
+  void sn_rust_set_large_cache(size_t limit);
+
+  /// Behaves like `sn_rust_alloc` (or `sn_rust_alloc_zeroed` if `zero` is
+  /// set), but serves large objects from the large-object cache when possible.
+  void* sn_rust_alloc_large_cached(size_t alignment, size_t size, bool zero);
+
+  /// Behaves like `sn_rust_dealloc`, but keeps large objects in the
+  /// large-object cache while it has room for them.
+  void sn_rust_dealloc_large_cached(void* ptr, size_t alignment, size_t size);
+
+  /// Behaves like `sn_rust_alloc` (or `sn_rust_alloc_zeroed` if `zero` is
+  /// set), but allocates from a process-wide allocator kept for long-lived
+  /// blocks, whose slabs are not shared with the thread-local allocators. The
+  /// block may be freed by `sn_rust_dealloc` from any thread.
+  void* sn_rust_alloc_hint_cold(size_t alignment, size_t size, bool zero);
+
+  typedef struct sn_rust_large_cache_stats_t
+  {
+    size_t hits;
+    size_t misses;
+    size_t cached_bytes;
+    size_t cached_objects;
+  } sn_rust_large_cache_stats_t;
+
+  /// Report the activity and content of the large-object cache.
+  void sn_rust_large_cache_stats(sn_rust_large_cache_stats_t* stats);
+
+  /// Corruption of a redzone found by `sn_rust_redzone_dealloc`.
+  typedef struct sn_rust_redzone_report_t
+  {
+    void* ptr;
+    size_t size;
+    ptrdiff_t offset;
+    uint64_t expected;
+    uint64_t found;
+  } sn_rust_redzone_report_t;
+
+  /// Allocate memory between two 16-byte redzones filled with canaries.
+  void* sn_rust_redzone_alloc(size_t alignment, size_t size, bool zero);
+
+  /// De-allocate memory returned by `sn_rust_redzone_alloc`. Returns false,
+  /// after describing the first overwritten canary in `report`, if a redzone
+  /// was corrupted; the memory is released either way.
+  bool sn_rust_redzone_dealloc(
+    void* ptr,
+    size_t alignment,
+    size_t size,
+    sn_rust_redzone_report_t* report);
+
+  /// Receives one range of `sn_rust_owned_ranges`.
+  typedef void (*sn_rust_range_callback)(
+    void* context, void* base, size_t size);
+
+  /// Call `callback` for every maximal range of chunks currently backing
+  /// allocations, in address order. Returns false, without calling it, if the
+  /// platform cannot enumerate the pagemap.
+  bool sn_rust_owned_ranges(sn_rust_range_callback callback, void* context);
+
+  /// Lock the pages spanned by `size` bytes at `ptr` in memory (`mlock`, or
+  /// `VirtualLock` on Windows), so that they are never swapped out. A lock
+  /// exceeding the limit of the process is retried once after raising the
+  /// soft `RLIMIT_MEMLOCK` to the hard one, or growing the working set on
+  /// Windows. Returns false if the pages could not be locked.
+  bool sn_rust_lock_pages(void* ptr, size_t size);
+
+  /// Return the bytes the PAL locked as it committed them, and write the
+  /// commits it could not lock to `failures`. Both are 0 unless the shim is
+  /// built with `SNMALLOC_RUST_LOCK_MEMORY`.
+  size_t sn_rust_locked_commits(size_t* failures);
+
+  /// Return the page size of the running kernel, which the shim rounds its
+  /// page-granular operations to when it exceeds the one snmalloc was
+  /// compiled for.
+  size_t sn_rust_os_page_size(void);
+
+  /// Receives the context passed to `sn_rust_at_thread_exit`.
+  typedef void (*sn_rust_thread_exit_callback)(void* context);
+
+  /// Call `callback` with `context` when the calling thread exits, from a C++
+  /// thread-local destructor. Callbacks run in reverse order of registration.
+  /// Returns false if the thread already has 8 callbacks.
+  bool sn_rust_at_thread_exit(
+    sn_rust_thread_exit_callback callback, void* context);
+
+  /// Copy the name the OS holds for the calling thread (`pthread_getname_np`,
+  /// or `GetThreadDescription` on Windows) to `buffer` as UTF-8 with a
+  /// terminating NUL, returning its length without the NUL. Returns 0 if the
+  /// thread has no name, the platform does not support thread names, or the
+  /// name does not fit.
+  size_t sn_rust_thread_name(char* buffer, size_t size);
+
+  /// Called before a deallocation: until `sn_rust_audit_dealloc_leave`, the
+  /// PAL aborts if the calling thread reserves or commits memory. A no-op
+  /// unless the shim is built with `SNMALLOC_RUST_AUDIT_DEALLOC`.
+  void sn_rust_audit_dealloc_enter(void);
+
+  /// Called after the deallocation audited by `sn_rust_audit_dealloc_enter`.
+  void sn_rust_audit_dealloc_leave(void);
+
+  /// Return 64 bits of entropy from the platform, as snmalloc seeds its own
+  /// randomisation with, or 0 if the platform has no source of entropy.
+  uint64_t sn_rust_entropy(void);
+
+  /// Set the `size` bytes at `ptr` to zero. The whole pages among them are
+  /// zeroed by the platform, e.g. by giving them back to the OS, which is
+  /// cheaper than writing large blocks.
+  void sn_rust_zero(void* ptr, size_t size);
+
+  /// Only available with the `checked-handles` feature: the allocator handle
+  /// functions of the hardened shim, which behave like their `sn_rust_`
+  /// counterparts. Handles of both shims must not be mixed.
+  typedef struct snc_rust_allocator snc_rust_allocator;
+  snc_rust_allocator* snc_rust_allocator_new(void);
+  snc_rust_allocator* snc_rust_allocator_new_with_config(
+    const sn_rust_allocator_config_t* config);
+  void snc_rust_allocator_free(snc_rust_allocator* handle);
+  void* snc_rust_allocator_allocate(
+    snc_rust_allocator* handle, size_t alignment, size_t size);
+  void* snc_rust_allocator_allocate_zeroed(
+    snc_rust_allocator* handle, size_t alignment, size_t size);
+  void* snc_rust_allocator_allocate_filled(
+    snc_rust_allocator* handle, size_t alignment, size_t size, uint8_t byte);
+  void snc_rust_allocator_deallocate(
+    snc_rust_allocator* handle, void* ptr, size_t alignment, size_t size);
+  void snc_rust_allocator_deallocate_many(
+    snc_rust_allocator* handle, sn_rust_block_t* blocks, size_t count);
+  void* snc_rust_allocator_reallocate(
+    snc_rust_allocator* handle,
+    void* ptr,
+    size_t alignment,
+    size_t old_size,
+    size_t new_size);
+  void snc_rust_allocator_flush(snc_rust_allocator* handle);
+  void snc_rust_allocator_set_name(
+    snc_rust_allocator* handle, const char* name, size_t len);
+  const char*
+  snc_rust_allocator_name(const snc_rust_allocator* handle, size_t* len);
+
+  /// Only available with the `cxx-new` feature: report whether the global
+  /// C++ `operator new` resolves to snmalloc.
+  bool sn_rust_operator_new_is_snmalloc(void);
+
+#ifdef __cplusplus
+}
+#endif
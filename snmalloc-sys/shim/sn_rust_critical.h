@@ -0,0 +1,51 @@
+// Critical section of the shims built with the `critical-section` feature
+// (`SNMALLOC_RUST_CRITICAL_SECTION`).
+//
+// Every entry point of the shims that reaches an allocator of snmalloc, or one
+// of the locks of the shim, enters the critical section first, so that an
+// interrupt handler cannot enter snmalloc while the code it interrupted holds
+// one of its spin locks, whoever called them. The hooks are defined by snmalloc-sys
+// on top of the `critical-section` crate; they are not part of `sn_rust.h`, as
+// the shims call them rather than export them.
+//
+// The entry points of upstream (`snmalloc/override/rust.cc`: `sn_rust_alloc`,
+// `sn_rust_alloc_zeroed`, `sn_rust_dealloc`, `sn_rust_realloc`, ...) are not
+// wrapped: the Rust side enters the critical section around its calls to them,
+// and C and C++ callers must do the same.
+#pragma once
+
+#if defined(SNMALLOC_RUST_CRITICAL_SECTION)
+extern "C"
+{
+  /// Enters the critical section. Sections nest, and are left in the reverse
+  /// order.
+  void sn_rust_critical_enter(void);
+
+  /// Leaves the critical section entered last.
+  void sn_rust_critical_leave(void);
+}
+
+namespace
+{
+  /// Holds the critical section until the end of its scope.
+  struct CriticalSection
+  {
+    CriticalSection()
+    {
+      sn_rust_critical_enter();
+    }
+
+    ~CriticalSection()
+    {
+      sn_rust_critical_leave();
+    }
+
+    CriticalSection(const CriticalSection&) = delete;
+    CriticalSection& operator=(const CriticalSection&) = delete;
+  };
+}
+
+#  define SN_RUST_CRITICAL_SECTION() CriticalSection sn_rust_critical_section
+#else
+#  define SN_RUST_CRITICAL_SECTION() static_cast<void>(0)
+#endif
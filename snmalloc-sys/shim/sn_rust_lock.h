@@ -0,0 +1,82 @@
+// Locking of memory (`mlock`, or `VirtualLock` on Windows), shared by the
+// locked handles through `sn_rust_lock_pages` and by the counting PAL of
+// `sn_rust_pal.h`, which locks committed memory with
+// `SNMALLOC_RUST_LOCK_MEMORY`.
+#pragma once
+
+#include <atomic>
+#include <cstddef>
+
+#if defined(_WIN32)
+#  ifndef WIN32_LEAN_AND_MEAN
+#    define WIN32_LEAN_AND_MEAN
+#  endif
+#  include <windows.h>
+#else
+#  include <cerrno>
+#  include <sys/mman.h>
+#  include <sys/resource.h>
+#endif
+
+namespace snmalloc
+{
+#if !defined(_WIN32)
+  /// The soft RLIMIT_MEMLOCK is raised to the hard one at most once, on the
+  /// first lock that exceeds it.
+  inline std::atomic<bool> sn_rust_memlock_raised{false};
+#endif
+
+  /// Makes room for `len` more locked bytes after a lock failed, returning
+  /// whether it is worth retrying.
+  inline bool sn_rust_raise_lock_limit(size_t len)
+  {
+#if defined(_WIN32)
+    // VirtualLock is bounded by the minimum working set, which must grow with
+    // the locked memory.
+    if (GetLastError() != ERROR_WORKING_SET_QUOTA)
+      return false;
+    HANDLE process = GetCurrentProcess();
+    SIZE_T min_size, max_size;
+    return GetProcessWorkingSetSize(process, &min_size, &max_size) &&
+      SetProcessWorkingSetSize(
+             process,
+             min_size + len,
+             max_size > min_size + len ? max_size : min_size + len);
+#else
+    (void)len;
+    if (
+      (errno != ENOMEM && errno != EAGAIN) ||
+      sn_rust_memlock_raised.exchange(true))
+      return false;
+    struct rlimit limit;
+    if (getrlimit(RLIMIT_MEMLOCK, &limit) != 0 || limit.rlim_cur == limit.rlim_max)
+      return false;
+    limit.rlim_cur = limit.rlim_max;
+    return setrlimit(RLIMIT_MEMLOCK, &limit) == 0;
+#endif
+  }
+
+  /// Locks the `len` bytes at `base`, page-aligned, once more after raising
+  /// the limit if needed. Returns false if they could not be locked.
+  inline bool sn_rust_lock_range(void* base, size_t len)
+  {
+#if defined(_WIN32)
+    auto lock = [](void* p, size_t n) { return VirtualLock(p, n) != 0; };
+#else
+    auto lock = [](void* p, size_t n) { return mlock(p, n) == 0; };
+#endif
+    return lock(base, len) ||
+      (sn_rust_raise_lock_limit(len) && lock(base, len));
+  }
+
+  /// Unlocks the `len` bytes at `base`, page-aligned, so that they can be
+  /// decommitted.
+  inline void sn_rust_unlock_range(void* base, size_t len)
+  {
+#if defined(_WIN32)
+    VirtualUnlock(base, len);
+#else
+    munlock(base, len);
+#endif
+  }
+}
@@ -0,0 +1,183 @@
+// Memory provider of the shims built with the statistics section
+// (`SNMALLOC_RUST_STATS_API`), the audit of frees (`SNMALLOC_RUST_AUDIT_DEALLOC`)
+// or the locking of memory (`SNMALLOC_RUST_LOCK_MEMORY`): the platform PAL of
+// snmalloc, also counting the address space the backend reserves and the
+// memory it commits, for `sn_rust_address_space`, aborting when a free audited
+// by `sn_rust_audit_dealloc_enter` does either, and locking the memory as it is
+// committed.
+//
+// The build force-includes this header ahead of every source of the shims, so
+// that `SNMALLOC_MEMORY_PROVIDER` names the counting PAL before `pal.h` picks
+// its default. Platforms not listed below keep the PAL of snmalloc, and
+// `sn_rust_address_space` falls back to the usage counters of the backend;
+// their frees are not audited, nor their memory locked.
+#pragma once
+
+#if !defined(SNMALLOC_MEMORY_PROVIDER) && !defined(OPEN_ENCLAVE)
+#  if defined(_WIN32)
+#    include "snmalloc/pal/pal_windows.h"
+#    define SN_RUST_PLATFORM_PAL PALWindows
+#  elif defined(__APPLE__)
+#    include "snmalloc/pal/pal_apple.h"
+#    define SN_RUST_PLATFORM_PAL PALApple<>
+#  elif defined(__linux__)
+#    include "snmalloc/pal/pal_linux.h"
+#    define SN_RUST_PLATFORM_PAL PALLinux
+#  elif defined(__FreeBSD__) && !defined(_KERNEL)
+#    include "snmalloc/pal/pal_freebsd.h"
+#    define SN_RUST_PLATFORM_PAL PALFreeBSD
+#  elif defined(__NetBSD__)
+#    include "snmalloc/pal/pal_netbsd.h"
+#    define SN_RUST_PLATFORM_PAL PALNetBSD
+#  elif defined(__OpenBSD__)
+#    include "snmalloc/pal/pal_openbsd.h"
+#    define SN_RUST_PLATFORM_PAL PALOpenBSD
+#  elif defined(__sun)
+#    include "snmalloc/pal/pal_solaris.h"
+#    define SN_RUST_PLATFORM_PAL PALSolaris
+#  elif defined(__DragonFly__)
+#    include "snmalloc/pal/pal_dragonfly.h"
+#    define SN_RUST_PLATFORM_PAL PALDragonfly
+#  elif defined(__HAIKU__)
+#    include "snmalloc/pal/pal_haiku.h"
+#    define SN_RUST_PLATFORM_PAL PALHaiku
+#  endif
+#endif
+
+#if defined(SN_RUST_PLATFORM_PAL)
+#  include <atomic>
+#  include <cstddef>
+#  include <cstdio>
+#  include <cstdlib>
+#  if defined(SNMALLOC_RUST_LOCK_MEMORY)
+#    include "sn_rust_lock.h"
+#  endif
+
+namespace snmalloc
+{
+#  if defined(SNMALLOC_RUST_AUDIT_DEALLOC)
+  /// Set while the thread frees through the shim, between
+  /// `sn_rust_audit_dealloc_enter` and `sn_rust_audit_dealloc_leave`.
+  inline thread_local bool sn_rust_freeing = false;
+#  endif
+
+  /// Aborts if the calling thread reserves or commits memory from inside an
+  /// audited free.
+  inline void sn_rust_audit_mapping()
+  {
+#  if defined(SNMALLOC_RUST_AUDIT_DEALLOC)
+    if (sn_rust_freeing)
+    {
+      fputs(
+        "snmalloc-rs: no-alloc-on-free: a free reserved or committed memory\n",
+        stderr);
+      abort();
+    }
+#  endif
+  }
+
+  /// `Base`, with the bytes reserved and committed through it counted. The
+  /// backend only calls the PAL to map or commit whole chunks, so counting
+  /// does not slow the allocation paths down.
+  template<typename Base>
+  class SnRustCountingPal : public Base
+  {
+  public:
+    /// Address space reserved from the OS; snmalloc never releases it.
+    static inline std::atomic<size_t> reserved{0};
+    /// Memory committed, i.e. reserved and not given back with
+    /// `notify_not_using`.
+    static inline std::atomic<size_t> committed{0};
+    /// Bytes locked as they were committed, with `SNMALLOC_RUST_LOCK_MEMORY`.
+    /// Memory decommitted and committed again is counted again.
+    static inline std::atomic<size_t> locked{0};
+    /// Commits whose pages could not be locked.
+    static inline std::atomic<size_t> lock_failures{0};
+
+    /// Locks memory being committed, so that it never reaches swap. A failure
+    /// leaves it unlocked, as the allocation in progress cannot fail: it is
+    /// counted, and reported once on `stderr`.
+    static void lock_committed(void* p, size_t size) noexcept
+    {
+#  if defined(SNMALLOC_RUST_LOCK_MEMORY)
+      if (sn_rust_lock_range(p, size))
+      {
+        locked.fetch_add(size, std::memory_order_relaxed);
+      }
+      else if (lock_failures.fetch_add(1, std::memory_order_relaxed) == 0)
+      {
+        fputs(
+          "snmalloc-rs: lock-memory: committed memory could not be locked, "
+          "it may be swapped out\n",
+          stderr);
+      }
+#  else
+      (void)p;
+      (void)size;
+#  endif
+    }
+
+    static void* reserve(size_t size) noexcept
+    {
+      sn_rust_audit_mapping();
+      void* p = Base::reserve(size);
+      if (p != nullptr)
+        reserved.fetch_add(size, std::memory_order_relaxed);
+      return p;
+    }
+
+    // Only forwarded if `Base` has it, so that the features of the PAL seen by
+    // the backend do not change.
+    template<bool state_using, typename B = Base>
+    static auto reserve_aligned(size_t size) noexcept
+      -> decltype(B::template reserve_aligned<state_using>(size))
+    {
+      sn_rust_audit_mapping();
+      void* p = B::template reserve_aligned<state_using>(size);
+      if (p != nullptr)
+      {
+        reserved.fetch_add(size, std::memory_order_relaxed);
+        if (state_using)
+        {
+          committed.fetch_add(size, std::memory_order_relaxed);
+          lock_committed(p, size);
+        }
+      }
+      return p;
+    }
+
+    template<ZeroMem zero_mem>
+    static void notify_using(void* p, size_t size) noexcept
+    {
+      sn_rust_audit_mapping();
+      Base::template notify_using<zero_mem>(p, size);
+      committed.fetch_add(size, std::memory_order_relaxed);
+      lock_committed(p, size);
+    }
+
+    template<typename B = Base>
+    static auto notify_using_readonly(void* p, size_t size) noexcept
+      -> decltype(B::notify_using_readonly(p, size))
+    {
+      sn_rust_audit_mapping();
+      committed.fetch_add(size, std::memory_order_relaxed);
+      return B::notify_using_readonly(p, size);
+    }
+
+    static void notify_not_using(void* p, size_t size) noexcept
+    {
+#  if defined(SNMALLOC_RUST_LOCK_MEMORY)
+      // Locked pages cannot be decommitted.
+      sn_rust_unlock_range(p, size);
+#  endif
+      Base::notify_not_using(p, size);
+      committed.fetch_sub(size, std::memory_order_relaxed);
+    }
+  };
+
+  using SnRustPal = SnRustCountingPal<SN_RUST_PLATFORM_PAL>;
+}
+
+#  define SNMALLOC_MEMORY_PROVIDER SnRustPal
+#  define SN_RUST_COUNTING_PAL
+#endif
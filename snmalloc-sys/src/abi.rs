@@ -0,0 +1,142 @@
+//! Signatures of the shim functions, as declared by `shim/sn_rust.h` and by this crate.
+//!
+//! The C++ side is checked against the header by the compiler, which includes it, but nothing
+//! checks the Rust declarations: a function renamed, removed or given another parameter in the
+//! shim only shows up as a link error, or as a call with the wrong arguments. This module reduces
+//! both spellings of a signature to the same canonical text and hashes it. It is used by the tests,
+//! which compare the declarations of `lib.rs` with the header, and by the build script, which
+//! checks that every function of the header is defined by the archives it built.
+use std::{string::String, vec::Vec};
+
+/// A shim function and the hash of its canonical signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    /// Canonical signature, e.g. `ptr(usize,usize)`.
+    pub text: String,
+    /// FNV-1a hash of `text`.
+    pub hash: u64,
+}
+
+impl Signature {
+    fn new(name: &str, ret: String, params: Vec<String>) -> Self {
+        let text = std::format!("{}({})", ret, params.join(","));
+        let hash = text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash: u64, b| {
+            (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+        Self { name: name.into(), text, hash }
+    }
+}
+
+/// Whether `name` is a function of the shim, rather than of the C library.
+pub fn is_shim(name: &str) -> bool {
+    name.starts_with("sn_rust_") || name.starts_with("snc_rust_")
+}
+
+/// Returns the functions declared by the shim header.
+pub fn header_signatures(header: &str) -> Vec<Signature> {
+    let code: Vec<&str> = header
+        .lines()
+        .map(|line| line.split("//").next().unwrap_or_default())
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect();
+    let code = code.join(" ");
+    let mut signatures = Vec::new();
+    for decl in code.split([';', '{', '}']).map(str::trim) {
+        if decl.starts_with("typedef") {
+            continue;
+        }
+        let Some((head, params)) = decl.strip_suffix(')').and_then(|decl| decl.split_once('(')) else {
+            continue;
+        };
+        let head = head.trim_end();
+        let ret = head.trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_');
+        let name = &head[ret.len()..];
+        if !is_shim(name) {
+            continue;
+        }
+        let params = params
+            .split(',')
+            .map(str::trim)
+            .filter(|param| !param.is_empty() && *param != "void")
+            .map(|param| c_type(param.trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_')))
+            .collect();
+        signatures.push(Signature::new(name, c_type(ret), params));
+    }
+    signatures
+}
+
+/// Returns the shim functions declared by the `extern` blocks of `source`.
+pub fn rust_signatures(source: &str) -> Vec<Signature> {
+    let mut signatures = Vec::new();
+    for decl in source.split("pub fn ").skip(1) {
+        let Some((name, rest)) = decl.split_once('(') else {
+            continue;
+        };
+        let Some((params, rest)) = rest.split_once(')') else {
+            continue;
+        };
+        if !is_shim(name.trim()) {
+            continue;
+        }
+        let ret = rest.split(';').next().unwrap_or_default().trim();
+        let ret = ret.strip_prefix("->").map_or("void".into(), |ret| rust_type(ret.trim()));
+        let params = params
+            .split(',')
+            .filter_map(|param| param.split_once(':'))
+            .map(|(_, ty)| rust_type(ty.trim()))
+            .collect();
+        signatures.push(Signature::new(name.trim(), ret, params));
+    }
+    signatures
+}
+
+fn c_type(ty: &str) -> String {
+    if ty.contains('*') {
+        return "ptr".into();
+    }
+    let ty = ty.split_whitespace().rfind(|word| !matches!(*word, "const" | "struct"));
+    match ty.unwrap_or("void") {
+        "size_t" => "usize",
+        "ptrdiff_t" => "isize",
+        "int" => "i32",
+        "unsigned" => "u32",
+        "uint8_t" => "u8",
+        "uint32_t" => "u32",
+        "uint64_t" => "u64",
+        other => other,
+    }
+    .into()
+}
+
+fn rust_type(ty: &str) -> String {
+    if ty.starts_with('*') {
+        return "ptr".into();
+    }
+    match ty {
+        "c_int" => "i32",
+        "c_uint" => "u32",
+        other => other,
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_declares_the_shim_functions() {
+        let header = header_signatures(include_str!("../shim/sn_rust.h"));
+        let rust = rust_signatures(include_str!("lib.rs"));
+        assert!(header.iter().any(|sig| sig.name == "sn_rust_alloc" && sig.text == "ptr(usize,usize)"));
+        for sig in &header {
+            let decl = rust.iter().find(|decl| decl.name == sig.name);
+            let decl = decl.unwrap_or_else(|| panic!("{} is in sn_rust.h but not declared", sig.name));
+            assert_eq!(decl.hash, sig.hash, "{} is {} in sn_rust.h but declared as {}", sig.name, sig.text, decl.text);
+        }
+        for decl in &rust {
+            assert!(header.iter().any(|sig| sig.name == decl.name), "{} is not in sn_rust.h", decl.name);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Critical section of the shim (`critical-section` feature).
+//!
+//! Built with this feature, every entry point of the shim that reaches an allocator or a lock of
+//! snmalloc calls [`sn_rust_critical_enter`] first and [`sn_rust_critical_leave`] on its way out,
+//! see `shim/sn_rust_critical.h`, so that C and C++ callers of those entry points are covered as
+//! well; the entry points of upstream's `rust.cc` (`sn_rust_alloc`, `sn_rust_dealloc`, ...) are
+//! only covered when called from Rust. They enter the critical section of the
+//! [`critical_section`] crate, whose implementation the application provides.
+use core::cell::UnsafeCell;
+
+use critical_section::RestoreState;
+
+/// Deepest nesting of the critical sections of the shim: its entry points seldom call each other.
+const MAX_DEPTH: usize = 8;
+
+/// States to restore on leaving, innermost last. Only the holder of the critical section touches
+/// them.
+struct Nesting {
+    depth: UnsafeCell<usize>,
+    states: UnsafeCell<[RestoreState; MAX_DEPTH]>,
+}
+
+unsafe impl Sync for Nesting {}
+
+static NESTING: Nesting = Nesting {
+    depth: UnsafeCell::new(0),
+    states: UnsafeCell::new([RestoreState::invalid(); MAX_DEPTH]),
+};
+
+/// Enters the critical section. Sections nest, and are left in the reverse order.
+#[export_name = concat!(env!("SNMALLOC_SYS_SYMBOL_PREFIX"), "sn_rust_critical_enter")]
+pub extern "C" fn sn_rust_critical_enter() {
+    // SAFETY: released by the matching `sn_rust_critical_leave`, in the reverse order.
+    let state = unsafe { critical_section::acquire() };
+    // SAFETY: nothing else touches the nesting while the section is held.
+    unsafe {
+        let depth = &mut *NESTING.depth.get();
+        if *depth == MAX_DEPTH {
+            nesting_failed(format_args!("critical sections of the shim nested deeper than {}", MAX_DEPTH));
+        }
+        (*NESTING.states.get())[*depth] = state;
+        *depth += 1;
+    }
+}
+
+/// Leaves the critical section entered last.
+#[export_name = concat!(env!("SNMALLOC_SYS_SYMBOL_PREFIX"), "sn_rust_critical_leave")]
+pub extern "C" fn sn_rust_critical_leave() {
+    // SAFETY: the section is still held, by the matching `sn_rust_critical_enter`.
+    let state = unsafe {
+        let depth = &mut *NESTING.depth.get();
+        if *depth == 0 {
+            nesting_failed(format_args!("critical section of the shim left without being entered"));
+        }
+        *depth -= 1;
+        (*NESTING.states.get())[*depth]
+    };
+    // SAFETY: the state of the innermost section, as the nesting of `critical_section` requires.
+    unsafe { critical_section::release(state) };
+}
+
+/// Reports a broken nesting. The panic cannot unwind out of the `extern "C"` entry points, so
+/// the program aborts with `message` rather than indexing out of the saved states.
+#[cold]
+#[inline(never)]
+fn nesting_failed(message: core::fmt::Arguments) -> ! {
+    panic!("snmalloc-sys: {}", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_leaves_nested_sections() {
+        sn_rust_critical_enter();
+        sn_rust_critical_enter();
+        sn_rust_critical_leave();
+        sn_rust_critical_leave();
+        // Another thread could not enter if a section were still held.
+        std::thread::spawn(|| critical_section::with(|_| ())).join().unwrap();
+    }
+}
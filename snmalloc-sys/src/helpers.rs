@@ -0,0 +1,94 @@
+//! Null-checked wrappers around the raw functions.
+//!
+//! The raw layer reports failure as a null pointer; these helpers turn it into `None`, so that
+//! users of the raw layer do not have to repeat the checks. They are `no_std` and add no cost
+//! over the functions they wrap.
+use core::{ffi::c_void, ptr::NonNull};
+
+use crate::*;
+
+/// Allocates `size` bytes with `malloc` semantics.
+#[inline]
+pub fn try_malloc(size: usize) -> Option<NonNull<c_void>> {
+    NonNull::new(unsafe { malloc(size) })
+}
+
+/// Allocates `count * size` zeroed bytes with `calloc` semantics, failing on overflow.
+#[inline]
+pub fn try_calloc(count: usize, size: usize) -> Option<NonNull<c_void>> {
+    NonNull::new(unsafe { calloc(count, size) })
+}
+
+/// Re-allocates `p` to `size` bytes with `realloc` semantics. On failure `p` is left untouched.
+///
+/// # Safety
+/// `p` must be null or have been allocated by `malloc`, `calloc` or `realloc`.
+#[inline]
+pub unsafe fn try_realloc(p: *mut c_void, size: usize) -> Option<NonNull<c_void>> {
+    NonNull::new(realloc(p, size))
+}
+
+/// Allocates `size` bytes aligned to `alignment` (a power of two).
+#[inline]
+pub fn try_alloc(alignment: usize, size: usize) -> Option<NonNull<c_void>> {
+    NonNull::new(unsafe { sn_rust_alloc(alignment, size) })
+}
+
+/// Behaves like [`try_alloc`], but also zeroes the memory.
+#[inline]
+pub fn try_alloc_zeroed(alignment: usize, size: usize) -> Option<NonNull<c_void>> {
+    NonNull::new(unsafe { sn_rust_alloc_zeroed(alignment, size) })
+}
+
+/// Re-allocates `ptr` from `old_size` to `new_size` bytes. On failure `ptr` is left untouched.
+///
+/// # Safety
+/// `ptr` must have been allocated by [`sn_rust_alloc`] (or a wrapper) with `alignment` and `old_size`.
+#[inline]
+pub unsafe fn try_realloc_aligned(
+    ptr: NonNull<c_void>,
+    alignment: usize,
+    old_size: usize,
+    new_size: usize,
+) -> Option<NonNull<c_void>> {
+    NonNull::new(sn_rust_realloc(ptr.as_ptr(), alignment, old_size, new_size))
+}
+
+/// Returns the usable size of the block at `p`, or `None` if `p` is null.
+///
+/// # Safety
+/// `p` must be null or point to the start of memory allocated by snmalloc.
+#[inline]
+pub unsafe fn usable_size(p: *const c_void) -> Option<usize> {
+    match p.is_null() {
+        true => None,
+        false => Some(sn_rust_usable_size(p)),
+    }
+}
+
+/// Creates a dedicated allocator handle, released with [`sn_rust_allocator_free`].
+#[cfg(feature = "handle-api")]
+#[inline]
+pub fn try_allocator_new() -> Option<NonNull<sn_rust_allocator>> {
+    NonNull::new(unsafe { sn_rust_allocator_new() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_wraps_successful_allocations() {
+        let ptr = try_malloc(64).unwrap();
+        let ptr = unsafe { try_realloc(ptr.as_ptr(), 128) }.unwrap();
+        assert!(unsafe { usable_size(ptr.as_ptr()) }.unwrap() >= 128);
+        unsafe { free(ptr.as_ptr()) };
+    }
+
+    #[test]
+    fn it_reports_failures_as_none() {
+        assert!(try_calloc(usize::MAX, 2).is_none());
+        assert!(try_alloc(8, usize::MAX / 2).is_none());
+        assert_eq!(unsafe { usable_size(core::ptr::null()) }, None);
+    }
+}
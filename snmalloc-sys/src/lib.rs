@@ -1,9 +1,151 @@
 #![no_std]
 #![allow(non_camel_case_types)]
 
-use core::ffi::c_void;
+use core::ffi::{c_char, c_int, c_void};
 
-extern "C" {
+pub mod helpers;
+pub mod size_classes;
+
+#[cfg(feature = "critical-section")]
+mod critical;
+
+#[cfg(test)]
+extern crate std;
+
+#[cfg(test)]
+mod abi;
+
+// With the `bindgen` feature, the shim declarations are generated from `shim/sn_rust.h` by the
+// build script. The checked-in declarations below are used otherwise, or if the generation fails.
+#[cfg(snmalloc_sys_bindgen)]
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+/// Declares functions of the shim, linked under the prefix the build script gave its symbols:
+/// empty, unless the `prefix-symbols` feature is enabled.
+#[cfg(not(snmalloc_sys_bindgen))]
+macro_rules! shim_functions {
+    ($($(#[$attr:meta])* pub fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;)*) => {
+        extern "C" {
+            $(
+                $(#[$attr])*
+                #[link_name = concat!(env!("SNMALLOC_SYS_SYMBOL_PREFIX"), stringify!($name))]
+                pub fn $name($($arg: $ty),*) $(-> $ret)?;
+            )*
+        }
+    };
+}
+
+/// The prefix of the symbols of the shim, e.g. `snmalloc_sys_0_3_` with the `prefix-symbols`
+/// feature, and empty otherwise. C code calls the functions of `sn_rust.h` by their prefixed
+/// names, which the header maps when `SN_RUST_PREFIXED` is defined.
+pub const SN_RUST_SYMBOL_PREFIX: &str = env!("SNMALLOC_SYS_SYMBOL_PREFIX");
+
+/// The longest label of an allocator handle, see [`sn_rust_allocator_set_name`], in bytes.
+pub const SN_RUST_ALLOCATOR_NAME_MAX: usize = 31;
+
+/// Whether snmalloc was built to be loaded dynamically, through the `dynamic-loading` feature
+/// or `SNMALLOC_DYNAMIC_LOADING=1` at build time.
+pub const SN_RUST_DYNAMIC_LOADING: bool = cfg!(snmalloc_sys_dynamic_loading);
+
+/// Opaque handle to a dedicated snmalloc allocator.
+///
+/// Handles are created by [`sn_rust_allocator_new`] and must be released with
+/// [`sn_rust_allocator_free`]. A handle is not thread-safe: it may move between
+/// threads, but must not be used by more than one thread at a time.
+#[cfg(not(snmalloc_sys_bindgen))]
+#[repr(C)]
+pub struct sn_rust_allocator {
+    _private: [u8; 0],
+}
+
+/// A block to free with [`sn_rust_dealloc_many`], with the alignment and size it was allocated
+/// with.
+#[cfg(not(snmalloc_sys_bindgen))]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct sn_rust_block_t {
+    pub ptr: *mut c_void,
+    pub alignment: usize,
+    pub size: usize,
+}
+
+/// Options of [`sn_rust_allocator_new_with_config`]. Zeroed fields keep the defaults of
+/// [`sn_rust_allocator_new`]. The pre-allocated pool and the locking of `snmalloc-rs` are applied
+/// on the Rust side and have no field.
+#[cfg(not(snmalloc_sys_bindgen))]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct sn_rust_allocator_config_t {
+    /// Label of the handle, `name_len` bytes long, see [`sn_rust_allocator_set_name`]. May be
+    /// null.
+    pub name: *const c_char,
+    pub name_len: usize,
+    /// Largest size the handle allocates or reallocates to: larger requests return a null
+    /// pointer. `0` for no limit.
+    pub max_alloc_size: usize,
+}
+
+/// Activity and content of the large-object cache, filled in by [`sn_rust_large_cache_stats`].
+#[cfg(not(snmalloc_sys_bindgen))]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct sn_rust_large_cache_stats_t {
+    /// Large allocations served from the cache.
+    pub hits: usize,
+    /// Large allocations the cache could not serve.
+    pub misses: usize,
+    /// Bytes currently held by the cache.
+    pub cached_bytes: usize,
+    /// Objects currently held by the cache.
+    pub cached_objects: usize,
+}
+
+/// Geometry of the slab holding a small object, filled in by [`sn_rust_slab_info`].
+#[cfg(not(snmalloc_sys_bindgen))]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct sn_rust_slab_info_t {
+    /// Index of the size class of the object.
+    pub sizeclass: usize,
+    /// Size of every object of the size class.
+    pub object_size: usize,
+    /// First byte of the slab.
+    pub slab_base: *mut c_void,
+    /// Size of the slab in bytes.
+    pub slab_size: usize,
+    /// Number of objects carved out of the slab.
+    pub objects_per_slab: usize,
+}
+
+/// Corruption of a redzone, filled in by [`sn_rust_redzone_dealloc`].
+#[cfg(not(snmalloc_sys_bindgen))]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct sn_rust_redzone_report_t {
+    /// The allocation whose redzone was corrupted.
+    pub ptr: *mut c_void,
+    /// Size of the allocation.
+    pub size: usize,
+    /// Offset of the first overwritten canary word from `ptr`: negative before the allocation,
+    /// `size` or more after it.
+    pub offset: isize,
+    /// Canary word stored at `offset`.
+    pub expected: u64,
+    /// Value found at `offset` instead.
+    pub found: u64,
+}
+
+/// Receives one range of [`sn_rust_owned_ranges`]: `context` as passed to it, and the `base` and
+/// `size` of the range.
+#[cfg(not(snmalloc_sys_bindgen))]
+pub type sn_rust_range_callback = Option<unsafe extern "C" fn(context: *mut c_void, base: *mut c_void, size: usize)>;
+
+/// Receives the `context` passed to [`sn_rust_at_thread_exit`].
+#[cfg(not(snmalloc_sys_bindgen))]
+pub type sn_rust_thread_exit_callback = Option<unsafe extern "C" fn(context: *mut c_void)>;
+
+#[cfg(not(snmalloc_sys_bindgen))]
+shim_functions! {
     /// Allocate the memory with the given alignment and size.
     /// On success, it returns a pointer pointing to the required memory address.
     /// On failure, it returns a null pointer.
@@ -18,7 +160,7 @@ extern "C" {
     /// - the memory is acquired using the same allocator and the pointer points to the start position.
     /// - `alignment` and `size` is the same as allocation
     /// The program may be forced to abort if the constrains are not full-filled.
-    pub fn sn_rust_dealloc(ptr: *mut c_void, alignment: usize, size: usize) -> c_void;
+    pub fn sn_rust_dealloc(ptr: *mut c_void, alignment: usize, size: usize);
 
     /// Behaves like rust_alloc, but also ensures that the contents are set to zero before being returned.
     pub fn sn_rust_alloc_zeroed(alignment: usize, size: usize) -> *mut c_void;
@@ -39,6 +181,379 @@ extern "C" {
         new_size: usize,
     ) -> *mut c_void;
 
+    /// Return the available bytes in a memory block.
+    pub fn sn_rust_usable_size(p: *const c_void) -> usize;
+
+    /// Create a dedicated allocator, independent from the thread-local one.
+    /// Returns a null pointer if the handle cannot be allocated.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_new() -> *mut sn_rust_allocator;
+
+    /// Create a dedicated allocator configured by `config`, which is only read during the call.
+    /// Returns a null pointer if the handle cannot be allocated.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_new_with_config(config: *const sn_rust_allocator_config_t) -> *mut sn_rust_allocator;
+
+    /// Release a dedicated allocator.
+    /// Memory still owned by the allocator is returned to snmalloc, but the
+    /// handle itself must not be used anymore.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_free(handle: *mut sn_rust_allocator);
+
+    /// Same as [`sn_rust_alloc`], but allocates from the given handle.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_allocate(
+        handle: *mut sn_rust_allocator,
+        alignment: usize,
+        size: usize,
+    ) -> *mut c_void;
+
+    /// Same as [`sn_rust_alloc_zeroed`], but allocates from the given handle.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_allocate_zeroed(
+        handle: *mut sn_rust_allocator,
+        alignment: usize,
+        size: usize,
+    ) -> *mut c_void;
+
+    /// Same as [`sn_rust_alloc_filled`], but allocates from the given handle.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_allocate_filled(
+        handle: *mut sn_rust_allocator,
+        alignment: usize,
+        size: usize,
+        byte: u8,
+    ) -> *mut c_void;
+
+    /// Same as [`sn_rust_dealloc`], but deallocates through the given handle.
+    /// `ptr` may come from any handle of the same shim, or from [`sn_rust_alloc`]: memory owned
+    /// by another allocator is sent back to it as a remote free.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_deallocate(
+        handle: *mut sn_rust_allocator,
+        ptr: *mut c_void,
+        alignment: usize,
+        size: usize,
+    );
+
+    /// Same as [`sn_rust_dealloc_many`], but deallocates through the given handle.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_deallocate_many(handle: *mut sn_rust_allocator, blocks: *mut sn_rust_block_t, count: usize);
+
+    /// Same as [`sn_rust_realloc`], but reallocates through the given handle.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_reallocate(
+        handle: *mut sn_rust_allocator,
+        ptr: *mut c_void,
+        alignment: usize,
+        old_size: usize,
+        new_size: usize,
+    ) -> *mut c_void;
+
+    /// Send the remote frees buffered by the handle to their owners, process the frees sent to
+    /// it, and return its cached memory to the global pool. The handle stays usable.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_flush(handle: *mut sn_rust_allocator);
+
+    /// Labels the handle for diagnostics with the `len` bytes at `name`, truncated to
+    /// [`SN_RUST_ALLOCATOR_NAME_MAX`] bytes. An empty name removes the label.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_set_name(handle: *mut sn_rust_allocator, name: *const c_char, len: usize);
+
+    /// Returns the NUL-terminated label of the handle, storing its length in `len`, or null if it
+    /// has none. The label lives until the next call of `sn_rust_allocator_set_name`.
+    #[cfg(feature = "handle-api")]
+    pub fn sn_rust_allocator_name(handle: *const sn_rust_allocator, len: *mut usize) -> *const c_char;
+
+    /// Allocate memory followed by an inaccessible guard page, so that linear overflows fault immediately.
+    /// The returned region ends exactly where the guard page starts (up to the `alignment` padding).
+    /// If `leading_guard` is set, an inaccessible page is also placed before the region.
+    /// The memory must be released with [`sn_rust_guarded_dealloc`].
+    #[cfg(feature = "guard-api")]
+    pub fn sn_rust_guarded_alloc(alignment: usize, size: usize, zero: bool, leading_guard: bool) -> *mut c_void;
+
+    /// De-allocate memory returned by either [`sn_rust_guarded_alloc`] or [`sn_rust_alloc`].
+    /// The client must assure the following things:
+    /// - `alignment` and `size` is the same as allocation
+    #[cfg(feature = "guard-api")]
+    pub fn sn_rust_guarded_dealloc(ptr: *mut c_void, alignment: usize, size: usize);
+
+    /// Return the number of bytes from `ptr` to the end of the allocation containing it.
+    /// `ptr` may point anywhere inside an allocation; the result is unspecified for memory
+    /// not allocated by snmalloc.
+    pub fn sn_rust_remaining_bytes(ptr: *const c_void) -> usize;
+
+    /// Behaves like [`sn_rust_alloc`], but also sets every byte of the first `size` bytes to `byte`.
+    /// A zero `byte` uses the same path as [`sn_rust_alloc_zeroed`].
+    pub fn sn_rust_alloc_filled(alignment: usize, size: usize, byte: u8) -> *mut c_void;
+
+    /// Report the memory obtained from the OS and currently used by the allocator, and the peak of
+    /// that value over the lifetime of the process, in bytes.
+    #[cfg(feature = "stats-api")]
+    pub fn sn_rust_memory_usage(current_memory_usage: *mut usize, peak_memory_usage: *mut usize);
+
+    /// Report the address space reserved from the OS and the part of it committed, in bytes,
+    /// counted by the memory provider of the shim. Platforms it does not count report the peak and
+    /// current values of [`sn_rust_memory_usage`] instead.
+    #[cfg(feature = "stats-api")]
+    pub fn sn_rust_address_space(reserved: *mut usize, committed: *mut usize);
+
+    /// Behaves like [`sn_rust_alloc`], but also stores the usable size of the block in `usable`.
+    /// All of it may be used, and it may be de-allocated with any size between `size` and
+    /// `*usable`.
+    pub fn sn_rust_alloc_usable(alignment: usize, size: usize, usable: *mut usize) -> *mut c_void;
+
+    /// Behaves like [`sn_rust_dealloc`], but sends buffered frees of memory owned by other threads
+    /// back to their owners once more than `batch` bytes (at least 1) are pending, instead of the
+    /// compile-time default.
+    pub fn sn_rust_dealloc_batched(ptr: *mut c_void, alignment: usize, size: usize, batch: usize);
+
+    /// Whether [`sn_rust_dealloc_batched`] can clamp the batches of the snmalloc the shim was
+    /// compiled against: it behaves like [`sn_rust_dealloc`] otherwise.
+    pub fn sn_rust_remote_batch_supported() -> bool;
+
+    /// Free the `count` blocks of `blocks`, like [`sn_rust_dealloc`] on each of them. The blocks
+    /// are sorted by address first, in place, so that the blocks of a slab are freed together.
+    pub fn sn_rust_dealloc_many(blocks: *mut sn_rust_block_t, count: usize);
+
+    /// Free the block at `ptr`, allocated by any allocator of the shim, without initialising the
+    /// allocator of the calling thread: the free is posted to the message queue of the owner of
+    /// the block through a process-wide allocator, under a lock. The thread gathers 64 frees
+    /// before posting them together; the rest are posted when it exits or calls
+    /// [`sn_rust_flush_thread_cache`] or [`sn_rust_shutdown`].
+    pub fn sn_rust_remote_dealloc(ptr: *mut c_void);
+
+    /// Report whether the static initializer of the shim, which runs before `main` and ahead of
+    /// the C++ constructors of user code where the toolchain allows it, allocated from snmalloc
+    /// successfully. Always false without the `early-init` feature.
+    pub fn sn_rust_initialized_before_main() -> bool;
+
+    /// Report whether the allocator lives in a shared object (e.g. a `cdylib`) rather than in the
+    /// main program.
+    pub fn sn_rust_is_shared_object() -> bool;
+
+    /// Store up to `count` return addresses of the callers of this function in `frames`, innermost
+    /// first, without allocating. Returns how many were stored: 0 where the stack cannot be walked.
+    pub fn sn_rust_return_addresses(frames: *mut *mut c_void, count: usize) -> usize;
+
+    /// Make the whole pages of the allocation at `ptr` read-only, or writable again if `read_only`
+    /// is false. Returns `false`, leaving the memory untouched, if `ptr` is not page aligned, if the
+    /// allocation does not span `size` rounded up to whole pages, or if the OS refuses the change.
+    pub fn sn_rust_protect_read_only(ptr: *mut c_void, size: usize, read_only: bool) -> bool;
+
+    /// Behaves like [`sn_rust_protect_read_only`], but makes the pages inaccessible, or readable
+    /// and writable again if `no_access` is false.
+    pub fn sn_rust_protect_no_access(ptr: *mut c_void, size: usize, no_access: bool) -> bool;
+
+    /// Describe the slab holding `ptr`, which may point anywhere inside an object.
+    /// Returns `false`, leaving `info` untouched, if `ptr` is not part of a small object owned by
+    /// snmalloc (large objects are not carved out of slabs).
+    #[cfg(feature = "stats-api")]
+    pub fn sn_rust_slab_info(ptr: *const c_void, info: *mut sn_rust_slab_info_t) -> bool;
+
+    /// Behaves like [`sn_rust_realloc`], but also sets the bytes between `old_size` and `new_size`
+    /// to zero when growing. Only those bytes are written, the rest of the block is left untouched.
+    pub fn sn_rust_realloc_zeroed(
+        ptr: *mut c_void,
+        alignment: usize,
+        old_size: usize,
+        new_size: usize,
+    ) -> *mut c_void;
+
+    /// Behaves like [`sn_rust_alloc_zeroed`], but replaces the pages of large objects with fresh
+    /// ones of the OS instead of writing zeros, so that they only become resident when written.
+    /// If the pages cannot be replaced, the object is zeroed by `memset`, or freed and null is
+    /// returned if `strict` is set.
+    pub fn sn_rust_alloc_zeroed_fresh(alignment: usize, size: usize, strict: bool) -> *mut c_void;
+
+    /// Clear the OS error of the calling thread (`errno`, or `GetLastError()` on Windows), so that
+    /// [`sn_rust_record_os_error`] only sees a value set by the next allocation.
+    pub fn sn_rust_clear_os_error();
+
+    /// Record the OS error of the calling thread (`errno`, or `GetLastError()` on Windows) right
+    /// after an allocation returned null, and return it; `0` if the allocation left it cleared.
+    /// The shim keeps it in a thread-local, where later OS calls of the thread cannot overwrite it.
+    pub fn sn_rust_record_os_error() -> c_int;
+
+    /// Return the OS error recorded by the last [`sn_rust_record_os_error`] of the calling thread,
+    /// `0` if none was recorded.
+    pub fn sn_rust_last_os_error() -> c_int;
+
+    /// Return the memory cached by the calling thread, including pending frees of memory owned by
+    /// other threads, to the global pool. The thread can keep allocating afterwards.
+    pub fn sn_rust_flush_thread_cache();
+
+    /// Initialise the allocator of the calling thread, which otherwise happens on its first
+    /// allocation.
+    pub fn sn_rust_thread_init();
+
+    /// Return the allocator of the calling thread, with its caches and pending remote frees, to
+    /// the global pool, which otherwise happens when the thread exits. An allocation of the
+    /// thread afterwards takes a new allocator.
+    pub fn sn_rust_thread_teardown();
+
+    /// Return the memory cached by the calling thread and by the allocators left behind by exited
+    /// threads, including their pending remote frees, to the global pool.
+    pub fn sn_rust_shutdown();
+
+    /// Allocate a chunk of at least `size` bytes, rounded up to a power of two no smaller than
+    /// snmalloc's minimum chunk size (16KiB), aligned to its own size and registered in the
+    /// pagemap like any other snmalloc allocation. The actual size is stored in `chunk_size`.
+    /// Returns `null` on failure, leaving `chunk_size` untouched.
+    pub fn sn_rust_alloc_chunk(size: usize, chunk_size: *mut usize) -> *mut c_void;
+
+    /// De-allocate a chunk returned by [`sn_rust_alloc_chunk`], where `chunk_size` is the size it
+    /// reported.
+    pub fn sn_rust_dealloc_chunk(ptr: *mut c_void, chunk_size: usize);
+
+    /// Return the number of bytes at the start of freed objects that snmalloc leaves untouched
+    /// (`CACHE_FRIENDLY_OFFSET`, set by the `cache-friendly` feature), `0` if unset.
+    pub fn sn_rust_cache_friendly_offset() -> usize;
+
+    /// Return the size of the block that serves a request of `size` bytes aligned to `alignment`,
+    /// i.e. the usable size an allocation of that layout would have.
+    pub fn sn_rust_round_size(alignment: usize, size: usize) -> usize;
+
+    /// Allocate `size` bytes aligned like `malloc`, for C code that frees with [`sn_rust_free`].
+    /// Returns a unique pointer for a `size` of 0, or null if out of memory.
+    pub fn sn_rust_malloc(size: usize) -> *mut c_void;
+
+    /// De-allocate a block without knowing its size nor its alignment, as C's `free` does. Any
+    /// block of the thread-local allocator is accepted, whatever function allocated it; null is
+    /// ignored.
+    pub fn sn_rust_free(ptr: *mut c_void);
+
+    /// Cap the bytes held by the large-object cache, freeing the cached objects above the new
+    /// cap. `0`, the default, disables the cache.
+    pub fn sn_rust_set_large_cache(limit: usize);
+
+    /// Behaves like [`sn_rust_alloc`] (or [`sn_rust_alloc_zeroed`] if `zero` is set), but serves
+    /// large objects from the large-object cache when it holds one of the same size.
+    pub fn sn_rust_alloc_large_cached(alignment: usize, size: usize, zero: bool) -> *mut c_void;
+
+    /// Behaves like [`sn_rust_dealloc`], but keeps large objects in the large-object cache, still
+    /// committed, while it has room for them. Memory from either function may be freed by the other
+    /// functions of the shim.
+    pub fn sn_rust_dealloc_large_cached(ptr: *mut c_void, alignment: usize, size: usize);
+
+    /// Behaves like [`sn_rust_alloc`] (or [`sn_rust_alloc_zeroed`] if `zero` is set), but allocates
+    /// from a process-wide allocator kept for long-lived blocks, so that they do not share slabs
+    /// with the short-lived blocks of the thread-local allocators. The block is freed by
+    /// [`sn_rust_dealloc`], from any thread.
+    pub fn sn_rust_alloc_hint_cold(alignment: usize, size: usize, zero: bool) -> *mut c_void;
+
+    /// Fill `stats` with the activity and content of the large-object cache.
+    #[cfg(feature = "stats-api")]
+    pub fn sn_rust_large_cache_stats(stats: *mut sn_rust_large_cache_stats_t);
+
+    /// Allocate memory between two 16-byte redzones filled with canaries, checked by
+    /// [`sn_rust_redzone_dealloc`]. The memory must be released with it.
+    #[cfg(feature = "guard-api")]
+    pub fn sn_rust_redzone_alloc(alignment: usize, size: usize, zero: bool) -> *mut c_void;
+
+    /// De-allocate memory returned by [`sn_rust_redzone_alloc`] with the same `alignment` and
+    /// `size`, checking the canaries of both redzones first. Returns `false`, after describing the
+    /// first overwritten canary word in `report`, if one was corrupted; the memory is released
+    /// either way.
+    #[cfg(feature = "guard-api")]
+    pub fn sn_rust_redzone_dealloc(
+        ptr: *mut c_void,
+        alignment: usize,
+        size: usize,
+        report: *mut sn_rust_redzone_report_t,
+    ) -> bool;
+
+    /// Call `callback` for every maximal range of snmalloc chunks currently backing allocations,
+    /// in address order, found by walking the committed part of the pagemap. Returns `false`,
+    /// without calling it, if the platform cannot enumerate the pagemap.
+    ///
+    /// The callback may allocate, but ranges allocated or freed meanwhile may or may not be
+    /// reported.
+    #[cfg(feature = "stats-api")]
+    pub fn sn_rust_owned_ranges(callback: sn_rust_range_callback, context: *mut c_void) -> bool;
+
+    /// Locks the pages spanned by `size` bytes at `ptr` in memory, raising the soft
+    /// `RLIMIT_MEMLOCK` (or the working set on Windows) once if needed. Returns false if the
+    /// pages could not be locked.
+    pub fn sn_rust_lock_pages(ptr: *mut c_void, size: usize) -> bool;
+
+    /// Returns the bytes the PAL locked as it committed them, and writes the commits it could not
+    /// lock to `failures`. Both are 0 unless built with the `lock-memory` feature.
+    pub fn sn_rust_locked_commits(failures: *mut usize) -> usize;
+
+    /// Returns the page size of the running kernel. Page-granular operations of the shim are
+    /// rounded to it when it exceeds the page size snmalloc was compiled for.
+    pub fn sn_rust_os_page_size() -> usize;
+
+    /// Calls `callback` with `context` when the calling thread exits, from a C++ thread-local
+    /// destructor, so that no Rust thread-local with `Drop` is needed from inside an allocator.
+    /// Returns false if the thread already has 8 callbacks.
+    pub fn sn_rust_at_thread_exit(callback: sn_rust_thread_exit_callback, context: *mut c_void) -> bool;
+
+    /// Copies the OS name of the calling thread, NUL-terminated, to the `size` bytes at `buffer`,
+    /// returning its length. Returns 0 if the thread has no name or the platform has none, such
+    /// as musl targets.
+    pub fn sn_rust_thread_name(buffer: *mut c_char, size: usize) -> usize;
+
+    /// Called before a deallocation: with the `no-alloc-on-free` feature, the PAL aborts if the
+    /// calling thread reserves or commits memory until [`sn_rust_audit_dealloc_leave`].
+    pub fn sn_rust_audit_dealloc_enter();
+
+    /// Called after the deallocation audited by [`sn_rust_audit_dealloc_enter`].
+    pub fn sn_rust_audit_dealloc_leave();
+
+    /// Returns 64 bits of entropy from the platform, as snmalloc seeds its own randomisation
+    /// with, or 0 if the platform has no source of entropy.
+    pub fn sn_rust_entropy() -> u64;
+
+    /// Sets the `size` bytes at `ptr` to zero. The whole pages among them are zeroed by the
+    /// platform, e.g. by giving them back to the OS, which is cheaper than writing large blocks.
+    pub fn sn_rust_zero(ptr: *mut c_void, size: usize);
+
+    /// Report whether the global C++ `operator new` resolves to snmalloc, i.e. whether the
+    /// replacement built by the `cxx-new` feature won symbol resolution.
+    #[cfg(feature = "cxx-new")]
+    pub fn sn_rust_operator_new_is_snmalloc() -> bool;
+}
+
+/// Opaque handle to a dedicated allocator of the hardened shim, see [`snc_rust_allocator_new`].
+#[cfg(all(feature = "checked-handles", not(snmalloc_sys_bindgen)))]
+#[repr(C)]
+pub struct snc_rust_allocator {
+    _private: [u8; 0],
+}
+
+// Allocator handles of the hardened shim, linked next to the fast one with the
+// `checked-handles` feature. Each function behaves like its `sn_rust_allocator_` counterpart,
+// with the client checks of the `check` feature; handles of both shims must not be mixed.
+#[cfg(all(feature = "checked-handles", not(snmalloc_sys_bindgen)))]
+shim_functions! {
+    pub fn snc_rust_allocator_new() -> *mut snc_rust_allocator;
+    pub fn snc_rust_allocator_new_with_config(config: *const sn_rust_allocator_config_t) -> *mut snc_rust_allocator;
+    pub fn snc_rust_allocator_free(handle: *mut snc_rust_allocator);
+    pub fn snc_rust_allocator_allocate(handle: *mut snc_rust_allocator, alignment: usize, size: usize) -> *mut c_void;
+    pub fn snc_rust_allocator_allocate_zeroed(handle: *mut snc_rust_allocator, alignment: usize, size: usize) -> *mut c_void;
+    pub fn snc_rust_allocator_allocate_filled(
+        handle: *mut snc_rust_allocator,
+        alignment: usize,
+        size: usize,
+        byte: u8,
+    ) -> *mut c_void;
+    pub fn snc_rust_allocator_deallocate(handle: *mut snc_rust_allocator, ptr: *mut c_void, alignment: usize, size: usize);
+    pub fn snc_rust_allocator_deallocate_many(handle: *mut snc_rust_allocator, blocks: *mut sn_rust_block_t, count: usize);
+    pub fn snc_rust_allocator_reallocate(
+        handle: *mut snc_rust_allocator,
+        ptr: *mut c_void,
+        alignment: usize,
+        old_size: usize,
+        new_size: usize,
+    ) -> *mut c_void;
+    pub fn snc_rust_allocator_flush(handle: *mut snc_rust_allocator);
+    pub fn snc_rust_allocator_set_name(handle: *mut snc_rust_allocator, name: *const c_char, len: usize);
+    pub fn snc_rust_allocator_name(handle: *const snc_rust_allocator, len: *mut usize) -> *const c_char;
+}
+
+extern "C" {
     /// Allocate `count` items of `size` length each.
     /// Returns `null` if `count * size` overflows or on out-of-memory.
     /// All items are initialized to zero.
@@ -62,9 +577,6 @@ extern "C" {
     /// Free previously allocated memory.
     /// The pointer `p` must have been allocated before (or be null).
     pub fn free(p: *mut c_void);
-
-    /// Return the available bytes in a memory block.
-    pub fn sn_rust_usable_size(p: *const c_void) -> usize;
 }
 
 #[cfg(test)]
@@ -80,6 +592,49 @@ mod tests {
         unsafe { sn_rust_dealloc(ptr as *mut c_void, 8, 1024) };
     }
 
+    #[test]
+    fn it_fills_allocations() {
+        let ptr = unsafe { sn_rust_alloc_filled(8, 1024, 0xAA) } as *mut u8 as *mut [u8; 1024];
+        unsafe {
+            assert!((*ptr).iter().all(|x| *x == 0xAA));
+        };
+        unsafe { sn_rust_dealloc(ptr as *mut c_void, 8, 1024) };
+    }
+
+    #[test]
+    fn it_bounds_the_waste_of_huge_alignments() {
+        for alignment in [1 << 16, 1 << 20] {
+            for size in [1, alignment / 2, alignment + 1, 3 * alignment] {
+                let mut usable = 0;
+                let ptr = unsafe { sn_rust_alloc_usable(alignment, size, &mut usable) };
+                assert_eq!(ptr as usize % alignment, 0);
+                assert!(usable >= size);
+                assert!(usable <= size.max(alignment).next_power_of_two());
+                unsafe { sn_rust_dealloc(ptr, alignment, usable) };
+            }
+        }
+    }
+
+    #[test]
+    fn it_links_under_the_symbol_prefix() {
+        assert_eq!(SN_RUST_SYMBOL_PREFIX.is_empty(), !cfg!(feature = "prefix-symbols"));
+        // Resolved through the prefixed name, or the link of this test would have failed.
+        let ptr = unsafe { sn_rust_alloc(8, 8) };
+        assert!(!ptr.is_null());
+        unsafe { sn_rust_dealloc(ptr, 8, 8) };
+    }
+
+    #[test]
+    #[cfg(feature = "stats-api")]
+    fn it_reports_memory_usage() {
+        let ptr = unsafe { sn_rust_alloc(8, 1 << 20) };
+        let (mut current, mut peak) = (0, 0);
+        unsafe { sn_rust_memory_usage(&mut current, &mut peak) };
+        assert!(current >= 1 << 20);
+        assert!(peak >= current);
+        unsafe { sn_rust_dealloc(ptr, 8, 1 << 20) };
+    }
+
     #[test]
     fn it_frees_memory_malloc() {
         let ptr = unsafe { sn_rust_alloc(8, 8) } as *mut u8;
@@ -115,6 +670,25 @@ mod tests {
         unsafe { sn_rust_dealloc(ptr as *mut c_void, 8, 16) };
     }
 
+    #[test]
+    fn it_keeps_multi_byte_values_across_the_boundary() {
+        // Catches byte-order mistakes in the shim on big-endian targets (s390x, powerpc64).
+        let value = 0x0102_0304_0506_0708_090A_0B0C_0D0E_0F10_u128;
+        let ptr = unsafe { sn_rust_alloc(16, 16) } as *mut u128;
+        unsafe { ptr.write(value) };
+        let bytes = unsafe { *(ptr as *const [u8; 16]) };
+        assert_eq!(bytes, value.to_ne_bytes());
+        let ptr = unsafe { sn_rust_realloc(ptr as *mut c_void, 16, 16, 4096) } as *mut u128;
+        unsafe { assert_eq!(ptr.read(), value) };
+        // A `size_t` out-parameter is written whole, not into the low bytes of a wider slot.
+        let mut usable = usize::MAX;
+        let other = unsafe { sn_rust_alloc_usable(8, 100, &mut usable) };
+        assert_eq!(usable, unsafe { sn_rust_usable_size(other) });
+        assert_eq!(usable, unsafe { sn_rust_round_size(8, 100) });
+        unsafe { sn_rust_dealloc(other, 8, usable) };
+        unsafe { sn_rust_dealloc(ptr as *mut c_void, 16, 4096) };
+    }
+
     #[test]
     fn it_calculates_usable_size() {
         let ptr = unsafe { sn_rust_alloc(32, 8) } as *mut u8;
@@ -125,4 +699,29 @@ mod tests {
         );
         unsafe { sn_rust_dealloc(ptr as *mut c_void, 32, 8) };
     }
+
+    #[test]
+    fn it_calculates_remaining_bytes() {
+        let ptr = unsafe { sn_rust_alloc(8, 64) } as *mut u8;
+        let usable_size = unsafe { sn_rust_usable_size(ptr as *const c_void) };
+        let remaining = unsafe { sn_rust_remaining_bytes(ptr.add(16) as *const c_void) };
+        assert_eq!(remaining, usable_size - 16);
+        unsafe { sn_rust_dealloc(ptr as *mut c_void, 8, 64) };
+    }
+
+    #[test]
+    #[cfg(feature = "handle-api")]
+    fn it_allocates_from_handle() {
+        let handle = unsafe { sn_rust_allocator_new() };
+        assert!(!handle.is_null());
+        let mut ptr = unsafe { sn_rust_allocator_allocate(handle, 8, 8) } as *mut u8;
+        unsafe {
+            *ptr = 127;
+            assert_eq!(*ptr, 127)
+        };
+        ptr = unsafe { sn_rust_allocator_reallocate(handle, ptr as *mut c_void, 8, 8, 4096) } as *mut u8;
+        unsafe { assert_eq!(*ptr, 127) };
+        unsafe { sn_rust_allocator_deallocate(handle, ptr as *mut c_void, 8, 4096) };
+        unsafe { sn_rust_allocator_free(handle) };
+    }
 }
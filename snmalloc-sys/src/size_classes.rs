@@ -0,0 +1,54 @@
+//! Rounding of small sizes without calling into the shim.
+//!
+//! Which size class serves a request is a pure function of snmalloc's table of size classes, so
+//! the build script generates the small classes as [`SIZE_CLASSES`], and [`round_size`] looks
+//! them up in Rust. Sizes beyond the table, and every size on targets where it is empty, are
+//! still rounded by [`sn_rust_round_size`](crate::sn_rust_round_size).
+//!
+//! The table follows the constants of the default configuration of snmalloc, which another
+//! checkout or a system install may change: its results are estimates, e.g. for statistics, and
+//! must not decide whether a block is large enough to reuse.
+//!
+//! The generated file also holds the limits of the shim: [`MIN_ALLOC_SIZE`], the smallest block
+//! and the step between the smallest size classes, and [`ADDRESS_BITS`], the bits of address
+//! space snmalloc manages, which bound the largest allocation.
+
+include!(concat!(env!("OUT_DIR"), "/size_classes.rs"));
+
+/// The largest size served by a small size class, or 0 if the table is empty.
+pub const MAX_SMALL_SIZE: usize = match SIZE_CLASSES.last() {
+    Some(size) => *size,
+    None => 0,
+};
+
+/// Rounds `size` bytes aligned to `alignment` (a power of two) up to the size snmalloc
+/// allocates for them, like [`sn_rust_round_size`](crate::sn_rust_round_size).
+#[inline]
+pub fn round_size(alignment: usize, size: usize) -> usize {
+    match small_size(alignment, size) {
+        Some(aligned) => SIZE_CLASSES[SIZE_CLASSES.partition_point(|class| *class < aligned)],
+        None => unsafe { crate::sn_rust_round_size(alignment, size) },
+    }
+}
+
+/// The size to round for a request, as snmalloc's `aligned_size`, if it is served by the table.
+/// Zero-sized requests are left to the shim, which rounds them regardless of alignment.
+#[inline(always)]
+fn small_size(alignment: usize, size: usize) -> Option<usize> {
+    let aligned = (alignment.wrapping_sub(1) | size.wrapping_sub(1)).wrapping_add(1);
+    (aligned.wrapping_sub(1) < MAX_SMALL_SIZE).then_some(aligned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_rounds_like_the_shim() {
+        for alignment in [1, 8, 16, 64, 4096] {
+            for size in 0..=MAX_SMALL_SIZE + 1 {
+                assert_eq!(round_size(alignment, size), unsafe { crate::sn_rust_round_size(alignment, size) });
+            }
+        }
+    }
+}
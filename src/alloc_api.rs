@@ -0,0 +1,345 @@
+//! Implementations of the `Allocator` trait (`allocator-api2` feature).
+//!
+//! The trait is the one of the [`allocator-api2`](https://crates.io/crates/allocator-api2) crate,
+//! which is usable on stable Rust and by its collections, and re-exports the unstable trait of
+//! the standard library when its `nightly` feature is enabled, so that `Vec::new_in(SnMalloc)`
+//! works with the standard collections on nightly.
+//!
+//! With the `handle-api` feature, [`SnAllocator`] implements it by value, so that a collection can own its handle, and by
+//! reference through the blanket implementation of the trait, which also covers
+//! `Allocator::by_ref`. With `std`, `Box<SnAllocator>` implements it too, and several collections
+//! can share one handle through a [`SharedSnAllocator`]: the coherence rules forbid implementing
+//! the trait for `Rc<SnAllocator>` or `Arc<SnAllocator>` outside of the crate of the trait, and a
+//! handle is not `Sync` anyway.
+use core::{alloc::Layout, ptr::NonNull};
+
+use allocator_api2::alloc::{AllocError, Allocator};
+
+#[cfg(feature = "handle-api")]
+use crate::SnAllocator;
+use crate::{ColdAllocator, SnMalloc};
+
+#[inline(always)]
+fn block(ptr: *mut u8, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+    NonNull::new(ptr).map(|ptr| NonNull::slice_from_raw_parts(ptr, size)).ok_or(AllocError)
+}
+
+/// Backed by the thread-local allocator of the calling thread, like the `GlobalAlloc`
+/// implementation, with the same hooks, limits and statistics, so that no handle is needed.
+///
+/// Growing and shrinking keep the block in place whenever both sizes are served by the same size
+/// class, and move it otherwise; a change of alignment always moves it.
+unsafe impl Allocator for SnMalloc {
+    #[inline(always)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        block(unsafe { core::alloc::GlobalAlloc::alloc(self, layout) }, layout.size())
+    }
+
+    #[inline(always)]
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        block(unsafe { core::alloc::GlobalAlloc::alloc_zeroed(self, layout) }, layout.size())
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        core::alloc::GlobalAlloc::dealloc(self, ptr.as_ptr(), layout)
+    }
+
+    #[inline(always)]
+    unsafe fn grow(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old, new, false)
+    }
+
+    #[inline(always)]
+    unsafe fn grow_zeroed(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old, new, true)
+    }
+
+    #[inline(always)]
+    unsafe fn shrink(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old, new, false)
+    }
+}
+
+impl SnMalloc {
+    /// Resizes through `realloc`, whose paths return `ptr` itself when the size class does not
+    /// change, unless the alignment changes.
+    #[inline(always)]
+    unsafe fn resize(&self, ptr: NonNull<u8>, old: Layout, new: Layout, zero: bool) -> Result<NonNull<[u8]>, AllocError> {
+        if old.align() == new.align() {
+            let new_ptr = match zero {
+                true => self.realloc_zeroed(ptr.as_ptr(), old, new.size()),
+                false => core::alloc::GlobalAlloc::realloc(self, ptr.as_ptr(), old, new.size()),
+            };
+            return block(new_ptr, new.size());
+        }
+        let new_block = match zero {
+            true => self.allocate_zeroed(new)?,
+            false => self.allocate(new)?,
+        };
+        core::ptr::copy_nonoverlapping(ptr.as_ptr(), new_block.cast().as_ptr(), old.size().min(new.size()));
+        self.deallocate(ptr, old);
+        Ok(new_block)
+    }
+}
+
+#[cfg(feature = "handle-api")]
+/// Backed by the handle. Growing and shrinking go through
+/// [`SnAllocator::reallocate`], unless the alignment changes, which moves the block.
+unsafe impl Allocator for SnAllocator {
+    #[inline(always)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        block(SnAllocator::allocate(self, layout).map_or(core::ptr::null_mut(), NonNull::as_ptr), layout.size())
+    }
+
+    #[inline(always)]
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        block(SnAllocator::allocate_zeroed(self, layout).map_or(core::ptr::null_mut(), NonNull::as_ptr), layout.size())
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        SnAllocator::deallocate(self, ptr, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn grow(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old, new, false)
+    }
+
+    #[inline(always)]
+    unsafe fn grow_zeroed(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old, new, true)
+    }
+
+    #[inline(always)]
+    unsafe fn shrink(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old, new, false)
+    }
+}
+
+#[cfg(feature = "handle-api")]
+impl SnAllocator {
+    /// Resizes through `reallocate` when the alignment is kept, zeroing the growth if asked.
+    #[inline(always)]
+    unsafe fn resize(&self, ptr: NonNull<u8>, old: Layout, new: Layout, zero: bool) -> Result<NonNull<[u8]>, AllocError> {
+        if old.align() == new.align() {
+            let new_ptr = self.reallocate(ptr, old, new.size()).ok_or(AllocError)?;
+            if zero && new.size() > old.size() {
+                new_ptr.as_ptr().add(old.size()).write_bytes(0, new.size() - old.size());
+            }
+            return block(new_ptr.as_ptr(), new.size());
+        }
+        let new_block = match zero {
+            true => Allocator::allocate_zeroed(self, new)?,
+            false => Allocator::allocate(self, new)?,
+        };
+        core::ptr::copy_nonoverlapping(ptr.as_ptr(), new_block.cast().as_ptr(), old.size().min(new.size()));
+        SnAllocator::deallocate(self, ptr, old);
+        Ok(new_block)
+    }
+}
+
+/// Forwards to the boxed handle, e.g. one kept at a stable address by its collection.
+#[cfg(all(feature = "std", feature = "handle-api"))]
+unsafe impl Allocator for std::boxed::Box<SnAllocator> {
+    #[inline(always)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::allocate(&**self, layout)
+    }
+
+    #[inline(always)]
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::allocate_zeroed(&**self, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        Allocator::deallocate(&**self, ptr, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn grow(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::grow(&**self, ptr, old, new)
+    }
+
+    #[inline(always)]
+    unsafe fn grow_zeroed(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::grow_zeroed(&**self, ptr, old, new)
+    }
+
+    #[inline(always)]
+    unsafe fn shrink(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::shrink(&**self, ptr, old, new)
+    }
+}
+
+/// A handle shared by several owners of the same thread, e.g. collections that outlive the scope
+/// that created it. The handle is destroyed with its last owner, once every collection backed by
+/// it has freed its memory.
+///
+/// ```rust
+/// use allocator_api2::vec::Vec;
+/// use snmalloc_rs::{SharedSnAllocator, SnAllocator};
+/// let alloc = SharedSnAllocator::new(SnAllocator::new().unwrap());
+/// let mut names = Vec::new_in(alloc.clone());
+/// let mut sizes = Vec::new_in(alloc);
+/// names.push("a");
+/// sizes.push(1);
+/// ```
+#[cfg(all(feature = "std", feature = "handle-api"))]
+#[derive(Debug, Clone)]
+pub struct SharedSnAllocator(std::rc::Rc<SnAllocator>);
+
+#[cfg(all(feature = "std", feature = "handle-api"))]
+impl SharedSnAllocator {
+    /// Shares `alloc`.
+    pub fn new(alloc: SnAllocator) -> Self {
+        Self(std::rc::Rc::new(alloc))
+    }
+
+    /// Returns the shared handle.
+    #[inline(always)]
+    pub fn as_rc(&self) -> &std::rc::Rc<SnAllocator> {
+        &self.0
+    }
+}
+
+#[cfg(all(feature = "std", feature = "handle-api"))]
+impl From<SnAllocator> for SharedSnAllocator {
+    fn from(alloc: SnAllocator) -> Self {
+        Self::new(alloc)
+    }
+}
+
+#[cfg(all(feature = "std", feature = "handle-api"))]
+impl From<std::rc::Rc<SnAllocator>> for SharedSnAllocator {
+    fn from(alloc: std::rc::Rc<SnAllocator>) -> Self {
+        Self(alloc)
+    }
+}
+
+#[cfg(all(feature = "std", feature = "handle-api"))]
+impl core::ops::Deref for SharedSnAllocator {
+    type Target = SnAllocator;
+
+    #[inline(always)]
+    fn deref(&self) -> &SnAllocator {
+        &self.0
+    }
+}
+
+/// Forwards to the shared handle.
+#[cfg(all(feature = "std", feature = "handle-api"))]
+unsafe impl Allocator for SharedSnAllocator {
+    #[inline(always)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::allocate(&*self.0, layout)
+    }
+
+    #[inline(always)]
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::allocate_zeroed(&*self.0, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        Allocator::deallocate(&*self.0, ptr, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn grow(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::grow(&*self.0, ptr, old, new)
+    }
+
+    #[inline(always)]
+    unsafe fn grow_zeroed(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::grow_zeroed(&*self.0, ptr, old, new)
+    }
+
+    #[inline(always)]
+    unsafe fn shrink(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::shrink(&*self.0, ptr, old, new)
+    }
+}
+
+/// Backed by the cold allocator of the shim. Resizing always moves the block to a new cold one.
+unsafe impl Allocator for ColdAllocator {
+    #[inline(always)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        block(crate::cold::alloc(layout, false), layout.size())
+    }
+
+    #[inline(always)]
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        block(crate::cold::alloc(layout, true), layout.size())
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        crate::cold::dealloc(ptr.as_ptr(), layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_allocates_without_a_handle() {
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = SnMalloc.allocate_zeroed(layout).unwrap();
+        assert_eq!(ptr.len(), 24);
+        unsafe {
+            let ptr = ptr.cast::<u8>();
+            ptr.as_ptr().write_bytes(7, 24);
+            // 24 and 32 bytes share a size class.
+            let grown = SnMalloc.grow(ptr, layout, Layout::from_size_align(32, 8).unwrap()).unwrap();
+            if cfg!(not(any(feature = "redzones", feature = "quarantine"))) {
+                assert_eq!(grown.cast::<u8>(), ptr);
+            }
+            let big = Layout::from_size_align(4096, 64).unwrap();
+            let moved = SnMalloc.grow_zeroed(grown.cast(), Layout::from_size_align(32, 8).unwrap(), big).unwrap();
+            let bytes = core::slice::from_raw_parts(moved.cast::<u8>().as_ptr(), 4096);
+            assert_eq!(moved.cast::<u8>().as_ptr() as usize % 64, 0);
+            assert!(bytes[..24].iter().all(|b| *b == 7) && bytes[32..].iter().all(|b| *b == 0));
+            let shrunk = SnMalloc.shrink(moved.cast(), big, Layout::from_size_align(10, 64).unwrap()).unwrap();
+            assert_eq!(*shrunk.cast::<u8>().as_ptr(), 7);
+            SnMalloc.deallocate(shrunk.cast(), Layout::from_size_align(10, 64).unwrap());
+        }
+    }
+
+    #[cfg(feature = "handle-api")]
+    #[test]
+    fn handles_back_the_collections_owning_them() {
+        let alloc = SnAllocator::new().unwrap();
+        {
+            let mut borrowed = allocator_api2::vec::Vec::new_in(alloc.by_ref());
+            borrowed.extend(0..1000u64);
+            borrowed.shrink_to_fit();
+            assert_eq!(borrowed.iter().sum::<u64>(), 499_500);
+        }
+        let mut owned = allocator_api2::vec::Vec::new_in(alloc);
+        owned.extend_from_slice(&[1u8; 4096]);
+        assert!(owned.iter().all(|b| *b == 1));
+        #[cfg(feature = "std")]
+        {
+            let mut boxed = allocator_api2::vec::Vec::new_in(std::boxed::Box::new(SnAllocator::new().unwrap()));
+            boxed.push(1u32);
+            let shared = SharedSnAllocator::new(SnAllocator::new().unwrap());
+            let mut a = allocator_api2::vec::Vec::new_in(shared.clone());
+            let mut b = allocator_api2::vec::Vec::new_in(shared);
+            a.extend(0..100u32);
+            b.extend(a.iter().copied());
+            drop(a);
+            assert_eq!(b.len(), 100);
+        }
+    }
+
+    #[test]
+    fn it_grows_cold_blocks() {
+        let mut values = allocator_api2::vec::Vec::new_in(ColdAllocator);
+        values.extend(0..10_000u32);
+        assert!(values.iter().copied().eq(0..10_000));
+    }
+}
@@ -0,0 +1,726 @@
+use core::{
+    alloc::Layout,
+    fmt,
+    marker::PhantomData,
+    mem::{self, MaybeUninit},
+    ptr::{self, NonNull},
+};
+
+use crate::{config::truncate_name, ffi_safe::ReallocError, layout, limit, lock, pool::Pool, shim::Shim, sync, AllocConfig, FrozenAllocator};
+
+/// A dedicated snmalloc allocator, independent from the thread-local one behind [`SnMalloc`](crate::SnMalloc).
+///
+/// Memory allocated from a handle is normally returned through the same handle. Unless the
+/// handle is pre-allocated, it can also free memory of other handles served by the same shim and
+/// of [`SnMalloc`](crate::SnMalloc), which is sent back to its owner (see
+/// [`can_free_foreign`](Self::can_free_foreign)).
+/// The handle can be moved to another thread, but it is not `Sync`: concurrent use must be
+/// synchronised by the caller (see [`GlobalSnAllocator`](crate::GlobalSnAllocator)).
+pub struct SnAllocator {
+    handle: NonNull<ffi::sn_rust_allocator>,
+    shim: Shim,
+    pool: Option<Pool>,
+    locked: bool,
+    max_alloc_size: usize,
+}
+
+unsafe impl Send for SnAllocator {}
+
+/// Opaque type of the pointers produced by [`SnAllocator::into_raw`] and [`SnAllocator::as_raw`],
+/// meant to be passed through `extern "C"` signatures (as `*mut RawSnAllocator`, or `void *` on
+/// the C side).
+///
+/// The pointee is only meaningful to the same build of this crate: C code must not dereference
+/// it, only hand it back.
+#[repr(C)]
+pub struct RawSnAllocator {
+    _opaque: [u8; 0],
+    _marker: PhantomData<(*mut u8, core::marker::PhantomPinned)>,
+}
+
+impl SnAllocator {
+    /// Creates a new allocator handle, returning `None` if the handle cannot be allocated.
+    #[inline(always)]
+    pub fn new() -> Option<Self> {
+        Self::with_shim(Shim::FAST)
+    }
+
+    /// Creates a handle served by the fast shim, even when the hardened one is linked too.
+    #[cfg(feature = "checked-handles")]
+    #[inline(always)]
+    pub fn new_fast() -> Option<Self> {
+        Self::with_shim(Shim::FAST)
+    }
+
+    /// Creates a handle served by the hardened shim, which performs the client checks of the
+    /// `check` feature (see the upstream security docs) on every operation of this handle only.
+    #[cfg(feature = "checked-handles")]
+    #[inline(always)]
+    pub fn new_checked() -> Option<Self> {
+        Self::with_shim(Shim::CHECKED)
+    }
+
+    #[inline(always)]
+    fn with_shim(shim: Shim) -> Option<Self> {
+        NonNull::new(sync::exclusive(|| unsafe { shim.create_handle() }))
+            .map(|handle| Self { handle, shim, pool: None, locked: false, max_alloc_size: usize::MAX })
+    }
+
+    /// Creates a handle with all the options of `config` applied, returning `None` if the handle
+    /// or its pool cannot be allocated, or if the pool of a locked handle cannot be locked.
+    ///
+    /// The pool is allocated regardless of the limits on the size of requests, which only apply to
+    /// the requests served from it.
+    ///
+    /// ```rust
+    /// use snmalloc_rs::{AllocConfig, SnAllocator};
+    /// let config = AllocConfig::builder().name("frame-arena").preallocate(1 << 20).build();
+    /// let alloc = SnAllocator::with_config(&config).unwrap();
+    /// assert!(alloc.is_preallocated());
+    /// ```
+    pub fn with_config(config: &AllocConfig) -> Option<Self> {
+        #[cfg(feature = "checked-handles")]
+        let shim = if config.checked { Shim::CHECKED } else { Shim::FAST };
+        #[cfg(not(feature = "checked-handles"))]
+        let shim = Shim::FAST;
+        let raw = config.as_ffi();
+        let handle = NonNull::new(sync::exclusive(|| unsafe { shim.create_handle_with_config(&raw) }))?;
+        let mut alloc = Self { handle, shim, pool: None, locked: false, max_alloc_size: config.max_alloc_size };
+        if config.preallocated != 0 {
+            // Taken from the allocator of the thread, so that neither the limit of the handle nor
+            // the process-wide one, which bound the requests served from the pool, apply to it.
+            let base = NonNull::new(sync::exclusive(|| unsafe { ffi::sn_rust_alloc(Pool::ALIGN, config.preallocated) }).cast::<u8>())?;
+            if config.locked && !lock::lock(base.as_ptr(), config.preallocated) {
+                sync::exclusive(|| unsafe { ffi::sn_rust_dealloc(base.as_ptr().cast(), Pool::ALIGN, config.preallocated) });
+                return None;
+            }
+            alloc.pool = Some(unsafe { Pool::new(base, config.preallocated) });
+        }
+        alloc.locked = config.locked;
+        Some(alloc)
+    }
+
+    /// Creates a handle whose allocations are locked in memory (`mlock`, or `VirtualLock` on
+    /// Windows), so that secrets stored in them are never written to swap.
+    ///
+    /// Each allocation locks the pages it spans, which costs a system call. If they cannot be
+    /// locked, typically past `RLIMIT_MEMLOCK` even after raising the soft limit to the hard one,
+    /// the allocation fails (and [`stats::locked_memory`](crate::stats::locked_memory) counts the
+    /// failure) rather than handing out swappable memory. Pages are never unlocked, as they may be
+    /// shared with other allocations: freed memory stays locked for its next use, and on Linux
+    /// stays resident. Returns `None` if the handle cannot be allocated.
+    pub fn new_locked() -> Option<Self> {
+        Self::with_config(&AllocConfig::builder().locked(true).build())
+    }
+
+    /// Returns whether the allocations of this handle are locked in memory.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Returns whether the handle is served by the hardened shim.
+    #[inline(always)]
+    pub fn is_checked(&self) -> bool {
+        self.shim.is_checked()
+    }
+
+    /// Creates a handle serving every allocation from `bytes` committed up front.
+    ///
+    /// The pool is carved by this crate, not by snmalloc: once created, the allocation,
+    /// re-allocation and de-allocation methods of the handle never call into the OS nor fault in
+    /// new pages, and requests that do not fit in the remaining pool fail instead, which makes
+    /// them suitable for real-time threads. Creating and dropping the handle still go through
+    /// snmalloc, as does C code calling the shim on the snmalloc allocator behind the handle.
+    /// Blocks are rounded up to powers of two, so the usable capacity depends on the mix of
+    /// sizes. Returns `None` if the pool cannot be allocated.
+    pub fn with_preallocated(bytes: usize) -> Option<Self> {
+        Self::with_config(&AllocConfig::builder().preallocate(bytes).build())
+    }
+
+    /// Returns whether this handle serves allocations from a pre-committed pool.
+    #[inline(always)]
+    pub fn is_preallocated(&self) -> bool {
+        self.pool.is_some()
+    }
+
+    /// Returns whether [`deallocate`](Self::deallocate) accepts memory allocated elsewhere: by
+    /// [`SnMalloc`](crate::SnMalloc), or by another handle for which this also holds and that is
+    /// served by the same shim ([`is_checked`](Self::is_checked) is equal).
+    ///
+    /// Such frees are buffered and sent back to the owning allocator, which reuses the memory once
+    /// it processes its messages, on a later allocation or on [`flush`](Self::flush). Pre-allocated
+    /// handles only know their own pool, so this is false for them.
+    #[inline(always)]
+    pub fn can_free_foreign(&self) -> bool {
+        self.pool.is_none()
+    }
+
+    /// Sends the frees of foreign memory buffered by this handle to their owners, processes the
+    /// frees other allocators sent to it, and returns its cached memory to the global pool.
+    ///
+    /// The handle stays usable; this is a no-op for pre-allocated handles.
+    pub fn flush(&self) {
+        if self.pool.is_none() {
+            sync::exclusive(|| unsafe { self.shim.flush(self.handle.as_ptr()) });
+        }
+    }
+
+    /// Labels the handle, e.g. `"texture-cache"`, so that diagnostics tell which allocator they
+    /// refer to: the label is kept by the shim next to the allocator, where C code holding the
+    /// handle finds it too, and is part of the `Debug` output of the handle. Names longer than
+    /// 31 bytes are truncated to a character boundary; an empty name removes the label.
+    pub fn set_name(&mut self, name: &str) {
+        unsafe { self.shim.set_name(self.handle.as_ptr(), truncate_name(name)) }
+    }
+
+    /// Returns the label set by [`set_name`](Self::set_name), if any.
+    pub fn name(&self) -> Option<&str> {
+        let mut len = 0;
+        let name = unsafe { self.shim.name(self.handle.as_ptr(), &mut len) };
+        match name.is_null() {
+            true => None,
+            false => core::str::from_utf8(unsafe { core::slice::from_raw_parts(name.cast(), len) }).ok(),
+        }
+    }
+
+    /// Seals the handle: it can no longer allocate nor free, and it becomes shareable between
+    /// threads for reading the memory it already handed out (see [`FrozenAllocator`]).
+    ///
+    /// The pool of a handle created with [`with_preallocated`](Self::with_preallocated), which
+    /// spans whole pages of the OS, is also made read-only, so that a stray write faults. The
+    /// chunks of other handles are shared with the metadata snmalloc keeps writing, so they stay
+    /// writable, see [`FrozenAllocator::is_read_only`].
+    pub fn freeze(self) -> FrozenAllocator {
+        FrozenAllocator::new(self)
+    }
+
+    /// Moves the handle behind a raw pointer, e.g. to pass it through a C plugin interface.
+    ///
+    /// The handle is kept in a small block allocated from snmalloc: if that block cannot be
+    /// allocated, the handle is given back. The pointer must eventually be passed to
+    /// [`from_raw`](Self::from_raw), or the handle and all of its memory are leaked.
+    #[allow(clippy::result_large_err)] // the handle itself is given back
+    pub fn into_raw(self) -> Result<NonNull<RawSnAllocator>, Self> {
+        let layout = Layout::new::<Self>();
+        match NonNull::new(sync::exclusive(|| unsafe { ffi::sn_rust_alloc(layout.align(), layout.size()) }).cast::<Self>()) {
+            Some(cell) => {
+                unsafe { cell.as_ptr().write(self) };
+                Ok(cell.cast())
+            }
+            None => Err(self),
+        }
+    }
+
+    /// Takes back the ownership of a handle moved out by [`into_raw`](Self::into_raw).
+    ///
+    /// # Safety
+    /// `raw` must come from `into_raw`, in the same build of this crate, and must not be used
+    /// afterwards.
+    pub unsafe fn from_raw(raw: NonNull<RawSnAllocator>) -> Self {
+        let layout = Layout::new::<Self>();
+        let alloc = raw.cast::<Self>().as_ptr().read();
+        sync::exclusive(|| ffi::sn_rust_dealloc(raw.as_ptr().cast(), layout.align(), layout.size()));
+        alloc
+    }
+
+    /// Lends the handle as a raw pointer, which stays valid as long as the handle is neither moved
+    /// nor dropped. No ownership is transferred: use [`from_raw_ref`](Self::from_raw_ref) on the
+    /// other side of the boundary.
+    #[inline(always)]
+    pub fn as_raw(&self) -> NonNull<RawSnAllocator> {
+        NonNull::from(self).cast()
+    }
+
+    /// Borrows the handle behind a pointer from [`as_raw`](Self::as_raw) or
+    /// [`into_raw`](Self::into_raw).
+    ///
+    /// # Safety
+    /// `raw` must come from one of these methods in the same build of this crate, and the handle
+    /// must stay alive and in place for `'a`. As the handle is not `Sync`, it must not be used
+    /// from another thread at the same time.
+    #[inline(always)]
+    pub unsafe fn from_raw_ref<'a>(raw: NonNull<RawSnAllocator>) -> &'a Self {
+        raw.cast::<Self>().as_ref()
+    }
+
+    pub(crate) fn pool_region(&self) -> Option<(NonNull<u8>, usize)> {
+        self.pool.as_ref().map(Pool::region)
+    }
+
+    /// Allocates memory with the given layout, returning a non-null pointer on success.
+    #[inline(always)]
+    #[track_caller]
+    pub fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        layout::check(layout.size(), layout.align());
+        match layout.size() {
+            0 => NonNull::new(layout.align() as *mut u8),
+            size if self.exceeds(size) => None,
+            _ if self.pool.is_some() => self.pool.as_ref()?.allocate(layout),
+            size => self.lock(NonNull::new(sync::exclusive(|| unsafe {
+                self.shim.allocate(self.handle.as_ptr(), layout.align(), size)
+            }).cast()), layout)
+        }
+    }
+
+    /// Behaves like `allocate`, but also ensures that the contents are set to zero.
+    #[inline(always)]
+    #[track_caller]
+    pub fn allocate_zeroed(&self, layout: Layout) -> Option<NonNull<u8>> {
+        layout::check(layout.size(), layout.align());
+        match layout.size() {
+            0 => NonNull::new(layout.align() as *mut u8),
+            size if self.exceeds(size) => None,
+            size if self.pool.is_some() => {
+                let ptr = self.pool.as_ref()?.allocate(layout)?;
+                unsafe { ptr.as_ptr().write_bytes(0, size) };
+                Some(ptr)
+            }
+            size => self.lock(NonNull::new(sync::exclusive(|| unsafe {
+                self.shim.allocate_zeroed(self.handle.as_ptr(), layout.align(), size)
+            }).cast()), layout)
+        }
+    }
+
+    /// Behaves like `allocate`, but also sets every byte to `byte` (see [`fill`](crate::fill)
+    /// for common patterns).
+    #[inline(always)]
+    #[track_caller]
+    pub fn allocate_filled(&self, layout: Layout, byte: u8) -> Option<NonNull<u8>> {
+        layout::check(layout.size(), layout.align());
+        match layout.size() {
+            0 => NonNull::new(layout.align() as *mut u8),
+            size if self.exceeds(size) => None,
+            size if self.pool.is_some() => {
+                let ptr = self.pool.as_ref()?.allocate(layout)?;
+                unsafe { ptr.as_ptr().write_bytes(byte, size) };
+                Some(ptr)
+            }
+            size => self.lock(NonNull::new(sync::exclusive(|| unsafe {
+                self.shim.allocate_filled(self.handle.as_ptr(), layout.align(), size, byte)
+            }).cast()), layout)
+        }
+    }
+
+    /// Whether a request is above the process-wide or the per-handle limit.
+    #[inline(always)]
+    fn exceeds(&self, size: usize) -> bool {
+        limit::exceeds(size) || size > self.max_alloc_size
+    }
+
+    /// Locks a new block of a locked handle, freeing it if its pages cannot be locked.
+    #[inline(always)]
+    fn lock(&self, ptr: Option<NonNull<u8>>, layout: Layout) -> Option<NonNull<u8>> {
+        match ptr {
+            Some(block) if self.locked && !lock::lock(block.as_ptr(), layout.size()) => {
+                unsafe { self.deallocate(block, layout) };
+                None
+            }
+            ptr => ptr,
+        }
+    }
+
+    /// Allocates an uninitialised slice of `len` values of `T`, returning `None` if the size of
+    /// the slice overflows `isize` or on failure. Free it with
+    /// [`deallocate_slice`](Self::deallocate_slice).
+    #[inline(always)]
+    #[track_caller]
+    pub fn allocate_uninit_slice<T>(&self, len: usize) -> Option<NonNull<[MaybeUninit<T>]>> {
+        let ptr = self.allocate(Layout::array::<T>(len).ok()?)?;
+        Some(NonNull::slice_from_raw_parts(ptr.cast(), len))
+    }
+
+    /// Frees a slice allocated by [`allocate_uninit_slice`](Self::allocate_uninit_slice),
+    /// initialised or not. The values are not dropped.
+    ///
+    /// # Safety
+    /// `slice` must have been returned by `allocate_uninit_slice` of this handle (or of one whose
+    /// memory [`deallocate`](Self::deallocate) accepts), for the same `T` and length, and must not
+    /// be used afterwards.
+    #[inline(always)]
+    #[track_caller]
+    pub unsafe fn deallocate_slice<T>(&self, slice: NonNull<[T]>) {
+        let layout = Layout::from_size_align_unchecked(mem::size_of::<T>() * slice.len(), mem::align_of::<T>());
+        self.deallocate(slice.cast(), layout);
+    }
+
+    /// De-allocates the memory at the given address with the given layout.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated with the same `layout`, by this handle or, if
+    /// [`can_free_foreign`](Self::can_free_foreign) holds, by an allocator it accepts.
+    #[inline(always)]
+    #[track_caller]
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        layout::check(layout.size(), layout.align());
+        match &self.pool {
+            _ if layout.size() == 0 => {}
+            Some(pool) => pool.deallocate(ptr, layout),
+            None => sync::exclusive(|| {
+                self.shim.deallocate(self.handle.as_ptr(), ptr.as_ptr().cast(), layout.align(), layout.size());
+            }),
+        }
+    }
+
+    /// Frees many blocks at once, sorted by address and freed by a single call into the shim per
+    /// batch, see [`SnMalloc::dealloc_many`](crate::SnMalloc::dealloc_many).
+    ///
+    /// # Safety
+    /// Every block must satisfy the requirements of [`deallocate`](Self::deallocate), and appear
+    /// once.
+    #[track_caller]
+    pub unsafe fn deallocate_many(&self, blocks: &[(NonNull<u8>, Layout)]) {
+        if self.pool.is_some() {
+            for &(ptr, layout) in blocks {
+                self.deallocate(ptr, layout);
+            }
+            return;
+        }
+        let mut batch = [crate::BATCH_ENTRY; crate::BATCH_LEN];
+        let mut len = 0;
+        for &(ptr, layout) in blocks {
+            layout::check(layout.size(), layout.align());
+            if layout.size() == 0 {
+                continue;
+            }
+            batch[len] = ffi::sn_rust_block_t { ptr: ptr.as_ptr().cast(), alignment: layout.align(), size: layout.size() };
+            len += 1;
+            if len == crate::BATCH_LEN {
+                sync::exclusive(|| self.shim.deallocate_many(self.handle.as_ptr(), &mut batch));
+                len = 0;
+            }
+        }
+        if len != 0 {
+            sync::exclusive(|| self.shim.deallocate_many(self.handle.as_ptr(), &mut batch[..len]));
+        }
+    }
+
+    /// Re-allocates the memory at the given address to `new_size` bytes, keeping the alignment.
+    /// On failure, the previous memory is left untouched and `None` is returned.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated by this handle with the same `layout`, and `new_size`
+    /// rounded up to `layout.align()` must not overflow `isize`.
+    #[inline(always)]
+    #[track_caller]
+    pub unsafe fn reallocate(&self, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> Option<NonNull<u8>> {
+        layout::check(layout.size(), layout.align());
+        layout::check(new_size, layout.align());
+        match new_size {
+            0 => {
+                self.deallocate(ptr, layout);
+                NonNull::new(layout.align() as *mut u8)
+            }
+            new_size if self.exceeds(new_size) => None,
+            new_size if layout.size() == 0 => {
+                self.allocate(Layout::from_size_align_unchecked(new_size, layout.align()))
+            }
+            _ if self.pool.is_some() => self.pool.as_ref()?.reallocate(ptr, layout, new_size),
+            // Moved to a new block, so that a failure to lock it leaves the original in place.
+            _ if self.locked => {
+                let new_ptr = self.allocate(Layout::from_size_align_unchecked(new_size, layout.align()))?;
+                ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr(), layout.size().min(new_size));
+                self.deallocate(ptr, layout);
+                Some(new_ptr)
+            }
+            _ => NonNull::new(sync::exclusive(|| self.shim.reallocate(
+                self.handle.as_ptr(),
+                ptr.as_ptr().cast(),
+                layout.align(),
+                layout.size(),
+                new_size,
+            )).cast())
+        }
+    }
+
+    /// Grows the memory at the given address to `new_size` bytes, keeping the alignment and the
+    /// contents.
+    ///
+    /// Unlike [`reallocate`](Self::reallocate), every failure is reported as an error, never as a
+    /// panic or an abort, and leaves the original block untouched and still owned by the caller, so
+    /// that containers can recover from running out of memory without losing their elements:
+    /// - [`ReallocError::InvalidLayout`] if `new_size` is smaller than `layout.size()`, or
+    ///   overflows `isize` once rounded up to the alignment;
+    /// - [`ReallocError::OutOfMemory`] if the new block cannot be allocated, including when
+    ///   `new_size` exceeds [`max_alloc_size`](crate::max_alloc_size) or the limit of the handle
+    ///   (see [`AllocConfigBuilder::max_alloc_size`](crate::AllocConfigBuilder::max_alloc_size)).
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated by this handle with the same `layout`. On success the old
+    /// pointer must no longer be used.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn try_grow(&self, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> Result<NonNull<u8>, ReallocError> {
+        if new_size < layout.size() || Layout::from_size_align(new_size, layout.align()).is_err() {
+            return Err(ReallocError::InvalidLayout);
+        }
+        self.reallocate(ptr, layout, new_size).ok_or(ReallocError::OutOfMemory)
+    }
+
+    /// Behaves like [`try_grow`](Self::try_grow), but also sets the bytes between the old and the
+    /// new size to zero.
+    ///
+    /// # Safety
+    /// Same as [`try_grow`](Self::try_grow).
+    #[inline]
+    #[track_caller]
+    pub unsafe fn try_grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, ReallocError> {
+        let new_ptr = self.try_grow(ptr, layout, new_size)?;
+        new_ptr.as_ptr().add(layout.size()).write_bytes(0, new_size - layout.size());
+        Ok(new_ptr)
+    }
+}
+
+impl fmt::Debug for SnAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SnAllocator")
+            .field("name", &self.name())
+            .field("handle", &self.handle)
+            .field("shim", &self.shim)
+            .field("pool", &self.pool)
+            .field("locked", &self.locked)
+            .field("max_alloc_size", &self.max_alloc_size)
+            .finish()
+    }
+}
+
+impl Drop for SnAllocator {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            let (base, size) = pool.region();
+            sync::exclusive(|| unsafe { ffi::sn_rust_dealloc(base.as_ptr().cast(), Pool::ALIGN, size) });
+        }
+        sync::exclusive(|| unsafe { self.shim.free_handle(self.handle.as_ptr()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_allocation_lifecycle() {
+        let alloc = SnAllocator::new().expect("failed to create allocator handle");
+        unsafe {
+            let layout = Layout::from_size_align(8, 8).unwrap();
+
+            let ptr = alloc.allocate(layout).unwrap();
+            alloc.deallocate(ptr, layout);
+
+            let ptr = alloc.allocate_zeroed(layout).unwrap();
+            assert_eq!(*ptr.as_ptr(), 0);
+            alloc.deallocate(ptr, layout);
+
+            let ptr = alloc.allocate_filled(layout, 0xAA).unwrap();
+            assert_eq!(*ptr.as_ptr().add(7), 0xAA);
+            alloc.deallocate(ptr, layout);
+
+            let ptr = alloc.allocate(layout).unwrap();
+            *ptr.as_ptr() = 42;
+            let ptr = alloc.reallocate(ptr, layout, 1 << 20).unwrap();
+            assert_eq!(*ptr.as_ptr(), 42);
+            alloc.deallocate(ptr, Layout::from_size_align(1 << 20, 8).unwrap());
+        }
+    }
+
+    #[test]
+    fn handle_deallocates_many_blocks() {
+        let alloc = SnAllocator::new().unwrap();
+        let blocks: std::vec::Vec<_> = (0..100usize)
+            .map(|i| {
+                let layout = Layout::from_size_align((i % 5) * 1000, 16).unwrap();
+                (alloc.allocate(layout).unwrap(), layout)
+            })
+            .collect();
+        unsafe { alloc.deallocate_many(&blocks) };
+    }
+
+    #[test]
+    fn it_names_the_handle() {
+        let mut alloc = SnAllocator::new().unwrap();
+        assert_eq!(alloc.name(), None);
+        alloc.set_name("texture-cache");
+        assert_eq!(alloc.name(), Some("texture-cache"));
+        assert!(std::format!("{:?}", alloc).contains("texture-cache"));
+        // Truncated to 31 bytes, without splitting the two-byte characters.
+        alloc.set_name(&"é".repeat(20));
+        assert_eq!(alloc.name(), Some(&*"é".repeat(15)));
+        alloc.set_name("");
+        assert_eq!(alloc.name(), None);
+    }
+
+    #[test]
+    fn handle_slices() {
+        let alloc = SnAllocator::new().unwrap();
+        let slice = alloc.allocate_uninit_slice::<u32>(100).unwrap();
+        assert_eq!(slice.len(), 100);
+        assert_eq!(slice.cast::<u32>().as_ptr() as usize % mem::align_of::<u32>(), 0);
+        unsafe {
+            for (i, value) in (*slice.as_ptr()).iter_mut().enumerate() {
+                value.write(i as u32);
+            }
+            alloc.deallocate_slice(slice);
+        }
+        let empty = alloc.allocate_uninit_slice::<()>(usize::MAX).unwrap();
+        unsafe { alloc.deallocate_slice(empty) };
+        assert!(alloc.allocate_uninit_slice::<u16>(usize::MAX / 2).is_none());
+    }
+
+    #[test]
+    fn handle_preallocated_pool() {
+        let alloc = SnAllocator::with_preallocated(1 << 20).unwrap();
+        assert!(alloc.is_preallocated());
+        let (base, size) = alloc.pool.as_ref().unwrap().region();
+        let region = base.as_ptr() as usize..base.as_ptr() as usize + size;
+        unsafe {
+            let layout = Layout::from_size_align(1000, 8).unwrap();
+            let ptrs: [_; 256] = core::array::from_fn(|_| alloc.allocate(layout).unwrap());
+            // Every block comes from the pool, none from the backend.
+            assert!(ptrs.iter().all(|ptr| region.contains(&(ptr.as_ptr() as usize))));
+            assert!(alloc.allocate(Layout::from_size_align(1 << 20, 8).unwrap()).is_none());
+            for ptr in ptrs {
+                alloc.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn handle_grow_failures_keep_the_original() {
+        let alloc = SnAllocator::with_preallocated(1 << 16).unwrap();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            let ptr = alloc.allocate_filled(layout, 0x5A).unwrap();
+            assert_eq!(alloc.try_grow(ptr, layout, 50), Err(ReallocError::InvalidLayout));
+            assert_eq!(alloc.try_grow(ptr, layout, isize::MAX as usize), Err(ReallocError::InvalidLayout));
+            // Does not fit in the pool.
+            assert_eq!(alloc.try_grow(ptr, layout, 1 << 20), Err(ReallocError::OutOfMemory));
+            assert!((0..100).all(|i| *ptr.as_ptr().add(i) == 0x5A));
+            let ptr = alloc.try_grow_zeroed(ptr, layout, 1000).unwrap();
+            assert_eq!((*ptr.as_ptr().add(99), *ptr.as_ptr().add(999)), (0x5A, 0));
+            alloc.deallocate(ptr, Layout::from_size_align(1000, 8).unwrap());
+        }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn handle_grow_survives_exhausting_memory() {
+        let alloc = SnAllocator::new().unwrap();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let ptr = alloc.allocate_filled(layout, 0x5A).unwrap();
+            // Far more than any address space can provide.
+            assert_eq!(alloc.try_grow(ptr, layout, 1 << 62), Err(ReallocError::OutOfMemory));
+            assert!((0..64).all(|i| *ptr.as_ptr().add(i) == 0x5A));
+            let ptr = alloc.try_grow(ptr, layout, 4096).unwrap();
+            assert_eq!(*ptr.as_ptr().add(63), 0x5A);
+            alloc.deallocate(ptr, Layout::from_size_align(4096, 8).unwrap());
+        }
+    }
+
+    #[test]
+    fn handle_applies_its_config() {
+        let config = AllocConfig::builder().name("handle-config").max_alloc_size(4096).preallocate(1 << 16).build();
+        let alloc = SnAllocator::with_config(&config).unwrap();
+        assert_eq!(alloc.name(), Some("handle-config"));
+        assert!(alloc.is_preallocated() && !alloc.is_locked());
+        assert!(alloc.allocate(Layout::from_size_align(8192, 8).unwrap()).is_none());
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let ptr = alloc.allocate(layout).unwrap();
+        unsafe {
+            assert!(alloc.reallocate(ptr, layout, 4097).is_none());
+            alloc.deallocate(ptr, layout);
+        }
+        // The shim enforces the limit for C code holding the handle too.
+        let alloc = SnAllocator::with_config(&AllocConfig::builder().max_alloc_size(100).build()).unwrap();
+        assert!(unsafe { ffi::sn_rust_allocator_allocate(alloc.handle.as_ptr(), 8, 101) }.is_null());
+    }
+
+    #[cfg(feature = "checked-handles")]
+    #[test]
+    fn handle_selects_the_shim() {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        for (alloc, checked) in [(SnAllocator::new_fast().unwrap(), false), (SnAllocator::new_checked().unwrap(), true)] {
+            assert_eq!(alloc.is_checked(), checked);
+            unsafe {
+                let ptr = alloc.allocate_filled(layout, 3).unwrap();
+                let ptr = alloc.reallocate(ptr, layout, 4096).unwrap();
+                assert_eq!(*ptr.as_ptr().add(63), 3);
+                alloc.deallocate(ptr, Layout::from_size_align(4096, 8).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn handle_crosses_a_raw_pointer() {
+        extern "C" fn fill(raw: *mut RawSnAllocator) -> *mut u8 {
+            let alloc = unsafe { SnAllocator::from_raw_ref(NonNull::new(raw).unwrap()) };
+            alloc.allocate_filled(Layout::from_size_align(64, 8).unwrap(), 9).unwrap().as_ptr()
+        }
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let alloc = SnAllocator::with_preallocated(1 << 16).unwrap();
+        let lent = fill(alloc.as_raw().as_ptr());
+        let raw = alloc.into_raw().unwrap();
+        let moved = fill(raw.as_ptr());
+        let alloc = unsafe { SnAllocator::from_raw(raw) };
+        assert!(alloc.is_preallocated());
+        for ptr in [lent, moved] {
+            unsafe {
+                assert_eq!(*ptr.add(63), 9);
+                alloc.deallocate(NonNull::new(ptr).unwrap(), layout);
+            }
+        }
+    }
+
+    #[test]
+    fn handle_frees_foreign_memory() {
+        use core::alloc::GlobalAlloc;
+
+        let (a, b) = (SnAllocator::new().unwrap(), SnAllocator::new().unwrap());
+        assert!(a.can_free_foreign() && b.can_free_foreign());
+        assert!(!SnAllocator::with_preallocated(1 << 16).unwrap().can_free_foreign());
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        for _ in 0..1000 {
+            unsafe {
+                b.deallocate(a.allocate(layout).unwrap(), layout);
+                a.deallocate(NonNull::new(crate::SnMalloc.alloc(layout)).unwrap(), layout);
+            }
+        }
+        b.flush();
+        a.flush();
+        let ptr = a.allocate(layout).unwrap();
+        unsafe { a.deallocate(ptr, layout) };
+    }
+
+    #[test]
+    fn handle_locks_its_memory() {
+        let alloc = SnAllocator::new_locked().unwrap();
+        assert!(alloc.is_locked() && !SnAllocator::new().unwrap().is_locked());
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let before = crate::stats::locked_memory();
+        match alloc.allocate_filled(layout, 7) {
+            Some(ptr) => unsafe {
+                assert!(crate::stats::locked_memory().bytes >= before.bytes + 100);
+                let ptr = alloc.reallocate(ptr, layout, 5000).unwrap();
+                assert!((0..100).all(|i| *ptr.as_ptr().add(i) == 7));
+                alloc.deallocate(ptr, Layout::from_size_align(5000, 8).unwrap());
+            },
+            // RLIMIT_MEMLOCK may be zero in a sandbox.
+            None => assert!(crate::stats::locked_memory().failures > before.failures),
+        }
+    }
+
+    #[test]
+    fn handle_zero_sized_allocation() {
+        let alloc = SnAllocator::new().unwrap();
+        let layout = Layout::from_size_align(0, 64).unwrap();
+        let ptr = alloc.allocate(layout).unwrap();
+        assert_eq!(ptr.as_ptr() as usize, 64);
+        unsafe { alloc.deallocate(ptr, layout) };
+    }
+}
@@ -0,0 +1,183 @@
+//! Request-scoped memory released en masse.
+use core::{
+    alloc::Layout,
+    cell::Cell,
+    marker::PhantomData,
+    mem,
+    ptr::{self, NonNull},
+};
+
+use crate::SnAllocator;
+
+/// Size of the first chunk; later chunks double up to [`MAX_CHUNK`].
+const MIN_CHUNK: usize = 64 << 10;
+const MAX_CHUNK: usize = 4 << 20;
+const CHUNK_ALIGN: usize = 64;
+
+/// Header at the start of every chunk, linking the chunks of an arena.
+struct Chunk {
+    next: *mut Chunk,
+    size: usize,
+}
+
+/// An arena on its own [`SnAllocator`] handle, whose allocations are all released when it drops.
+///
+/// Allocation is a pointer bump inside chunks obtained from the handle; individual
+/// de-allocations are no-ops. Values stored in the arena may borrow from `'scope`, and their
+/// destructors are not run. In debug builds, freeing a pointer that does not belong to the arena
+/// (e.g. one that escaped an arena that is gone) panics, and released chunks are filled with
+/// [`fill::FREED`](crate::fill::FREED) to expose use after the scope.
+///
+/// ```rust
+/// let arena = snmalloc_rs::ScopedArena::new().unwrap();
+/// let request = arena.alloc([0u8; 256]).unwrap();
+/// request[0] = 1;
+/// // everything is released here
+/// drop(arena);
+/// ```
+pub struct ScopedArena<'scope> {
+    handle: SnAllocator,
+    chunks: Cell<*mut Chunk>,
+    cursor: Cell<usize>,
+    end: Cell<usize>,
+    allocated: Cell<usize>,
+    _scope: PhantomData<Cell<&'scope ()>>,
+}
+
+impl<'scope> ScopedArena<'scope> {
+    /// Creates an arena with a fresh handle, returning `None` if the handle cannot be allocated.
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            handle: SnAllocator::new()?,
+            chunks: Cell::new(ptr::null_mut()),
+            cursor: Cell::new(0),
+            end: Cell::new(0),
+            allocated: Cell::new(0),
+            _scope: PhantomData,
+        })
+    }
+
+    /// Returns the bytes handed out by the arena so far.
+    #[inline(always)]
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated.get()
+    }
+
+    /// Allocates memory with the given layout, valid until the arena drops.
+    #[inline]
+    pub fn alloc_layout(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let start = self.cursor.get().checked_next_multiple_of(layout.align())?;
+        let end = start.checked_add(layout.size())?;
+        let ptr = match end <= self.end.get() && self.cursor.get() != 0 {
+            true => {
+                self.cursor.set(end);
+                start as *mut u8
+            }
+            false => self.grow(layout)?,
+        };
+        self.allocated.set(self.allocated.get() + layout.size());
+        NonNull::new(ptr)
+    }
+
+    /// Moves `value` into the arena.
+    #[inline]
+    #[allow(clippy::mut_from_ref)] // every call returns distinct memory
+    pub fn alloc<T: 'scope>(&self, value: T) -> Option<&mut T> {
+        let ptr = self.alloc_layout(Layout::new::<T>())?.cast::<T>();
+        unsafe {
+            ptr.as_ptr().write(value);
+            Some(&mut *ptr.as_ptr())
+        }
+    }
+
+    /// Releases nothing: the memory is reclaimed when the arena drops.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated by this arena.
+    #[inline(always)]
+    pub unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        debug_assert!(
+            self.owns(ptr.as_ptr(), layout.size()),
+            "snmalloc: {:p} was not allocated by this arena",
+            ptr
+        );
+        let _ = (ptr, layout);
+    }
+
+    /// Returns whether `size` bytes at `ptr` lie in one of the chunks of the arena.
+    pub fn owns(&self, ptr: *const u8, size: usize) -> bool {
+        let (start, end) = (ptr as usize, ptr as usize + size);
+        let mut chunk = self.chunks.get();
+        while let Some(header) = unsafe { chunk.as_ref() } {
+            let base = chunk as usize;
+            if start >= base + mem::size_of::<Chunk>() && end <= base + header.size {
+                return true;
+            }
+            chunk = header.next;
+        }
+        false
+    }
+
+    /// Starts a new chunk large enough for `layout`, and allocates from it.
+    #[cold]
+    fn grow(&self, layout: Layout) -> Option<*mut u8> {
+        let previous = unsafe { self.chunks.get().as_ref() }.map_or(MIN_CHUNK / 2, |chunk| chunk.size);
+        let needed = mem::size_of::<Chunk>()
+            .checked_add(layout.align().max(CHUNK_ALIGN))?
+            .checked_add(layout.size())?;
+        let size = (previous * 2).min(MAX_CHUNK).max(needed);
+        let chunk = self.handle.allocate(Layout::from_size_align(size, CHUNK_ALIGN).ok()?)?.cast::<Chunk>();
+        unsafe {
+            chunk.as_ptr().write(Chunk { next: self.chunks.get(), size });
+        }
+        self.chunks.set(chunk.as_ptr());
+        let base = chunk.as_ptr() as usize;
+        let start = (base + mem::size_of::<Chunk>()).next_multiple_of(layout.align());
+        self.cursor.set(start + layout.size());
+        self.end.set(base + size);
+        Some(start as *mut u8)
+    }
+}
+
+impl Drop for ScopedArena<'_> {
+    fn drop(&mut self) {
+        let mut chunk = self.chunks.get();
+        while !chunk.is_null() {
+            unsafe {
+                let Chunk { next, size } = chunk.read();
+                if cfg!(debug_assertions) {
+                    chunk.cast::<u8>().write_bytes(crate::fill::FREED, size);
+                }
+                let layout = Layout::from_size_align_unchecked(size, CHUNK_ALIGN);
+                self.handle.deallocate(NonNull::new_unchecked(chunk.cast()), layout);
+                chunk = next;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_allocates_across_chunks() {
+        let arena = ScopedArena::new().unwrap();
+        let small = arena.alloc(42u64).unwrap();
+        let large = arena.alloc_layout(Layout::from_size_align(1 << 20, 4096).unwrap()).unwrap();
+        assert_eq!(large.as_ptr() as usize % 4096, 0);
+        assert_eq!(*small, 42);
+        assert!(arena.owns(large.as_ptr(), 1 << 20));
+        assert!(arena.allocated_bytes() >= (1 << 20) + 8);
+        unsafe { arena.dealloc(large, Layout::from_size_align(1 << 20, 4096).unwrap()) };
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "not allocated by this arena")]
+    fn it_detects_foreign_pointers() {
+        let arena = ScopedArena::new().unwrap();
+        let mut value = 0u8;
+        unsafe { arena.dealloc(NonNull::from(&mut value), Layout::new::<u8>()) };
+    }
+}
@@ -0,0 +1,28 @@
+//! Audit of the guarantee that freeing never maps memory (`no-alloc-on-free` feature).
+//!
+//! Real-time code often needs `dealloc` to be bounded: no allocation, and no call into the OS for
+//! more memory. snmalloc's deallocation paths only ever give memory back, with one exception: a
+//! thread's allocator is initialised lazily, by the first operation of the thread, so a thread
+//! whose first operation is a free may map the metadata of its allocator (`mmap`, or
+//! `VirtualAlloc` on Windows) from inside `dealloc`. With this feature the shim marks the frees
+//! of [`SnMalloc`](crate::SnMalloc), and its PAL aborts with a message when one of them reserves
+//! or commits memory, whatever the cause.
+//!
+//! The other system calls a free can still make are listed in the README.
+//!
+//! Guarded allocations (`guard-large-allocs`) are unmapped when freed, and the quarantine
+//! (`quarantine`) grows its queue from `dealloc`, so neither can be combined with this feature.
+//! Frees through handles ([`SnAllocator`](crate::SnAllocator)) use their own allocator and are
+//! not audited.
+
+/// Runs `free`, a deallocation through the shim, aborting with the feature if it reserves or
+/// commits memory.
+#[inline(always)]
+pub(crate) fn on_dealloc<R>(free: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "no-alloc-on-free")]
+    unsafe { ffi::sn_rust_audit_dealloc_enter() };
+    let result = free();
+    #[cfg(feature = "no-alloc-on-free")]
+    unsafe { ffi::sn_rust_audit_dealloc_leave() };
+    result
+}
@@ -0,0 +1,176 @@
+//! Heap values initialised in place.
+//!
+//! `Box::new(value)` builds `value` on the stack before moving it to the heap, which overflows
+//! the stack for multi-megabyte types in debug builds. The constructors here hand the freshly
+//! allocated memory to an initialiser instead.
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    mem::{self, MaybeUninit},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+#[cfg(feature = "handle-api")]
+use crate::SnAllocator;
+use crate::SnMalloc;
+
+/// Stands in for the handles without the `handle-api` feature: boxes then all come from
+/// [`SnMalloc`].
+#[cfg(not(feature = "handle-api"))]
+enum SnAllocator {}
+
+#[cfg(not(feature = "handle-api"))]
+impl SnAllocator {
+    fn allocate(&self, _: Layout) -> Option<NonNull<u8>> {
+        match *self {}
+    }
+
+    unsafe fn deallocate(&self, _: NonNull<u8>, _: Layout) {
+        match *self {}
+    }
+}
+
+/// An owned value living on snmalloc, freed through the allocator it came from.
+///
+/// A box sends its value, and frees it, on whichever thread it is moved to. Boxes from a handle
+/// borrow it, and a handle cannot be used from two threads, so they are only `Send` if a shared
+/// reference to the handle is.
+pub struct SnBox<'a, T> {
+    ptr: NonNull<T>,
+    handle: Option<&'a SnAllocator>,
+}
+
+#[cfg(feature = "handle-api")]
+unsafe impl<'a, T: Send> Send for SnBox<'a, T> where &'a SnAllocator: Send {}
+#[cfg(not(feature = "handle-api"))]
+unsafe impl<T: Send> Send for SnBox<'_, T> {}
+unsafe impl<T: Sync> Sync for SnBox<'_, T> {}
+
+/// Frees the memory if the initialiser unwinds.
+struct Uninit<'a, T> {
+    ptr: NonNull<T>,
+    handle: Option<&'a SnAllocator>,
+}
+
+impl<'a, T> Uninit<'a, T> {
+    fn new(handle: Option<&'a SnAllocator>) -> Option<Self> {
+        let layout = Layout::new::<T>();
+        let ptr = match handle {
+            Some(handle) => handle.allocate(layout)?,
+            None => NonNull::new(unsafe { SnMalloc.alloc(layout) })?,
+        };
+        Some(Self { ptr: ptr.cast(), handle })
+    }
+
+    fn init(self, init: impl FnOnce(&mut MaybeUninit<T>)) -> SnBox<'a, T> {
+        init(unsafe { &mut *self.ptr.as_ptr().cast::<MaybeUninit<T>>() });
+        let boxed = SnBox { ptr: self.ptr, handle: self.handle };
+        mem::forget(self);
+        boxed
+    }
+}
+
+impl<T> Drop for Uninit<'_, T> {
+    fn drop(&mut self) {
+        unsafe { free(self.ptr, self.handle) }
+    }
+}
+
+unsafe fn free<T>(ptr: NonNull<T>, handle: Option<&SnAllocator>) {
+    let layout = Layout::new::<T>();
+    match handle {
+        Some(handle) => handle.deallocate(ptr.cast(), layout),
+        None => SnMalloc.dealloc(ptr.as_ptr().cast(), layout),
+    }
+}
+
+/// Allocates a `T` from [`SnMalloc`] and lets `init` initialise it in place.
+/// Returns `None` if the allocation fails.
+///
+/// # Safety
+/// `init` must fully initialise the value it is given.
+///
+/// ```rust
+/// use core::mem::MaybeUninit;
+/// let table = unsafe {
+///     snmalloc_rs::boxed::try_new_with(|slot: &mut MaybeUninit<[u64; 1 << 20]>| {
+///         slot.as_mut_ptr().cast::<u64>().write_bytes(0, 1 << 20);
+///     })
+/// }
+/// .unwrap();
+/// assert_eq!(table[42], 0);
+/// ```
+#[inline(always)]
+pub unsafe fn try_new_with<T>(init: impl FnOnce(&mut MaybeUninit<T>)) -> Option<SnBox<'static, T>> {
+    Some(Uninit::new(None)?.init(init))
+}
+
+/// Behaves like [`try_new_with`], but allocates from, and frees through, `handle`.
+#[cfg(feature = "handle-api")]
+///
+/// # Safety
+/// `init` must fully initialise the value it is given.
+#[inline(always)]
+pub unsafe fn try_new_with_in<T>(
+    handle: &SnAllocator,
+    init: impl FnOnce(&mut MaybeUninit<T>),
+) -> Option<SnBox<'_, T>> {
+    Some(Uninit::new(Some(handle))?.init(init))
+}
+
+impl<T> Deref for SnBox<'_, T> {
+    type Target = T;
+
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SnBox<'_, T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SnBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for SnBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            free(self.ptr, self.handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_initialises_in_place() {
+        let boxed = unsafe {
+            try_new_with(|slot: &mut MaybeUninit<[u8; 1 << 16]>| {
+                slot.as_mut_ptr().cast::<u8>().write_bytes(7, 1 << 16);
+            })
+        }
+        .unwrap();
+        assert!(boxed.iter().all(|b| *b == 7));
+    }
+
+    #[cfg(feature = "handle-api")]
+    #[test]
+    fn it_allocates_from_a_handle() {
+        let handle = SnAllocator::new().unwrap();
+        let mut boxed = unsafe { try_new_with_in(&handle, |slot| { slot.write(41u64); }) }.unwrap();
+        *boxed += 1;
+        assert_eq!(*boxed, 42);
+    }
+}
@@ -0,0 +1,204 @@
+//! Per-thread allocation budgets (`thread-budget` feature).
+//!
+//! On top of the process-wide [`set_max_alloc_size`](crate::set_max_alloc_size), each thread may
+//! be given a budget of live bytes with [`set_thread_budget`], e.g. by a server for the thread
+//! handling a request, so that a runaway handler is stopped before it takes the whole heap down.
+//! What happens when an allocation through [`SnMalloc`](crate::SnMalloc) would exceed it is set
+//! process-wide by [`set_budget_policy`]: the allocation fails (the default), a handler decides,
+//! or the breach is only counted.
+//!
+//! The usage of a thread is the bytes it allocated minus the bytes it freed, whoever allocated
+//! them, and never drops below zero: memory handed to another thread is still charged to the
+//! thread that allocated it until that thread frees something else.
+//!
+//! ```rust
+//! use core::alloc::{GlobalAlloc, Layout};
+//! std::thread::spawn(|| {
+//!     snmalloc_rs::set_thread_budget(1 << 20);
+//!     let layout = Layout::from_size_align(2 << 20, 8).unwrap();
+//!     assert!(unsafe { snmalloc_rs::SnMalloc.alloc(layout) }.is_null());
+//! })
+//! .join()
+//! .unwrap();
+//! ```
+use core::{
+    cell::Cell,
+    mem, ptr,
+    sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering},
+};
+
+/// An allocation that would take a thread over its budget.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BudgetBreach {
+    /// Bytes requested, or added by a growing re-allocation.
+    pub size: usize,
+    /// Live bytes of the thread before the allocation.
+    pub usage: usize,
+    /// Budget of the thread.
+    pub budget: usize,
+}
+
+/// What an allocation exceeding the budget of its thread does.
+#[derive(Debug, Copy, Clone)]
+pub enum BudgetPolicy {
+    /// Fails the allocation, returning null (or `None`).
+    Fail,
+    /// Calls the handler, which returns whether to serve the allocation anyway. The handler runs
+    /// inside the global allocator: allocations it makes are not checked against the budget.
+    Handler(fn(&BudgetBreach) -> bool),
+    /// Serves the allocation, only counting the breach.
+    Count,
+}
+
+const FAIL: u8 = 0;
+const HANDLER: u8 = 1;
+const COUNT: u8 = 2;
+
+static POLICY: AtomicU8 = AtomicU8::new(FAIL);
+static HANDLER_FN: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+static BREACHES: AtomicUsize = AtomicUsize::new(0);
+
+std::thread_local! {
+    static BUDGET: Cell<usize> = const { Cell::new(usize::MAX) };
+    static USAGE: Cell<usize> = const { Cell::new(0) };
+    static IN_HANDLER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sets the budget of live bytes of the current thread, or lifts it with `usize::MAX` (the
+/// default). Bytes the thread already holds count against it, see [`reset_thread_usage`].
+#[inline(always)]
+pub fn set_thread_budget(bytes: usize) {
+    let _ = BUDGET.try_with(|budget| budget.set(bytes));
+}
+
+/// Returns the budget of the current thread set by [`set_thread_budget`].
+#[inline(always)]
+pub fn thread_budget() -> usize {
+    BUDGET.try_with(Cell::get).unwrap_or(usize::MAX)
+}
+
+/// Returns the live bytes charged to the current thread.
+#[inline(always)]
+pub fn thread_usage() -> usize {
+    USAGE.try_with(Cell::get).unwrap_or(0)
+}
+
+/// Forgets the bytes charged to the current thread, e.g. when a pooled thread starts on a new
+/// request.
+#[inline(always)]
+pub fn reset_thread_usage() {
+    let _ = USAGE.try_with(|usage| usage.set(0));
+}
+
+/// Sets what allocations exceeding the budget of their thread do, for every thread.
+pub fn set_budget_policy(policy: BudgetPolicy) {
+    let code = match policy {
+        BudgetPolicy::Fail => FAIL,
+        BudgetPolicy::Handler(handler) => {
+            HANDLER_FN.store(handler as *mut (), Ordering::Release);
+            HANDLER
+        }
+        BudgetPolicy::Count => COUNT,
+    };
+    POLICY.store(code, Ordering::Release);
+}
+
+/// Returns the policy set by [`set_budget_policy`].
+pub fn budget_policy() -> BudgetPolicy {
+    match POLICY.load(Ordering::Acquire) {
+        HANDLER => BudgetPolicy::Handler(unsafe {
+            mem::transmute::<*mut (), fn(&BudgetBreach) -> bool>(HANDLER_FN.load(Ordering::Acquire))
+        }),
+        COUNT => BudgetPolicy::Count,
+        _ => BudgetPolicy::Fail,
+    }
+}
+
+/// Returns the number of allocations that exceeded the budget of their thread, whatever the
+/// policy did with them.
+#[inline(always)]
+pub fn budget_breaches() -> usize {
+    BREACHES.load(Ordering::Relaxed)
+}
+
+/// Whether an allocation of `size` more bytes by the current thread is refused.
+#[inline(always)]
+pub(crate) fn refuses(size: usize) -> bool {
+    let budget = thread_budget();
+    if budget == usize::MAX {
+        return false;
+    }
+    let usage = thread_usage();
+    usage.saturating_add(size) > budget && breach(BudgetBreach { size, usage, budget })
+}
+
+#[cold]
+fn breach(breach: BudgetBreach) -> bool {
+    if IN_HANDLER.try_with(Cell::get).unwrap_or(true) {
+        return false;
+    }
+    BREACHES.fetch_add(1, Ordering::Relaxed);
+    match budget_policy() {
+        BudgetPolicy::Fail => true,
+        BudgetPolicy::Count => false,
+        BudgetPolicy::Handler(handler) => {
+            let _ = IN_HANDLER.try_with(|flag| flag.set(true));
+            let serve = handler(&breach);
+            let _ = IN_HANDLER.try_with(|flag| flag.set(false));
+            !serve
+        }
+    }
+}
+
+#[inline(always)]
+pub(crate) fn on_alloc(size: usize) {
+    let _ = USAGE.try_with(|usage| usage.set(usage.get().saturating_add(size)));
+}
+
+#[inline(always)]
+pub(crate) fn on_dealloc(size: usize) {
+    let _ = USAGE.try_with(|usage| usage.set(usage.get().saturating_sub(size)));
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    use super::*;
+    use crate::SnMalloc;
+
+    fn deny_large(breach: &BudgetBreach) -> bool {
+        breach.size < 1 << 20
+    }
+
+    #[test]
+    fn it_enforces_the_thread_budget() {
+        std::thread::spawn(|| unsafe {
+            let small = Layout::from_size_align(64 << 10, 8).unwrap();
+            let large = Layout::from_size_align(2 << 20, 8).unwrap();
+            set_thread_budget(1 << 20);
+            let ptr = SnMalloc.alloc(small);
+            assert!(!ptr.is_null());
+            assert!(thread_usage() >= 64 << 10);
+            assert!(SnMalloc.alloc(large).is_null());
+            // Growing past the budget fails too, leaving the block in place.
+            assert!(SnMalloc.realloc(ptr, small, 2 << 20).is_null());
+            let breaches = budget_breaches();
+
+            set_budget_policy(BudgetPolicy::Handler(deny_large));
+            assert!(SnMalloc.alloc(large).is_null());
+            let medium = Layout::from_size_align(1 << 20, 8).unwrap();
+            let served = SnMalloc.alloc(medium);
+            assert!(!served.is_null());
+            SnMalloc.dealloc(served, medium);
+            set_budget_policy(BudgetPolicy::Fail);
+            assert!(budget_breaches() >= breaches + 2);
+
+            SnMalloc.dealloc(ptr, small);
+            set_thread_budget(usize::MAX);
+            SnMalloc.dealloc(SnMalloc.alloc(large), large);
+        })
+        .join()
+        .unwrap();
+    }
+}
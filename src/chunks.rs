@@ -0,0 +1,54 @@
+//! Chunk-level allocation for sub-allocator authors.
+//!
+//! A chunk is a naturally aligned block of a power-of-two size, above the largest small size class
+//! (64KiB by default, so at least 128KiB), that snmalloc registers in its pagemap as a large
+//! object of its own. Custom pools can carve their own objects
+//! out of chunks while sharing snmalloc's address space, and keep using
+//! [`SnMalloc`](crate::SnMalloc) for everything else.
+use core::ptr::NonNull;
+
+/// Allocates a chunk of at least `size` bytes, aligned to its own size.
+///
+/// The returned slice covers the whole chunk, whose length is `size` rounded up to a power of
+/// two larger than the largest small size class. Returns `None` on failure, and for sizes above
+/// the largest power of two.
+#[inline(always)]
+pub fn alloc(size: usize) -> Option<NonNull<[u8]>> {
+    let mut chunk_size = 0;
+    let ptr = NonNull::new(unsafe { ffi::sn_rust_alloc_chunk(size, &mut chunk_size) }.cast())?;
+    Some(NonNull::slice_from_raw_parts(ptr, chunk_size))
+}
+
+/// Returns a chunk to snmalloc.
+///
+/// # Safety
+/// `chunk` must have been returned by [`alloc`] and must not be used afterwards.
+#[inline(always)]
+pub unsafe fn dealloc(chunk: NonNull<[u8]>) {
+    ffi::sn_rust_dealloc_chunk(chunk.as_ptr().cast(), chunk.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_allocates_aligned_chunks() {
+        for size in [0, 1, 16 << 10, (16 << 10) + 1, 1 << 20] {
+            let chunk = alloc(size).unwrap();
+            assert!(chunk.len() >= size && chunk.len() > ffi::size_classes::MAX_SMALL_SIZE);
+            assert!(chunk.len().is_power_of_two());
+            assert_eq!(chunk.cast::<u8>().as_ptr() as usize % chunk.len(), 0);
+            unsafe {
+                chunk.cast::<u8>().as_ptr().write_bytes(0x5A, chunk.len());
+                dealloc(chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn it_rejects_sizes_beyond_the_largest_power_of_two() {
+        assert!(alloc((usize::MAX >> 1) + 2).is_none());
+        assert!(alloc(usize::MAX).is_none());
+    }
+}
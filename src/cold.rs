@@ -0,0 +1,121 @@
+//! Allocation of long-lived blocks, see [`SnMalloc::alloc_cold`](crate::SnMalloc::alloc_cold).
+//!
+//! Caches, configuration blobs and other data kept for the lifetime of the program are allocated
+//! by a process-wide allocator of the shim instead of the thread-local one. Its slabs only hold
+//! cold blocks, so that a few of them surviving among short-lived ones no longer keep whole slabs
+//! and chunks of the thread-local allocators from being reused or returned to the OS.
+//!
+//! The cold allocator is shared by all threads behind a lock: it suits blocks allocated rarely,
+//! not hot paths. Cold blocks may be freed from any thread.
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+
+use crate::{layout, limit, stats, sync, trace, tuning};
+
+/// A global allocator allocating every block from the cold allocator of the shim, for collections
+/// of long-lived data (e.g. `Vec::new_in(ColdAllocator)` with the `allocator-api2` feature).
+///
+/// Blocks must be freed by `ColdAllocator` or [`SnMalloc::dealloc_cold`](crate::SnMalloc::dealloc_cold).
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ColdAllocator;
+
+#[inline(always)]
+#[track_caller]
+pub(crate) fn alloc(layout: Layout, zero: bool) -> *mut u8 {
+    layout::check(layout.size(), layout.align());
+    stats::on_request(layout.size(), layout.align());
+    trace::on_request(layout.size(), layout.align());
+    match layout.size() {
+        0 => layout.align() as *mut u8,
+        size if limit::refuses(tuning::knobs(), size) => ptr::null_mut(),
+        size => stats::on_alloc(sync::exclusive(|| unsafe { ffi::sn_rust_alloc_hint_cold(layout.align(), size, zero) }).cast(), size),
+    }
+}
+
+/// Frees a cold block, bypassing the paths of the global allocator that depend on the size
+/// (guard pages, redzones, the large-object cache): cold blocks never went through them.
+#[inline(always)]
+#[track_caller]
+pub(crate) unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+    layout::check(layout.size(), layout.align());
+    if layout.size() != 0 {
+        stats::on_dealloc(layout.size());
+        sync::exclusive(|| ffi::sn_rust_dealloc(ptr.cast(), layout.align(), layout.size()));
+    }
+}
+
+unsafe impl GlobalAlloc for ColdAllocator {
+    #[inline(always)]
+    #[track_caller]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc(layout, false)
+    }
+
+    #[inline(always)]
+    #[track_caller]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        alloc(layout, true)
+    }
+
+    #[inline(always)]
+    #[track_caller]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        dealloc(ptr, layout)
+    }
+}
+
+impl crate::SnMalloc {
+    /// Allocates a block expected to be long-lived or rarely touched from the cold allocator, see
+    /// the [`cold`](crate::cold) module, returning a non-null pointer on success.
+    ///
+    /// ```rust
+    /// use core::alloc::Layout;
+    /// let alloc = snmalloc_rs::SnMalloc::new();
+    /// let layout = Layout::from_size_align(4096, 64).unwrap();
+    /// let ptr = alloc.alloc_cold(layout).unwrap();
+    /// unsafe { alloc.dealloc_cold(ptr, layout) };
+    /// ```
+    #[inline(always)]
+    #[track_caller]
+    pub fn alloc_cold(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(alloc(layout, false))
+    }
+
+    /// Frees a block allocated by [`alloc_cold`](Self::alloc_cold) or [`ColdAllocator`], from any
+    /// thread.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated from the cold allocator with `layout`, and must not be used
+    /// afterwards.
+    #[inline(always)]
+    #[track_caller]
+    pub unsafe fn dealloc_cold(&self, ptr: NonNull<u8>, layout: Layout) {
+        dealloc(ptr.as_ptr(), layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SnMalloc;
+
+    #[test]
+    fn it_allocates_cold_blocks() {
+        let layout = Layout::from_size_align(1000, 128).unwrap();
+        let ptr = SnMalloc.alloc_cold(layout).unwrap();
+        assert_eq!(ptr.as_ptr() as usize % 128, 0);
+        unsafe {
+            ptr.as_ptr().write_bytes(3, 1000);
+            let zeroed = ColdAllocator.alloc_zeroed(layout);
+            assert!(core::slice::from_raw_parts(zeroed, 1000).iter().all(|b| *b == 0));
+            ColdAllocator.dealloc(zeroed, layout);
+        }
+        // Cold blocks are freed from any thread.
+        let ptr = ptr.as_ptr() as usize;
+        std::thread::spawn(move || unsafe { SnMalloc.dealloc_cold(NonNull::new_unchecked(ptr as *mut u8), layout) })
+            .join()
+            .unwrap();
+    }
+}
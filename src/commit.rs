@@ -0,0 +1,107 @@
+//! Notifications of the memory snmalloc commits and decommits, for container-aware accounting.
+//!
+//! snmalloc commits memory from the OS as the heap grows, and decommits it when memory is given
+//! back, e.g. by [`flush_thread_cache`](crate::flush_thread_cache). Applications running under a
+//! cgroup memory limit can subscribe to these changes, to keep their own soft-limit accounting
+//! or to trim caches before the kernel kills them.
+//!
+//! snmalloc has no hook on its platform layer: once a subscriber is registered, a background
+//! thread reads the memory committed by snmalloc (the `current` of
+//! [`memory_usage`](crate::stats::memory_usage)) every [`poll_interval`] and calls the
+//! subscribers with the change. Commits and decommits between two reads offset each other;
+//! [`poll`] reads at once, e.g. right after a burst of allocations. The first change reported
+//! is the commit of all the memory snmalloc held until then, so that the sum of the changes
+//! always matches the memory committed.
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use std::{
+    sync::{Mutex, Once},
+    vec::Vec,
+};
+
+/// A change of the memory committed by snmalloc.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// `bytes` were committed, bringing the total to `committed` bytes.
+    Commit { bytes: usize, committed: usize },
+    /// `bytes` were decommitted, bringing the total to `committed` bytes.
+    Decommit { bytes: usize, committed: usize },
+}
+
+/// The interval used unless [`set_poll_interval`] is called.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+static INTERVAL_MS: AtomicUsize = AtomicUsize::new(DEFAULT_POLL_INTERVAL.as_millis() as usize);
+/// Memory committed at the previous read.
+static COMMITTED: AtomicUsize = AtomicUsize::new(0);
+static SUBSCRIBERS: Mutex<Vec<fn(Change)>> = Mutex::new(Vec::new());
+static WATCHER: Once = Once::new();
+
+/// Calls `callback` with every change of the memory committed by snmalloc, starting the watcher
+/// thread if needed.
+pub fn subscribe(callback: fn(Change)) {
+    SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner()).push(callback);
+    WATCHER.call_once(|| {
+        let _ = std::thread::Builder::new().name("snmalloc-commit".into()).spawn(|| loop {
+            std::thread::sleep(poll_interval());
+            poll();
+        });
+    });
+}
+
+/// Sets how often the watcher thread reads the memory committed, at least every millisecond.
+pub fn set_poll_interval(interval: Duration) {
+    INTERVAL_MS.store(interval.as_millis().clamp(1, usize::MAX as u128) as usize, Ordering::Relaxed);
+}
+
+/// Returns the interval set by [`set_poll_interval`].
+pub fn poll_interval() -> Duration {
+    Duration::from_millis(INTERVAL_MS.load(Ordering::Relaxed) as u64)
+}
+
+/// Reads the memory committed now, and calls the subscribers from the current thread if it
+/// changed since the previous read.
+pub fn poll() {
+    let committed = crate::stats::memory_usage().current;
+    let previous = COMMITTED.swap(committed, Ordering::Relaxed);
+    let change = match committed.cmp(&previous) {
+        core::cmp::Ordering::Greater => Change::Commit { bytes: committed - previous, committed },
+        core::cmp::Ordering::Less => Change::Decommit { bytes: previous - committed, committed },
+        core::cmp::Ordering::Equal => return,
+    };
+    let subscribers = SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    for subscriber in subscribers {
+        subscriber(change);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        sync::atomic::AtomicIsize,
+    };
+
+    static NET: AtomicIsize = AtomicIsize::new(0);
+
+    fn record(change: Change) {
+        match change {
+            Change::Commit { bytes, .. } => NET.fetch_add(bytes as isize, Ordering::Relaxed),
+            Change::Decommit { bytes, .. } => NET.fetch_sub(bytes as isize, Ordering::Relaxed),
+        };
+    }
+
+    #[test]
+    fn it_reports_commits() {
+        subscribe(record);
+        let layout = Layout::from_size_align(64 << 20, 4096).unwrap();
+        let ptr = unsafe { crate::SnMalloc.alloc(layout) };
+        unsafe { ptr.write_bytes(1, layout.size()) };
+        poll();
+        assert!(NET.load(Ordering::Relaxed) >= 64 << 20);
+        unsafe { crate::SnMalloc.dealloc(ptr, layout) };
+    }
+}
@@ -0,0 +1,175 @@
+//! Options of allocator handles, see [`SnAllocator::with_config`](crate::SnAllocator::with_config).
+//!
+//! The per-handle options (label, pre-allocated pool, memory locking, allocation limit, shim)
+//! are gathered in an [`AllocConfig`], built once and applied when the handle is created, so that
+//! the handle never exists half-configured, e.g. unnamed or unlocked.
+//!
+//! Only the label and the allocation limit reach the shim, through
+//! `sn_rust_allocator_new_with_config`, so that C code holding the handle sees them too. The
+//! pool and the locking are applied by this crate around the handle, and the hardened shim is
+//! picked by which constructor of the shim is called. snmalloc has no per-allocator NUMA node nor
+//! guard pages, so neither is an option; see the `guard-large-allocs` and `redzones` features
+//! for guard pages of the global allocator.
+
+/// The options of a new allocator handle, built with [`AllocConfig::builder`]. The default is the
+/// configuration of [`SnAllocator::new`](crate::SnAllocator::new).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AllocConfig {
+    name: [u8; ffi::SN_RUST_ALLOCATOR_NAME_MAX],
+    name_len: usize,
+    pub(crate) preallocated: usize,
+    pub(crate) max_alloc_size: usize,
+    pub(crate) locked: bool,
+    #[cfg(feature = "checked-handles")]
+    pub(crate) checked: bool,
+}
+
+impl AllocConfig {
+    /// Starts from the default configuration.
+    pub const fn builder() -> AllocConfigBuilder {
+        AllocConfigBuilder(Self {
+            name: [0; ffi::SN_RUST_ALLOCATOR_NAME_MAX],
+            name_len: 0,
+            preallocated: 0,
+            max_alloc_size: usize::MAX,
+            locked: false,
+            #[cfg(feature = "checked-handles")]
+            checked: false,
+        })
+    }
+
+    /// Returns the label of the handle, see [`AllocConfigBuilder::name`].
+    pub fn name(&self) -> Option<&str> {
+        match self.name_len {
+            0 => None,
+            len => core::str::from_utf8(&self.name[..len]).ok(),
+        }
+    }
+
+    /// Returns the size of the pre-allocated pool, `0` without one.
+    #[inline(always)]
+    pub fn preallocated(&self) -> usize {
+        self.preallocated
+    }
+
+    /// Returns the largest allocation of the handle.
+    #[inline(always)]
+    pub fn max_alloc_size(&self) -> usize {
+        self.max_alloc_size
+    }
+
+    /// Returns whether the allocations of the handle are locked in memory.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Returns whether the handle is served by the hardened shim.
+    #[inline(always)]
+    pub fn is_checked(&self) -> bool {
+        #[cfg(feature = "checked-handles")]
+        return self.checked;
+        #[cfg(not(feature = "checked-handles"))]
+        false
+    }
+
+    /// The options handled by the shim, borrowing the name of `self`.
+    pub(crate) fn as_ffi(&self) -> ffi::sn_rust_allocator_config_t {
+        ffi::sn_rust_allocator_config_t {
+            name: self.name.as_ptr().cast(),
+            name_len: self.name_len,
+            max_alloc_size: self.max_alloc_size,
+        }
+    }
+}
+
+impl Default for AllocConfig {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Builder of an [`AllocConfig`].
+///
+/// ```rust
+/// use snmalloc_rs::{AllocConfig, SnAllocator};
+/// let config = AllocConfig::builder().name("parser").max_alloc_size(1 << 20).build();
+/// let alloc = SnAllocator::with_config(&config).unwrap();
+/// assert_eq!(alloc.name(), Some("parser"));
+/// assert!(alloc.allocate(core::alloc::Layout::from_size_align(2 << 20, 8).unwrap()).is_none());
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct AllocConfigBuilder(AllocConfig);
+
+impl AllocConfigBuilder {
+    /// Labels the handle, see [`SnAllocator::set_name`](crate::SnAllocator::set_name). Names
+    /// longer than 31 bytes are truncated to a character boundary.
+    pub const fn name(mut self, name: &str) -> Self {
+        let name = truncate_name(name).as_bytes();
+        let mut i = 0;
+        // The tail is cleared, so that configurations with the same name compare equal.
+        while i < self.0.name.len() {
+            self.0.name[i] = if i < name.len() { name[i] } else { 0 };
+            i += 1;
+        }
+        self.0.name_len = name.len();
+        self
+    }
+
+    /// Serves every allocation from `bytes` committed up front, see
+    /// [`SnAllocator::with_preallocated`](crate::SnAllocator::with_preallocated). `0` disables
+    /// the pool.
+    pub const fn preallocate(mut self, bytes: usize) -> Self {
+        self.0.preallocated = bytes;
+        self
+    }
+
+    /// Fails the allocations of the handle larger than `bytes`, in addition to the process-wide
+    /// [`max_alloc_size`](crate::max_alloc_size). C code holding the handle is bound by it too.
+    pub const fn max_alloc_size(mut self, bytes: usize) -> Self {
+        self.0.max_alloc_size = bytes;
+        self
+    }
+
+    /// Locks the allocations of the handle in memory, see
+    /// [`SnAllocator::new_locked`](crate::SnAllocator::new_locked). With a pre-allocated pool,
+    /// the whole pool is locked when the handle is created.
+    pub const fn locked(mut self, locked: bool) -> Self {
+        self.0.locked = locked;
+        self
+    }
+
+    /// Serves the handle by the hardened shim, see
+    /// [`SnAllocator::new_checked`](crate::SnAllocator::new_checked).
+    #[cfg(feature = "checked-handles")]
+    pub const fn checked(mut self, checked: bool) -> Self {
+        self.0.checked = checked;
+        self
+    }
+
+    /// Returns the configuration.
+    pub const fn build(self) -> AllocConfig {
+        self.0
+    }
+}
+
+/// Truncates `name` to the longest label kept by the shim, on a character boundary.
+pub(crate) const fn truncate_name(name: &str) -> &str {
+    let mut len = if name.len() < ffi::SN_RUST_ALLOCATOR_NAME_MAX { name.len() } else { ffi::SN_RUST_ALLOCATOR_NAME_MAX };
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    name.split_at(len).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_compares_renamed_configs() {
+        let renamed = AllocConfig::builder().name("a-much-longer-name").name("short").build();
+        assert_eq!(renamed, AllocConfig::builder().name("short").build());
+        assert_eq!(renamed.name(), Some("short"));
+    }
+}
@@ -0,0 +1,193 @@
+//! Property tests of the allocation contract over random sequences of layouts.
+//!
+//! Sizes range from zero to beyond the small size classes and alignments from one byte to beyond
+//! a chunk. Every path is checked against what the documentation of `GlobalAlloc`, `Allocator`
+//! and [`SnAllocator`] promises: blocks are aligned and writable over their whole size and do not
+//! overlap, zeroed blocks read as zero, resizing keeps the common prefix, zero-sized layouts get
+//! an aligned dangling pointer from the handles, and a block may be freed from any thread.
+use core::alloc::{GlobalAlloc, Layout};
+#[cfg(feature = "handle-api")]
+use core::ptr::NonNull;
+use std::vec::Vec;
+
+use proptest::prelude::*;
+
+#[cfg(feature = "handle-api")]
+use crate::SnAllocator;
+use crate::SnMalloc;
+
+/// The size classes of interest: empty, small, medium up to the largest small class, and large.
+fn size() -> impl Strategy<Value = usize> {
+    prop_oneof![Just(0usize), 1..=256usize, 257..=(64usize << 10), (64usize << 10) + 1..=(1usize << 20)]
+}
+
+/// Alignments from 1 byte to 2MiB, beyond the chunks snmalloc carves its slabs from.
+fn layout() -> impl Strategy<Value = Layout> {
+    (size(), 0..=21u32).prop_map(|(size, bits)| Layout::from_size_align(size, 1 << bits).unwrap())
+}
+
+/// `GlobalAlloc` leaves zero-sized layouts undefined.
+fn sized_layout() -> impl Strategy<Value = Layout> {
+    layout().prop_map(|layout| Layout::from_size_align(layout.size().max(1), layout.align()).unwrap())
+}
+
+/// Whether `size` bytes at `ptr` all read as `byte`.
+unsafe fn holds(ptr: *const u8, size: usize, byte: u8) -> bool {
+    core::slice::from_raw_parts(ptr, size).iter().all(|b| *b == byte)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn global_blocks_are_aligned_and_disjoint(layouts in prop::collection::vec((sized_layout(), any::<bool>()), 1..16)) {
+        let mut blocks = Vec::new();
+        for (i, (layout, zeroed)) in layouts.iter().enumerate() {
+            let ptr = unsafe {
+                match zeroed {
+                    true => SnMalloc.alloc_zeroed(*layout),
+                    false => SnMalloc.alloc(*layout),
+                }
+            };
+            prop_assert!(!ptr.is_null());
+            prop_assert_eq!(ptr as usize % layout.align(), 0);
+            prop_assert!(!zeroed || unsafe { holds(ptr, layout.size(), 0) }, "{:?} is not zeroed", layout);
+            prop_assert!(SnMalloc.usable_size(ptr).unwrap_or(usize::MAX) >= layout.size());
+            unsafe { ptr.write_bytes(i as u8, layout.size()) };
+            blocks.push((ptr, *layout));
+        }
+        for (i, (ptr, layout)) in blocks.into_iter().enumerate() {
+            prop_assert!(unsafe { holds(ptr, layout.size(), i as u8) }, "block {} was overwritten", i);
+            unsafe { SnMalloc.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test]
+    fn global_realloc_keeps_the_prefix(start in sized_layout(), sizes in prop::collection::vec(size(), 1..8)) {
+        let (mut ptr, mut layout) = (unsafe { SnMalloc.alloc(start) }, start);
+        prop_assert!(!ptr.is_null());
+        unsafe { ptr.write_bytes(0, layout.size()) };
+        for (i, size) in sizes.into_iter().enumerate() {
+            let new_size = size.max(1);
+            let new_ptr = unsafe { SnMalloc.realloc(ptr, layout, new_size) };
+            prop_assert!(!new_ptr.is_null());
+            prop_assert_eq!(new_ptr as usize % layout.align(), 0);
+            prop_assert!(
+                unsafe { holds(new_ptr, layout.size().min(new_size), i as u8) },
+                "lost the prefix from {:?} to {}", layout, new_size
+            );
+            ptr = new_ptr;
+            layout = Layout::from_size_align(new_size, layout.align()).unwrap();
+            unsafe { ptr.write_bytes(i as u8 + 1, new_size) };
+        }
+        unsafe { SnMalloc.dealloc(ptr, layout) };
+    }
+
+    #[cfg(feature = "handle-api")]
+    #[test]
+    fn handles_follow_grow_then_shrink_chains(start in layout(), sizes in prop::collection::vec(size(), 1..8)) {
+        let alloc = SnAllocator::new().expect("failed to create allocator handle");
+        let (mut ptr, mut layout) = (alloc.allocate_zeroed(start).unwrap(), start);
+        prop_assert_eq!(ptr.as_ptr() as usize % layout.align(), 0);
+        prop_assert!(unsafe { holds(ptr.as_ptr(), layout.size(), 0) }, "{:?} is not zeroed", layout);
+        // Grow through the chain, then shrink back through it.
+        let mut chain = sizes;
+        chain.sort_unstable();
+        let back: Vec<usize> = chain.iter().rev().copied().collect();
+        chain.extend(back);
+        for (i, new_size) in chain.into_iter().enumerate() {
+            let new_ptr = unsafe { alloc.reallocate(ptr, layout, new_size) }.unwrap();
+            prop_assert_eq!(new_ptr.as_ptr() as usize % layout.align(), 0);
+            prop_assert!(
+                unsafe { holds(new_ptr.as_ptr(), layout.size().min(new_size), i as u8) },
+                "lost the prefix from {:?} to {}", layout, new_size
+            );
+            ptr = new_ptr;
+            layout = Layout::from_size_align(new_size, layout.align()).unwrap();
+            unsafe { ptr.as_ptr().write_bytes(i as u8 + 1, new_size) };
+        }
+        unsafe { alloc.deallocate(ptr, layout) };
+    }
+
+    #[cfg(feature = "handle-api")]
+    #[test]
+    fn blocks_are_freed_from_other_threads(layouts in prop::collection::vec(sized_layout(), 1..16)) {
+        let blocks: Vec<(usize, Layout)> = layouts
+            .into_iter()
+            .map(|layout| {
+                let ptr = unsafe { SnMalloc.alloc(layout) };
+                unsafe { ptr.write_bytes(0x5A, layout.size()) };
+                (ptr as usize, layout)
+            })
+            .collect();
+        let freed = std::thread::spawn(move || {
+            let alloc = SnAllocator::new().filter(SnAllocator::can_free_foreign);
+            blocks.into_iter().all(|(ptr, layout)| unsafe {
+                let intact = holds(ptr as *const u8, layout.size(), 0x5A);
+                match &alloc {
+                    Some(alloc) => alloc.deallocate(NonNull::new_unchecked(ptr as *mut u8), layout),
+                    None => SnMalloc.dealloc(ptr as *mut u8, layout),
+                }
+                intact
+            })
+        });
+        prop_assert!(freed.join().unwrap());
+    }
+
+    #[cfg(feature = "handle-api")]
+    #[test]
+    fn handles_free_on_other_threads(layouts in prop::collection::vec(layout(), 1..16)) {
+        let alloc = SnAllocator::new().expect("failed to create allocator handle");
+        let blocks: Vec<(usize, Layout)> = layouts
+            .into_iter()
+            .map(|layout| (alloc.allocate(layout).unwrap().as_ptr() as usize, layout))
+            .collect();
+        for (ptr, layout) in &blocks {
+            prop_assert_eq!(ptr % layout.align(), 0);
+            unsafe { (*ptr as *mut u8).write_bytes(0xA5, layout.size()) };
+        }
+        // The handle moves to the other thread with its blocks.
+        let freed = std::thread::spawn(move || {
+            blocks.into_iter().all(|(ptr, layout)| unsafe {
+                let intact = holds(ptr as *const u8, layout.size(), 0xA5);
+                alloc.deallocate(NonNull::new_unchecked(ptr as *mut u8), layout);
+                intact
+            })
+        });
+        prop_assert!(freed.join().unwrap());
+    }
+}
+
+#[cfg(feature = "allocator-api2")]
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn allocator_resizes_across_alignments(start in layout(), steps in prop::collection::vec(layout(), 1..8)) {
+        use allocator_api2::alloc::Allocator;
+
+        let block = SnMalloc.allocate_zeroed(start).unwrap();
+        prop_assert!(block.len() >= start.size());
+        prop_assert!(unsafe { holds(block.cast::<u8>().as_ptr(), start.size(), 0) }, "{:?} is not zeroed", start);
+        let (mut ptr, mut layout) = (block.cast::<u8>(), start);
+        for (i, new) in steps.into_iter().enumerate() {
+            let block = unsafe {
+                match new.size() >= layout.size() {
+                    true => SnMalloc.grow(ptr, layout, new),
+                    false => SnMalloc.shrink(ptr, layout, new),
+                }
+            }
+            .unwrap();
+            prop_assert!(block.len() >= new.size());
+            prop_assert_eq!(block.cast::<u8>().as_ptr() as usize % new.align(), 0);
+            prop_assert!(
+                unsafe { holds(block.cast::<u8>().as_ptr(), layout.size().min(new.size()), i as u8) },
+                "lost the prefix from {:?} to {:?}", layout, new
+            );
+            ptr = block.cast();
+            layout = new;
+            unsafe { ptr.as_ptr().write_bytes(i as u8 + 1, layout.size()) };
+        }
+        unsafe { SnMalloc.deallocate(ptr, layout) };
+    }
+}
@@ -0,0 +1,147 @@
+//! String-keyed access to the allocator controls, in the spirit of jemalloc's `mallctl`.
+//!
+//! Every value is a `usize`. Tooling written against `mallctl` can map its names onto the dotted
+//! keys listed in [`KEYS`], each of which forwards to the typed function named in its
+//! description.
+use core::fmt;
+
+/// Every key known to [`get`] and [`set`]:
+///
+/// | key | access | meaning |
+/// |-----|--------|---------|
+/// | `stats.allocated` | read | [`stats::live_bytes`](crate::stats::live_bytes), with the `stats` feature |
+/// | `stats.committed` | read | memory committed by snmalloc, see [`stats::address_space`](crate::stats::address_space), with the `stats-api` feature |
+/// | `stats.reserved` | read | address space reserved by snmalloc, with the `stats-api` feature |
+/// | `thread.cache.flush` | write | [`flush_thread_cache`](crate::flush_thread_cache), the value is ignored |
+/// | `decommit.policy` | read/write | [`set_cache_decay`](crate::set_cache_decay) in milliseconds, `usize::MAX` when disabled, with the `std` feature |
+/// | `opt.max_alloc_size` | read/write | [`set_max_alloc_size`](crate::set_max_alloc_size) |
+/// | `opt.remote_batch_size` | read/write | [`set_remote_batch_size`](crate::set_remote_batch_size) |
+/// | `opt.cache_friendly_offset` | read | [`cache_friendly_offset`](crate::cache_friendly_offset) |
+/// | `opt.large_cache` | read/write | [`set_large_cache`](crate::set_large_cache) |
+pub const KEYS: &[&str] = &[
+    "stats.allocated",
+    "stats.committed",
+    "stats.reserved",
+    "thread.cache.flush",
+    "decommit.policy",
+    "opt.max_alloc_size",
+    "opt.remote_batch_size",
+    "opt.cache_friendly_offset",
+    "opt.large_cache",
+];
+
+/// Error of [`get`] and [`set`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CtlError {
+    /// The key is not one of [`KEYS`].
+    UnknownKey,
+    /// The key cannot be written.
+    ReadOnly,
+    /// The key cannot be read.
+    WriteOnly,
+    /// The key needs a feature this build does not have.
+    Unavailable,
+}
+
+impl fmt::Display for CtlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CtlError::UnknownKey => "unknown key",
+            CtlError::ReadOnly => "read-only key",
+            CtlError::WriteOnly => "write-only key",
+            CtlError::Unavailable => "key not available in this build",
+        })
+    }
+}
+
+/// Reads the value behind `key`.
+///
+/// ```rust
+/// let committed = snmalloc_rs::ctl::get("stats.committed").unwrap();
+/// assert!(committed <= snmalloc_rs::ctl::get("stats.reserved").unwrap());
+/// ```
+pub fn get(key: &str) -> Result<usize, CtlError> {
+    match key {
+        #[cfg(feature = "stats")]
+        "stats.allocated" => Ok(crate::stats::live_bytes()),
+        #[cfg(not(feature = "stats"))]
+        "stats.allocated" => Err(CtlError::Unavailable),
+        #[cfg(feature = "stats-api")]
+        "stats.committed" => Ok(crate::stats::memory_usage().current),
+        #[cfg(feature = "stats-api")]
+        "stats.reserved" => Ok(crate::stats::memory_usage().peak),
+        #[cfg(not(feature = "stats-api"))]
+        "stats.committed" | "stats.reserved" => Err(CtlError::Unavailable),
+        "thread.cache.flush" => Err(CtlError::WriteOnly),
+        #[cfg(feature = "std")]
+        "decommit.policy" => Ok(crate::cache_decay().map_or(usize::MAX, |decay| {
+            decay.as_millis().min(usize::MAX as u128 - 1) as usize
+        })),
+        #[cfg(not(feature = "std"))]
+        "decommit.policy" => Err(CtlError::Unavailable),
+        "opt.max_alloc_size" => Ok(crate::max_alloc_size()),
+        "opt.remote_batch_size" => Ok(crate::remote_batch_size()),
+        "opt.cache_friendly_offset" => Ok(crate::cache_friendly_offset()),
+        "opt.large_cache" => Ok(crate::large_cache()),
+        _ => Err(CtlError::UnknownKey),
+    }
+}
+
+/// Writes `value` to the control behind `key`.
+pub fn set(key: &str, value: usize) -> Result<(), CtlError> {
+    match key {
+        "stats.allocated" | "stats.committed" | "stats.reserved" | "opt.cache_friendly_offset" => {
+            Err(CtlError::ReadOnly)
+        }
+        "thread.cache.flush" => {
+            crate::flush_thread_cache();
+            Ok(())
+        }
+        #[cfg(feature = "std")]
+        "decommit.policy" => {
+            let decay = (value != usize::MAX).then(|| core::time::Duration::from_millis(value as u64));
+            crate::set_cache_decay(decay);
+            Ok(())
+        }
+        #[cfg(not(feature = "std"))]
+        "decommit.policy" => Err(CtlError::Unavailable),
+        "opt.max_alloc_size" => {
+            crate::set_max_alloc_size(value);
+            Ok(())
+        }
+        "opt.remote_batch_size" => match crate::set_remote_batch_size(value) {
+            true => Ok(()),
+            false => Err(CtlError::Unavailable),
+        },
+        "opt.large_cache" => {
+            crate::set_large_cache(value);
+            Ok(())
+        }
+        _ => Err(CtlError::UnknownKey),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_resolves_every_key() {
+        for key in KEYS {
+            assert_ne!(get(key), Err(CtlError::UnknownKey), "{}", key);
+        }
+        assert_eq!(get("arenas.narenas"), Err(CtlError::UnknownKey));
+        assert_eq!(set("stats.reserved", 0), Err(CtlError::ReadOnly));
+        assert_eq!(get("thread.cache.flush"), Err(CtlError::WriteOnly));
+        assert_eq!(set("thread.cache.flush", 0), Ok(()));
+    }
+
+    #[test]
+    fn it_reads_through_to_the_controls() {
+        assert_eq!(get("opt.cache_friendly_offset"), Ok(crate::cache_friendly_offset()));
+        #[cfg(feature = "stats")]
+        assert!(get("stats.allocated").is_ok());
+        #[cfg(not(feature = "stats"))]
+        assert_eq!(get("stats.allocated"), Err(CtlError::Unavailable));
+    }
+}
@@ -0,0 +1,39 @@
+//! Interoperability with C++ code linked into the same binary (`cxx-new` feature).
+//!
+//! With this feature the static library also replaces the global C++ `operator new` and
+//! `operator delete`, including the sized and aligned variants, so that objects created by C++
+//! code (e.g. built through `cc` or `cxx`) live on snmalloc as well.
+
+/// Referencing the replacement keeps it, and the operators next to it, in the final link.
+#[used]
+static KEEP_OPERATOR_NEW: unsafe extern "C" fn() -> bool = ffi::sn_rust_operator_new_is_snmalloc;
+
+/// Returns whether the global C++ `operator new` is served by snmalloc.
+///
+/// Another replacement winning symbol resolution, e.g. from a library linked before this one or
+/// from a preloaded allocator when building a shared object, makes this return `false`.
+#[inline(always)]
+pub fn operator_new_is_snmalloc() -> bool {
+    unsafe { ffi::sn_rust_operator_new_is_snmalloc() }
+}
+
+/// Panics unless the global C++ `operator new` is served by snmalloc.
+///
+/// Meant to be called once at start-up, before any C++ object is created.
+#[track_caller]
+pub fn assert_operator_new_is_snmalloc() {
+    assert!(
+        operator_new_is_snmalloc(),
+        "the global C++ operator new is not provided by snmalloc; another replacement takes precedence"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_replaces_operator_new() {
+        assert_operator_new_is_snmalloc();
+    }
+}
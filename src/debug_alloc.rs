@@ -0,0 +1,284 @@
+//! A global allocator recording a truncated backtrace for every live allocation (`debug-backtrace` feature).
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    mem, ptr,
+};
+use std::{io, string::ToString};
+
+use crate::{sync::{self, SpinLock}, SnMalloc};
+
+/// Number of frames recorded per allocation.
+const FRAMES: usize = 8;
+
+/// Frames belonging to the tracker itself, skipped when capturing.
+const SKIPPED_FRAMES: usize = 3;
+
+const EMPTY: usize = 0;
+const TOMBSTONE: usize = usize::MAX;
+
+std::thread_local! {
+    /// Set while the current thread is inside the tracker, so that allocations made by the
+    /// unwinder, the symbolizer or the dump writer are served but not recorded.
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    ptr: usize,
+    size: usize,
+    frames: [usize; FRAMES],
+}
+
+/// Open-addressing hash table keyed by pointer, backed by raw shim allocations so that it
+/// never re-enters the global allocator.
+struct Table {
+    entries: *mut Entry,
+    capacity: usize,
+    len: usize,
+    tombstones: usize,
+}
+
+unsafe impl Send for Table {}
+
+impl Table {
+    const fn new() -> Self {
+        Self {
+            entries: ptr::null_mut(),
+            capacity: 0,
+            len: 0,
+            tombstones: 0,
+        }
+    }
+
+    #[inline(always)]
+    fn slot(&self, ptr: usize) -> usize {
+        (ptr >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize) & (self.capacity - 1)
+    }
+
+    fn entries(&self) -> &[Entry] {
+        match self.capacity {
+            0 => &[],
+            capacity => unsafe { core::slice::from_raw_parts(self.entries, capacity) },
+        }
+    }
+
+    unsafe fn grow(&mut self) -> bool {
+        let capacity = (self.capacity * 2).max(1024);
+        let entries: *mut Entry =
+            sync::exclusive(|| ffi::sn_rust_alloc_zeroed(mem::align_of::<Entry>(), capacity * mem::size_of::<Entry>())).cast();
+        if entries.is_null() {
+            return false;
+        }
+        let old = mem::replace(self, Self { entries, capacity, len: 0, tombstones: 0 });
+        for entry in old.entries().iter().filter(|e| e.ptr != EMPTY && e.ptr != TOMBSTONE) {
+            self.insert(*entry);
+        }
+        if !old.entries.is_null() {
+            sync::exclusive(|| ffi::sn_rust_dealloc(old.entries.cast(), mem::align_of::<Entry>(), old.capacity * mem::size_of::<Entry>()));
+        }
+        true
+    }
+
+    unsafe fn insert(&mut self, entry: Entry) {
+        if (self.len + self.tombstones + 1) * 4 > self.capacity * 3 && !self.grow() {
+            return;
+        }
+        let mut slot = self.slot(entry.ptr);
+        loop {
+            let current = &mut *self.entries.add(slot);
+            if current.ptr == EMPTY || current.ptr == TOMBSTONE {
+                if current.ptr == TOMBSTONE {
+                    self.tombstones -= 1;
+                }
+                *current = entry;
+                self.len += 1;
+                return;
+            }
+            slot = (slot + 1) & (self.capacity - 1);
+        }
+    }
+
+    unsafe fn remove(&mut self, ptr: usize) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut slot = self.slot(ptr);
+        loop {
+            let current = &mut *self.entries.add(slot);
+            match current.ptr {
+                EMPTY => return,
+                p if p == ptr => {
+                    current.ptr = TOMBSTONE;
+                    self.len -= 1;
+                    self.tombstones += 1;
+                    return;
+                }
+                _ => slot = (slot + 1) & (self.capacity - 1),
+            }
+        }
+    }
+}
+
+/// A wrapper around [`SnMalloc`] recording the size and a truncated backtrace of every live allocation.
+///
+/// The side table is allocated directly from the shim and protected by a spin lock, so this is
+/// only meant for debug builds.
+///
+/// ```rust,no_run
+/// #[global_allocator]
+/// static ALLOC: snmalloc_rs::SnMallocDebug = snmalloc_rs::SnMallocDebug::new();
+///
+/// ALLOC.dump_live_allocations(&mut std::io::stderr()).unwrap();
+/// ```
+pub struct SnMallocDebug {
+    table: SpinLock<Table>,
+}
+
+impl Default for SnMallocDebug {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SnMallocDebug {
+    pub const fn new() -> Self {
+        Self {
+            table: SpinLock::new(Table::new()),
+        }
+    }
+
+    /// Runs `f` unless the current thread is already inside the tracker.
+    #[inline(always)]
+    fn untracked<R>(f: impl FnOnce() -> R) -> Option<R> {
+        if BUSY.try_with(|busy| busy.replace(true)).unwrap_or(true) {
+            return None;
+        }
+        let result = f();
+        let _ = BUSY.try_with(|busy| busy.set(false));
+        Some(result)
+    }
+
+    fn track(&self, ptr: *mut u8, size: usize) {
+        if ptr.is_null() || size == 0 {
+            return;
+        }
+        Self::untracked(|| {
+            let mut frames = [0; FRAMES];
+            let mut index = 0;
+            backtrace::trace(|frame| {
+                if index >= SKIPPED_FRAMES {
+                    frames[index - SKIPPED_FRAMES] = frame.ip() as usize;
+                }
+                index += 1;
+                index < FRAMES + SKIPPED_FRAMES
+            });
+            unsafe {
+                self.table.lock().insert(Entry { ptr: ptr as usize, size, frames });
+            }
+        });
+    }
+
+    fn untrack(&self, ptr: *mut u8, size: usize) {
+        if size != 0 {
+            Self::untracked(|| unsafe { self.table.lock().remove(ptr as usize) });
+        }
+    }
+
+    /// Writes every live allocation with its size and symbolized backtrace to `writer`.
+    ///
+    /// Allocations made while dumping (e.g. by the writer) are not recorded. Other threads
+    /// block on their allocations until the dump is done.
+    pub fn dump_live_allocations(&self, writer: &mut impl io::Write) -> io::Result<()> {
+        Self::untracked(|| {
+            let table = self.table.lock();
+            for entry in table.entries().iter().filter(|e| e.ptr != EMPTY && e.ptr != TOMBSTONE) {
+                writeln!(writer, "live allocation {:#x} ({} bytes)", entry.ptr, entry.size)?;
+                for (i, ip) in entry.frames.iter().take_while(|ip| **ip != 0).enumerate() {
+                    let mut name = None;
+                    backtrace::resolve(*ip as *mut _, |symbol| {
+                        name = name.take().or_else(|| symbol.name().map(|n| n.to_string()));
+                    });
+                    writeln!(writer, "  #{} {:#x} {}", i, ip, name.as_deref().unwrap_or("<unknown>"))?;
+                }
+            }
+            Ok(())
+        })
+        .unwrap_or(Ok(()))
+    }
+
+    /// Returns the number of live allocations currently recorded.
+    pub fn live_allocations(&self) -> usize {
+        Self::untracked(|| self.table.lock().len).unwrap_or(0)
+    }
+}
+
+unsafe impl GlobalAlloc for SnMallocDebug {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = SnMalloc.alloc(layout);
+        self.track(ptr, layout.size());
+        ptr
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.untrack(ptr, layout.size());
+        SnMalloc.dealloc(ptr, layout);
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = SnMalloc.alloc_zeroed(layout);
+        self.track(ptr, layout.size());
+        ptr
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = SnMalloc.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            self.untrack(ptr, layout.size());
+            self.track(new_ptr, new_size);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn it_records_live_allocations() {
+        let alloc = SnMallocDebug::new();
+        unsafe {
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            let ptr = alloc.alloc(layout);
+            assert_eq!(alloc.live_allocations(), 1);
+
+            let mut out = Vec::new();
+            alloc.dump_live_allocations(&mut out).unwrap();
+            let out = std::string::String::from_utf8(out).unwrap();
+            assert!(out.contains(&std::format!("{:#x} (24 bytes)", ptr as usize)));
+
+            let ptr = alloc.realloc(ptr, layout, 4096);
+            assert_eq!(alloc.live_allocations(), 1);
+            alloc.dealloc(ptr, Layout::from_size_align(4096, 8).unwrap());
+            assert_eq!(alloc.live_allocations(), 0);
+        }
+    }
+
+    #[test]
+    fn it_grows_the_table() {
+        let alloc = SnMallocDebug::new();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let ptrs: Vec<_> = (0..5000).map(|_| unsafe { alloc.alloc(layout) }).collect();
+        assert_eq!(alloc.live_allocations(), 5000);
+        for ptr in ptrs {
+            unsafe { alloc.dealloc(ptr, layout) };
+        }
+        assert_eq!(alloc.live_allocations(), 0);
+    }
+}
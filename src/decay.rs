@@ -0,0 +1,171 @@
+//! Time-based decay of the thread caches.
+//!
+//! Memory freed by a thread stays in its local cache, ready to be reused without
+//! synchronisation. For bursty workloads this keeps memory resident long after the burst. Once a
+//! decay is set, a thread returns its cache to the global pool when it frees memory and the cache
+//! has not been flushed for longer than the decay.
+#[cfg(feature = "std")]
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+#[cfg(feature = "std")]
+use crate::tuning::{self, Knobs};
+
+/// Decay in milliseconds, `usize::MAX` meaning disabled.
+#[cfg(feature = "std")]
+static DECAY_MS: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Number of frees between two looks at the clock.
+#[cfg(feature = "std")]
+const TICKS: u32 = 256;
+
+/// Returns the memory cached by the current thread to the global pool, so that it can be
+/// reused by other threads or returned to the OS.
+///
+/// Without the `std` feature there is no clock to drive [`set_cache_decay`]: this can be called
+/// from a timer instead.
+#[inline(always)]
+pub fn flush_thread_cache() {
+    unsafe { ffi::sn_rust_flush_thread_cache() }
+}
+
+/// Initialises the allocator of the current thread, so that its first allocation does not pay for
+/// it. See the `std-thread-hook` feature to do so for every spawned thread.
+#[inline(always)]
+pub fn thread_init() {
+    unsafe { ffi::sn_rust_thread_init() }
+}
+
+/// Returns the allocator of the current thread to the global pool with its caches, as the thread
+/// exit does, for threads that are done allocating but keep running, or whose exit does not run
+/// the C++ thread-local destructors. Allocating afterwards takes an allocator again.
+#[inline(always)]
+pub fn thread_teardown() {
+    unsafe { ffi::sn_rust_thread_teardown() }
+}
+
+/// Returns the memory cached by the current thread and by the allocators left behind by exited
+/// threads to the global pool, before a leak check or the unloading of a plugin.
+///
+/// Once the caches are empty, no snmalloc structure holds on to freed objects, so LeakSanitizer
+/// or Valgrind only report memory that is actually leaked. Other live threads and dedicated
+/// [`SnAllocator`](crate::SnAllocator) handles keep their caches until they flush or are
+/// dropped. snmalloc never unmaps the address space it reserved, but the flushed memory is
+/// decommitted and the allocator stays usable afterwards.
+pub fn shutdown() {
+    unsafe { ffi::sn_rust_shutdown() }
+}
+
+/// Sets how long memory may sit in a thread cache before the thread returns it on its next
+/// frees through [`SnMalloc`](crate::SnMalloc), or disables the decay with `None` (the default).
+///
+/// The clock is only read every few hundred frees, so the decay is a lower bound.
+#[cfg(feature = "std")]
+pub fn set_cache_decay(decay: Option<Duration>) {
+    let ms = decay.map_or(usize::MAX, |decay| decay.as_millis().min(usize::MAX as u128 - 1) as usize);
+    tuning::set_knob(Knobs::CACHE_DECAY, || {
+        DECAY_MS.store(ms, Ordering::Relaxed);
+        ms != usize::MAX
+    });
+}
+
+/// Returns the decay set by [`set_cache_decay`].
+#[cfg(feature = "std")]
+pub fn cache_decay() -> Option<Duration> {
+    match DECAY_MS.load(Ordering::Relaxed) {
+        usize::MAX => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    }
+}
+
+/// Counts a free, flushing the thread cache once the decay has elapsed or under memory pressure.
+/// Frees are only counted while `knobs` say that a decay is set.
+#[inline(always)]
+pub(crate) fn tick(knobs: crate::tuning::Knobs) {
+    #[cfg(feature = "memory-pressure")]
+    crate::pressure::tick();
+    #[cfg(feature = "std")]
+    if knobs.has(Knobs::CACHE_DECAY) {
+        clock::tick();
+    }
+    #[cfg(not(feature = "std"))]
+    let _ = knobs;
+}
+
+#[cfg(feature = "std")]
+mod clock {
+    use core::{cell::Cell, time::Duration};
+    use std::time::Instant;
+
+    // Neither needs a destructor, so using them from the allocator never allocates.
+    std::thread_local! {
+        static FREES: Cell<u32> = const { Cell::new(0) };
+        static LAST_FLUSH: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    #[cold]
+    fn check() {
+        let now = Instant::now();
+        let decay = Duration::from_millis(super::DECAY_MS.load(super::Ordering::Relaxed) as u64);
+        let _ = LAST_FLUSH.try_with(|last| match last.get() {
+            Some(flushed) if now.duration_since(flushed) < decay => {}
+            Some(_) => {
+                super::flush_thread_cache();
+                last.set(Some(now));
+            }
+            // The first look at the clock starts the period.
+            None => last.set(Some(now)),
+        });
+    }
+
+    #[inline(always)]
+    pub(super) fn tick() {
+        let due = FREES.try_with(|frees| {
+            let count = frees.get().wrapping_add(1);
+            frees.set(count);
+            count % super::TICKS == 0
+        });
+        if due == Ok(true) {
+            check();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    use super::*;
+
+    #[test]
+    fn it_flushes_the_thread_cache() {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let ptr = crate::SnMalloc.alloc(layout);
+            crate::SnMalloc.dealloc(ptr, layout);
+        }
+        flush_thread_cache();
+        // The allocator keeps working after its cache was flushed.
+        unsafe {
+            let ptr = crate::SnMalloc.alloc(layout);
+            assert!(!ptr.is_null());
+            crate::SnMalloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn it_sets_the_decay() {
+        set_cache_decay(Some(Duration::from_millis(10)));
+        assert_eq!(cache_decay(), Some(Duration::from_millis(10)));
+        assert!(tuning::knobs().has(Knobs::CACHE_DECAY));
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        for _ in 0..4 * TICKS {
+            unsafe { crate::SnMalloc.dealloc(crate::SnMalloc.alloc(layout), layout) };
+        }
+        set_cache_decay(None);
+        assert_eq!(cache_decay(), None);
+        assert!(!tuning::knobs().has(Knobs::CACHE_DECAY));
+    }
+}
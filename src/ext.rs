@@ -0,0 +1,71 @@
+//! Extensions of standard collections that take snmalloc's size classes into account.
+//!
+//! These assume that the collections are allocated by snmalloc, i.e. that [`SnMalloc`] (or one
+//! of the wrappers around it) is the global allocator.
+//!
+//! [`SnMalloc`]: crate::SnMalloc
+use core::mem;
+use std::{string::String, vec::Vec};
+
+/// Shrinks collections only when it releases memory.
+///
+/// `shrink_to_fit` reallocates whenever the capacity exceeds the length, even when the smaller
+/// request would be served by a block of the same size class: the data is then copied for
+/// nothing. `shrink_to_fit_exact` only reallocates when the block actually gets smaller.
+pub trait ShrinkToFitExact {
+    /// Shrinks the capacity as `shrink_to_fit` does, if that moves the collection to a smaller
+    /// size class. Returns whether the collection was shrunk.
+    fn shrink_to_fit_exact(&mut self) -> bool;
+}
+
+/// Returns whether shrinking `capacity` items of `T` to `len` moves to a smaller block.
+fn shrinks<T>(len: usize, capacity: usize) -> bool {
+    let (size, align) = (mem::size_of::<T>(), mem::align_of::<T>());
+    match (len, capacity) {
+        _ if size == 0 || len == capacity => false,
+        // Shrinking to nothing frees the block.
+        (0, _) => true,
+        _ => ffi::size_classes::round_size(align, len * size) < ffi::size_classes::round_size(align, capacity * size),
+    }
+}
+
+impl<T> ShrinkToFitExact for Vec<T> {
+    fn shrink_to_fit_exact(&mut self) -> bool {
+        let shrink = shrinks::<T>(self.len(), self.capacity());
+        if shrink {
+            self.shrink_to_fit();
+        }
+        shrink
+    }
+}
+
+impl ShrinkToFitExact for String {
+    fn shrink_to_fit_exact(&mut self) -> bool {
+        let shrink = shrinks::<u8>(self.len(), self.capacity());
+        if shrink {
+            self.shrink_to_fit();
+        }
+        shrink
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_skips_shrinks_within_a_size_class() {
+        let rounded = unsafe { ffi::sn_rust_round_size(1, 100) };
+        assert!(rounded >= 100);
+        assert!(!shrinks::<u8>(100, rounded));
+        assert!(shrinks::<u8>(100, 4 * rounded));
+        assert!(shrinks::<u64>(0, 8));
+        assert!(!shrinks::<()>(0, usize::MAX));
+
+        let mut string = String::with_capacity(4096);
+        string.push_str("snmalloc");
+        assert!(string.shrink_to_fit_exact());
+        assert_eq!(string.capacity(), string.len());
+        assert!(!string.shrink_to_fit_exact());
+    }
+}
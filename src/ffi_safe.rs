@@ -0,0 +1,201 @@
+//! Misuse-resistant wrappers over the allocation functions of `snmalloc-sys`.
+//!
+//! Where [`ffi::helpers`] only null-checks the raw functions, these take a `Layout`, which is
+//! valid by construction, validate the sizes derived from it, report failures as `None` or
+//! [`ReallocError`], and are `#[must_use]`, so that code calling `snmalloc-sys` directly can move
+//! to them without adopting [`SnMalloc`](crate::SnMalloc). Like the raw functions, they bypass
+//! the hooks, limits and statistics of `SnMalloc`, but enter the critical section of the
+//! `critical-section` feature.
+//!
+//! Zero-sized layouts never reach snmalloc: they get a dangling pointer aligned to the layout,
+//! which the other functions accept.
+//!
+//! The other functions of `snmalloc-sys` are wrapped elsewhere: dedicated allocator handles by
+//! [`SnAllocator`](crate::SnAllocator), chunks by [`chunks`](crate::chunks), memory usage by
+//! [`stats`](crate::stats), and cache flushing by [`flush_thread_cache`](crate::flush_thread_cache).
+use core::{alloc::Layout, fmt, ptr::NonNull};
+
+use crate::sync;
+
+/// Error of [`realloc`] and [`realloc_zeroed`]; the original block is left untouched.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReallocError {
+    /// The new size rounded up to the alignment overflows `isize`.
+    InvalidLayout,
+    /// snmalloc could not serve the new size.
+    OutOfMemory,
+}
+
+impl fmt::Display for ReallocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReallocError::InvalidLayout => "invalid layout",
+            ReallocError::OutOfMemory => "out of memory",
+        })
+    }
+}
+
+#[inline(always)]
+fn dangling(layout: Layout) -> NonNull<u8> {
+    unsafe { NonNull::new_unchecked(layout.align() as *mut u8) }
+}
+
+/// Allocates a block for `layout`, returning `None` on failure.
+#[must_use]
+#[inline]
+pub fn alloc(layout: Layout) -> Option<NonNull<u8>> {
+    match layout.size() {
+        0 => Some(dangling(layout)),
+        size => NonNull::new(sync::exclusive(|| unsafe { ffi::sn_rust_alloc(layout.align(), size) }).cast()),
+    }
+}
+
+/// Behaves like [`alloc`], but also zeroes the block.
+#[must_use]
+#[inline]
+pub fn alloc_zeroed(layout: Layout) -> Option<NonNull<u8>> {
+    match layout.size() {
+        0 => Some(dangling(layout)),
+        size => NonNull::new(sync::exclusive(|| unsafe { ffi::sn_rust_alloc_zeroed(layout.align(), size) }).cast()),
+    }
+}
+
+/// Behaves like [`alloc`], but also sets every byte of the layout to `byte`.
+#[must_use]
+#[inline]
+pub fn alloc_filled(layout: Layout, byte: u8) -> Option<NonNull<u8>> {
+    match layout.size() {
+        0 => Some(dangling(layout)),
+        size => NonNull::new(sync::exclusive(|| unsafe { ffi::sn_rust_alloc_filled(layout.align(), size, byte) }).cast()),
+    }
+}
+
+/// Behaves like [`alloc`], but returns the whole block, which may be larger than the layout.
+///
+/// The block may be freed with any size between `layout.size()` and its length.
+#[must_use]
+#[inline]
+pub fn alloc_usable(layout: Layout) -> Option<NonNull<[u8]>> {
+    match layout.size() {
+        0 => Some(NonNull::slice_from_raw_parts(dangling(layout), 0)),
+        size => {
+            let mut usable = 0;
+            let ptr = sync::exclusive(|| unsafe { ffi::sn_rust_alloc_usable(layout.align(), size, &mut usable) });
+            let ptr = NonNull::new(ptr.cast())?;
+            Some(NonNull::slice_from_raw_parts(ptr, usable))
+        }
+    }
+}
+
+/// Frees a block returned by one of the functions of this module.
+///
+/// # Safety
+/// `ptr` must have been returned for `layout` (or the size reported by [`alloc_usable`]) and not
+/// freed since.
+#[inline]
+pub unsafe fn dealloc(ptr: NonNull<u8>, layout: Layout) {
+    if layout.size() != 0 {
+        sync::exclusive(|| ffi::sn_rust_dealloc(ptr.as_ptr().cast(), layout.align(), layout.size()));
+    }
+}
+
+/// Moves a block to `new_size` bytes, keeping its alignment and contents.
+///
+/// # Safety
+/// Same as [`dealloc`]. On success the old pointer must no longer be used.
+#[must_use = "on success the old pointer is invalid"]
+#[inline]
+pub unsafe fn realloc(ptr: NonNull<u8>, layout: Layout, new_size: usize) -> Result<NonNull<u8>, ReallocError> {
+    resize(ptr, layout, new_size, false)
+}
+
+/// Behaves like [`realloc`], but also zeroes the bytes past `layout.size()` when growing.
+///
+/// # Safety
+/// Same as [`realloc`].
+#[must_use = "on success the old pointer is invalid"]
+#[inline]
+pub unsafe fn realloc_zeroed(ptr: NonNull<u8>, layout: Layout, new_size: usize) -> Result<NonNull<u8>, ReallocError> {
+    resize(ptr, layout, new_size, true)
+}
+
+unsafe fn resize(ptr: NonNull<u8>, layout: Layout, new_size: usize, zero: bool) -> Result<NonNull<u8>, ReallocError> {
+    let new_layout = Layout::from_size_align(new_size, layout.align()).map_err(|_| ReallocError::InvalidLayout)?;
+    match (layout.size(), new_size) {
+        (_, 0) => {
+            dealloc(ptr, layout);
+            Ok(dangling(new_layout))
+        }
+        (0, _) if zero => alloc_zeroed(new_layout).ok_or(ReallocError::OutOfMemory),
+        (0, _) => alloc(new_layout).ok_or(ReallocError::OutOfMemory),
+        (size, _) => {
+            let ptr = ptr.as_ptr().cast();
+            let new_ptr = sync::exclusive(|| match zero {
+                true => ffi::sn_rust_realloc_zeroed(ptr, layout.align(), size, new_size),
+                false => ffi::sn_rust_realloc(ptr, layout.align(), size, new_size),
+            });
+            NonNull::new(new_ptr.cast()).ok_or(ReallocError::OutOfMemory)
+        }
+    }
+}
+
+/// Returns the usable size of a block returned by one of the functions of this module.
+///
+/// # Safety
+/// `ptr` must point to the start of a live block of non-zero size.
+#[must_use]
+#[inline]
+pub unsafe fn usable_size(ptr: NonNull<u8>) -> usize {
+    ffi::sn_rust_usable_size(ptr.as_ptr().cast())
+}
+
+/// Returns the number of bytes from `ptr` to the end of the snmalloc block containing it, e.g. to
+/// bound a copy into a buffer. The result is meaningless for memory not allocated by snmalloc.
+#[must_use]
+#[inline]
+pub fn remaining_bytes(ptr: *const u8) -> usize {
+    unsafe { ffi::sn_rust_remaining_bytes(ptr.cast()) }
+}
+
+/// Returns the size of the block an allocation of `layout` would get.
+#[must_use]
+#[inline]
+pub fn round_size(layout: Layout) -> usize {
+    unsafe { ffi::sn_rust_round_size(layout.align(), layout.size()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_wraps_the_allocation_family() {
+        let layout = Layout::from_size_align(100, 64).unwrap();
+        let block = alloc_usable(layout).unwrap();
+        assert_eq!(block.len(), round_size(layout));
+        unsafe {
+            assert!(usable_size(block.cast()) >= 100);
+            assert_eq!(remaining_bytes(block.cast::<u8>().as_ptr().add(10)), block.len() - 10);
+            dealloc(block.cast(), Layout::from_size_align(block.len(), 64).unwrap());
+
+            let ptr = alloc_filled(layout, 7).unwrap();
+            let ptr = realloc_zeroed(ptr, layout, 300).unwrap();
+            assert_eq!(ptr.as_ptr() as usize % 64, 0);
+            assert_eq!((*ptr.as_ptr().add(99), *ptr.as_ptr().add(299)), (7, 0));
+            let grown = Layout::from_size_align(300, 64).unwrap();
+            assert_eq!(realloc(ptr, grown, isize::MAX as usize), Err(ReallocError::InvalidLayout));
+            dealloc(ptr, grown);
+        }
+    }
+
+    #[test]
+    fn it_keeps_zero_sized_blocks_away_from_snmalloc() {
+        let layout = Layout::from_size_align(0, 32).unwrap();
+        let ptr = alloc_zeroed(layout).unwrap();
+        assert_eq!(ptr.as_ptr() as usize, 32);
+        unsafe {
+            let ptr = realloc(ptr, layout, 16).unwrap();
+            assert_eq!(realloc(ptr, Layout::from_size_align(16, 32).unwrap(), 0).unwrap().as_ptr() as usize, 32);
+        }
+    }
+}
@@ -0,0 +1,14 @@
+//! Common byte patterns for [`SnMalloc::alloc_filled`](crate::SnMalloc::alloc_filled) and
+//! [`SnAllocator::allocate_filled`](crate::SnAllocator::allocate_filled).
+//!
+//! Filling fresh allocations with a recognisable, non-zero pattern makes reads of uninitialised
+//! memory stand out in debuggers and crash dumps, and makes them misbehave deterministically.
+
+/// Pattern for memory that has been allocated but not yet initialised.
+pub const UNINITIALIZED: u8 = 0xAA;
+
+/// Pattern for memory that is about to be released.
+pub const FREED: u8 = 0xDD;
+
+/// Pattern for padding that must never be read or written.
+pub const NO_MANS_LAND: u8 = 0xFD;
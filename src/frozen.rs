@@ -0,0 +1,120 @@
+//! Read-only snapshots of an allocator handle, see [`SnAllocator::freeze`].
+//!
+//! A frozen handle can no longer allocate nor free, so the memory it owns never changes and may
+//! be shared by any number of readers, while new data is built in a fresh handle. The pool of a
+//! handle created with [`SnAllocator::with_preallocated`] is also made read-only, so that a stray
+//! write faults instead of corrupting the snapshot.
+use core::mem::ManuallyDrop;
+
+use crate::SnAllocator;
+
+/// An [`SnAllocator`] sealed by [`SnAllocator::freeze`], keeping its memory alive and unchanged.
+///
+/// Pointers obtained from the handle before it was frozen stay valid for reads until the frozen
+/// allocator is dropped or thawed.
+#[derive(Debug)]
+pub struct FrozenAllocator {
+    inner: ManuallyDrop<SnAllocator>,
+    read_only: bool,
+}
+
+// No method can allocate, free or otherwise touch the state of the handle.
+unsafe impl Sync for FrozenAllocator {}
+
+impl FrozenAllocator {
+    pub(crate) fn new(inner: SnAllocator) -> Self {
+        let read_only = match inner.pool_region() {
+            Some((base, size)) => unsafe { ffi::sn_rust_protect_read_only(base.as_ptr().cast(), size, true) },
+            None => false,
+        };
+        Self { inner: ManuallyDrop::new(inner), read_only }
+    }
+
+    /// Returns whether the memory of the handle was made read-only, which only the pool of a
+    /// handle created with [`SnAllocator::with_preallocated`] can be.
+    #[inline(always)]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns the label of the handle, see [`SnAllocator::set_name`].
+    #[inline(always)]
+    pub fn name(&self) -> Option<&str> {
+        self.inner.name()
+    }
+
+    /// Unseals the handle, making its memory writable again.
+    pub fn thaw(mut self) -> SnAllocator {
+        self.unprotect();
+        let inner = unsafe { ManuallyDrop::take(&mut self.inner) };
+        core::mem::forget(self);
+        inner
+    }
+
+    fn unprotect(&mut self) {
+        match self.inner.pool_region() {
+            Some((base, size)) if self.read_only => {
+                unsafe { ffi::sn_rust_protect_read_only(base.as_ptr().cast(), size, false) };
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for FrozenAllocator {
+    fn drop(&mut self) {
+        // snmalloc writes into memory as it is freed.
+        self.unprotect();
+        unsafe { ManuallyDrop::drop(&mut self.inner) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::Layout;
+
+    use super::*;
+
+    #[test]
+    fn it_shares_a_frozen_pool() {
+        let alloc = SnAllocator::with_preallocated(1 << 16).unwrap();
+        let layout = Layout::array::<u64>(512).unwrap();
+        let data = alloc.allocate(layout).unwrap().cast::<u64>();
+        for i in 0..512 {
+            unsafe { data.as_ptr().add(i).write(i as u64) };
+        }
+        let frozen = alloc.freeze();
+        assert!(frozen.is_read_only());
+        let data = data.as_ptr() as usize;
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let _frozen = &frozen;
+                    let sum: u64 = (0..512).map(|i| unsafe { (data as *const u64).add(i).read() }).sum();
+                    assert_eq!(sum, 511 * 512 / 2);
+                });
+            }
+        });
+        let alloc = frozen.thaw();
+        unsafe { (data as *mut u64).write(1) };
+        drop(alloc);
+    }
+
+    #[test]
+    fn it_freezes_a_regular_handle() {
+        let alloc = SnAllocator::new().unwrap();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = alloc.allocate_filled(layout, 7).unwrap();
+        let frozen = alloc.freeze();
+        assert!(!frozen.is_read_only());
+        let data = ptr.as_ptr() as usize;
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _frozen = &frozen;
+                assert_eq!(unsafe { *(data as *const u8).add(63) }, 7);
+            });
+        });
+        let alloc = frozen.thaw();
+        unsafe { alloc.deallocate(ptr, layout) };
+    }
+}
@@ -0,0 +1,139 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+
+use crate::{sync::SpinLock, AllocConfig, SnAllocator};
+
+/// A global allocator routing every allocation to one dedicated [`SnAllocator`] handle.
+///
+/// The handle is created lazily on the first allocation. Since a handle can only be used by one
+/// thread at a time, all operations are serialised through a spin lock, which yields to the
+/// holder under contention with the `std` feature; prefer [`SnMalloc`](crate::SnMalloc) unless
+/// you need all allocations to come from a single, separately configured allocator.
+///
+/// ```rust
+/// #[global_allocator]
+/// static ALLOC: snmalloc_rs::GlobalSnAllocator = snmalloc_rs::GlobalSnAllocator::new();
+/// ```
+pub struct GlobalSnAllocator {
+    handle: SpinLock<Option<SnAllocator>>,
+    config: AllocConfig,
+}
+
+impl Default for GlobalSnAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GlobalSnAllocator {
+    #[inline(always)]
+    pub const fn new() -> Self {
+        Self::with_config(AllocConfig::builder().build())
+    }
+
+    /// Creates the handle with `config` (see [`SnAllocator::with_config`]) on the first
+    /// allocation, e.g. to label it or to serve the whole program from a pre-allocated pool.
+    ///
+    /// ```rust
+    /// use snmalloc_rs::{AllocConfig, GlobalSnAllocator};
+    /// #[global_allocator]
+    /// static ALLOC: GlobalSnAllocator =
+    ///     GlobalSnAllocator::with_config(AllocConfig::builder().name("main").max_alloc_size(1 << 30).build());
+    /// ```
+    #[inline(always)]
+    pub const fn with_config(config: AllocConfig) -> Self {
+        Self {
+            handle: SpinLock::new(None),
+            config,
+        }
+    }
+
+    /// Returns the configuration of the handle.
+    #[inline(always)]
+    pub fn config(&self) -> &AllocConfig {
+        &self.config
+    }
+
+    /// Runs `f` with exclusive access to the handle, creating it if needed.
+    /// Returns `default` if the handle cannot be created.
+    #[inline(always)]
+    fn with_handle<R>(&self, default: R, f: impl FnOnce(&SnAllocator) -> R) -> R {
+        let mut handle = self.handle.lock();
+        if handle.is_none() {
+            *handle = SnAllocator::with_config(&self.config);
+        }
+        match &*handle {
+            Some(handle) => f(handle),
+            None => default,
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for GlobalSnAllocator {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_handle(ptr::null_mut(), |handle| {
+            handle.allocate(layout).map_or(ptr::null_mut(), NonNull::as_ptr)
+        })
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_handle((), |handle| handle.deallocate(NonNull::new_unchecked(ptr), layout))
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.with_handle(ptr::null_mut(), |handle| {
+            handle.allocate_zeroed(layout).map_or(ptr::null_mut(), NonNull::as_ptr)
+        })
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.with_handle(ptr::null_mut(), |handle| {
+            handle
+                .reallocate(NonNull::new_unchecked(ptr), layout, new_size)
+                .map_or(ptr::null_mut(), NonNull::as_ptr)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ALLOC: GlobalSnAllocator = GlobalSnAllocator::new();
+
+    #[test]
+    fn global_handle_allocation_lifecycle() {
+        unsafe {
+            let layout = Layout::from_size_align(8, 8).unwrap();
+
+            let ptr = ALLOC.alloc(layout);
+            assert!(!ptr.is_null());
+            ALLOC.dealloc(ptr, layout);
+
+            let ptr = ALLOC.alloc_zeroed(layout);
+            assert_eq!(*ptr, 0);
+            let ptr = ALLOC.realloc(ptr, layout, 16);
+            ALLOC.dealloc(ptr, Layout::from_size_align(16, 8).unwrap());
+        }
+    }
+
+    #[test]
+    fn global_handle_uses_its_config() {
+        static LIMITED: GlobalSnAllocator =
+            GlobalSnAllocator::with_config(AllocConfig::builder().name("limited").max_alloc_size(4096).build());
+        unsafe {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let ptr = LIMITED.alloc(layout);
+            assert!(!ptr.is_null());
+            LIMITED.dealloc(ptr, layout);
+            assert!(LIMITED.alloc(Layout::from_size_align(8192, 8).unwrap()).is_null());
+        }
+        assert_eq!(LIMITED.config().name(), Some("limited"));
+    }
+}
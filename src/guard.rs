@@ -0,0 +1,114 @@
+//! Guard pages around large allocations made through [`SnMalloc`](crate::SnMalloc).
+//!
+//! With the `guard-large-allocs` feature, every allocation of at least [`threshold`] bytes is
+//! placed right before an inaccessible page, so that a linear overflow of a large buffer faults
+//! immediately instead of silently corrupting its neighbour. Optionally, an inaccessible page is
+//! also placed before the allocation to catch underflows.
+//!
+//! Each guarded allocation costs at least two extra pages of address space and two
+//! `mprotect`/`VirtualProtect` calls on both allocation and de-allocation.
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// The smallest threshold accepted by [`set_threshold`].
+pub const MIN_THRESHOLD: usize = 4096;
+
+/// The threshold used unless [`set_threshold`] is called.
+pub const DEFAULT_THRESHOLD: usize = 1 << 20;
+
+static THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_THRESHOLD);
+static LEADING_GUARD: AtomicBool = AtomicBool::new(false);
+
+/// Returns the size from which allocations get guard pages.
+#[inline(always)]
+pub fn threshold() -> usize {
+    THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Sets the size from which allocations get guard pages. Values below [`MIN_THRESHOLD`] are raised to it.
+///
+/// Changing the threshold does not affect existing allocations: they are released correctly either way.
+#[inline(always)]
+pub fn set_threshold(bytes: usize) {
+    THRESHOLD.store(bytes.max(MIN_THRESHOLD), Ordering::Relaxed);
+}
+
+/// Enables or disables the additional guard page placed before each guarded allocation.
+#[inline(always)]
+pub fn set_leading_guard(enabled: bool) {
+    LEADING_GUARD.store(enabled, Ordering::Relaxed);
+}
+
+#[inline(always)]
+pub(crate) fn should_guard(size: usize) -> bool {
+    size >= threshold()
+}
+
+/// Whether an allocation of this size may have been guarded, whatever the threshold was at that time.
+#[inline(always)]
+pub(crate) fn may_be_guarded(size: usize) -> bool {
+    size >= MIN_THRESHOLD
+}
+
+#[inline(always)]
+pub(crate) unsafe fn alloc(layout: Layout, zero: bool) -> *mut u8 {
+    let leading = LEADING_GUARD.load(Ordering::Relaxed);
+    crate::sync::exclusive(|| ffi::sn_rust_guarded_alloc(layout.align(), layout.size(), zero, leading)).cast()
+}
+
+#[inline(always)]
+pub(crate) unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+    crate::sync::exclusive(|| ffi::sn_rust_guarded_dealloc(ptr.cast(), layout.align(), layout.size()))
+}
+
+/// Moves an allocation when either side of a reallocation may be guarded.
+pub(crate) unsafe fn realloc(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    let new_ptr = match should_guard(new_size) {
+        true => alloc(new_layout, false),
+        false => crate::sync::exclusive(|| ffi::sn_rust_alloc(layout.align(), new_size)).cast(),
+    };
+    if !new_ptr.is_null() {
+        core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+        dealloc(ptr, layout);
+    }
+    new_ptr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SnMalloc;
+    use core::alloc::GlobalAlloc;
+
+    #[test]
+    fn it_fills_guarded_allocations() {
+        let alloc = SnMalloc::new();
+        unsafe {
+            let layout = Layout::from_size_align(DEFAULT_THRESHOLD + 3, 64).unwrap();
+            let ptr = alloc.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 64, 0);
+            core::ptr::write_bytes(ptr, 0xAA, layout.size());
+            alloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn it_reallocates_across_the_threshold() {
+        let alloc = SnMalloc::new();
+        unsafe {
+            let layout = Layout::from_size_align(MIN_THRESHOLD, 8).unwrap();
+            let ptr = alloc.alloc(layout);
+            *ptr = 42;
+            let ptr = alloc.realloc(ptr, layout, DEFAULT_THRESHOLD * 2);
+            assert_eq!(*ptr, 42);
+            let layout = Layout::from_size_align(DEFAULT_THRESHOLD * 2, 8).unwrap();
+            let ptr = alloc.realloc(ptr, layout, 16);
+            assert_eq!(*ptr, 42);
+            alloc.dealloc(ptr, Layout::from_size_align(16, 8).unwrap());
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Ownership transfer of single allocations between Rust and C.
+//!
+//! Rust frees with the `Layout` an allocation was made with, while C code calls a size-less
+//! `free`. snmalloc recovers the size of a block from its own metadata, so an allocation of
+//! [`SnMalloc`](crate::SnMalloc) can usually cross the boundary as is, but not always: a
+//! zero-sized allocation is only a dangling pointer, a guarded allocation or one with redzones
+//! does not start its block, and a C allocation may not match the size class or the alignment of
+//! the `Layout` Rust will free it with. In these cases the helpers move the contents to a block
+//! that fits the other convention, which is why they return a pointer that may differ from their
+//! argument.
+//!
+//! On the C side, allocate with `sn_rust_malloc` (or any allocation function of the shim) and
+//! free with `sn_rust_free`, both declared in `sn_rust.h`:
+//!
+//! ```rust
+//! use core::{alloc::{GlobalAlloc, Layout}, ptr::NonNull};
+//! let layout = Layout::array::<u32>(16).unwrap();
+//! let ptr = NonNull::new(unsafe { snmalloc_rs::SnMalloc.alloc(layout) }).unwrap();
+//! let c_ptr = unsafe { snmalloc_rs::leak_to_c(ptr, layout) }.unwrap();
+//! // ... hand `c_ptr` to C code, which calls `sn_rust_free(c_ptr)` when done.
+//! unsafe { snmalloc_sys::sn_rust_free(c_ptr.as_ptr()) };
+//! ```
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ffi::c_void,
+    ptr::{self, NonNull},
+};
+
+use crate::{stats, sync, SnMalloc};
+
+/// Whether the block of an allocation of `size` bytes may not start at the allocation.
+#[inline(always)]
+fn may_be_offset(size: usize) -> bool {
+    #[cfg(feature = "guard-large-allocs")]
+    if crate::guard::may_be_guarded(size) {
+        return true;
+    }
+    #[cfg(feature = "redzones")]
+    if crate::redzone::covers(size) {
+        return true;
+    }
+    let _ = size;
+    false
+}
+
+/// Gives up the Rust ownership of an allocation, returning a pointer that C code frees with
+/// `sn_rust_free` (`snmalloc_sys::sn_rust_free` from Rust).
+///
+/// The returned pointer is `ptr` itself unless the allocation is zero-sized or guarded, in which
+/// case the contents are moved to a plain block and `ptr` is freed. Returns `None`, leaving `ptr`
+/// owned by the caller, if that block cannot be allocated.
+///
+/// # Safety
+/// `ptr` must have been allocated by [`SnMalloc`](crate::SnMalloc) with `layout`, and must not
+/// be used by Rust once this returns `Some`.
+pub unsafe fn leak_to_c(ptr: NonNull<u8>, layout: Layout) -> Option<NonNull<c_void>> {
+    if layout.size() != 0 && !may_be_offset(layout.size()) {
+        stats::on_dealloc(layout.size());
+        return Some(ptr.cast());
+    }
+    let block = NonNull::new(sync::exclusive(|| ffi::sn_rust_alloc(layout.align(), layout.size().max(1))))?;
+    ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_ptr().cast(), layout.size());
+    SnMalloc.dealloc(ptr.as_ptr(), layout);
+    Some(block)
+}
+
+/// Takes the Rust ownership of a block allocated by C code through the shim, returning a pointer
+/// that [`SnMalloc`](crate::SnMalloc) frees with `layout`.
+///
+/// The returned pointer is `ptr` itself when the block is aligned to `layout` and has exactly the
+/// size class Rust will free it with. Otherwise the first `layout.size()` bytes are moved to a
+/// new allocation and `ptr` is freed. Returns `None`, leaving `ptr` owned by the C side, if that
+/// allocation fails.
+///
+/// # Safety
+/// `ptr` must point to the start of a live block allocated by the shim, with at least
+/// `layout.size()` initialised bytes, and must not be used by C once this returns `Some`.
+pub unsafe fn adopt_from_c(ptr: NonNull<c_void>, layout: Layout) -> Option<NonNull<u8>> {
+    let (align, size) = (layout.align(), layout.size());
+    let fits = size != 0
+        && ptr.as_ptr() as usize & (align - 1) == 0
+        && !may_be_offset(size)
+        && sync::exclusive(|| ffi::sn_rust_usable_size(ptr.as_ptr())) == ffi::sn_rust_round_size(align, size);
+    if fits {
+        return NonNull::new(stats::on_alloc(ptr.as_ptr().cast(), size));
+    }
+    let block = NonNull::new(SnMalloc.alloc(layout))?;
+    ptr::copy_nonoverlapping(ptr.as_ptr().cast(), block.as_ptr(), size);
+    sync::exclusive(|| ffi::sn_rust_free(ptr.as_ptr()));
+    Some(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_hands_allocations_to_c() {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            let ptr = NonNull::new(SnMalloc.alloc(layout)).unwrap();
+            let c_ptr = leak_to_c(ptr, layout).unwrap();
+            assert_eq!(c_ptr == ptr.cast(), !may_be_offset(100));
+            ffi::sn_rust_free(c_ptr.as_ptr());
+
+            let empty = Layout::from_size_align(0, 64).unwrap();
+            let c_ptr = leak_to_c(NonNull::new(64 as *mut u8).unwrap(), empty).unwrap();
+            assert_ne!(c_ptr.as_ptr() as usize, 64);
+            ffi::sn_rust_free(c_ptr.as_ptr());
+        }
+    }
+
+    #[test]
+    fn it_adopts_c_allocations() {
+        unsafe {
+            let c_ptr = NonNull::new(ffi::sn_rust_malloc(100)).unwrap();
+            c_ptr.as_ptr().cast::<u8>().write_bytes(7, 100);
+            // Same size class, adopted in place unless Rust allocations are offset.
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let ptr = adopt_from_c(c_ptr, layout).unwrap();
+            assert_eq!(ptr.cast() == c_ptr, !may_be_offset(100));
+            SnMalloc.dealloc(ptr.as_ptr(), layout);
+
+            let c_ptr = NonNull::new(ffi::sn_rust_malloc(100)).unwrap();
+            c_ptr.as_ptr().cast::<u8>().write_bytes(7, 100);
+            // Rust only claims a prefix, in a smaller size class: the bytes move.
+            let layout = Layout::from_size_align(10, 8).unwrap();
+            let ptr = adopt_from_c(c_ptr, layout).unwrap();
+            assert!((0..10).all(|i| *ptr.as_ptr().add(i) == 7));
+            SnMalloc.dealloc(ptr.as_ptr(), layout);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Slab geometry of snmalloc allocations.
+//!
+//! snmalloc carves small objects of one size class out of naturally aligned slabs. Data
+//! structures aware of the allocator (e.g. intrusive free lists or object pools) can use the
+//! geometry to keep related objects together or to find the neighbours of an object.
+//!
+//! Conservative garbage collectors embedding the allocator can also restrict their heap scan to
+//! the memory snmalloc currently hands out, with [`owned_ranges`] or [`for_each_owned_range`].
+use core::{ffi::c_void, ops::Range, ptr::NonNull};
+
+/// Slab metadata of a small object, returned by [`slab_info`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabInfo {
+    /// Index of the size class of the object.
+    pub sizeclass: usize,
+    /// Size of every object of the size class, i.e. the usable size of the object.
+    pub object_size: usize,
+    /// First byte of the slab holding the object.
+    pub slab_base: NonNull<u8>,
+    /// Size of the slab in bytes, a power of two to which `slab_base` is aligned.
+    pub slab_size: usize,
+    /// Number of objects carved out of the slab.
+    pub objects_per_slab: usize,
+}
+
+/// Describes the slab holding `ptr`, which may point anywhere inside an object.
+///
+/// Returns `None` for null pointers, for large allocations (which are not carved out of slabs),
+/// and for memory not owned by snmalloc.
+pub fn slab_info(ptr: *const u8) -> Option<SlabInfo> {
+    if ptr.is_null() {
+        return None;
+    }
+    let mut info = ffi::sn_rust_slab_info_t {
+        sizeclass: 0,
+        object_size: 0,
+        slab_base: core::ptr::null_mut(),
+        slab_size: 0,
+        objects_per_slab: 0,
+    };
+    if !unsafe { ffi::sn_rust_slab_info(ptr.cast(), &mut info) } {
+        return None;
+    }
+    Some(SlabInfo {
+        sizeclass: info.sizeclass,
+        object_size: info.object_size,
+        slab_base: NonNull::new(info.slab_base.cast())?,
+        slab_size: info.slab_size,
+        objects_per_slab: info.objects_per_slab,
+    })
+}
+
+/// Calls `f` with every range of address space currently backing snmalloc allocations, in
+/// address order. Ranges are made of whole chunks (16KiB or more), so they also cover free
+/// objects of partially used slabs, and adjacent chunks are merged.
+///
+/// The ranges are found by walking the committed part of snmalloc's pagemap, which takes a few
+/// milliseconds on 64-bit targets. Memory allocated or freed concurrently, including by `f`, may
+/// or may not be reported, so a collector should call it with the mutators stopped. Returns
+/// `false`, without calling `f`, if the platform cannot enumerate the pagemap (it can on Windows,
+/// Linux, Android, macOS, FreeBSD, NetBSD and illumos).
+pub fn for_each_owned_range<F: FnMut(Range<usize>)>(mut f: F) -> bool {
+    unsafe extern "C" fn report<F: FnMut(Range<usize>)>(context: *mut c_void, base: *mut c_void, size: usize) {
+        let f = &mut *context.cast::<F>();
+        f(base as usize..base as usize + size);
+    }
+    unsafe { ffi::sn_rust_owned_ranges(Some(report::<F>), (&mut f as *mut F).cast()) }
+}
+
+/// Returns the ranges of address space currently backing snmalloc allocations, see
+/// [`for_each_owned_range`]. The ranges are collected before the iterator is returned, and none
+/// are returned on platforms that cannot enumerate them.
+///
+/// ```rust
+/// let heap: usize = snmalloc_rs::introspect::owned_ranges().map(|range| range.len()).sum();
+/// println!("{} bytes to scan", heap);
+/// ```
+#[cfg(feature = "std")]
+pub fn owned_ranges() -> impl Iterator<Item = Range<usize>> {
+    let mut ranges = std::vec::Vec::new();
+    for_each_owned_range(|range| ranges.push(range));
+    ranges.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    use super::*;
+
+    #[test]
+    fn it_describes_small_objects() {
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        unsafe {
+            let ptr = crate::SnMalloc.alloc(layout);
+            let info = slab_info(ptr.add(10)).unwrap();
+            assert_eq!(info.object_size, crate::SnMalloc.usable_size(ptr).unwrap());
+            assert!(info.slab_size.is_power_of_two());
+            assert_eq!(info.slab_base.as_ptr() as usize % info.slab_size, 0);
+            let offset = ptr as usize - info.slab_base.as_ptr() as usize;
+            assert!(offset < info.objects_per_slab * info.object_size);
+            crate::SnMalloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn it_ignores_large_and_foreign_memory() {
+        let layout = Layout::from_size_align(1 << 22, 8).unwrap();
+        unsafe {
+            let ptr = crate::SnMalloc.alloc(layout);
+            assert_eq!(slab_info(ptr), None);
+            crate::SnMalloc.dealloc(ptr, layout);
+        }
+        let local = 0u8;
+        assert_eq!(slab_info(&local), None);
+        assert_eq!(slab_info(core::ptr::null()), None);
+    }
+
+    #[test]
+    fn it_enumerates_owned_ranges() {
+        let (small, large) = (Layout::from_size_align(48, 8).unwrap(), Layout::from_size_align(1 << 22, 8).unwrap());
+        unsafe {
+            let (a, b) = (crate::SnMalloc.alloc(small) as usize, crate::SnMalloc.alloc(large) as usize);
+            let mut ranges = std::vec::Vec::new();
+            if for_each_owned_range(|range| ranges.push(range)) {
+                assert!(ranges.windows(2).all(|pair| pair[0].end < pair[1].start));
+                assert!(ranges.iter().any(|range| range.contains(&a)));
+                assert!(ranges.iter().any(|range| range.start <= b && b + large.size() <= range.end));
+                let local = 0u8;
+                assert!(!ranges.iter().any(|range| range.contains(&(&local as *const u8 as usize))));
+            }
+            crate::SnMalloc.dealloc(a as *mut u8, small);
+            crate::SnMalloc.dealloc(b as *mut u8, large);
+        }
+    }
+}
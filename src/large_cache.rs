@@ -0,0 +1,153 @@
+//! Cache of freed large objects.
+//!
+//! snmalloc hands freed large objects back to its backend, which decommits their pages, so that
+//! repeatedly allocating and freeing multi-MiB buffers (image frames, decompression windows)
+//! costs a round of page faults and `madvise` calls every time. Once a limit is set, objects of
+//! at least [`MIN_SIZE`] freed through [`SnMalloc`](crate::SnMalloc) are kept committed in a
+//! process-wide cache instead, a few per size, and handed out again to the next allocation of the
+//! same size, from any thread.
+#[cfg(feature = "stats-api")]
+use core::fmt;
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::tuning::{self, Knobs};
+
+/// Smallest allocation routed through the cache.
+pub const MIN_SIZE: usize = 1 << 20;
+
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// Lets up to `limit_bytes` of freed large objects stay cached for reuse, or disables the cache
+/// with `0` (the default). Lowering the limit frees the objects cached above it.
+///
+/// ```rust
+/// use core::alloc::{GlobalAlloc, Layout};
+/// snmalloc_rs::set_large_cache(256 << 20);
+/// let layout = Layout::from_size_align(32 << 20, 8).unwrap();
+/// for _ in 0..100 {
+///     unsafe { snmalloc_rs::SnMalloc.dealloc(snmalloc_rs::SnMalloc.alloc(layout), layout) };
+/// }
+/// println!("{}", snmalloc_rs::large_cache_stats());
+/// snmalloc_rs::set_large_cache(0);
+/// ```
+pub fn set_large_cache(limit_bytes: usize) {
+    tuning::set_knob(Knobs::LARGE_CACHE, || {
+        LIMIT.store(limit_bytes, Ordering::Relaxed);
+        limit_bytes != 0
+    });
+    crate::sync::exclusive(|| unsafe { ffi::sn_rust_set_large_cache(limit_bytes) });
+}
+
+/// Returns the limit set by [`set_large_cache`].
+#[inline(always)]
+pub fn large_cache() -> usize {
+    LIMIT.load(Ordering::Relaxed)
+}
+
+#[cfg(feature = "stats-api")]
+/// Activity and content of the large-object cache, see [`large_cache_stats`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct LargeCacheStats {
+    /// Allocations served from the cache.
+    pub hits: usize,
+    /// Allocations routed through the cache that it could not serve.
+    pub misses: usize,
+    /// Bytes currently cached.
+    pub cached_bytes: usize,
+    /// Objects currently cached.
+    pub cached_objects: usize,
+}
+
+#[cfg(feature = "stats-api")]
+impl LargeCacheStats {
+    /// Returns the fraction of the allocations routed through the cache that it served, `0.0`
+    /// before the first one.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+#[cfg(feature = "stats-api")]
+impl fmt::Display for LargeCacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses ({:.1}% hit rate), {} bytes in {} cached objects",
+            self.hits,
+            self.misses,
+            self.hit_rate() * 100.0,
+            self.cached_bytes,
+            self.cached_objects
+        )
+    }
+}
+
+#[cfg(feature = "stats-api")]
+/// Returns the counters of the large-object cache, which accumulate from the start of the process.
+pub fn large_cache_stats() -> LargeCacheStats {
+    let mut stats = ffi::sn_rust_large_cache_stats_t::default();
+    crate::sync::exclusive(|| unsafe { ffi::sn_rust_large_cache_stats(&mut stats) });
+    LargeCacheStats {
+        hits: stats.hits,
+        misses: stats.misses,
+        cached_bytes: stats.cached_bytes,
+        cached_objects: stats.cached_objects,
+    }
+}
+
+/// Returns whether an allocation of `size` bytes goes through the cache. Smaller sizes are told
+/// apart by a constant, and larger ones by the `knobs` already loaded, so that nothing is loaded
+/// for the cache.
+#[inline(always)]
+pub(crate) fn serves(knobs: Knobs, size: usize) -> bool {
+    size >= MIN_SIZE && knobs.has(Knobs::LARGE_CACHE)
+}
+
+pub(crate) unsafe fn alloc(layout: Layout, zero: bool) -> *mut u8 {
+    crate::sync::exclusive(|| ffi::sn_rust_alloc_large_cached(layout.align(), layout.size(), zero)).cast()
+}
+
+pub(crate) unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+    crate::sync::exclusive(|| ffi::sn_rust_dealloc_large_cached(ptr.cast(), layout.align(), layout.size()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::GlobalAlloc;
+
+    #[cfg(feature = "stats-api")]
+    #[test]
+    fn it_reuses_large_objects() {
+        let layout = Layout::from_size_align(8 << 20, 8).unwrap();
+        set_large_cache(64 << 20);
+        let before = large_cache_stats();
+        unsafe {
+            let ptr = crate::SnMalloc.alloc(layout);
+            ptr.write_bytes(0xAA, layout.size());
+            crate::SnMalloc.dealloc(ptr, layout);
+            let ptr = crate::SnMalloc.alloc_zeroed(layout);
+            assert!((0..layout.size()).step_by(4096).all(|i| *ptr.add(i) == 0));
+            crate::SnMalloc.dealloc(ptr, layout);
+        }
+        let after = large_cache_stats();
+        // Other tests may take the cached object in between.
+        assert!(after.hits + after.misses >= before.hits + before.misses + 2);
+        set_large_cache(0);
+        assert_eq!(large_cache_stats().cached_bytes, 0);
+    }
+
+    #[cfg(feature = "stats-api")]
+    #[test]
+    fn it_reports_the_hit_rate() {
+        let stats = LargeCacheStats { hits: 3, misses: 1, ..Default::default() };
+        assert_eq!(stats.hit_rate(), 0.75);
+        assert_eq!(LargeCacheStats::default().hit_rate(), 0.0);
+    }
+}
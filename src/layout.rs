@@ -0,0 +1,82 @@
+//! Layout sanity checks performed before crossing the FFI boundary.
+//!
+//! With the `debug-assert-layout` feature, invalid layouts (usually built with
+//! `Layout::from_size_align_unchecked`) panic at the offending call site instead of
+//! making snmalloc abort or return garbage. Without the feature these checks compile to nothing.
+//!
+//! Note that panicking inside a `#[global_allocator]` aborts the process, but the panic
+//! message is still printed.
+
+/// The smallest block snmalloc hands out: smaller requests, including zero-sized ones passed to
+/// the shim, use a block of this size.
+pub const MIN_ALLOC_SIZE: usize = ffi::size_classes::MIN_ALLOC_SIZE;
+
+/// The step between the smallest size classes: every block is a multiple of it in size, and
+/// aligned to it whatever the requested alignment.
+pub const GRANULARITY: usize = MIN_ALLOC_SIZE;
+
+/// The largest allocation snmalloc can serve, half of the address space it manages (capped to
+/// `isize::MAX`): larger requests always fail. The runtime cap of
+/// [`set_max_alloc_size`](crate::set_max_alloc_size) applies on top of it.
+pub const MAX_ALLOC_SIZE: usize = {
+    let half = 1usize << (ffi::size_classes::ADDRESS_BITS - 1);
+    if half > isize::MAX as usize { isize::MAX as usize } else { half }
+};
+
+/// The largest alignment snmalloc honours. Blocks beyond the page size are served from naturally
+/// aligned chunks, so any power of two up to [`MAX_ALLOC_SIZE`] is supported.
+pub const MAX_ALIGN: usize = 1 << (usize::BITS - 1 - MAX_ALLOC_SIZE.leading_zeros());
+
+/// Largest alignment every path into snmalloc honours; re-allocations of more aligned blocks are
+/// moved by the Rust layer, which allocates the new block with the original alignment.
+pub(crate) const MIN_ALIGN: usize = 8;
+
+/// Asserts that `align` is a non-zero power of two and that `size` rounded up to `align`
+/// does not overflow `isize`.
+#[inline(always)]
+#[track_caller]
+pub(crate) fn check(size: usize, align: usize) {
+    #[cfg(feature = "debug-assert-layout")]
+    {
+        assert!(align != 0, "snmalloc: zero alignment (size = {})", size);
+        assert!(align.is_power_of_two(), "snmalloc: alignment {} is not a power of two", align);
+        assert!(
+            size <= isize::MAX as usize - (align - 1),
+            "snmalloc: size {} rounded up to alignment {} overflows isize",
+            size,
+            align
+        );
+    }
+    #[cfg(not(feature = "debug-assert-layout"))]
+    let _ = (size, align);
+}
+
+#[cfg(all(test, feature = "debug-assert-layout"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_accepts_valid_layouts() {
+        check(0, 1);
+        check(8, 8);
+        check(isize::MAX as usize, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a power of two")]
+    fn it_rejects_non_power_of_two_alignment() {
+        check(8, 24);
+    }
+
+    #[test]
+    #[should_panic(expected = "zero alignment")]
+    fn it_rejects_zero_alignment() {
+        check(8, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "overflows isize")]
+    fn it_rejects_overflowing_size() {
+        check(isize::MAX as usize, 64);
+    }
+}
@@ -6,12 +6,14 @@
 //! - The allocator uses large ranges of pages to reduce the amount of meta-data required.
 //!
 //! The benchmark is available at the [paper](https://github.com/microsoft/snmalloc/blob/master/snmalloc.pdf) of `snmalloc`
-//! There are three features defined in this crate:
+//! Every feature of this crate is described in the feature list of the [README](https://github.com/SchrodingerZhu/snmalloc-rs#readme). Among them:
 //! - `debug`: Enable the `Debug` mode in `snmalloc`.
-//! - `1mib`: Use the `1mib` chunk configuration.
 //! - `cache-friendly`: Make the allocator more cache friendly (setting `CACHE_FRIENDLY_OFFSET` to `64` in building the library).
+//! - `handle-api`, `stats-api` (on by default): Provide the allocator handles (`SnAllocator`) and the memory usage reported by snmalloc.
+//! - `std`: Enable the parts of the API that require the standard library.
 //!
-//! The whole library supports `no_std`.
+//! The library supports `no_std`, except for the parts behind the `std` feature and the features implying it: `debug-backtrace`, `tagging`,
+//! `memory-pressure`, `tracing`, `sampling`, `quarantine`, `replay`, `std-thread-hook` and `thread-budget`.
 //!
 //! To use `snmalloc-rs` add it as a dependency:
 //! ```toml
@@ -26,10 +28,138 @@
 //! static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 //! ```
 extern crate snmalloc_sys as ffi;
+#[cfg(any(feature = "std", test))]
+extern crate std;
+
+#[cfg(all(feature = "single-threaded", feature = "memory-pressure"))]
+compile_error!("`single-threaded` and `memory-pressure`: the pressure watcher allocates from its own thread, drop one of them");
+#[cfg(all(feature = "no-alloc-on-free", feature = "guard-large-allocs"))]
+compile_error!("`no-alloc-on-free` and `guard-large-allocs`: guarded allocations are unmapped when freed, drop one of them");
+#[cfg(all(feature = "no-alloc-on-free", feature = "quarantine"))]
+compile_error!("`no-alloc-on-free` and `quarantine`: the quarantine grows its queue when freeing, drop one of them");
+
+#[cfg(feature = "allocator-api2")]
+mod alloc_api;
+#[cfg(feature = "handle-api")]
+mod allocator;
+#[cfg(feature = "handle-api")]
+mod arena;
+mod audit;
+pub mod boxed;
+#[cfg(feature = "thread-budget")]
+pub mod budget;
+pub mod chunks;
+pub mod cold;
+#[cfg(feature = "handle-api")]
+mod config;
+#[cfg(all(feature = "std", feature = "stats-api"))]
+pub mod commit;
+#[cfg(test)]
+mod conformance;
+pub mod ctl;
+#[cfg(feature = "cxx-new")]
+pub mod cxx;
+#[cfg(feature = "debug-backtrace")]
+mod debug_alloc;
+mod decay;
+#[cfg(feature = "std")]
+pub mod ext;
+pub mod ffi_safe;
+pub mod fill;
+#[cfg(feature = "handle-api")]
+mod frozen;
+#[cfg(feature = "handle-api")]
+mod global;
+pub mod handoff;
+#[cfg(feature = "guard-large-allocs")]
+pub mod guard;
+#[cfg(feature = "introspection")]
+pub mod introspect;
+mod large_cache;
+mod layout;
+mod limit;
+pub mod loading;
+mod lock;
+mod oom;
+#[cfg(feature = "stats")]
+pub mod measure;
+#[cfg(feature = "handle-api")]
+mod pool;
+#[cfg(feature = "memory-pressure")]
+pub mod pressure;
+#[cfg(feature = "quarantine")]
+pub mod quarantine;
+#[cfg(feature = "randomize")]
+pub mod random;
+pub mod raw;
+#[cfg(feature = "redzones")]
+pub mod redzone;
+#[cfg(feature = "replay")]
+pub mod replay;
+#[cfg(feature = "sampling")]
+pub mod sample;
+#[cfg(feature = "handle-api")]
+mod shim;
+pub mod stats;
+#[cfg(feature = "std")]
+mod switch;
+mod sync;
+#[cfg(feature = "tagging")]
+pub mod tag;
+#[cfg(feature = "std")]
+pub mod testing;
+#[cfg(all(feature = "stats", feature = "std"))]
+mod thread_stats;
+#[cfg(feature = "std-thread-hook")]
+pub mod thread_hook;
+pub mod trace;
+mod tuning;
+#[cfg(feature = "zero-on-free")]
+pub mod zero;
+
+#[cfg(all(feature = "allocator-api2", feature = "std", feature = "handle-api"))]
+pub use alloc_api::SharedSnAllocator;
+#[cfg(feature = "handle-api")]
+pub use allocator::{RawSnAllocator, SnAllocator};
+#[cfg(feature = "thread-budget")]
+pub use budget::{set_thread_budget, thread_budget};
+#[cfg(feature = "handle-api")]
+pub use arena::ScopedArena;
+pub use cold::ColdAllocator;
+#[cfg(feature = "handle-api")]
+pub use config::{AllocConfig, AllocConfigBuilder};
+#[cfg(feature = "debug-backtrace")]
+pub use debug_alloc::SnMallocDebug;
+pub use decay::{flush_thread_cache, shutdown, thread_init, thread_teardown};
+#[cfg(feature = "std")]
+pub use decay::{cache_decay, set_cache_decay};
+#[cfg(feature = "handle-api")]
+pub use frozen::FrozenAllocator;
+#[cfg(feature = "handle-api")]
+pub use global::GlobalSnAllocator;
+pub use handoff::{adopt_from_c, leak_to_c};
+pub use large_cache::{large_cache, set_large_cache};
+#[cfg(feature = "stats-api")]
+pub use large_cache::{large_cache_stats, LargeCacheStats};
+pub use layout::{GRANULARITY, MAX_ALIGN, MAX_ALLOC_SIZE, MIN_ALLOC_SIZE};
+pub use limit::{max_alloc_size, set_max_alloc_size};
+pub use oom::{alloc_failure_hook, last_os_error, set_alloc_failure_hook};
+pub use raw::SnMallocRaw;
+#[cfg(feature = "std")]
+pub use switch::{SnMallocOrSystem, DISABLE_ENV};
+#[cfg(feature = "tagging")]
+pub use tag::{SnMallocTagged, Tagged};
+#[cfg(feature = "tracing")]
+pub use trace::{set_trace_threshold, trace_threshold};
+pub use tuning::{
+    cache_friendly_offset, lazy_zero_fallback, lazy_zero_threshold, os_page_size, remote_batch_size, set_lazy_zero,
+    set_remote_batch_size, LazyZeroFallback,
+};
 
 use core::{
     alloc::{GlobalAlloc, Layout},
-    ptr::NonNull,
+    mem::{self, MaybeUninit},
+    ptr::{self, NonNull},
 };
 
 #[derive(Debug, Copy, Clone)]
@@ -54,12 +184,250 @@ impl SnMalloc {
         }
     }
 
+    /// Returns the number of bytes from `ptr` to the end of the memory block containing it.
+    /// Unlike `usable_size`, `ptr` may point anywhere inside the block, which allows checking
+    /// in-place appends to buffers handed over as interior pointers.
+    /// The result is unspecified if the memory was not allocated by snmalloc.
+    #[inline(always)]
+    pub fn remaining_bytes(&self, ptr: *const u8) -> Option<usize> {
+        match ptr.is_null() {
+            true => None,
+            false => Some(unsafe { ffi::sn_rust_remaining_bytes(ptr.cast()) })
+        }
+    }
+
     /// Allocates memory with the given layout, returning a non-null pointer on success
     #[inline(always)]
+    #[track_caller]
     pub fn alloc_aligned(&self, layout: Layout) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { self.alloc(layout) })
+    }
+
+    /// Allocates an uninitialised slice of `len` values of `T`, returning `None` if the size of
+    /// the slice overflows `isize` or on failure. Free it with [`dealloc_slice`](Self::dealloc_slice).
+    ///
+    /// ```rust
+    /// let alloc = snmalloc_rs::SnMalloc::new();
+    /// let slice = alloc.alloc_uninit_slice::<u64>(1000).unwrap();
+    /// unsafe {
+    ///     for value in (*slice.as_ptr()).iter_mut() {
+    ///         value.write(7);
+    ///     }
+    ///     alloc.dealloc_slice(slice);
+    /// }
+    /// assert!(alloc.alloc_uninit_slice::<u64>(usize::MAX).is_none());
+    /// ```
+    #[inline(always)]
+    #[track_caller]
+    pub fn alloc_uninit_slice<T>(&self, len: usize) -> Option<NonNull<[MaybeUninit<T>]>> {
+        let ptr = self.alloc_aligned(Layout::array::<T>(len).ok()?)?;
+        Some(NonNull::slice_from_raw_parts(ptr.cast(), len))
+    }
+
+    /// Frees a slice allocated by [`alloc_uninit_slice`](Self::alloc_uninit_slice), initialised
+    /// or not. The values are not dropped.
+    ///
+    /// # Safety
+    /// `slice` must have been returned by `alloc_uninit_slice` of `SnMalloc`, for the same `T`
+    /// and length, and must not be used afterwards.
+    #[inline(always)]
+    #[track_caller]
+    pub unsafe fn dealloc_slice<T>(&self, slice: NonNull<[T]>) {
+        let layout = Layout::from_size_align_unchecked(mem::size_of::<T>() * slice.len(), mem::align_of::<T>());
+        self.dealloc(slice.as_ptr().cast(), layout);
+    }
+
+    /// Frees many blocks at once, e.g. when tearing down a large graph: the blocks are sorted by
+    /// address and freed by a single call into the shim per batch, so that the blocks of a slab
+    /// are freed together. Behaves like [`dealloc`](GlobalAlloc::dealloc) on each block.
+    ///
+    /// ```rust
+    /// use core::alloc::{GlobalAlloc, Layout};
+    /// let alloc = snmalloc_rs::SnMalloc::new();
+    /// let layout = Layout::from_size_align(48, 8).unwrap();
+    /// let blocks: Vec<_> = (0..1000).map(|_| (unsafe { alloc.alloc(layout) }, layout)).collect();
+    /// unsafe { alloc.dealloc_many(&blocks) };
+    /// ```
+    ///
+    /// # Safety
+    /// Every block must satisfy the requirements of `dealloc`, and appear once.
+    #[track_caller]
+    pub unsafe fn dealloc_many(&self, blocks: &[(*mut u8, Layout)]) {
+        let knobs = tuning::knobs();
+        let mut batch = [BATCH_ENTRY; BATCH_LEN];
+        let mut len = 0;
+        for &(ptr, layout) in blocks {
+            layout::check(layout.size(), layout.align());
+            stats::on_dealloc(layout.size());
+            #[cfg(feature = "quarantine")]
+            if quarantine::hold(ptr, layout) {
+                continue;
+            }
+            if !is_batched(knobs, layout.size()) {
+                release(ptr, layout, knobs);
+                continue;
+            }
+            #[cfg(feature = "zero-on-free")]
+            zero::on_free(ptr, layout.size());
+            batch[len] = ffi::sn_rust_block_t { ptr: ptr.cast(), alignment: layout.align(), size: layout.size() };
+            len += 1;
+            if len == BATCH_LEN {
+                release_batch(&mut batch);
+                len = 0;
+            }
+        }
+        release_batch(&mut batch[..len]);
+        decay::tick(knobs);
+    }
+
+    /// Frees a block without initialising the allocator of the calling thread, e.g. from a helper
+    /// thread that only drops what other threads allocated: the frees are posted to the owners of
+    /// the blocks through an allocator shared by the process, under a lock, 64 at a time, and the
+    /// last ones when the thread exits or calls [`flush_thread_cache`] or [`shutdown`]. Otherwise
+    /// behaves like [`dealloc`](GlobalAlloc::dealloc), which is faster on threads that allocate
+    /// too. With the `no-alloc-on-free` feature, the shared allocator is created up front, so that
+    /// these frees pass the audit.
+    ///
+    /// Blocks taking the paths of the features that keep their own bookkeeping (guard pages,
+    /// redzones, randomised padding, the large-object cache) are freed by `dealloc`.
+    ///
+    /// ```rust
+    /// use core::alloc::{GlobalAlloc, Layout};
+    /// let layout = Layout::from_size_align(64, 8).unwrap();
+    /// let ptr = unsafe { snmalloc_rs::SnMalloc.alloc(layout) } as usize;
+    /// std::thread::spawn(move || unsafe { snmalloc_rs::SnMalloc.dealloc_remote(ptr as *mut u8, layout) })
+    ///     .join()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// # Safety
+    /// The same requirements as for `GlobalAlloc::dealloc` apply.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn dealloc_remote(&self, ptr: *mut u8, layout: Layout) {
+        layout::check(layout.size(), layout.align());
+        stats::on_dealloc(layout.size());
+        #[cfg(feature = "quarantine")]
+        if quarantine::hold(ptr, layout) {
+            return;
+        }
+        let knobs = tuning::knobs();
+        if !is_batched(knobs, layout.size()) {
+            return release(ptr, layout, knobs);
+        }
+        #[cfg(feature = "zero-on-free")]
+        zero::on_free(ptr, layout.size());
+        sync::exclusive(|| audit::on_dealloc(|| ffi::sn_rust_remote_dealloc(ptr.cast())));
+    }
+
+    /// Allocates memory with the given layout and sets every byte to `byte` (see [`fill`] for
+    /// common patterns), returning a non-null pointer on success.
+    #[inline(always)]
+    #[track_caller]
+    pub fn alloc_filled(&self, layout: Layout, byte: u8) -> Option<NonNull<u8>> {
+        layout::check(layout.size(), layout.align());
+        stats::on_request(layout.size(), layout.align());
+        trace::on_request(layout.size(), layout.align());
+        #[cfg(feature = "sampling")]
+        sample::on_request(layout.size());
+        let knobs = tuning::knobs();
+        let ptr = match layout.size() {
+            0 => return NonNull::new(layout.align() as *mut u8),
+            size if limit::refuses(knobs, size) => return None,
+            #[cfg(feature = "guard-large-allocs")]
+            size if guard::should_guard(size) => oom::on_failure(layout, || unsafe { guard::alloc(layout, byte == 0) }),
+            #[cfg(feature = "redzones")]
+            size if redzone::covers(size) => oom::on_failure(layout, || unsafe { redzone::alloc(layout, byte == 0) }),
+            #[cfg(feature = "randomize")]
+            size if random::pads(size) => oom::on_failure(layout, || unsafe { random::alloc(layout, byte == 0) }),
+            size if large_cache::serves(knobs, size) => oom::on_failure(layout, || unsafe { large_cache::alloc(layout, byte == 0) }),
+            // Filled by the shim, in the same call as the allocation.
+            size => {
+                let ptr = oom::on_failure(layout, || sync::exclusive(|| unsafe { ffi::sn_rust_alloc_filled(layout.align(), size, byte) }).cast());
+                return NonNull::new(stats::on_alloc(ptr, size));
+            }
+        };
+        let ptr = NonNull::new(stats::on_alloc(ptr, layout.size()))?;
+        if byte != 0 {
+            unsafe { ptr.as_ptr().write_bytes(byte, layout.size()) };
+        }
+        Some(ptr)
+    }
+
+    /// Allocates memory with the given layout, returning the pointer together with the usable
+    /// size of the block.
+    ///
+    /// The whole block may be used, and de-allocated with any size between `layout.size()` and
+    /// the usable size. The block is the size class serving `layout`, as for `alloc`, and the
+    /// rounding is returned to the caller rather than wasted.
+    ///
+    /// The [statistics](crate::stats) count `layout.size()` bytes, so they stay exact when the
+    /// block is de-allocated with `layout`.
+    #[inline(always)]
+    #[track_caller]
+    pub fn alloc_with_usable_size(&self, layout: Layout) -> Option<(NonNull<u8>, usize)> {
+        layout::check(layout.size(), layout.align());
+        stats::on_request(layout.size(), layout.align());
+        trace::on_request(layout.size(), layout.align());
+        #[cfg(feature = "sampling")]
+        sample::on_request(layout.size());
+        let knobs = tuning::knobs();
         match layout.size() {
-            0 => NonNull::new(layout.align() as *mut u8),
-            size => NonNull::new(unsafe { ffi::sn_rust_alloc(layout.align(), size) }.cast())
+            0 => Some((NonNull::new(layout.align() as *mut u8)?, 0)),
+            size if limit::refuses(knobs, size) => None,
+            #[cfg(feature = "guard-large-allocs")]
+            size if guard::should_guard(size) => {
+                let ptr = oom::on_failure(layout, || unsafe { guard::alloc(layout, false) });
+                Some((NonNull::new(stats::on_alloc(ptr, size))?, size))
+            }
+            #[cfg(feature = "redzones")]
+            size if redzone::covers(size) => {
+                let ptr = oom::on_failure(layout, || unsafe { redzone::alloc(layout, false) });
+                Some((NonNull::new(stats::on_alloc(ptr, size))?, size))
+            }
+            size => {
+                let mut usable = 0;
+                let ptr = oom::on_failure(layout, || sync::exclusive(|| unsafe { ffi::sn_rust_alloc_usable(layout.align(), size, &mut usable) }).cast());
+                Some((NonNull::new(stats::on_alloc(ptr, size))?, usable))
+            }
+        }
+    }
+
+    /// Behaves like `realloc`, but also ensures that the bytes between the old and the new size
+    /// are set to zero when growing. Only those bytes are written, unlike `realloc` followed by
+    /// clearing the tail of the new block.
+    ///
+    /// # Safety
+    /// The same requirements as for `GlobalAlloc::realloc` apply.
+    #[inline(always)]
+    #[track_caller]
+    pub unsafe fn realloc_zeroed(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        layout::check(layout.size(), layout.align());
+        layout::check(new_size, layout.align());
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let knobs = tuning::knobs();
+        match new_size {
+            0 => {
+                self.dealloc(ptr, layout);
+                layout.align() as *mut u8
+            }
+            new_size if limit::refuses_growth(knobs, layout.size(), new_size) => ptr::null_mut(),
+            _ if layout.size() == 0 => self.alloc_zeroed(new_layout),
+            #[cfg(any(feature = "guard-large-allocs", feature = "quarantine", feature = "redzones", feature = "randomize"))]
+            new_size if realloc_moves(layout.size(), new_size) => {
+                let new_ptr = self.realloc(ptr, layout, new_size);
+                if !new_ptr.is_null() && new_size > layout.size() {
+                    new_ptr.add(layout.size()).write_bytes(0, new_size - layout.size());
+                }
+                new_ptr
+            }
+            _ => stats::on_realloc(
+                oom::on_failure(new_layout, || {
+                    sync::exclusive(|| ffi::sn_rust_realloc_zeroed(ptr.cast(), layout.align(), layout.size(), new_size)).cast()
+                }),
+                layout.size(),
+                new_size,
+            )
         }
     }
 }
@@ -74,10 +442,25 @@ unsafe impl GlobalAlloc for SnMalloc {
     ///
     /// The program may be forced to abort if the constrains are not full-filled.
     #[inline(always)]
+    #[track_caller]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        layout::check(layout.size(), layout.align());
+        stats::on_request(layout.size(), layout.align());
+        trace::on_request(layout.size(), layout.align());
+        #[cfg(feature = "sampling")]
+        sample::on_request(layout.size());
+        let knobs = tuning::knobs();
         match layout.size() {
             0 => layout.align() as *mut u8,
-            size => ffi::sn_rust_alloc(layout.align(), size).cast()
+            size if limit::refuses(knobs, size) => ptr::null_mut(),
+            #[cfg(feature = "guard-large-allocs")]
+            size if guard::should_guard(size) => stats::on_alloc(oom::on_failure(layout, || guard::alloc(layout, false)), size),
+            #[cfg(feature = "redzones")]
+            size if redzone::covers(size) => stats::on_alloc(oom::on_failure(layout, || redzone::alloc(layout, false)), size),
+            #[cfg(feature = "randomize")]
+            size if random::pads(size) => stats::on_alloc(oom::on_failure(layout, || random::alloc(layout, false)), size),
+            size if large_cache::serves(knobs, size) => stats::on_alloc(oom::on_failure(layout, || large_cache::alloc(layout, false)), size),
+            size => stats::on_alloc(oom::on_failure(layout, || sync::exclusive(|| ffi::sn_rust_alloc(layout.align(), size)).cast()), size)
         }
     }
 
@@ -88,18 +471,41 @@ unsafe impl GlobalAlloc for SnMalloc {
     ///
     /// The program may be forced to abort if the constrains are not full-filled.
     #[inline(always)]
+    #[track_caller]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if layout.size() != 0 {
-            ffi::sn_rust_dealloc(ptr as _, layout.align(), layout.size());
+        layout::check(layout.size(), layout.align());
+        stats::on_dealloc(layout.size());
+        #[cfg(feature = "quarantine")]
+        if quarantine::hold(ptr, layout) {
+            return;
         }
+        let knobs = tuning::knobs();
+        release(ptr, layout, knobs);
+        decay::tick(knobs);
     }
 
     /// Behaves like alloc, but also ensures that the contents are set to zero before being returned.
     #[inline(always)]
+    #[track_caller]
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        layout::check(layout.size(), layout.align());
+        stats::on_request(layout.size(), layout.align());
+        trace::on_request(layout.size(), layout.align());
+        #[cfg(feature = "sampling")]
+        sample::on_request(layout.size());
+        let knobs = tuning::knobs();
         match layout.size() {
             0 => layout.align() as *mut u8,
-            size => ffi::sn_rust_alloc_zeroed(layout.align(), size).cast()
+            size if limit::refuses(knobs, size) => ptr::null_mut(),
+            #[cfg(feature = "guard-large-allocs")]
+            size if guard::should_guard(size) => stats::on_alloc(oom::on_failure(layout, || guard::alloc(layout, true)), size),
+            #[cfg(feature = "redzones")]
+            size if redzone::covers(size) => stats::on_alloc(oom::on_failure(layout, || redzone::alloc(layout, true)), size),
+            #[cfg(feature = "randomize")]
+            size if random::pads(size) => stats::on_alloc(oom::on_failure(layout, || random::alloc(layout, true)), size),
+            size if tuning::zeroes_lazily(knobs, size) => stats::on_alloc(oom::on_failure(layout, || tuning::alloc_zeroed_fresh(layout)), size),
+            size if large_cache::serves(knobs, size) => stats::on_alloc(oom::on_failure(layout, || large_cache::alloc(layout, true)), size),
+            size => stats::on_alloc(oom::on_failure(layout, || sync::exclusive(|| ffi::sn_rust_alloc_zeroed(layout.align(), size)).cast()), size)
         }
     }
 
@@ -114,20 +520,172 @@ unsafe impl GlobalAlloc for SnMalloc {
     ///
     /// The program may be forced to abort if the constrains are not full-filled.
     #[inline(always)]
+    #[track_caller]
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        layout::check(layout.size(), layout.align());
+        layout::check(new_size, layout.align());
+        if layout.size() != 0 && new_size > layout.size() {
+            trace::on_request(new_size, layout.align());
+            #[cfg(feature = "sampling")]
+            sample::on_request(new_size - layout.size());
+        }
+        let knobs = tuning::knobs();
         match new_size {
             0 => {
                 self.dealloc(ptr, layout);
                 layout.align() as *mut u8
             }
+            new_size if limit::refuses_growth(knobs, layout.size(), new_size) => ptr::null_mut(),
             new_size if layout.size() == 0 => {
                 self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()))
             }
-            _ => ffi::sn_rust_realloc(ptr.cast(), layout.align(), layout.size(), new_size).cast()
+            // Redzones sit at both ends of the block, so it is never resized in place.
+            #[cfg(feature = "redzones")]
+            new_size if redzone::covers(layout.size()) || redzone::covers(new_size) => self.move_block(ptr, layout, new_size),
+            #[cfg(feature = "guard-large-allocs")]
+            new_size if guard::may_be_guarded(layout.size()) || guard::should_guard(new_size) => {
+                stats::on_realloc(
+                    oom::on_failure(Layout::from_size_align_unchecked(new_size, layout.align()), || guard::realloc(ptr, layout, new_size)),
+                    layout.size(),
+                    new_size,
+                )
+            }
+            #[cfg(feature = "randomize")]
+            new_size if random::pads(layout.size()) || random::pads(new_size) => self.move_block(ptr, layout, new_size),
+            #[cfg(feature = "quarantine")]
+            // Moving every block lets the old one go through the quarantine.
+            _ if quarantine::enabled() => self.move_block(ptr, layout, new_size),
+            _ if layout.align() > layout::MIN_ALIGN => {
+                stats::on_realloc(realloc_aligned(ptr, layout, new_size), layout.size(), new_size)
+            }
+            _ => stats::on_realloc(
+                oom::on_failure(Layout::from_size_align_unchecked(new_size, layout.align()), || {
+                    sync::exclusive(|| ffi::sn_rust_realloc(ptr.cast(), layout.align(), layout.size(), new_size)).cast()
+                }),
+                layout.size(),
+                new_size,
+            )
         }
     }
 }
 
+impl SnMalloc {
+    /// Re-allocates by allocating a new block and freeing the old one through the paths of
+    /// `alloc` and `dealloc`, which also keep the statistics.
+    #[cfg(any(feature = "redzones", feature = "quarantine", feature = "randomize"))]
+    unsafe fn move_block(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// Whether `realloc` moves the block through the Rust layer rather than the shim.
+#[cfg(any(feature = "guard-large-allocs", feature = "quarantine", feature = "redzones", feature = "randomize"))]
+#[inline(always)]
+fn realloc_moves(size: usize, new_size: usize) -> bool {
+    #[cfg(feature = "guard-large-allocs")]
+    if guard::may_be_guarded(size) || guard::should_guard(new_size) {
+        return true;
+    }
+    #[cfg(feature = "quarantine")]
+    if quarantine::enabled() {
+        return true;
+    }
+    #[cfg(feature = "redzones")]
+    if redzone::covers(size) || redzone::covers(new_size) {
+        return true;
+    }
+    #[cfg(feature = "randomize")]
+    if random::pads(size) || random::pads(new_size) {
+        return true;
+    }
+    let _ = (size, new_size);
+    false
+}
+
+/// Hands the memory at `ptr` back to snmalloc, through the path that allocated it, given the
+/// runtime knobs loaded by the caller.
+#[inline(always)]
+pub(crate) unsafe fn release(ptr: *mut u8, layout: Layout, knobs: tuning::Knobs) {
+    #[cfg(feature = "zero-on-free")]
+    zero::on_free(ptr, layout.size());
+    match layout.size() {
+        0 => {}
+        #[cfg(feature = "guard-large-allocs")]
+        size if guard::may_be_guarded(size) => guard::dealloc(ptr, layout),
+        #[cfg(feature = "redzones")]
+        size if redzone::covers(size) => redzone::dealloc(ptr, layout),
+        #[cfg(feature = "randomize")]
+        size if random::pads(size) => random::dealloc(ptr),
+        size if large_cache::serves(knobs, size) => large_cache::dealloc(ptr, layout),
+        size => sync::exclusive(|| {
+            let batch = match knobs.has(tuning::Knobs::REMOTE_BATCH) {
+                true => tuning::remote_batch_size(),
+                false => 0,
+            };
+            audit::on_dealloc(|| match batch {
+                0 => {
+                    ffi::sn_rust_dealloc(ptr as _, layout.align(), size);
+                }
+                batch => ffi::sn_rust_dealloc_batched(ptr as _, layout.align(), size, batch),
+            })
+        }),
+    }
+}
+
+/// Blocks freed by one call into the shim in [`SnMalloc::dealloc_many`] and
+/// [`SnAllocator::deallocate_many`].
+pub(crate) const BATCH_LEN: usize = 64;
+
+pub(crate) const BATCH_ENTRY: ffi::sn_rust_block_t = ffi::sn_rust_block_t { ptr: ptr::null_mut(), alignment: 0, size: 0 };
+
+/// Whether a block of `size` bytes is freed by the shim directly, and may thus be freed in a
+/// batch: the other paths of [`release`] keep their own bookkeeping.
+#[inline(always)]
+fn is_batched(knobs: tuning::Knobs, size: usize) -> bool {
+    #[cfg(feature = "guard-large-allocs")]
+    if guard::may_be_guarded(size) {
+        return false;
+    }
+    #[cfg(feature = "redzones")]
+    if redzone::covers(size) {
+        return false;
+    }
+    #[cfg(feature = "randomize")]
+    if random::pads(size) {
+        return false;
+    }
+    size != 0 && !large_cache::serves(knobs, size)
+}
+
+/// Hands a batch of blocks taking the plain path of [`release`] back to snmalloc.
+#[inline(always)]
+unsafe fn release_batch(blocks: &mut [ffi::sn_rust_block_t]) {
+    if !blocks.is_empty() {
+        sync::exclusive(|| audit::on_dealloc(|| ffi::sn_rust_dealloc_many(blocks.as_mut_ptr(), blocks.len())));
+    }
+}
+
+/// Re-allocates an over-aligned block, moving it to a block allocated with the same alignment
+/// unless both sizes are served by the same block.
+#[inline(never)]
+unsafe fn realloc_aligned(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    if ffi::sn_rust_round_size(layout.align(), new_size) == ffi::sn_rust_round_size(layout.align(), layout.size()) {
+        return ptr;
+    }
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    let new_ptr = oom::on_failure(new_layout, || sync::exclusive(|| ffi::sn_rust_alloc(layout.align(), new_size)).cast());
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+        sync::exclusive(|| ffi::sn_rust_dealloc(ptr.cast(), layout.align(), layout.size()));
+    }
+    new_ptr
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -212,4 +770,242 @@ mod tests {
             assert!(usz >= 8);
         }
     }
+
+    #[test]
+    fn it_fills_allocations() {
+        let alloc = SnMalloc::new();
+        let layout = Layout::from_size_align(100, 16).unwrap();
+        let ptr = alloc.alloc_filled(layout, fill::UNINITIALIZED).unwrap();
+        unsafe {
+            let bytes = core::slice::from_raw_parts(ptr.as_ptr(), 100);
+            assert!(bytes.iter().all(|b| *b == fill::UNINITIALIZED));
+            alloc.dealloc(ptr.as_ptr(), layout);
+        }
+    }
+
+    #[test]
+    fn it_deallocates_from_threads_without_an_allocator() {
+        let layout = Layout::from_size_align(96, 16).unwrap();
+        let blocks: [usize; 64] = core::array::from_fn(|_| unsafe { SnMalloc.alloc(layout) } as usize);
+        std::thread::spawn(move || {
+            for ptr in blocks {
+                unsafe { SnMalloc.dealloc_remote(ptr as *mut u8, layout) };
+            }
+        })
+        .join()
+        .unwrap();
+        // The owner takes its blocks back from its message queue.
+        unsafe {
+            for _ in 0..1000 {
+                let ptr = SnMalloc.alloc(layout);
+                assert!(!ptr.is_null());
+                SnMalloc.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn it_deallocates_with_small_remote_batches() {
+        use std::sync::mpsc::channel;
+
+        let _lock = tuning::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let (to_freer, blocks) = channel::<std::vec::Vec<usize>>();
+        let (freed, wait_freed) = channel();
+        let (release, wait_release) = channel::<()>();
+        // The freeing thread stays alive until the owner checked, so that its exit, which flushes
+        // every buffered free, does not hand the blocks back instead of the batches.
+        let freer = std::thread::spawn(move || {
+            assert!(set_remote_batch_size(128));
+            for ptr in blocks.recv().unwrap() {
+                unsafe { SnMalloc.dealloc(ptr as *mut u8, layout) };
+            }
+            assert!(set_remote_batch_size(0));
+            freed.send(()).unwrap();
+            let _ = wait_release.recv();
+        });
+        let owner = std::thread::spawn(move || {
+            let ptrs: std::vec::Vec<usize> = (0..4096).map(|_| unsafe { SnMalloc.alloc(layout) } as usize).collect();
+            let owned: std::collections::HashSet<usize> = ptrs.iter().copied().collect();
+            to_freer.send(ptrs).unwrap();
+            wait_freed.recv().unwrap();
+            // The frees were posted in small batches, so the owner reuses its blocks.
+            let again: std::vec::Vec<usize> = (0..8192).map(|_| unsafe { SnMalloc.alloc(layout) } as usize).collect();
+            let reused = again.iter().any(|ptr| owned.contains(ptr));
+            for ptr in again {
+                unsafe { SnMalloc.dealloc(ptr as *mut u8, layout) };
+            }
+            reused
+        });
+        let reused = owner.join().unwrap();
+        release.send(()).unwrap();
+        freer.join().unwrap();
+        assert!(reused);
+    }
+
+    #[test]
+    fn it_deallocates_many_blocks() {
+        // Sizes of every path, in more blocks than a batch holds.
+        let blocks: std::vec::Vec<_> = (0..200usize)
+            .map(|i| {
+                let layout = Layout::from_size_align([0, 24, 4096, 1 << 20][i % 4], 8 << (i % 3)).unwrap();
+                match layout.size() {
+                    0 => (layout.align() as *mut u8, layout),
+                    _ => (unsafe { SnMalloc.alloc(layout) }, layout),
+                }
+            })
+            .collect();
+        unsafe { SnMalloc.dealloc_many(&blocks) };
+        unsafe { SnMalloc.dealloc_many(&[]) };
+    }
+
+    #[test]
+    fn it_returns_the_usable_size_of_huge_alignments() {
+        let layout = Layout::from_size_align((1 << 16) + 1, 1 << 16).unwrap();
+        let (ptr, usable) = SnMalloc.alloc_with_usable_size(layout).unwrap();
+        assert_eq!(ptr.as_ptr() as usize % (1 << 16), 0);
+        assert!(usable >= layout.size() && usable <= 1 << 17);
+        unsafe { SnMalloc.dealloc(ptr.as_ptr(), layout) };
+    }
+
+    #[test]
+    fn it_honours_the_published_limits() {
+        assert!(MIN_ALLOC_SIZE.is_power_of_two() && MAX_ALIGN.is_power_of_two() && MAX_ALIGN <= MAX_ALLOC_SIZE);
+        for size in [1, MIN_ALLOC_SIZE + 1, 1000] {
+            let layout = Layout::from_size_align(size, 1).unwrap();
+            let (ptr, usable) = SnMalloc.alloc_with_usable_size(layout).unwrap();
+            assert!(usable >= MIN_ALLOC_SIZE && usable % GRANULARITY == 0);
+            assert_eq!(ptr.as_ptr() as usize % GRANULARITY, 0);
+            unsafe { SnMalloc.dealloc(ptr.as_ptr(), layout) };
+        }
+        // Beyond the largest allocation, requests fail instead of aborting.
+        if let Ok(layout) = Layout::from_size_align(MAX_ALLOC_SIZE + 1, 1) {
+            assert!(SnMalloc.alloc_aligned(layout).is_none());
+        }
+    }
+
+    #[test]
+    fn test_remaining_bytes() {
+        let alloc = SnMalloc::new();
+        unsafe {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let ptr = alloc.alloc(layout);
+            let usz = alloc.usable_size(ptr).unwrap();
+            assert_eq!(alloc.remaining_bytes(ptr), Some(usz));
+            assert_eq!(alloc.remaining_bytes(ptr.add(10)), Some(usz - 10));
+            assert_eq!(alloc.remaining_bytes(core::ptr::null()), None);
+            alloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn it_keeps_the_alignment_of_reallocations() {
+        for align in [64, 4096] {
+            unsafe {
+                let layout = Layout::from_size_align(align / 2, align).unwrap();
+                let mut ptr = SnMalloc.alloc(layout);
+                ptr.write_bytes(0x5A, layout.size());
+                let mut size = layout.size();
+                for new_size in [align + 1, 3 * align, 100 * align, align / 4] {
+                    ptr = SnMalloc.realloc(ptr, Layout::from_size_align(size, align).unwrap(), new_size);
+                    assert_eq!(ptr as usize % align, 0);
+                    assert!((0..size.min(new_size).min(align / 2)).all(|i| *ptr.add(i) == 0x5A));
+                    size = new_size;
+                }
+                SnMalloc.dealloc(ptr, Layout::from_size_align(size, align).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn it_decommits_and_protects_whole_kernel_pages() {
+        let page = os_page_size();
+        for size in [page - 1, page + 1, 3 * page + 1, 16 * page + page / 2] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            unsafe {
+                let ptr = SnMalloc.alloc(layout);
+                ptr.write_bytes(0xAB, size);
+                SnMalloc.dealloc(ptr, layout);
+                // Returning the cached memory lets snmalloc decommit it.
+                flush_thread_cache();
+                let ptr = SnMalloc.alloc_zeroed(layout);
+                assert!((0..size).step_by(page / 4).all(|i| *ptr.add(i) == 0));
+                SnMalloc.dealloc(ptr, layout);
+            }
+        }
+        unsafe {
+            let ptr = ffi::sn_rust_alloc(page, page);
+            assert!(ffi::sn_rust_protect_read_only(ptr, page, true));
+            assert!(ffi::sn_rust_protect_read_only(ptr, page, false));
+            ptr.cast::<u8>().write(1);
+            ffi::sn_rust_dealloc(ptr, page, page);
+        }
+    }
+
+    #[test]
+    fn it_allocates_after_shutdown() {
+        for _ in 0..4 {
+            std::thread::spawn(|| drop(std::vec![0u8; 4096])).join().unwrap();
+        }
+        shutdown();
+        unsafe {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let ptr = SnMalloc.alloc(layout);
+            assert!(!ptr.is_null());
+            SnMalloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn it_zeroes_the_grown_part_of_reallocations() {
+        let alloc = SnMalloc::new();
+        unsafe {
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            let ptr = alloc.alloc_filled(layout, 0xAA).unwrap().as_ptr();
+            // Grows within the size class, then into another one.
+            let ptr = alloc.realloc_zeroed(ptr, layout, 32);
+            assert_eq!(*ptr.add(23), 0xAA);
+            assert_eq!(*ptr.add(24), 0);
+            let ptr = alloc.realloc_zeroed(ptr, Layout::from_size_align(32, 8).unwrap(), 1 << 16);
+            assert_eq!(*ptr.add(23), 0xAA);
+            assert!((24..1 << 16).all(|i| *ptr.add(i) == 0));
+            alloc.dealloc(ptr, Layout::from_size_align(1 << 16, 8).unwrap());
+        }
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn it_survives_exhausting_the_address_space() {
+        let alloc = SnMalloc::new();
+        // More than the address space can hold fails cleanly.
+        assert!(alloc.alloc_aligned(Layout::from_size_align(3 << 30, 8).unwrap()).is_none());
+        let layout = Layout::from_size_align(64 << 20, 8).unwrap();
+        let mut blocks = [None; 64];
+        for block in blocks.iter_mut() {
+            *block = alloc.alloc_aligned(layout);
+            if block.is_none() {
+                break;
+            }
+        }
+        assert!(blocks.iter().flatten().count() >= 4);
+        for block in blocks.iter().flatten() {
+            unsafe { alloc.dealloc(block.as_ptr(), layout) };
+        }
+        // The memory freed near the limit is reused.
+        let ptr = alloc.alloc_aligned(layout).unwrap();
+        unsafe { alloc.dealloc(ptr.as_ptr(), layout) };
+    }
+
+    #[cfg(feature = "critical-section")]
+    #[test]
+    fn it_allocates_inside_a_critical_section() {
+        // Critical sections nest, so allocating from one (e.g. an interrupt handler) works.
+        critical_section::with(|_| unsafe {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let ptr = SnMalloc.alloc(layout);
+            assert!(!ptr.is_null());
+            let ptr = SnMalloc.realloc(ptr, layout, 4096);
+            SnMalloc.dealloc(ptr, Layout::from_size_align(4096, 8).unwrap());
+        });
+    }
 }
@@ -0,0 +1,85 @@
+//! Process-wide cap on the size of a single allocation.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::tuning::{self, Knobs};
+
+static MAX_ALLOC_SIZE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Makes every single allocation above `bytes` fail, returning null (or `None`) instead of
+/// reserving the address space for it.
+///
+/// This guards against untrusted length fields driving multi-GiB allocations; use
+/// `usize::MAX` to lift the limit. It applies to [`SnMalloc`](crate::SnMalloc) and to every
+/// [`SnAllocator`](crate::SnAllocator), including growing re-allocations, which leave the
+/// original memory untouched.
+///
+/// ```rust
+/// use core::alloc::{GlobalAlloc, Layout};
+/// snmalloc_rs::set_max_alloc_size(1 << 20);
+/// let layout = Layout::from_size_align(1 << 30, 8).unwrap();
+/// assert!(unsafe { snmalloc_rs::SnMalloc.alloc(layout) }.is_null());
+/// snmalloc_rs::set_max_alloc_size(usize::MAX);
+/// ```
+pub fn set_max_alloc_size(bytes: usize) {
+    tuning::set_knob(Knobs::MAX_ALLOC_SIZE, || {
+        MAX_ALLOC_SIZE.store(bytes, Ordering::Relaxed);
+        bytes != usize::MAX
+    });
+}
+
+/// Returns the current limit set by [`set_max_alloc_size`].
+#[inline(always)]
+pub fn max_alloc_size() -> usize {
+    MAX_ALLOC_SIZE.load(Ordering::Relaxed)
+}
+
+#[cfg(feature = "handle-api")]
+#[inline(always)]
+pub(crate) fn exceeds(size: usize) -> bool {
+    size > max_alloc_size()
+}
+
+/// Whether an allocation of `size` bytes through [`SnMalloc`](crate::SnMalloc) is refused, by the
+/// limit or, with the `thread-budget` feature, by the budget of the current thread. The limit is
+/// only loaded when `knobs` say that one is set.
+#[inline(always)]
+pub(crate) fn refuses(knobs: Knobs, size: usize) -> bool {
+    refuses_growth(knobs, 0, size)
+}
+
+/// Same as [`refuses`] for growing a block of `old_size` bytes, of which only the growth counts
+/// against the budget of the thread.
+#[inline(always)]
+pub(crate) fn refuses_growth(knobs: Knobs, old_size: usize, new_size: usize) -> bool {
+    let exceeds = knobs.has(Knobs::MAX_ALLOC_SIZE) && exceeds(new_size);
+    #[cfg(feature = "thread-budget")]
+    return exceeds || crate::budget::refuses(new_size.saturating_sub(old_size));
+    #[cfg(not(feature = "thread-budget"))]
+    {
+        let _ = old_size;
+        exceeds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_is_unlimited_by_default() {
+        let _lock = tuning::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        assert_eq!(max_alloc_size(), usize::MAX);
+        assert!(!tuning::knobs().has(Knobs::MAX_ALLOC_SIZE));
+    }
+
+    #[test]
+    fn it_only_checks_a_limit_that_is_set() {
+        let _lock = tuning::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_max_alloc_size(1 << 20);
+        assert!(tuning::knobs().has(Knobs::MAX_ALLOC_SIZE));
+        assert!(refuses(tuning::knobs(), 2 << 20));
+        set_max_alloc_size(usize::MAX);
+        assert!(!tuning::knobs().has(Knobs::MAX_ALLOC_SIZE));
+        assert!(!refuses(tuning::knobs(), 2 << 20));
+    }
+}
@@ -0,0 +1,92 @@
+//! Detection of TLS misconfiguration when snmalloc is linked into a shared object, and of its
+//! initialisation before `main`.
+//!
+//! By default snmalloc uses the initial-exec TLS model, which is fastest but makes a `cdylib`
+//! crash when it is `dlopen`ed (e.g. as a Python extension or a plugin). Such crates must enable
+//! the `dynamic-loading` feature, or build with `SNMALLOC_DYNAMIC_LOADING=1`.
+//!
+//! [`SnMalloc`](crate::SnMalloc) may be used from `#[ctor]` functions and C++ static
+//! initializers: snmalloc initialises itself on first use from whichever of them runs first. With
+//! the `early-init` feature, the shim also brings it up from its own static initializer, which
+//! runs ahead of the C++ constructors of user code on ELF and MSVC targets, unless
+//! `SNMALLOC_DISABLE=1` hands the process to the system allocator.
+
+/// Returns whether snmalloc was built to be loaded dynamically.
+#[inline(always)]
+pub const fn built_for_dynamic_loading() -> bool {
+    ffi::SN_RUST_DYNAMIC_LOADING
+}
+
+/// Returns whether snmalloc lives in a shared object rather than in the main program.
+#[inline(always)]
+pub fn is_shared_object() -> bool {
+    unsafe { ffi::sn_rust_is_shared_object() }
+}
+
+/// Returns whether the static initializer of the shim allocated from snmalloc successfully
+/// before `main`.
+///
+/// A `false` from `main` onwards means the initializer did not run, e.g. without the
+/// `early-init` feature, with `SNMALLOC_DISABLE=1`, or because the platform runs no C++ static
+/// initializers; snmalloc then initialises itself on the first allocation.
+#[inline(always)]
+pub fn initialized_before_main() -> bool {
+    unsafe { ffi::sn_rust_initialized_before_main() }
+}
+
+/// Reports snmalloc running from a shared object without having been built for it.
+///
+/// Meant to be called from the initialisation routine of a plugin, where the error can still
+/// be surfaced to the host; a misconfigured library may crash on its first allocation instead.
+pub fn self_check() -> Result<(), &'static str> {
+    match is_shared_object() && !built_for_dynamic_loading() {
+        true => Err("snmalloc is loaded from a shared object but was built with initial-exec TLS; \
+                     enable the `dynamic-loading` feature or set SNMALLOC_DYNAMIC_LOADING=1"),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    static ALLOCATED_BEFORE_MAIN: AtomicBool = AtomicBool::new(false);
+
+    /// What `#[ctor]` expands to.
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "netbsd"))]
+    #[used]
+    #[link_section = ".init_array"]
+    static CTOR: extern "C" fn() = allocate_before_main;
+
+    extern "C" fn allocate_before_main() {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            let ptr = crate::SnMalloc.alloc(layout);
+            if !ptr.is_null() {
+                ptr.write_bytes(1, layout.size());
+                crate::SnMalloc.dealloc(ptr, layout);
+                ALLOCATED_BEFORE_MAIN.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[test]
+    fn it_allocates_before_main() {
+        #[cfg(feature = "early-init")]
+        assert!(initialized_before_main());
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "netbsd"))]
+        assert!(ALLOCATED_BEFORE_MAIN.load(Ordering::Relaxed));
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "netbsd")))]
+        allocate_before_main();
+    }
+
+    #[test]
+    fn it_runs_from_the_main_program() {
+        assert!(!is_shared_object());
+        assert_eq!(self_check(), Ok(()));
+    }
+}
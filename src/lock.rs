@@ -0,0 +1,38 @@
+//! Locking of allocations in memory, for [locked handles](crate::SnAllocator::new_locked) and
+//! the global allocator with the `lock-memory` feature.
+//!
+//! The pages spanned by each allocation of a locked handle are locked (`mlock`, or `VirtualLock`
+//! on Windows) when it is handed out, so that secrets never reach swap. Pages are never unlocked:
+//! a page may be shared with other live allocations, and freed memory is reused by later ones. On
+//! Linux, locked pages cannot be decommitted either, so memory freed by a locked allocator stays
+//! resident.
+//!
+//! With the `lock-memory` feature, the shim locks instead all the memory snmalloc commits, once
+//! per commit, in its PAL: every allocation, of `SnMalloc` or otherwise, lands in locked pages
+//! without a system call of its own. Memory is unlocked as it is decommitted.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+static LOCKED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// Locks the pages of the `size` bytes at `ptr`, returning false if they could not be locked.
+#[cfg(feature = "handle-api")]
+#[inline(always)]
+pub(crate) fn lock(ptr: *mut u8, size: usize) -> bool {
+    if unsafe { ffi::sn_rust_lock_pages(ptr.cast(), size) } {
+        LOCKED_BYTES.fetch_add(size, Ordering::Relaxed);
+        true
+    } else {
+        FAILURES.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+}
+
+/// Returns the bytes locked and the locks that failed, by the handles and by the shim as it
+/// committed memory.
+#[inline(always)]
+pub(crate) fn totals() -> (usize, usize) {
+    let mut failures = 0;
+    let committed = unsafe { ffi::sn_rust_locked_commits(&mut failures) };
+    (LOCKED_BYTES.load(Ordering::Relaxed) + committed, FAILURES.load(Ordering::Relaxed) + failures)
+}
@@ -0,0 +1,77 @@
+//! Memory ceilings of a piece of code (`stats` feature).
+use crate::stats;
+
+/// Runs `f` and returns its result together with the peak of live bytes allocated through
+/// [`SnMalloc`](crate::SnMalloc) while it ran, on top of what was live when it started.
+///
+/// The high-water mark is process-wide: allocations made concurrently by other threads are
+/// counted as well, so measure on a quiet process. Each call tracks its own mark, so calls can
+/// be nested and made from several threads at once; beyond 64 calls running at the same time,
+/// the others return the peak since the start of the process, an upper bound.
+///
+/// ```rust
+/// #[global_allocator]
+/// static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
+///
+/// let (_, peak) = snmalloc_rs::measure::peak_during(|| {
+///     let _buffer = vec![0u8; 1 << 20];
+/// });
+/// assert!(peak >= 1 << 20);
+/// ```
+pub fn peak_during<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    /// Closes the scope even if `f` unwinds, so that its slot is not lost.
+    struct Scope(Option<usize>);
+
+    impl Scope {
+        fn peak(&mut self) -> usize {
+            self.0.take().map_or_else(stats::peak_bytes, stats::close_scope)
+        }
+    }
+
+    impl Drop for Scope {
+        fn drop(&mut self) {
+            self.peak();
+        }
+    }
+
+    let start = stats::live_bytes();
+    let mut scope = Scope(stats::open_scope(start));
+    let result = f();
+    (result, scope.peak().saturating_sub(start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::{GlobalAlloc, Layout};
+
+    #[test]
+    fn it_measures_the_peak() {
+        let layout = Layout::from_size_align(1 << 16, 8).unwrap();
+        let ((), peak) = peak_during(|| unsafe {
+            let a = crate::SnMalloc.alloc(layout);
+            let b = crate::SnMalloc.alloc(layout);
+            crate::SnMalloc.dealloc(a, layout);
+            crate::SnMalloc.dealloc(b, layout);
+        });
+        assert!(peak >= 2 << 16);
+    }
+
+    #[test]
+    fn it_measures_nested_and_concurrent_scopes() {
+        let layout = Layout::from_size_align(1 << 20, 8).unwrap();
+        let small = Layout::from_size_align(1 << 10, 8).unwrap();
+        let worker = std::thread::spawn(move || {
+            peak_during(|| unsafe { crate::SnMalloc.dealloc(crate::SnMalloc.alloc(small), small) }).1
+        });
+        let ((inner, worker), outer) = peak_during(|| unsafe {
+            let ptr = crate::SnMalloc.alloc(layout);
+            crate::SnMalloc.dealloc(ptr, layout);
+            let inner = peak_during(|| crate::SnMalloc.dealloc(crate::SnMalloc.alloc(small), small)).1;
+            (inner, worker.join().unwrap())
+        });
+        // The scopes closing meanwhile left the mark of the outer one in place.
+        assert!(outer >= 1 << 20);
+        assert!(inner >= 1 << 10 && worker >= 1 << 10);
+    }
+}
@@ -0,0 +1,152 @@
+//! Telemetry for allocation failures.
+//!
+//! The hook runs whenever snmalloc itself fails to serve a request through [`SnMalloc`], before
+//! the null pointer is returned. It sees failures that the caller recovers from (e.g. through
+//! `try_reserve`), which never reach `handle_alloc_error`. Requests rejected by
+//! [`set_max_alloc_size`](crate::set_max_alloc_size) are not reported.
+//!
+//! The OS error behind each failure is kept per thread, see [`last_os_error`].
+//!
+//! [`SnMalloc`]: crate::SnMalloc
+use core::{
+    alloc::Layout,
+    mem,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+static HOOK: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Sets the function called with the layout of every request snmalloc fails to serve, or
+/// removes it with `None`.
+///
+/// The hook runs inside the global allocator: it must not allocate, and should only do cheap
+/// work such as bumping a counter.
+#[inline(always)]
+pub fn set_alloc_failure_hook(hook: Option<fn(Layout)>) {
+    HOOK.store(hook.map_or(ptr::null_mut(), |hook| hook as *mut ()), Ordering::Release);
+}
+
+/// Returns the hook set by [`set_alloc_failure_hook`].
+#[inline(always)]
+pub fn alloc_failure_hook() -> Option<fn(Layout)> {
+    let hook = HOOK.load(Ordering::Acquire);
+    (!hook.is_null()).then(|| unsafe { mem::transmute::<*mut (), fn(Layout)>(hook) })
+}
+
+/// Returns the OS error (`errno`, or `GetLastError()` on Windows) observed when snmalloc last
+/// failed to serve a request of the current thread through [`SnMalloc`](crate::SnMalloc), or
+/// `None` if no failure came with one.
+///
+/// This tells an exhausted address space or overcommit limit (`ENOMEM`) from a sandbox denying
+/// the mapping (`EPERM`, `EACCES`) in logs. The error slot is cleared and the failed request
+/// tried once more, so the value kept was set by that very request: one rejected before reaching
+/// the OS records `None`. With the `std` feature, the code converts with
+/// `std::io::Error::from_raw_os_error`:
+///
+/// ```rust
+/// use core::alloc::{GlobalAlloc, Layout};
+/// let layout = Layout::from_size_align(1 << 60, 8).unwrap();
+/// if unsafe { snmalloc_rs::SnMalloc.alloc(layout) }.is_null() {
+///     if let Some(code) = snmalloc_rs::last_os_error() {
+///         eprintln!("allocation failed: {}", std::io::Error::from_raw_os_error(code));
+///     }
+/// }
+/// ```
+#[inline(always)]
+pub fn last_os_error() -> Option<i32> {
+    match unsafe { ffi::sn_rust_last_os_error() } {
+        0 => None,
+        code => Some(code),
+    }
+}
+
+/// Runs `alloc`, the request for `layout`. If it returns null, runs it again with the thread's
+/// error slot cleared, then records the OS error and reports `layout` to the hook if it still
+/// fails.
+///
+/// `errno` may still hold the value of any earlier libc call when the allocation fails without
+/// setting it. Clearing it ahead of every request would slow the allocation paths down, while
+/// retrying only costs a failing request, which does not change state.
+#[inline(always)]
+pub(crate) fn on_failure(layout: Layout, mut alloc: impl FnMut() -> *mut u8) -> *mut u8 {
+    let ptr = alloc();
+    if ptr.is_null() {
+        return retry(layout, alloc);
+    }
+    ptr
+}
+
+#[cold]
+#[inline(never)]
+fn retry(layout: Layout, mut alloc: impl FnMut() -> *mut u8) -> *mut u8 {
+    unsafe { ffi::sn_rust_clear_os_error() };
+    let ptr = alloc();
+    if ptr.is_null() {
+        unsafe { ffi::sn_rust_record_os_error() };
+        if let Some(hook) = alloc_failure_hook() {
+            hook(layout);
+        }
+    }
+    ptr
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{alloc::GlobalAlloc, sync::atomic::AtomicUsize};
+
+    use super::*;
+
+    static FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Failing size only requested by `it_reports_failed_allocations`, so that failures of the
+    /// other tests running meanwhile are not counted.
+    const COUNTED: usize = (1 << 60) + 1;
+
+    fn count(layout: Layout) {
+        if layout.size() == COUNTED {
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn it_reports_failed_allocations() {
+        set_alloc_failure_hook(Some(count));
+        let layout = Layout::from_size_align(COUNTED, 8).unwrap();
+        assert!(unsafe { crate::SnMalloc.alloc(layout) }.is_null());
+        assert!(unsafe { crate::SnMalloc.alloc_zeroed(layout) }.is_null());
+        set_alloc_failure_hook(None);
+        assert_eq!(FAILURES.load(Ordering::Relaxed), 2);
+        assert!(alloc_failure_hook().is_none());
+    }
+
+    #[test]
+    fn it_keeps_the_os_error_per_thread() {
+        std::thread::spawn(|| {
+            assert_eq!(last_os_error(), None);
+            let layout = Layout::from_size_align(1 << 60, 8).unwrap();
+            assert!(unsafe { crate::SnMalloc.alloc(layout) }.is_null());
+            let error = last_os_error();
+            // Later successful requests leave it in place.
+            let small = Layout::from_size_align(64, 8).unwrap();
+            unsafe { crate::SnMalloc.dealloc(crate::SnMalloc.alloc(small), small) };
+            assert_eq!(last_os_error(), error);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn it_ignores_stale_os_errors() {
+        std::thread::spawn(|| {
+            // Leaves `ENOENT` in `errno`, which the failing request must not report.
+            assert!(std::fs::File::open("/nonexistent/snmalloc-rs").is_err());
+            let layout = Layout::from_size_align(1 << 60, 8).unwrap();
+            assert!(unsafe { crate::SnMalloc.alloc(layout) }.is_null());
+            assert_ne!(last_os_error(), Some(2)); // ENOENT
+        })
+        .join()
+        .unwrap();
+    }
+}
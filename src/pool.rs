@@ -0,0 +1,103 @@
+//! Fixed, pre-committed memory pool behind [`SnAllocator::with_preallocated`](crate::SnAllocator::with_preallocated).
+//!
+//! The region is obtained from snmalloc once and touched up front, so that serving requests
+//! from it afterwards never faults in pages or calls into the OS. snmalloc does not know about
+//! the pool: blocks are carved out with a bump pointer and recycled through one free list per
+//! power-of-two size class, and a request that does not fit fails instead of growing the pool.
+//! Only the methods of the owning handle use it.
+use core::{alloc::Layout, cell::Cell, ptr::{self, NonNull}};
+
+/// Smallest block, large enough to hold the free list link.
+const MIN_BLOCK: usize = 16;
+const CLASSES: usize = (usize::BITS - MIN_BLOCK.trailing_zeros()) as usize;
+
+#[derive(Debug)]
+pub(crate) struct Pool {
+    base: NonNull<u8>,
+    size: usize,
+    next: Cell<usize>,
+    free: [Cell<*mut u8>; CLASSES],
+}
+
+impl Pool {
+    /// Alignment of the region, so that blocks up to a page are aligned without padding.
+    pub(crate) const ALIGN: usize = 4096;
+
+    /// Takes over `size` bytes at `base` and commits them by writing every page.
+    pub(crate) unsafe fn new(base: NonNull<u8>, size: usize) -> Self {
+        base.as_ptr().write_bytes(0, size);
+        Self {
+            base,
+            size,
+            next: Cell::new(0),
+            free: [const { Cell::new(ptr::null_mut()) }; CLASSES],
+        }
+    }
+
+    /// Returns the region, to be released by the owner once the pool is dropped.
+    pub(crate) fn region(&self) -> (NonNull<u8>, usize) {
+        (self.base, self.size)
+    }
+
+    #[inline(always)]
+    fn class(size: usize, align: usize) -> Option<usize> {
+        let block = size.max(align).max(MIN_BLOCK).checked_next_power_of_two()?;
+        Some((block.trailing_zeros() - MIN_BLOCK.trailing_zeros()) as usize)
+    }
+
+    #[inline(always)]
+    pub(crate) fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let class = Self::class(layout.size(), layout.align())?;
+        if let Some(block) = NonNull::new(self.free[class].get()) {
+            self.free[class].set(unsafe { block.as_ptr().cast::<*mut u8>().read() });
+            return Some(block);
+        }
+        let block = MIN_BLOCK << class;
+        let base = self.base.as_ptr() as usize;
+        let start = (base + self.next.get()).checked_next_multiple_of(block)? - base;
+        let end = start.checked_add(block)?;
+        if end > self.size {
+            return None;
+        }
+        self.next.set(end);
+        NonNull::new(unsafe { self.base.as_ptr().add(start) })
+    }
+
+    /// # Safety
+    /// `ptr` must have been allocated from this pool with the same `layout`.
+    #[inline(always)]
+    pub(crate) unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let class = Self::class(layout.size(), layout.align()).unwrap_unchecked();
+        ptr.as_ptr().cast::<*mut u8>().write(self.free[class].get());
+        self.free[class].set(ptr.as_ptr());
+    }
+
+    /// # Safety
+    /// `ptr` must have been allocated from this pool with the same `layout`.
+    #[inline(always)]
+    pub(crate) unsafe fn reallocate(&self, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> Option<NonNull<u8>> {
+        if Self::class(layout.size(), layout.align()) == Self::class(new_size, layout.align()) {
+            return Some(ptr);
+        }
+        let new = self.allocate(Layout::from_size_align_unchecked(new_size, layout.align()))?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new.as_ptr(), layout.size().min(new_size));
+        self.deallocate(ptr, layout);
+        Some(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_recycles_blocks_by_class() {
+        let mut region = [0u128; 64];
+        let pool = unsafe { Pool::new(NonNull::from(&mut region).cast(), 1024) };
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let a = pool.allocate(layout).unwrap();
+        unsafe { pool.deallocate(a, layout) };
+        assert_eq!(pool.allocate(layout), Some(a));
+        assert!(pool.allocate(Layout::from_size_align(2048, 8).unwrap()).is_none());
+    }
+}
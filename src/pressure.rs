@@ -0,0 +1,293 @@
+//! Reaction to memory pressure reported by the OS.
+//!
+//! Once a subscriber is registered, a background thread watches the memory-pressure signals of
+//! the OS: PSI triggers on `/proc/pressure/memory` on Linux (4.20+), low memory resource
+//! notifications on Windows, and the memory-pressure dispatch source on macOS and iOS. When the
+//! system is under pressure, every thread returns the memory cached by snmalloc to the global pool
+//! on its next free (see [`flush_thread_cache`](crate::flush_thread_cache)), critical pressure
+//! also empties the [large-object cache](crate::set_large_cache), and the subscribers are called.
+//!
+//! On iOS, Jetsam kills apps whose [`footprint`] goes over their limit, whether the memory is in
+//! use or only held by the allocator: reacting to the warnings keeps freed pages from counting.
+//!
+//! On other platforms, or to forward signals from an existing monitor, call [`signal`].
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use std::{
+    sync::{Mutex, Once},
+    vec::Vec,
+};
+
+/// Severity of the memory pressure.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Tasks are stalling on memory some of the time.
+    Moderate,
+    /// The system is about to run out of memory.
+    Critical,
+}
+
+/// Bumped on every signal; threads flush their cache when they see a new value.
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+static SUBSCRIBERS: Mutex<Vec<fn(Level)>> = Mutex::new(Vec::new());
+static WATCHER: Once = Once::new();
+
+std::thread_local! {
+    static SEEN: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Calls `callback` from the watcher thread whenever the OS reports memory pressure, starting
+/// the watcher if needed.
+pub fn subscribe(callback: fn(Level)) {
+    SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner()).push(callback);
+    WATCHER.call_once(|| {
+        if is_supported() {
+            let _ = std::thread::Builder::new().name("snmalloc-pressure".into()).spawn(watch);
+        }
+    });
+}
+
+/// Returns whether the OS memory-pressure signals are watched on this platform.
+pub const fn is_supported() -> bool {
+    cfg!(any(target_os = "linux", windows, target_vendor = "apple"))
+}
+
+/// Returns the physical footprint of the process, the metric the Jetsam limits of iOS apply to
+/// and the "Memory" column of Activity Monitor, or `None` on platforms other than Apple's.
+pub fn footprint() -> Option<usize> {
+    #[cfg(target_vendor = "apple")]
+    return apple::footprint();
+    #[cfg(not(target_vendor = "apple"))]
+    None
+}
+
+/// Reports memory pressure: every thread flushes its cache on its next free, and the
+/// subscribers are called from the current thread.
+pub fn signal(level: Level) {
+    EPOCH.fetch_add(1, Ordering::Relaxed);
+    crate::flush_thread_cache();
+    if level == Level::Critical {
+        // Frees every cached object; the cache refills up to its limit afterwards.
+        crate::sync::exclusive(|| unsafe {
+            ffi::sn_rust_set_large_cache(0);
+            ffi::sn_rust_set_large_cache(crate::large_cache());
+        });
+    }
+    let subscribers = SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    for subscriber in subscribers {
+        subscriber(level);
+    }
+}
+
+/// Flushes the cache of the current thread if pressure was signalled since its last flush.
+#[inline(always)]
+pub(crate) fn tick() {
+    let epoch = EPOCH.load(Ordering::Relaxed);
+    if epoch != 0 {
+        let _ = SEEN.try_with(|seen| {
+            if seen.get() != epoch {
+                seen.set(epoch);
+                crate::flush_thread_cache();
+            }
+        });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn watch() {
+    use std::{
+        fs::OpenOptions,
+        io::Write,
+        os::{fd::AsRawFd, raw::{c_int, c_short, c_ulong}},
+    };
+
+    #[repr(C)]
+    struct PollFd {
+        fd: c_int,
+        events: c_short,
+        revents: c_short,
+    }
+
+    extern "C" {
+        fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+    }
+
+    const POLLPRI: c_short = 0x2;
+    const POLLERR: c_short = 0x8;
+
+    // Stalls of 150ms (some tasks) or 100ms (all tasks) within a one second window.
+    let triggers = [(Level::Moderate, "some 150000 1000000"), (Level::Critical, "full 100000 1000000")];
+    let mut files = Vec::new();
+    for (level, trigger) in triggers {
+        let Ok(mut file) = OpenOptions::new().read(true).write(true).open("/proc/pressure/memory") else {
+            return;
+        };
+        if file.write_all(trigger.as_bytes()).is_err() {
+            return;
+        }
+        files.push((level, file));
+    }
+    let mut fds: Vec<PollFd> = files
+        .iter()
+        .map(|(_, file)| PollFd { fd: file.as_raw_fd(), events: POLLPRI, revents: 0 })
+        .collect();
+    loop {
+        if unsafe { poll(fds.as_mut_ptr(), fds.len() as c_ulong, -1) } < 0 {
+            continue;
+        }
+        if fds.iter().any(|fd| fd.revents & POLLERR != 0) {
+            return;
+        }
+        let triggered = fds.iter().zip(&files).filter(|(fd, _)| fd.revents & POLLPRI != 0);
+        if let Some(level) = triggered.map(|(_, (level, _))| *level).max() {
+            signal(level);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn watch() {
+    use core::ffi::c_void;
+
+    extern "system" {
+        fn CreateMemoryResourceNotification(kind: i32) -> *mut c_void;
+        fn WaitForSingleObject(handle: *mut c_void, milliseconds: u32) -> u32;
+    }
+
+    const LOW_MEMORY_RESOURCE_NOTIFICATION: i32 = 0;
+    const INFINITE: u32 = u32::MAX;
+    const WAIT_OBJECT_0: u32 = 0;
+
+    let handle = unsafe { CreateMemoryResourceNotification(LOW_MEMORY_RESOURCE_NOTIFICATION) };
+    if handle.is_null() {
+        return;
+    }
+    while unsafe { WaitForSingleObject(handle, INFINITE) } == WAIT_OBJECT_0 {
+        signal(Level::Critical);
+        // The notification stays signalled for as long as memory is low.
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
+
+#[cfg(target_vendor = "apple")]
+use apple::watch;
+
+#[cfg(target_vendor = "apple")]
+mod apple {
+    use core::ffi::c_void;
+
+    use super::{signal, Level};
+
+    #[repr(C)]
+    struct DispatchSourceType {
+        _private: [u8; 0],
+    }
+
+    extern "C" {
+        static _dispatch_source_type_memorypressure: DispatchSourceType;
+        fn dispatch_get_global_queue(identifier: isize, flags: usize) -> *mut c_void;
+        fn dispatch_source_create(
+            kind: *const DispatchSourceType,
+            handle: usize,
+            mask: usize,
+            queue: *mut c_void,
+        ) -> *mut c_void;
+        fn dispatch_set_context(object: *mut c_void, context: *mut c_void);
+        fn dispatch_source_set_event_handler_f(source: *mut c_void, handler: extern "C" fn(*mut c_void));
+        fn dispatch_source_get_data(source: *mut c_void) -> usize;
+        fn dispatch_resume(object: *mut c_void);
+
+        static mach_task_self_: u32;
+        fn task_info(task: u32, flavor: u32, info: *mut i32, count: *mut u32) -> i32;
+    }
+
+    const DISPATCH_MEMORYPRESSURE_WARN: usize = 0x2;
+    const DISPATCH_MEMORYPRESSURE_CRITICAL: usize = 0x4;
+    const QOS_CLASS_UTILITY: isize = 0x11;
+    const TASK_VM_INFO: u32 = 22;
+
+    extern "C" fn on_event(source: *mut c_void) {
+        let events = unsafe { dispatch_source_get_data(source) };
+        if events & DISPATCH_MEMORYPRESSURE_CRITICAL != 0 {
+            signal(Level::Critical);
+        } else if events & DISPATCH_MEMORYPRESSURE_WARN != 0 {
+            signal(Level::Moderate);
+        }
+    }
+
+    /// Registers the dispatch source, whose events are then delivered on a queue of the system:
+    /// the watcher thread returns at once. The source lives until the process exits.
+    pub(super) fn watch() {
+        unsafe {
+            let queue = dispatch_get_global_queue(QOS_CLASS_UTILITY, 0);
+            let mask = DISPATCH_MEMORYPRESSURE_WARN | DISPATCH_MEMORYPRESSURE_CRITICAL;
+            let source = dispatch_source_create(&_dispatch_source_type_memorypressure, 0, mask, queue);
+            if source.is_null() {
+                return;
+            }
+            dispatch_set_context(source, source);
+            dispatch_source_set_event_handler_f(source, on_event);
+            dispatch_resume(source);
+        }
+    }
+
+    /// The head of `task_vm_info_data_t`, up to the footprint added by its first revision.
+    #[repr(C, packed(4))]
+    #[derive(Default)]
+    struct TaskVmInfo {
+        virtual_size: u64,
+        region_count: i32,
+        page_size: i32,
+        /// From `resident_size` to `compressed_lifetime`.
+        counters: [u64; 16],
+        phys_footprint: u64,
+    }
+
+    pub(super) fn footprint() -> Option<usize> {
+        let mut info = TaskVmInfo::default();
+        let mut count = (core::mem::size_of::<TaskVmInfo>() / core::mem::size_of::<i32>()) as u32;
+        let result = unsafe { task_info(mach_task_self_, TASK_VM_INFO, (&mut info as *mut TaskVmInfo).cast(), &mut count) };
+        match result {
+            0 => Some(info.phys_footprint as usize),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows, target_vendor = "apple")))]
+fn watch() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static CRITICAL: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(level: Level) {
+        if level == Level::Critical {
+            CRITICAL.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn it_notifies_subscribers() {
+        subscribe(count);
+        let epoch = EPOCH.load(Ordering::Relaxed);
+        signal(Level::Critical);
+        assert!(EPOCH.load(Ordering::Relaxed) > epoch);
+        assert!(CRITICAL.load(Ordering::Relaxed) >= 1);
+        // The next free of this thread flushes its cache once.
+        tick();
+        assert_eq!(SEEN.with(Cell::get), EPOCH.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn it_reports_the_footprint_on_apple_platforms() {
+        assert_eq!(footprint().is_some(), cfg!(target_vendor = "apple"));
+        if let Some(footprint) = footprint() {
+            assert!(footprint > 0);
+        }
+    }
+}
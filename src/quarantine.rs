@@ -0,0 +1,277 @@
+//! Quarantine of freed memory, to catch use-after-free in staging (`quarantine` feature).
+//!
+//! Blocks freed through [`SnMalloc`](crate::SnMalloc) are not handed back to snmalloc right
+//! away: they are filled with [`fill::FREED`](crate::fill::FREED) and queued, oldest first, until
+//! the queue exceeds its [capacity](set_capacity) or the block exceeds the [maximum
+//! age](set_max_age). Until then, their memory cannot be reused, so a dangling pointer keeps
+//! reading the poison instead of someone else's data. When a block leaves the quarantine, the
+//! poison is checked, and a write through a dangling pointer aborts the process naming the
+//! block.
+//!
+//! With [`set_protect`], blocks spanning whole pages are also made inaccessible while
+//! quarantined, so that any access through a dangling pointer faults on the spot.
+use core::{
+    alloc::Layout,
+    mem, ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use std::time::Instant;
+
+use crate::{fill, sync::{self, SpinLock}};
+
+/// Capacity used unless [`set_capacity`] is called.
+pub const DEFAULT_CAPACITY: usize = 16 << 20;
+
+static CAPACITY: AtomicUsize = AtomicUsize::new(DEFAULT_CAPACITY);
+/// Maximum age in milliseconds, `usize::MAX` meaning unbounded.
+static MAX_AGE_MS: AtomicUsize = AtomicUsize::new(usize::MAX);
+static PROTECT: AtomicBool = AtomicBool::new(false);
+
+static QUEUE: SpinLock<Queue> = SpinLock::new(Queue::new());
+
+/// Sets how many freed bytes may sit in quarantine (16MiB by default), releasing the oldest
+/// blocks above the new capacity. `0` disables the quarantine.
+pub fn set_capacity(bytes: usize) {
+    CAPACITY.store(bytes, Ordering::Relaxed);
+    evict(None);
+}
+
+/// Returns the capacity set by [`set_capacity`].
+#[inline(always)]
+pub fn capacity() -> usize {
+    CAPACITY.load(Ordering::Relaxed)
+}
+
+/// Sets how long a freed block may sit in quarantine, or lifts the limit with `None` (the
+/// default). Expired blocks are released on the next free; blocks freed before the limit was set
+/// count as expired.
+pub fn set_max_age(age: Option<Duration>) {
+    let ms = age.map_or(usize::MAX, |age| age.as_millis().min(usize::MAX as u128 - 1) as usize);
+    MAX_AGE_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Returns the age set by [`set_max_age`].
+pub fn max_age() -> Option<Duration> {
+    match MAX_AGE_MS.load(Ordering::Relaxed) {
+        usize::MAX => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    }
+}
+
+/// Makes blocks of whole pages inaccessible while they are quarantined. This costs two
+/// `mprotect`/`VirtualProtect` calls per such block; smaller blocks are only poisoned.
+#[inline(always)]
+pub fn set_protect(enabled: bool) {
+    PROTECT.store(enabled, Ordering::Relaxed);
+}
+
+/// Occupancy of the quarantine, see [`stats`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct QuarantineStats {
+    /// Blocks currently quarantined.
+    pub blocks: usize,
+    /// Bytes currently quarantined.
+    pub bytes: usize,
+    /// Blocks released to snmalloc since the start of the process.
+    pub released: usize,
+}
+
+/// Returns the occupancy of the quarantine.
+pub fn stats() -> QuarantineStats {
+    let queue = QUEUE.lock();
+    QuarantineStats { blocks: queue.len, bytes: queue.bytes, released: queue.released }
+}
+
+/// Releases every quarantined block, e.g. before a leak check.
+pub fn flush() {
+    while let Some(block) = QUEUE.lock().pop() {
+        unsafe { release(block) };
+    }
+}
+
+/// Quarantines the block at `ptr`, returning false if it must be released right away.
+#[inline(always)]
+pub(crate) unsafe fn hold(ptr: *mut u8, layout: Layout) -> bool {
+    if layout.size() == 0 || layout.size() > capacity() {
+        return false;
+    }
+    hold_slow(ptr, layout)
+}
+
+/// Returns whether freed blocks are currently quarantined.
+#[inline(always)]
+pub(crate) fn enabled() -> bool {
+    capacity() != 0
+}
+
+#[inline(never)]
+unsafe fn hold_slow(ptr: *mut u8, layout: Layout) -> bool {
+    ptr.write_bytes(fill::FREED, layout.size());
+    let protected = PROTECT.load(Ordering::Relaxed) && ffi::sn_rust_protect_no_access(ptr.cast(), layout.size(), true);
+    let freed = (MAX_AGE_MS.load(Ordering::Relaxed) != usize::MAX).then(Instant::now);
+    let block = Block { ptr, size: layout.size(), align: layout.align(), protected, freed };
+    if !QUEUE.lock().push(block) {
+        unprotect(&block);
+        return false;
+    }
+    evict(freed);
+    true
+}
+
+/// Releases the oldest blocks while the quarantine is over capacity, or older than the maximum
+/// age measured from `now`.
+fn evict(now: Option<Instant>) {
+    let max_age = max_age();
+    loop {
+        let block = {
+            let mut queue = QUEUE.lock();
+            // Blocks quarantined before the age limit was set have no time and go first.
+            let expired = match (queue.front(), now, max_age) {
+                (Some(block), Some(now), Some(age)) => block.freed.is_none_or(|freed| now.duration_since(freed) > age),
+                _ => false,
+            };
+            if queue.bytes <= capacity() && !expired {
+                return;
+            }
+            match queue.pop() {
+                Some(block) => block,
+                None => return,
+            }
+        };
+        unsafe { release(block) };
+    }
+}
+
+unsafe fn release(block: Block) {
+    unprotect(&block);
+    let bytes = core::slice::from_raw_parts(block.ptr, block.size);
+    if let Some(offset) = bytes.iter().position(|b| *b != fill::FREED) {
+        std::eprintln!(
+            "snmalloc: use-after-free write detected at offset {} of the {}-byte block at {:p}",
+            offset,
+            block.size,
+            block.ptr
+        );
+        std::process::abort();
+    }
+    crate::release(block.ptr, Layout::from_size_align_unchecked(block.size, block.align), crate::tuning::knobs());
+}
+
+fn unprotect(block: &Block) {
+    if block.protected {
+        unsafe { ffi::sn_rust_protect_no_access(block.ptr.cast(), block.size, false) };
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Block {
+    ptr: *mut u8,
+    size: usize,
+    align: usize,
+    protected: bool,
+    freed: Option<Instant>,
+}
+
+/// FIFO ring of quarantined blocks, backed by raw shim allocations so that it never re-enters
+/// the allocator feeding it.
+struct Queue {
+    blocks: *mut Block,
+    capacity: usize,
+    head: usize,
+    len: usize,
+    bytes: usize,
+    released: usize,
+}
+
+unsafe impl Send for Queue {}
+
+impl Queue {
+    const fn new() -> Self {
+        Self { blocks: ptr::null_mut(), capacity: 0, head: 0, len: 0, bytes: 0, released: 0 }
+    }
+
+    fn front(&self) -> Option<&Block> {
+        (self.len != 0).then(|| unsafe { &*self.blocks.add(self.head) })
+    }
+
+    fn push(&mut self, block: Block) -> bool {
+        if self.len == self.capacity && !self.grow() {
+            return false;
+        }
+        unsafe { self.blocks.add((self.head + self.len) % self.capacity).write(block) };
+        self.len += 1;
+        self.bytes += block.size;
+        true
+    }
+
+    fn pop(&mut self) -> Option<Block> {
+        let block = *self.front()?;
+        self.head = (self.head + 1) % self.capacity;
+        self.len -= 1;
+        self.bytes -= block.size;
+        self.released += 1;
+        Some(block)
+    }
+
+    fn grow(&mut self) -> bool {
+        let capacity = (self.capacity * 2).max(256);
+        let (align, size) = (mem::align_of::<Block>(), mem::size_of::<Block>());
+        let blocks: *mut Block = sync::exclusive(|| unsafe { ffi::sn_rust_alloc(align, capacity * size) }).cast();
+        if blocks.is_null() {
+            return false;
+        }
+        for i in 0..self.len {
+            unsafe { blocks.add(i).write(self.blocks.add((self.head + i) % self.capacity).read()) };
+        }
+        if !self.blocks.is_null() {
+            sync::exclusive(|| unsafe { ffi::sn_rust_dealloc(self.blocks.cast(), align, self.capacity * size) });
+        }
+        self.blocks = blocks;
+        self.capacity = capacity;
+        self.head = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::GlobalAlloc;
+
+    #[test]
+    fn it_delays_reuse() {
+        set_protect(true);
+        let layout = Layout::from_size_align(4096, 4096).unwrap();
+        unsafe {
+            let ptr = crate::SnMalloc.alloc(layout);
+            crate::SnMalloc.dealloc(ptr, layout);
+            // The block is still quarantined, so it cannot come back.
+            let other = crate::SnMalloc.alloc(layout);
+            assert_ne!(ptr, other);
+            crate::SnMalloc.dealloc(other, layout);
+        }
+        let released = stats().released;
+        set_max_age(Some(Duration::ZERO));
+        let small = Layout::from_size_align(64, 8).unwrap();
+        unsafe { crate::SnMalloc.dealloc(crate::SnMalloc.alloc(small), small) };
+        set_max_age(None);
+        // Expired blocks, including the protected ones, were checked and released.
+        assert!(stats().released >= released + 2);
+        set_protect(false);
+    }
+
+    #[test]
+    fn it_keeps_blocks_in_order() {
+        let mut queue = Queue::new();
+        let block = |size| Block { ptr: ptr::null_mut(), size, align: 8, protected: false, freed: None };
+        for size in 1..=300 {
+            assert!(queue.push(block(size)));
+            if size % 2 == 0 {
+                queue.pop();
+            }
+        }
+        assert_eq!((queue.len, queue.front().map(|b| b.size)), (150, Some(151)));
+        assert_eq!(queue.bytes, (151..=300).sum::<usize>());
+    }
+}
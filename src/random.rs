@@ -0,0 +1,101 @@
+//! Randomisation of the layout of the heap (`randomize` feature).
+//!
+//! Heap-grooming exploits rely on predicting which block the next allocation gets. With this
+//! feature, the shim is built with snmalloc's randomisation mitigations: free lists are shuffled
+//! when built, and objects and slabs are randomly kept back from reuse. On top of this,
+//! [`SnMalloc`](crate::SnMalloc) pads every large allocation (above 64KiB) with a random slack of
+//! up to a quarter of its size, which moves it to the next larger block when it crosses the size
+//! of its block. Padded blocks are freed without their size and bypass the
+//! [large-object cache](crate::set_large_cache).
+//!
+//! The padding is drawn from a generator seeded from the platform's entropy; [`set_seed`] makes
+//! it reproducible when debugging. snmalloc always seeds its own randomisation from the
+//! platform. Large allocations are not padded with `guard-large-allocs`, whose guard pages
+//! already set them apart.
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// The largest size served from slabs, whose layout snmalloc randomises itself.
+const MAX_SLAB_SIZE: usize = 1 << 16;
+
+static STATE: AtomicUsize = AtomicUsize::new(0);
+static SEEDED: AtomicBool = AtomicBool::new(false);
+
+/// Seeds the generator of the padding of large allocations, so that a run can be reproduced.
+/// The sequence also depends on the order in which threads allocate.
+#[inline(always)]
+pub fn set_seed(seed: u64) {
+    STATE.store(seed as usize, Ordering::Relaxed);
+    SEEDED.store(true, Ordering::Relaxed);
+}
+
+/// Returns the next number of the generator, a SplitMix sequence over a shared counter.
+#[inline]
+fn next() -> u64 {
+    if !SEEDED.load(Ordering::Relaxed) {
+        seed_from_platform();
+    }
+    let mut z = STATE.fetch_add(0x9E37_79B9_7F4A_7C15_u64 as usize, Ordering::Relaxed) as u64;
+    z = z.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Seeds the generator on first use, mixing in the address of the state for platforms without
+/// entropy.
+#[cold]
+fn seed_from_platform() {
+    if SEEDED.compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+        let entropy = unsafe { ffi::sn_rust_entropy() } ^ (&STATE as *const AtomicUsize as u64);
+        STATE.store(entropy as usize, Ordering::Relaxed);
+    }
+}
+
+#[inline(always)]
+pub(crate) fn pads(size: usize) -> bool {
+    cfg!(not(feature = "guard-large-allocs")) && size > MAX_SLAB_SIZE
+}
+
+#[inline(always)]
+pub(crate) unsafe fn alloc(layout: Layout, zero: bool) -> *mut u8 {
+    let size = layout.size() + (next() % (layout.size() as u64 / 4 + 1)) as usize;
+    crate::sync::exclusive(|| match zero {
+        true => ffi::sn_rust_alloc_zeroed(layout.align(), size),
+        false => ffi::sn_rust_alloc(layout.align(), size),
+    })
+    .cast()
+}
+
+#[inline(always)]
+pub(crate) unsafe fn dealloc(ptr: *mut u8) {
+    crate::sync::exclusive(|| ffi::sn_rust_free(ptr.cast()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SnMalloc;
+    use core::alloc::GlobalAlloc;
+
+    #[test]
+    fn it_pads_large_allocations() {
+        // 240KiB, padded to a 256KiB or a 512KiB block.
+        let layout = Layout::from_size_align(240 << 10, 64).unwrap();
+        let mut sizes = std::vec::Vec::new();
+        set_seed(42);
+        for _ in 0..32 {
+            let ptr = unsafe { SnMalloc.alloc(layout) };
+            assert_eq!(ptr as usize % 64, 0);
+            unsafe { ptr.write_bytes(0xAA, layout.size()) };
+            sizes.push(SnMalloc.usable_size(ptr).unwrap());
+            unsafe { SnMalloc.dealloc(ptr, layout) };
+        }
+        assert!(sizes.iter().all(|size| *size >= layout.size()));
+        if cfg!(not(feature = "guard-large-allocs")) {
+            assert!(sizes.iter().any(|size| *size != sizes[0]));
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! The thinnest possible global allocator, for crates composing allocators.
+//!
+//! [`SnMallocRaw`] forwards every `GlobalAlloc` call straight to the snmalloc shim: no layout
+//! checks, no statistics, no limits and no hooks, so each method compiles down to a single call
+//! without branches, panics or formatting (made inside a critical section with the
+//! `critical-section` feature). Everything the other allocators of this crate check is left to
+//! the caller, exactly as the `GlobalAlloc` contract requires (in particular, sizes must be
+//! non-zero).
+use core::alloc::{GlobalAlloc, Layout};
+
+use crate::sync;
+
+/// A zero-sized, branch-free delegation to snmalloc's thread-local allocator.
+///
+/// Memory is interchangeable with [`SnMalloc`](crate::SnMalloc), except that it is not seen by
+/// the `stats` feature nor by [`set_max_alloc_size`](crate::set_max_alloc_size).
+#[derive(Debug, Copy, Clone, Default)]
+#[repr(transparent)]
+pub struct SnMallocRaw;
+
+unsafe impl GlobalAlloc for SnMallocRaw {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        sync::exclusive(|| ffi::sn_rust_alloc(layout.align(), layout.size())).cast()
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        sync::exclusive(|| ffi::sn_rust_dealloc(ptr.cast(), layout.align(), layout.size()));
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        sync::exclusive(|| ffi::sn_rust_alloc_zeroed(layout.align(), layout.size())).cast()
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        sync::exclusive(|| ffi::sn_rust_realloc(ptr.cast(), layout.align(), layout.size(), new_size)).cast()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_allocation_lifecycle() {
+        unsafe {
+            let layout = Layout::from_size_align(8, 8).unwrap();
+            let ptr = SnMallocRaw.alloc_zeroed(layout);
+            assert_eq!(*ptr, 0);
+            let ptr = SnMallocRaw.realloc(ptr, layout, 1 << 20);
+            // The memory is interchangeable with `SnMalloc`.
+            crate::SnMalloc.dealloc(ptr, Layout::from_size_align(1 << 20, 8).unwrap());
+        }
+    }
+
+    /// Fails to link if any path through the allocator may panic, like the `no_panic` crate.
+    /// Only meaningful with optimizations: run with `cargo test --release`.
+    #[cfg(not(debug_assertions))]
+    #[test]
+    fn raw_allocation_cannot_panic() {
+        struct NoPanic;
+
+        impl Drop for NoPanic {
+            fn drop(&mut self) {
+                extern "C" {
+                    fn snmalloc_raw_may_panic() -> !;
+                }
+                unsafe { snmalloc_raw_may_panic() }
+            }
+        }
+
+        #[inline(never)]
+        fn round_trip(layout: Layout) {
+            let guard = NoPanic;
+            unsafe {
+                let ptr = SnMallocRaw.alloc(layout);
+                let ptr = SnMallocRaw.realloc(ptr, layout, 2 * layout.size());
+                SnMallocRaw.dealloc(ptr, Layout::from_size_align_unchecked(2 * layout.size(), layout.align()));
+            }
+            core::mem::forget(guard);
+        }
+
+        round_trip(Layout::from_size_align(64, 16).unwrap());
+    }
+}
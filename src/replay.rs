@@ -0,0 +1,490 @@
+//! Deterministic replay of allocation logs (`replay` feature).
+//!
+//! [`SnMallocReplay`] wraps [`SnMalloc`] and, while recording, logs every operation: its kind,
+//! size, alignment, the addresses involved and the ordinal of the calling thread. The log goes to
+//! a ring pre-allocated by [`record_to_ring`], keeping the latest operations, or to a file opened
+//! by [`record_to_file`]. [`replay`] then runs a log against fresh allocator handles, one per
+//! recorded thread, so that fragmentation or a corruption seen in production can be reproduced
+//! offline, e.g. with the `replay` example:
+//!
+//! ```text
+//! cargo run --release --example replay --features replay -- allocations.log
+//! ```
+//!
+//! Records are ordered by a global sequence number, claimed before a block is freed and after it
+//! is allocated, so that a replayed free always precedes the reuse of its address.
+//!
+//! ```rust,no_run
+//! #[global_allocator]
+//! static ALLOC: snmalloc_rs::replay::SnMallocReplay = snmalloc_rs::replay::SnMallocReplay::new();
+//!
+//! snmalloc_rs::replay::record_to_file("allocations.log").unwrap();
+//! // ... reproduce the issue ...
+//! snmalloc_rs::replay::stop();
+//! ```
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, AtomicU16, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    sync::Mutex,
+    vec::Vec,
+};
+
+use crate::{sync, SnAllocator, SnMalloc};
+
+/// Kind of a recorded operation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Op {
+    Alloc = 0,
+    AllocZeroed = 1,
+    Dealloc = 2,
+    Realloc = 3,
+}
+
+/// One operation of the log.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Position of the operation in the log.
+    pub seq: u64,
+    pub op: Op,
+    /// Ordinal of the thread, in the order threads first allocated while recording, or
+    /// [`UNKNOWN_THREAD`] for threads being torn down.
+    pub thread: u16,
+    pub align: usize,
+    /// The size requested, the new size for [`Op::Realloc`].
+    pub size: usize,
+    /// The block returned, or freed by [`Op::Dealloc`]; `0` if the allocation failed.
+    pub ptr: usize,
+    /// The block reallocated by [`Op::Realloc`], `0` for the other operations.
+    pub old: usize,
+}
+
+/// Ordinal of the threads whose thread-locals are already destroyed.
+pub const UNKNOWN_THREAD: u16 = u16::MAX;
+
+/// Size of an encoded record.
+pub const RECORD_SIZE: usize = 40;
+
+/// First bytes of a log file.
+const MAGIC: &[u8; 8] = b"snreplay";
+
+impl Record {
+    /// Encodes the record as little-endian bytes, independent from the recording platform.
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0] = self.op as u8;
+        bytes[1] = self.align.trailing_zeros() as u8;
+        bytes[2..4].copy_from_slice(&self.thread.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[16..24].copy_from_slice(&(self.size as u64).to_le_bytes());
+        bytes[24..32].copy_from_slice(&(self.ptr as u64).to_le_bytes());
+        bytes[32..40].copy_from_slice(&(self.old as u64).to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a record written by [`to_bytes`](Self::to_bytes), returning `None` if it is
+    /// malformed.
+    pub fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let word = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let op = match bytes[0] {
+            0 => Op::Alloc,
+            1 => Op::AllocZeroed,
+            2 => Op::Dealloc,
+            3 => Op::Realloc,
+            _ => return None,
+        };
+        Some(Self {
+            seq: word(8),
+            op,
+            thread: u16::from_le_bytes([bytes[2], bytes[3]]),
+            align: 1usize.checked_shl(bytes[1] as u32)?,
+            size: usize::try_from(word(16)).ok()?,
+            ptr: usize::try_from(word(24)).ok()?,
+            old: usize::try_from(word(32)).ok()?,
+        })
+    }
+}
+
+const OFF: u8 = 0;
+const RING: u8 = 1;
+const FILE: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(OFF);
+static SEQ: AtomicU64 = AtomicU64::new(0);
+static NEXT_THREAD: AtomicU16 = AtomicU16::new(0);
+static RING_BASE: AtomicPtr<Record> = AtomicPtr::new(ptr::null_mut());
+static RING_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static SINK: Mutex<Option<FileSink>> = Mutex::new(None);
+
+std::thread_local! {
+    /// Ordinal of the thread plus one, `0` until its first recorded operation.
+    static THREAD: Cell<u16> = const { Cell::new(0) };
+}
+
+/// Records buffered before being written to the log file, so that the file is not written by
+/// every operation. Lives in a static: recording never allocates.
+struct FileSink {
+    file: File,
+    buffer: [u8; RECORD_SIZE * 128],
+    len: usize,
+}
+
+impl FileSink {
+    fn push(&mut self, record: &Record) {
+        self.buffer[self.len..self.len + RECORD_SIZE].copy_from_slice(&record.to_bytes());
+        self.len += RECORD_SIZE;
+        if self.len == self.buffer.len() {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        // A failed write loses records rather than failing the allocation.
+        let _ = self.file.write_all(&self.buffer[..self.len]);
+        self.len = 0;
+    }
+}
+
+/// Starts recording into a ring of the last `capacity` operations, replacing the previous ring.
+/// Returns false if a recording is in progress or the ring cannot be allocated.
+pub fn record_to_ring(capacity: usize) -> bool {
+    let Ok(layout) = Layout::array::<Record>(capacity.max(1)) else {
+        return false;
+    };
+    if MODE.load(Ordering::Relaxed) != OFF {
+        return false;
+    }
+    release_ring();
+    // Allocated from the shim directly, so that the ring is not part of the log.
+    let base = sync::exclusive(|| unsafe { ffi::sn_rust_alloc(layout.align(), layout.size()) }).cast::<Record>();
+    if base.is_null() {
+        return false;
+    }
+    let empty = Record { seq: u64::MAX, op: Op::Alloc, thread: 0, align: 1, size: 0, ptr: 0, old: 0 };
+    for slot in 0..capacity.max(1) {
+        unsafe { base.add(slot).write(empty) };
+    }
+    RING_CAPACITY.store(capacity.max(1), Ordering::Relaxed);
+    RING_BASE.store(base, Ordering::Relaxed);
+    start(RING);
+    true
+}
+
+/// Starts recording into the file at `path`, which is truncated.
+pub fn record_to_file(path: impl AsRef<Path>) -> io::Result<()> {
+    if MODE.load(Ordering::Relaxed) != OFF {
+        return Err(io::Error::other("a recording is in progress"));
+    }
+    let mut file = File::create(path)?;
+    file.write_all(MAGIC)?;
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some(FileSink { file, buffer: [0; RECORD_SIZE * 128], len: 0 });
+    start(FILE);
+    Ok(())
+}
+
+fn start(mode: u8) {
+    SEQ.store(0, Ordering::Relaxed);
+    MODE.store(mode, Ordering::Release);
+}
+
+/// Stops recording, writing out the records buffered for the log file. The ring is kept for
+/// [`ring_records`].
+pub fn stop() {
+    if MODE.swap(OFF, Ordering::AcqRel) == FILE {
+        if let Some(mut sink) = SINK.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            sink.flush();
+        }
+    }
+}
+
+/// Returns whether operations are being recorded.
+#[inline(always)]
+pub fn is_recording() -> bool {
+    MODE.load(Ordering::Relaxed) != OFF
+}
+
+/// Returns the operations held by the ring, oldest first. The ring must no longer be recorded
+/// to, see [`stop`].
+pub fn ring_records() -> Vec<Record> {
+    let base = RING_BASE.load(Ordering::Acquire);
+    let capacity = RING_CAPACITY.load(Ordering::Relaxed) as u64;
+    if base.is_null() {
+        return Vec::new();
+    }
+    let end = SEQ.load(Ordering::Acquire);
+    // Slots not written yet, or already overwritten, hold another sequence number.
+    (end.saturating_sub(capacity)..end)
+        .map(|seq| (seq, unsafe { base.add((seq % capacity) as usize).read() }))
+        .filter(|(seq, record)| record.seq == *seq)
+        .map(|(_, record)| record)
+        .collect()
+}
+
+fn release_ring() {
+    let base = RING_BASE.swap(ptr::null_mut(), Ordering::AcqRel);
+    if !base.is_null() {
+        let layout = Layout::array::<Record>(RING_CAPACITY.load(Ordering::Relaxed)).unwrap();
+        sync::exclusive(|| unsafe { ffi::sn_rust_dealloc(base.cast(), layout.align(), layout.size()) });
+    }
+}
+
+/// Writes `records` in the format of the log files.
+pub fn write_log(mut writer: impl Write, records: &[Record]) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    for record in records {
+        writer.write_all(&record.to_bytes())?;
+    }
+    Ok(())
+}
+
+/// Reads a log file, ordered by sequence number.
+pub fn read_log(mut reader: impl Read) -> io::Result<Vec<Record>> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not an allocation log"));
+    }
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    // A log cut short by a crash ends with a partial record, which is dropped.
+    let mut records = bytes
+        .chunks_exact(RECORD_SIZE)
+        .map(|chunk| Record::from_bytes(chunk.try_into().unwrap()).ok_or_else(|| invalid("malformed record")))
+        .collect::<io::Result<Vec<_>>>()?;
+    records.sort_by_key(|record| record.seq);
+    Ok(records)
+}
+
+/// Outcome of [`replay`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Replay {
+    /// Operations replayed.
+    pub operations: usize,
+    /// Frees and reallocations of blocks allocated before the log starts, e.g. overwritten in
+    /// the ring; reallocations are replayed as allocations.
+    pub unmatched: usize,
+    /// Allocations that succeeded when recorded but failed when replayed.
+    pub failed: usize,
+    /// Largest sum of the sizes of the live blocks.
+    pub peak_live_bytes: usize,
+    /// Blocks still live at the end of the log, freed by `replay`.
+    pub leaked: usize,
+}
+
+/// Replays `records`, ordered by sequence number, against fresh allocator handles: one per
+/// recorded thread, so that blocks freed by another thread than the one allocating them go
+/// through snmalloc's remote frees as when recorded. Recording must be stopped.
+pub fn replay(records: &[Record]) -> Replay {
+    let mut handles: HashMap<u16, SnAllocator> = HashMap::new();
+    // Recorded address to the replayed block, its layout and the thread owning it.
+    let mut blocks: HashMap<usize, (NonNull<u8>, Layout, u16)> = HashMap::new();
+    let mut report = Replay::default();
+    let mut live_bytes = 0;
+    for record in records {
+        report.operations += 1;
+        let Some(handle) = handle_of(&mut handles, record.thread) else {
+            report.failed += 1;
+            continue;
+        };
+        let layout = match Layout::from_size_align(record.size, record.align) {
+            Ok(layout) => layout,
+            Err(_) => continue,
+        };
+        match record.op {
+            Op::Dealloc => match blocks.remove(&record.ptr) {
+                Some((block, layout, _)) => {
+                    unsafe { handle.deallocate(block, layout) };
+                    live_bytes -= layout.size();
+                }
+                None => report.unmatched += 1,
+            },
+            _ if record.ptr == 0 => {}
+            Op::Alloc | Op::AllocZeroed | Op::Realloc => {
+                let previous = match record.op {
+                    Op::Realloc => blocks.remove(&record.old),
+                    _ => None,
+                };
+                if record.op == Op::Realloc && previous.is_none() {
+                    report.unmatched += 1;
+                }
+                let block = match previous {
+                    // Blocks are only reallocated in place by the handle owning them.
+                    Some((block, old, owner)) if owner == record.thread => {
+                        live_bytes -= old.size();
+                        unsafe { handle.reallocate(block, old, record.size) }
+                    }
+                    Some((block, old, _)) => {
+                        live_bytes -= old.size();
+                        let new = handle.allocate(layout);
+                        if let Some(new) = new {
+                            unsafe {
+                                ptr::copy_nonoverlapping(block.as_ptr(), new.as_ptr(), old.size().min(layout.size()));
+                                handle.deallocate(block, old);
+                            }
+                        }
+                        new
+                    }
+                    None if record.op == Op::AllocZeroed => handle.allocate_zeroed(layout),
+                    None => handle.allocate(layout),
+                };
+                match block {
+                    Some(block) => {
+                        live_bytes += layout.size();
+                        report.peak_live_bytes = report.peak_live_bytes.max(live_bytes);
+                        blocks.insert(record.ptr, (block, layout, record.thread));
+                    }
+                    None => report.failed += 1,
+                }
+            }
+        }
+    }
+    report.leaked = blocks.len();
+    for (block, layout, owner) in blocks.into_values() {
+        unsafe { handles[&owner].deallocate(block, layout) };
+    }
+    report
+}
+
+fn handle_of(handles: &mut HashMap<u16, SnAllocator>, thread: u16) -> Option<&SnAllocator> {
+    match handles.entry(thread) {
+        Entry::Occupied(entry) => Some(entry.into_mut()),
+        Entry::Vacant(entry) => Some(entry.insert(SnAllocator::new()?)),
+    }
+}
+
+#[inline(always)]
+fn next_seq() -> u64 {
+    SEQ.fetch_add(1, Ordering::Relaxed)
+}
+
+fn thread_ordinal() -> u16 {
+    THREAD
+        .try_with(|thread| {
+            if thread.get() == 0 {
+                thread.set(NEXT_THREAD.fetch_add(1, Ordering::Relaxed).wrapping_add(1).max(1));
+            }
+            thread.get() - 1
+        })
+        .unwrap_or(UNKNOWN_THREAD)
+}
+
+#[cold]
+fn log(seq: u64, op: Op, layout: Layout, size: usize, ptr: *mut u8, old: *mut u8) {
+    let record = Record { seq, op, thread: thread_ordinal(), align: layout.align(), size, ptr: ptr as usize, old: old as usize };
+    match MODE.load(Ordering::Acquire) {
+        RING => {
+            let base = RING_BASE.load(Ordering::Acquire);
+            let capacity = RING_CAPACITY.load(Ordering::Relaxed) as u64;
+            if !base.is_null() {
+                unsafe { base.add((seq % capacity) as usize).write(record) };
+            }
+        }
+        FILE => {
+            if let Some(sink) = SINK.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+                sink.push(&record);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A wrapper around [`SnMalloc`] logging its operations while recording is on, see the
+/// [module](self) documentation. Otherwise, the cost is one load per operation.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SnMallocReplay;
+
+impl SnMallocReplay {
+    #[inline(always)]
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+unsafe impl GlobalAlloc for SnMallocReplay {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = SnMalloc.alloc(layout);
+        if is_recording() {
+            log(next_seq(), Op::Alloc, layout, layout.size(), ptr, ptr::null_mut());
+        }
+        ptr
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = SnMalloc.alloc_zeroed(layout);
+        if is_recording() {
+            log(next_seq(), Op::AllocZeroed, layout, layout.size(), ptr, ptr::null_mut());
+        }
+        ptr
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Logged first: once freed, the address may be handed out and logged by another thread.
+        if is_recording() {
+            log(next_seq(), Op::Dealloc, layout, layout.size(), ptr, ptr::null_mut());
+        }
+        SnMalloc.dealloc(ptr, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let seq = is_recording().then(next_seq);
+        let new_ptr = SnMalloc.realloc(ptr, layout, new_size);
+        if let Some(seq) = seq {
+            log(seq, Op::Realloc, layout, new_size, new_ptr, ptr);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_encodes_records() {
+        let record = Record { seq: 7, op: Op::Realloc, thread: 3, align: 64, size: 1000, ptr: 0x1000, old: 0x2000 };
+        assert_eq!(Record::from_bytes(&record.to_bytes()), Some(record));
+        let mut log = Vec::new();
+        write_log(&mut log, &[record, Record { seq: 2, op: Op::Alloc, ..record }]).unwrap();
+        let records = read_log(&log[..]).unwrap();
+        assert_eq!(records.iter().map(|record| record.seq).collect::<Vec<_>>(), [2, 7]);
+        assert!(read_log(&b"notalog!"[..]).is_err());
+    }
+
+    #[test]
+    fn it_records_and_replays_operations() {
+        let alloc = SnMallocReplay::new();
+        assert!(record_to_ring(1024));
+        unsafe {
+            let small = Layout::from_size_align(24, 8).unwrap();
+            let a = alloc.alloc(small);
+            let b = alloc.alloc_zeroed(Layout::from_size_align(4096, 64).unwrap());
+            let a = alloc.realloc(a, small, 300);
+            alloc.dealloc(b, Layout::from_size_align(4096, 64).unwrap());
+            let c = std::thread::spawn(move || alloc.alloc(small) as usize).join().unwrap() as *mut u8;
+            alloc.dealloc(c, small);
+            stop();
+            alloc.dealloc(a, Layout::from_size_align(300, 8).unwrap());
+        }
+        let records = ring_records();
+        let ops: Vec<Op> = records.iter().map(|record| record.op).collect();
+        assert_eq!(ops, [Op::Alloc, Op::AllocZeroed, Op::Realloc, Op::Dealloc, Op::Alloc, Op::Dealloc]);
+        assert_ne!(records[4].thread, records[5].thread);
+        let report = replay(&records);
+        assert_eq!(report.operations, 6);
+        assert_eq!((report.unmatched, report.failed, report.leaked), (0, 0, 1));
+        assert_eq!(report.peak_live_bytes, 4096 + 300);
+    }
+}
@@ -0,0 +1,276 @@
+//! Allocation-site sampling for continuous profiling (`sampling` feature).
+//!
+//! Like jemalloc's profiler, allocations made through [`SnMalloc`](crate::SnMalloc) are sampled
+//! by bytes: each thread draws the distance to its next sample from an exponential distribution
+//! whose mean is the [sample interval](set_interval), so that a large allocation is far more
+//! likely to be sampled than a small one, and the profile is unbiased whatever the size mix.
+//! Every sample records a 16-frame stack and is weighted by the bytes it stands for.
+//!
+//! Unsampled allocations only pay for a thread-local countdown, and frees are not tracked at
+//! all: the profile accumulates the bytes allocated by every stack since the last [`reset`].
+//! [`dump`] writes it in the collapsed-stack format of `flamegraph.pl` and `inferno`.
+use core::{
+    cell::Cell,
+    mem, ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use std::{io, string::ToString};
+
+use crate::sync::{self, SpinLock};
+
+/// Number of frames recorded per sample.
+const FRAMES: usize = 16;
+
+/// Frames belonging to the sampler itself, skipped when capturing.
+const SKIPPED_FRAMES: usize = 3;
+
+/// Mean number of bytes between two samples, `0` disabling sampling.
+static INTERVAL: AtomicUsize = AtomicUsize::new(512 * 1024);
+
+static PROFILE: SpinLock<Profile> = SpinLock::new(Profile::new());
+
+std::thread_local! {
+    /// Bytes left until the next sample of this thread.
+    static COUNTDOWN: Cell<isize> = const { Cell::new(0) };
+    /// State of the xorshift generator of this thread, `0` until the first draw.
+    static SEED: Cell<u64> = const { Cell::new(0) };
+    /// Set while the thread records a sample or dumps the profile, which may allocate.
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sets the mean number of bytes allocated between two samples (512KiB by default), or disables
+/// sampling with `0`. Threads pick the new interval up at their next sample.
+#[inline(always)]
+pub fn set_interval(bytes: usize) {
+    INTERVAL.store(bytes, Ordering::Relaxed);
+}
+
+/// Returns the sample interval set by [`set_interval`].
+#[inline(always)]
+pub fn interval() -> usize {
+    INTERVAL.load(Ordering::Relaxed)
+}
+
+/// Counts an allocation request of `size` bytes, sampling it when the countdown runs out.
+#[inline(always)]
+pub(crate) fn on_request(size: usize) {
+    if interval() == 0 {
+        return;
+    }
+    let _ = COUNTDOWN.try_with(|countdown| {
+        let left = countdown.get().wrapping_sub(size as isize);
+        countdown.set(left);
+        if left < 0 {
+            sample(size, countdown);
+        }
+    });
+}
+
+#[cold]
+#[inline(never)]
+fn sample(size: usize, countdown: &Cell<isize>) {
+    let interval = interval();
+    let Ok(seed) = SEED.try_with(|seed| seed.get()) else {
+        return;
+    };
+    if seed == 0 {
+        // A fresh thread starts at a random point of the distribution, not on a sample.
+        countdown.set(next_gap(interval));
+        return;
+    }
+    countdown.set(next_gap(interval));
+    if interval == 0 || BUSY.try_with(|busy| busy.replace(true)).unwrap_or(true) {
+        return;
+    }
+    let mut frames = [0; FRAMES];
+    let mut index = 0;
+    backtrace::trace(|frame| {
+        if index >= SKIPPED_FRAMES {
+            frames[index - SKIPPED_FRAMES] = frame.ip() as usize;
+        }
+        index += 1;
+        index < FRAMES + SKIPPED_FRAMES
+    });
+    // Probability that an allocation of `size` bytes is sampled is `1 - exp(-size / interval)`.
+    let ratio = size as f64 / interval as f64;
+    let weight = (size as f64 / -(-ratio).exp_m1()) as usize;
+    unsafe { PROFILE.lock().add(frames, weight) };
+    let _ = BUSY.try_with(|busy| busy.set(false));
+}
+
+/// Draws the bytes until the next sample from an exponential distribution of mean `interval`.
+fn next_gap(interval: usize) -> isize {
+    let x = SEED
+        .try_with(|seed| {
+            let mut x = match seed.get() {
+                0 => seed as *const _ as u64 | 1,
+                x => x,
+            };
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            seed.set(x);
+            x
+        })
+        .unwrap_or(1);
+    // Uniform in (0, 1].
+    let u = ((x >> 11) + 1) as f64 / (1u64 << 53) as f64;
+    (-u.ln() * interval as f64).min(isize::MAX as f64) as isize
+}
+
+#[derive(Clone, Copy)]
+struct Stack {
+    frames: [usize; FRAMES],
+    samples: usize,
+    bytes: usize,
+}
+
+/// Open-addressing hash table keyed by stack, backed by raw shim allocations so that it never
+/// re-enters the allocator being sampled.
+struct Profile {
+    stacks: *mut Stack,
+    capacity: usize,
+    len: usize,
+}
+
+unsafe impl Send for Profile {}
+
+impl Profile {
+    const fn new() -> Self {
+        Self {
+            stacks: ptr::null_mut(),
+            capacity: 0,
+            len: 0,
+        }
+    }
+
+    fn slot(&self, frames: &[usize; FRAMES]) -> usize {
+        let hash = frames
+            .iter()
+            .fold(0usize, |h, ip| (h.rotate_left(5) ^ ip).wrapping_mul(0x9E37_79B9_7F4A_7C15_u64 as usize));
+        hash & (self.capacity - 1)
+    }
+
+    fn stacks(&self) -> &[Stack] {
+        match self.capacity {
+            0 => &[],
+            capacity => unsafe { core::slice::from_raw_parts(self.stacks, capacity) },
+        }
+    }
+
+    unsafe fn grow(&mut self) -> bool {
+        let capacity = (self.capacity * 2).max(256);
+        let stacks: *mut Stack =
+            sync::exclusive(|| ffi::sn_rust_alloc_zeroed(mem::align_of::<Stack>(), capacity * mem::size_of::<Stack>())).cast();
+        if stacks.is_null() {
+            return false;
+        }
+        let old = mem::replace(self, Self { stacks, capacity, len: 0 });
+        for stack in old.stacks().iter().filter(|s| s.samples != 0) {
+            self.add_stack(*stack);
+        }
+        old.release();
+        true
+    }
+
+    unsafe fn release(self) {
+        if !self.stacks.is_null() {
+            sync::exclusive(|| ffi::sn_rust_dealloc(self.stacks.cast(), mem::align_of::<Stack>(), self.capacity * mem::size_of::<Stack>()));
+        }
+    }
+
+    unsafe fn add(&mut self, frames: [usize; FRAMES], bytes: usize) {
+        self.add_stack(Stack { frames, samples: 1, bytes });
+    }
+
+    unsafe fn add_stack(&mut self, stack: Stack) {
+        if (self.len + 1) * 4 > self.capacity * 3 && !self.grow() {
+            return;
+        }
+        let mut slot = self.slot(&stack.frames);
+        loop {
+            let current = &mut *self.stacks.add(slot);
+            if current.samples == 0 {
+                *current = stack;
+                self.len += 1;
+                return;
+            }
+            if current.frames == stack.frames {
+                current.samples += stack.samples;
+                current.bytes = current.bytes.saturating_add(stack.bytes);
+                return;
+            }
+            slot = (slot + 1) & (self.capacity - 1);
+        }
+    }
+}
+
+/// Writes the profile in collapsed-stack format: one line per stack, with the symbolized frames
+/// from the outermost one, separated by `;`, followed by the estimated bytes allocated.
+///
+/// ```rust,no_run
+/// let mut out = std::fs::File::create("alloc.folded").unwrap();
+/// snmalloc_rs::sample::dump(&mut out).unwrap();
+/// // flamegraph.pl alloc.folded > alloc.svg
+/// ```
+///
+/// Samples taken while dumping are dropped, and other threads wait at their next sample until
+/// the dump is done.
+pub fn dump(writer: &mut impl io::Write) -> io::Result<()> {
+    if BUSY.try_with(|busy| busy.replace(true)).unwrap_or(true) {
+        return Ok(());
+    }
+    let result = (|| {
+        let profile = PROFILE.lock();
+        for stack in profile.stacks().iter().filter(|s| s.samples != 0) {
+            let depth = stack.frames.iter().take_while(|ip| **ip != 0).count();
+            for (i, ip) in stack.frames[..depth].iter().rev().enumerate() {
+                let mut name = None;
+                backtrace::resolve(*ip as *mut _, |symbol| {
+                    name = name.take().or_else(|| symbol.name().map(|n| n.to_string()));
+                });
+                let name = name.map_or_else(|| std::format!("{:#x}", ip), |n| n.replace(';', ":"));
+                write!(writer, "{}{}", if i == 0 { "" } else { ";" }, name)?;
+            }
+            writeln!(writer, " {}", stack.bytes)?;
+        }
+        Ok(())
+    })();
+    let _ = BUSY.try_with(|busy| busy.set(false));
+    result
+}
+
+/// Clears the profile, e.g. after each [`dump`] of a continuous profiler.
+pub fn reset() {
+    let profile = mem::replace(&mut *PROFILE.lock(), Profile::new());
+    unsafe { profile.release() };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::{GlobalAlloc, Layout};
+    use std::vec::Vec;
+
+    #[test]
+    fn it_samples_by_bytes() {
+        set_interval(1);
+        let layout = Layout::from_size_align(1 << 16, 8).unwrap();
+        for _ in 0..64 {
+            unsafe { crate::SnMalloc.dealloc(crate::SnMalloc.alloc(layout), layout) };
+        }
+        set_interval(512 * 1024);
+        let mut out = Vec::new();
+        dump(&mut out).unwrap();
+        let out = std::string::String::from_utf8(out).unwrap();
+        let bytes: usize = out.lines().filter_map(|line| line.rsplit(' ').next()?.parse::<usize>().ok()).sum();
+        // The first request of the thread only arms the countdown.
+        assert!(bytes >= 63 << 16, "{}", out);
+    }
+
+    #[test]
+    fn it_draws_gaps_around_the_interval() {
+        let mean = (0..10_000).map(|_| next_gap(1000) as f64).sum::<f64>() / 10_000.0;
+        assert!((900.0..1100.0).contains(&mean), "{}", mean);
+    }
+}
@@ -0,0 +1,123 @@
+//! Dispatch of allocator handles to the fast or, with the `checked-handles` feature, the
+//! hardened shim.
+//!
+//! Without the feature [`Shim`] is zero-sized and every call goes straight to the fast shim.
+use core::ffi::{c_char, c_void};
+
+use ffi::sn_rust_allocator;
+
+/// The shim serving an allocator handle.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Shim {
+    #[cfg(feature = "checked-handles")]
+    checked: bool,
+}
+
+macro_rules! dispatch {
+    ($self:ident, $fast:ident, $checked:ident, $handle:expr $(, $arg:expr)*) => {{
+        #[cfg(feature = "checked-handles")]
+        if $self.checked {
+            return ffi::$checked($handle.cast() $(, $arg)*) as _;
+        }
+        ffi::$fast($handle $(, $arg)*)
+    }};
+}
+
+impl Shim {
+    pub(crate) const FAST: Self = Self {
+        #[cfg(feature = "checked-handles")]
+        checked: false,
+    };
+
+    #[cfg(feature = "checked-handles")]
+    pub(crate) const CHECKED: Self = Self { checked: true };
+
+    #[inline(always)]
+    pub(crate) fn is_checked(self) -> bool {
+        #[cfg(feature = "checked-handles")]
+        return self.checked;
+        #[cfg(not(feature = "checked-handles"))]
+        false
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn create_handle(self) -> *mut sn_rust_allocator {
+        #[cfg(feature = "checked-handles")]
+        if self.checked {
+            return ffi::snc_rust_allocator_new().cast();
+        }
+        ffi::sn_rust_allocator_new()
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn create_handle_with_config(self, config: &ffi::sn_rust_allocator_config_t) -> *mut sn_rust_allocator {
+        #[cfg(feature = "checked-handles")]
+        if self.checked {
+            return ffi::snc_rust_allocator_new_with_config(config).cast();
+        }
+        ffi::sn_rust_allocator_new_with_config(config)
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn free_handle(self, handle: *mut sn_rust_allocator) {
+        dispatch!(self, sn_rust_allocator_free, snc_rust_allocator_free, handle)
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn allocate(self, handle: *mut sn_rust_allocator, align: usize, size: usize) -> *mut c_void {
+        dispatch!(self, sn_rust_allocator_allocate, snc_rust_allocator_allocate, handle, align, size)
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn allocate_zeroed(self, handle: *mut sn_rust_allocator, align: usize, size: usize) -> *mut c_void {
+        dispatch!(self, sn_rust_allocator_allocate_zeroed, snc_rust_allocator_allocate_zeroed, handle, align, size)
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn allocate_filled(
+        self,
+        handle: *mut sn_rust_allocator,
+        align: usize,
+        size: usize,
+        byte: u8,
+    ) -> *mut c_void {
+        dispatch!(self, sn_rust_allocator_allocate_filled, snc_rust_allocator_allocate_filled, handle, align, size, byte)
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn deallocate(self, handle: *mut sn_rust_allocator, ptr: *mut c_void, align: usize, size: usize) {
+        dispatch!(self, sn_rust_allocator_deallocate, snc_rust_allocator_deallocate, handle, ptr, align, size)
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn deallocate_many(self, handle: *mut sn_rust_allocator, blocks: &mut [ffi::sn_rust_block_t]) {
+        dispatch!(self, sn_rust_allocator_deallocate_many, snc_rust_allocator_deallocate_many, handle, blocks.as_mut_ptr(), blocks.len())
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn reallocate(
+        self,
+        handle: *mut sn_rust_allocator,
+        ptr: *mut c_void,
+        align: usize,
+        old_size: usize,
+        new_size: usize,
+    ) -> *mut c_void {
+        dispatch!(self, sn_rust_allocator_reallocate, snc_rust_allocator_reallocate, handle, ptr, align, old_size, new_size)
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn flush(self, handle: *mut sn_rust_allocator) {
+        dispatch!(self, sn_rust_allocator_flush, snc_rust_allocator_flush, handle)
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn set_name(self, handle: *mut sn_rust_allocator, name: &str) {
+        dispatch!(self, sn_rust_allocator_set_name, snc_rust_allocator_set_name, handle, name.as_ptr().cast(), name.len())
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn name(self, handle: *const sn_rust_allocator, len: &mut usize) -> *const c_char {
+        dispatch!(self, sn_rust_allocator_name, snc_rust_allocator_name, handle, len)
+    }
+}
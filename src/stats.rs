@@ -0,0 +1,773 @@
+//! Allocator statistics.
+//!
+//! The memory usage reported by snmalloc itself is available with the `stats-api` feature (on by
+//! default), which [`Snapshot`] and [`start_reporter`] need too. With the `stats` feature,
+//! allocations made through [`SnMalloc`](crate::SnMalloc) are also counted, by power-of-two size
+//! bucket and by small size class, at the cost of a few atomic operations per allocation.
+//!
+//! With the `debug` feature, requests are also counted by alignment, and a warning is printed
+//! (with the `std` feature) the first time a call site requests an alignment larger than the
+//! size, which usually comes from a mis-specified `Layout` and wastes a whole size class.
+//!
+//! With both the `stats` and `std` features, the bytes and allocations of each thread are
+//! counted as well, and [`per_thread`] tells which thread (or pool) is behind memory growth.
+//!
+//! With the `zero-on-free` feature, [`zeroed_memory`] reports the bytes zeroed when freed.
+//!
+//! Nothing in this module allocates, so it can be used from `no_std` environments and from
+//! inside allocation failure handlers. The exceptions are [`start_reporter`] (with the `std`
+//! feature), which hands a [`Snapshot`] to a sink from a background thread at a fixed interval,
+//! and [`per_thread`], which returns a `Vec`.
+use core::fmt;
+#[cfg(any(feature = "stats", feature = "debug"))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of size buckets: bucket `i` holds allocations of `2^(i-1) + 1 ..= 2^i` bytes.
+pub const BUCKETS: usize = usize::BITS as usize + 1;
+
+#[cfg(feature = "stats-api")]
+/// Memory obtained from the OS and used by snmalloc, in bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub current: usize,
+    pub peak: usize,
+}
+
+#[cfg(feature = "stats-api")]
+/// Returns the memory currently, and at most, used by snmalloc.
+#[inline(always)]
+pub fn memory_usage() -> MemoryUsage {
+    let (mut current, mut peak) = (0, 0);
+    unsafe { ffi::sn_rust_memory_usage(&mut current, &mut peak) };
+    MemoryUsage { current, peak }
+}
+
+#[cfg(feature = "stats-api")]
+/// Split of the memory held by snmalloc, in bytes, see [`address_space`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AddressSpace {
+    /// Address space reserved from the OS. snmalloc never unmaps memory, so this only grows; it is
+    /// what shows up in VSZ.
+    pub reserved: usize,
+    /// Part of `reserved` currently committed, i.e. not handed back to the OS as unused, which
+    /// bounds what snmalloc adds to RSS.
+    pub committed: usize,
+    /// Bytes requested by live allocations made through [`SnMalloc`](crate::SnMalloc); only
+    /// tracked with the `stats` feature.
+    pub live: Option<usize>,
+}
+
+#[cfg(feature = "stats-api")]
+/// Returns how the memory held by snmalloc splits between reserved, committed and live bytes.
+///
+/// `reserved` and `committed` are counted by the memory provider of the shim as the backend
+/// reserves and commits memory, so this is cheap enough to sample from a metrics exporter. On
+/// platforms without a counted provider (e.g. Open Enclave), they are the peak and current values
+/// of [`memory_usage`] instead.
+#[inline(always)]
+pub fn address_space() -> AddressSpace {
+    let (mut reserved, mut committed) = (0, 0);
+    unsafe { ffi::sn_rust_address_space(&mut reserved, &mut committed) };
+    AddressSpace {
+        reserved,
+        committed,
+        #[cfg(feature = "stats")]
+        live: Some(live_bytes()),
+        #[cfg(not(feature = "stats"))]
+        live: None,
+    }
+}
+
+/// Totals of the memory locking of [locked handles](crate::SnAllocator::new_locked) and of the
+/// `lock-memory` feature, see [`locked_memory`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct LockedMemory {
+    /// Bytes locked since the start of the process: allocations of locked handles, whose freed
+    /// memory stays locked and is counted again when reused, and memory committed by snmalloc
+    /// with the `lock-memory` feature, counted again when committed again.
+    pub bytes: usize,
+    /// Locks that failed, e.g. past `RLIMIT_MEMLOCK`: locked handles fail the allocation, while
+    /// with `lock-memory` the memory is used unlocked and the first failure reported on stderr.
+    pub failures: usize,
+}
+
+/// Returns the totals of the memory locking.
+#[inline(always)]
+pub fn locked_memory() -> LockedMemory {
+    let (bytes, failures) = crate::lock::totals();
+    LockedMemory { bytes, failures }
+}
+
+/// Totals of the zeroing of freed memory of the `zero-on-free` feature, see [`zeroed_memory`].
+#[cfg(feature = "zero-on-free")]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct ZeroedMemory {
+    /// Bytes zeroed since the start of the process.
+    pub bytes: usize,
+    /// Bytes zeroed per second since the previous call of [`zeroed_memory`]; 0 for the first
+    /// call, and without the `std` feature.
+    pub bytes_per_sec: f64,
+}
+
+/// Returns the totals of the zeroing of freed memory.
+#[cfg(feature = "zero-on-free")]
+pub fn zeroed_memory() -> ZeroedMemory {
+    let bytes = crate::zero::zeroed_bytes();
+    ZeroedMemory {
+        bytes,
+        #[cfg(feature = "std")]
+        bytes_per_sec: crate::zero::zeroed_bytes_per_sec(bytes),
+        #[cfg(not(feature = "std"))]
+        bytes_per_sec: 0.0,
+    }
+}
+
+/// Returns the bucket of an allocation of `size` bytes.
+#[inline(always)]
+pub const fn bucket(size: usize) -> usize {
+    match size {
+        0 => 0,
+        size => (usize::BITS - (size - 1).leading_zeros()) as usize,
+    }
+}
+
+#[cfg(feature = "stats")]
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "stats")]
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "stats")]
+static LIVE_ALLOCATIONS: [AtomicUsize; BUCKETS] = [const { AtomicUsize::new(0) }; BUCKETS];
+#[cfg(feature = "stats")]
+static LIVE_BLOCKS: [AtomicUsize; SIZE_CLASSES.len()] = [const { AtomicUsize::new(0) }; SIZE_CLASSES.len()];
+/// High-water marks of the scopes of [`measure::peak_during`](crate::measure::peak_during)
+/// running, plus one, `0` marking a free slot.
+#[cfg(feature = "stats")]
+static SCOPE_PEAKS: [AtomicUsize; 64] = [const { AtomicUsize::new(0) }; 64];
+#[cfg(feature = "stats")]
+static OPEN_SCOPES: AtomicUsize = AtomicUsize::new(0);
+
+/// The small size classes of snmalloc, in bytes, see [`live_blocks`].
+pub use ffi::size_classes::SIZE_CLASSES;
+
+/// Returns the index in [`SIZE_CLASSES`] of the small class serving `size` bytes at their natural
+/// alignment, or `None` for sizes served by large allocations.
+#[inline(always)]
+pub fn size_class(size: usize) -> Option<usize> {
+    match size {
+        0 => None,
+        size if size > ffi::size_classes::MAX_SMALL_SIZE => None,
+        size => Some(SIZE_CLASSES.partition_point(|class| *class < size)),
+    }
+}
+
+#[cfg(feature = "stats")]
+#[inline(always)]
+pub(crate) fn record_alloc(size: usize) {
+    let live = LIVE_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_BYTES.fetch_max(live, Ordering::Relaxed);
+    if OPEN_SCOPES.load(Ordering::Relaxed) != 0 {
+        raise_scopes(live);
+    }
+    LIVE_ALLOCATIONS[bucket(size)].fetch_add(1, Ordering::Relaxed);
+    if let Some(class) = size_class(size) {
+        LIVE_BLOCKS[class].fetch_add(1, Ordering::Relaxed);
+    }
+    #[cfg(feature = "std")]
+    crate::thread_stats::on_alloc(size);
+}
+
+#[cfg(feature = "stats")]
+#[inline(always)]
+pub(crate) fn record_dealloc(size: usize) {
+    LIVE_BYTES.fetch_sub(size, Ordering::Relaxed);
+    LIVE_ALLOCATIONS[bucket(size)].fetch_sub(1, Ordering::Relaxed);
+    if let Some(class) = size_class(size) {
+        LIVE_BLOCKS[class].fetch_sub(1, Ordering::Relaxed);
+    }
+    #[cfg(feature = "std")]
+    crate::thread_stats::on_dealloc(size);
+}
+
+#[cfg(feature = "debug")]
+static ALIGNED_REQUESTS: [AtomicUsize; usize::BITS as usize] = [const { AtomicUsize::new(0) }; usize::BITS as usize];
+#[cfg(feature = "debug")]
+static MISALIGNED_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+/// Hashes of the call sites and layouts already warned about, `0` marking a free slot.
+#[cfg(feature = "debug")]
+static WARNED: [AtomicUsize; 64] = [const { AtomicUsize::new(0) }; 64];
+
+/// Records the alignment of an allocation request; a no-op without the `debug` feature.
+#[inline(always)]
+#[track_caller]
+pub(crate) fn on_request(size: usize, align: usize) {
+    #[cfg(feature = "debug")]
+    {
+        ALIGNED_REQUESTS[align.trailing_zeros() as usize % usize::BITS as usize].fetch_add(1, Ordering::Relaxed);
+        if size != 0 && align > size {
+            MISALIGNED_REQUESTS.fetch_add(1, Ordering::Relaxed);
+            warn_misaligned(size, align);
+        }
+    }
+    #[cfg(not(feature = "debug"))]
+    let _ = (size, align);
+}
+
+/// Warns once per call site and layout about an alignment exceeding the size.
+///
+/// Requests made through the global allocator all share the call site inside `alloc`, so call
+/// sites are told apart by the return addresses on the stack above it, and by the layout where
+/// the stack cannot be walked. Once [`WARNED`] is full, a last warning says further ones are
+/// suppressed.
+#[cfg(feature = "debug")]
+#[cold]
+#[track_caller]
+fn warn_misaligned(size: usize, align: usize) {
+    let location = core::panic::Location::caller();
+    let mut frames = [core::ptr::null_mut(); 16];
+    let len = unsafe { ffi::sn_rust_return_addresses(frames.as_mut_ptr(), frames.len()) };
+    let site = match len {
+        0 => [location.file().as_ptr() as usize, location.line() as usize, location.column() as usize],
+        len => [frames[..len].iter().fold(0, |hash, frame| hash_word(hash, *frame as usize)), len, 0],
+    };
+    let key = site.iter().chain(&[size, align]).fold(0, |hash, word| hash_word(hash, *word)).max(1);
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    let message = match claim_warning(key) {
+        Some(true) => "wasting a size class",
+        Some(false) => return,
+        None if WARNINGS_SUPPRESSED.swap(true, Ordering::Relaxed) => return,
+        None => "wasting a size class; further warnings suppressed",
+    };
+    #[cfg(feature = "std")]
+    {
+        use std::io::Write;
+        let _ = writeln!(
+            std::io::stderr(),
+            "snmalloc: {}: alignment {} exceeds size {}, {}",
+            location,
+            align,
+            size,
+            message
+        );
+    }
+}
+
+#[cfg(feature = "debug")]
+static WARNINGS_SUPPRESSED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+#[cfg(feature = "debug")]
+#[inline(always)]
+fn hash_word(hash: usize, word: usize) -> usize {
+    (hash.rotate_left(5) ^ word).wrapping_mul(0x9E37_79B9)
+}
+
+/// Records `key` in [`WARNED`]: whether it is new, or `None` once every slot is taken.
+#[cfg(feature = "debug")]
+fn claim_warning(key: usize) -> Option<bool> {
+    for i in 0..WARNED.len() {
+        let slot = &WARNED[(key + i) % WARNED.len()];
+        match slot.compare_exchange(0, key, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return Some(true),
+            Err(seen) if seen == key => return Some(false),
+            Err(_) => {}
+        }
+    }
+    None
+}
+
+/// Returns the number of allocation requests made through `SnMalloc` with the given alignment.
+#[cfg(feature = "debug")]
+#[inline(always)]
+pub fn aligned_requests(align: usize) -> usize {
+    ALIGNED_REQUESTS[align.trailing_zeros() as usize % usize::BITS as usize].load(Ordering::Relaxed)
+}
+
+/// Returns the number of allocation requests made through `SnMalloc` whose alignment exceeded
+/// their size.
+#[cfg(feature = "debug")]
+#[inline(always)]
+pub fn misaligned_requests() -> usize {
+    MISALIGNED_REQUESTS.load(Ordering::Relaxed)
+}
+
+/// Records a successful allocation and charges it to the `thread-budget` of the thread; a no-op
+/// without these features.
+#[inline(always)]
+pub(crate) fn on_alloc(ptr: *mut u8, size: usize) -> *mut u8 {
+    #[cfg(feature = "thread-budget")]
+    if !ptr.is_null() {
+        crate::budget::on_alloc(size);
+    }
+    #[cfg(feature = "stats")]
+    if !ptr.is_null() && size != 0 {
+        record_alloc(size);
+    }
+    #[cfg(not(feature = "stats"))]
+    let _ = size;
+    ptr
+}
+
+/// Records a de-allocation and credits it to the `thread-budget` of the thread; a no-op without these
+/// features.
+#[inline(always)]
+pub(crate) fn on_dealloc(size: usize) {
+    #[cfg(feature = "thread-budget")]
+    crate::budget::on_dealloc(size);
+    #[cfg(feature = "stats")]
+    if size != 0 {
+        record_dealloc(size);
+    }
+    #[cfg(not(feature = "stats"))]
+    let _ = size;
+}
+
+/// Records a successful re-allocation; a no-op without the `stats` feature.
+#[inline(always)]
+pub(crate) fn on_realloc(ptr: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+    if !ptr.is_null() {
+        on_dealloc(old_size);
+    }
+    on_alloc(ptr, new_size)
+}
+
+/// Returns the bytes requested by live allocations made through `SnMalloc`.
+#[cfg(feature = "stats")]
+#[inline(always)]
+pub fn live_bytes() -> usize {
+    LIVE_BYTES.load(Ordering::Relaxed)
+}
+
+/// Returns the high-water mark of [`live_bytes`] since the start of the process.
+#[cfg(feature = "stats")]
+#[inline(always)]
+pub(crate) fn peak_bytes() -> usize {
+    PEAK_BYTES.load(Ordering::Relaxed)
+}
+
+/// Starts tracking the high-water mark of [`live_bytes`] from `live`, returning the slot to pass
+/// to [`close_scope`], or `None` if every slot is taken.
+#[cfg(feature = "stats")]
+pub(crate) fn open_scope(live: usize) -> Option<usize> {
+    let slot = SCOPE_PEAKS.iter().position(|slot| {
+        slot.compare_exchange(0, live.saturating_add(1), Ordering::Relaxed, Ordering::Relaxed).is_ok()
+    })?;
+    OPEN_SCOPES.fetch_add(1, Ordering::Relaxed);
+    Some(slot)
+}
+
+/// Stops tracking the slot of [`open_scope`], returning its high-water mark.
+#[cfg(feature = "stats")]
+pub(crate) fn close_scope(slot: usize) -> usize {
+    OPEN_SCOPES.fetch_sub(1, Ordering::Relaxed);
+    SCOPE_PEAKS[slot].swap(0, Ordering::Relaxed) - 1
+}
+
+/// Raises the marks of the open scopes to `live`, leaving free slots alone.
+#[cfg(feature = "stats")]
+#[cold]
+fn raise_scopes(live: usize) {
+    let mark = live.saturating_add(1);
+    for slot in &SCOPE_PEAKS {
+        let _ = slot.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |peak| (peak != 0 && peak < mark).then_some(mark));
+    }
+}
+
+/// Returns the number of live allocations made through `SnMalloc` in the given bucket.
+#[cfg(feature = "stats")]
+#[inline(always)]
+pub fn live_allocations(bucket: usize) -> usize {
+    LIVE_ALLOCATIONS[bucket].load(Ordering::Relaxed)
+}
+
+/// Returns the number of live allocations made through `SnMalloc` in the small size class of index
+/// `class` (see [`size_class`]), each occupying `SIZE_CLASSES[class]` bytes of a slab.
+///
+/// Allocations are classed by their size: one whose alignment exceeds its size occupies a larger
+/// class than the one it is counted in.
+#[cfg(feature = "stats")]
+#[inline(always)]
+pub fn live_blocks(class: usize) -> usize {
+    LIVE_BLOCKS[class].load(Ordering::Relaxed)
+}
+
+/// Counters of one live thread, see [`per_thread`].
+#[cfg(all(feature = "stats", feature = "std"))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadStats {
+    /// Name of the thread, as held by the OS at its first allocation, if
+    /// [`capture_thread_names`] was enabled by then. Linux truncates names to 15 bytes, and
+    /// platforms without thread names (e.g. musl) always report `None`.
+    pub name: Option<std::string::String>,
+    /// Bytes allocated minus bytes freed by the thread. A free is credited to the thread making
+    /// it, so a thread releasing memory allocated by others goes negative.
+    pub live_bytes: isize,
+    /// Allocations per second since the previous call to [`per_thread`], or since the first
+    /// allocation of the thread.
+    pub allocs_per_sec: f64,
+}
+
+/// Captures the name of each thread when it first allocates, for [`ThreadStats::name`]; off by
+/// default. Threads that already allocated keep the name they were registered with.
+#[cfg(all(feature = "stats", feature = "std"))]
+#[inline(always)]
+pub fn capture_thread_names(enabled: bool) {
+    crate::thread_stats::set_capture_names(enabled);
+}
+
+/// Returns the counters of every live thread that allocated through `SnMalloc`, up to 256
+/// threads at once.
+///
+/// ```rust
+/// snmalloc_rs::stats::capture_thread_names(true);
+/// for thread in snmalloc_rs::stats::per_thread() {
+///     println!("{:?}: {} bytes, {:.0} allocs/s", thread.name, thread.live_bytes, thread.allocs_per_sec);
+/// }
+/// ```
+#[cfg(all(feature = "stats", feature = "std"))]
+pub fn per_thread() -> std::vec::Vec<ThreadStats> {
+    crate::thread_stats::per_thread()
+}
+
+#[cfg(feature = "stats-api")]
+/// Copy of the statistics at one point of the program, to be compared with [`Snapshot::diff`].
+///
+/// The live counters are only tracked with the `stats` feature, and are zero otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Memory committed by snmalloc, in bytes.
+    pub committed: usize,
+    /// Bytes requested by live allocations made through [`SnMalloc`](crate::SnMalloc).
+    pub live_bytes: Option<usize>,
+    /// Live allocations made through `SnMalloc`, by [`bucket`].
+    pub live_allocations: [usize; BUCKETS],
+    /// Live blocks of each small size class, by [`size_class`], see [`live_blocks`].
+    pub live_blocks: [usize; SIZE_CLASSES.len()],
+}
+
+#[cfg(feature = "stats-api")]
+impl Snapshot {
+    /// Reads the current statistics. This does not allocate.
+    pub fn take() -> Self {
+        Self {
+            committed: memory_usage().current,
+            #[cfg(feature = "stats")]
+            live_bytes: Some(live_bytes()),
+            #[cfg(not(feature = "stats"))]
+            live_bytes: None,
+            #[cfg(feature = "stats")]
+            live_allocations: core::array::from_fn(live_allocations),
+            #[cfg(not(feature = "stats"))]
+            live_allocations: [0; BUCKETS],
+            #[cfg(feature = "stats")]
+            live_blocks: core::array::from_fn(live_blocks),
+            #[cfg(not(feature = "stats"))]
+            live_blocks: [0; SIZE_CLASSES.len()],
+        }
+    }
+
+    /// Returns what changed from `earlier` to this snapshot.
+    ///
+    /// The counters are process-wide, so allocations made concurrently by other threads are
+    /// included.
+    ///
+    /// ```rust
+    /// use snmalloc_rs::stats::Snapshot;
+    ///
+    /// let before = Snapshot::take();
+    /// let buffer = vec![0u8; 4096];
+    /// let delta = Snapshot::take().diff(&before);
+    /// // Without `snmalloc_rs::SnMalloc` as the global allocator, nothing is counted.
+    /// assert!(delta.live_bytes.unwrap_or(0) <= 4096);
+    /// # drop(buffer);
+    /// ```
+    pub fn diff(&self, earlier: &Snapshot) -> Delta {
+        let sub = |now: usize, then: usize| now.wrapping_sub(then) as isize;
+        Delta {
+            committed: sub(self.committed, earlier.committed),
+            live_bytes: self.live_bytes.zip(earlier.live_bytes).map(|(now, then)| sub(now, then)),
+            live_allocations: core::array::from_fn(|b| sub(self.live_allocations[b], earlier.live_allocations[b])),
+            live_blocks: core::array::from_fn(|c| sub(self.live_blocks[c], earlier.live_blocks[c])),
+        }
+    }
+}
+
+#[cfg(feature = "stats-api")]
+/// Difference between two [`Snapshot`]s; positive values are growth.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    /// Change of the memory committed by snmalloc, in bytes.
+    pub committed: isize,
+    /// Net change of the live bytes, `None` without the `stats` feature.
+    pub live_bytes: Option<isize>,
+    /// Net change of the live allocations, by [`bucket`].
+    pub live_allocations: [isize; BUCKETS],
+    /// Net change of the live blocks of each small size class, by [`size_class`].
+    pub live_blocks: [isize; SIZE_CLASSES.len()],
+}
+
+#[cfg(feature = "stats-api")]
+impl fmt::Display for Delta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "committed: {:+} bytes", self.committed)?;
+        match self.live_bytes {
+            Some(bytes) => {
+                let total = self.live_allocations.iter().sum::<isize>();
+                writeln!(f, "live: {:+} bytes in {:+} allocations", bytes, total)?;
+                for (class, size) in SIZE_CLASSES.iter().enumerate().filter(|(class, _)| self.live_blocks[*class] != 0) {
+                    writeln!(f, "     {:>20} B: {:+}", size, self.live_blocks[class])?;
+                }
+                // Sizes beyond the small size classes are only counted by bucket.
+                let large = |bucket: &usize| 1u128 << bucket > ffi::size_classes::MAX_SMALL_SIZE as u128;
+                for bucket in (0..BUCKETS).filter(large).filter(|b| self.live_allocations[*b] != 0) {
+                    writeln!(f, "  <= {:>20} B: {:+}", 1u128 << bucket, self.live_allocations[bucket])?;
+                }
+                Ok(())
+            }
+            None => writeln!(f, "live: unavailable (enable the `stats` feature)"),
+        }
+    }
+}
+
+/// Background thread handing a [`Snapshot`] to a sink at an interval, see [`start_reporter`].
+/// Dropping the handle stops the thread, after the report in progress if any.
+#[cfg(all(feature = "std", feature = "stats-api"))]
+#[derive(Debug)]
+pub struct Reporter {
+    stop: std::sync::Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(all(feature = "std", feature = "stats-api"))]
+impl Reporter {
+    /// Stops the thread and waits for it to exit, like dropping the handle.
+    pub fn stop(self) {
+        drop(self)
+    }
+}
+
+#[cfg(all(feature = "std", feature = "stats-api"))]
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Starts a thread taking a [`Snapshot`] every `interval` and handing it to `sink`, e.g. to log
+/// it or export it as metrics, until the returned [`Reporter`] is dropped.
+///
+/// Each wait is drawn uniformly within 10% of `interval`, and the first one within the whole
+/// interval, so that a fleet of processes started together does not report in lockstep.
+///
+/// ```rust
+/// use std::time::Duration;
+/// let reporter = snmalloc_rs::stats::start_reporter(Duration::from_secs(60), |snapshot| {
+///     println!("committed: {} bytes", snapshot.committed);
+/// })
+/// .unwrap();
+/// // ...
+/// reporter.stop();
+/// ```
+#[cfg(all(feature = "std", feature = "stats-api"))]
+pub fn start_reporter(
+    interval: core::time::Duration,
+    sink: impl Fn(&Snapshot) + Send + 'static,
+) -> std::io::Result<Reporter> {
+    use std::{
+        hash::{BuildHasher, Hasher},
+        sync::{Arc, Condvar, Mutex},
+    };
+
+    let stop = Arc::new((Mutex::new(false), Condvar::new()));
+    let shared = stop.clone();
+    // Seeded per process, so that two processes draw different waits.
+    let mut seed = std::collections::hash_map::RandomState::new().build_hasher().finish() | 1;
+    let mut jitter = move |range: f64| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed >> 11) as f64 / (1u64 << 53) as f64 * range
+    };
+    let thread = std::thread::Builder::new().name("snmalloc-reporter".into()).spawn(move || {
+        let (stopped, wake) = &*shared;
+        let mut wait = interval.mul_f64(jitter(1.0));
+        loop {
+            let guard = stopped.lock().unwrap_or_else(|e| e.into_inner());
+            let (guard, _) = wake.wait_timeout_while(guard, wait, |stopped| !*stopped).unwrap_or_else(|e| e.into_inner());
+            if *guard {
+                return;
+            }
+            drop(guard);
+            sink(&Snapshot::take());
+            wait = interval.mul_f64(0.9 + jitter(0.2));
+        }
+    })?;
+    Ok(Reporter { stop, thread: Some(thread) })
+}
+
+/// Writes a human-readable summary of the allocator state to `writer`.
+///
+/// ```rust
+/// struct Console;
+/// impl core::fmt::Write for Console {
+///     fn write_str(&mut self, s: &str) -> core::fmt::Result {
+///         // e.g. push `s` to a serial port
+///         Ok(())
+///     }
+/// }
+/// snmalloc_rs::stats::write_report(&mut Console).unwrap();
+/// ```
+pub fn write_report(writer: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(writer, "snmalloc statistics")?;
+    #[cfg(feature = "stats-api")]
+    {
+        let space = address_space();
+        writeln!(writer, "  reserved: {} bytes, committed: {} bytes", space.reserved, space.committed)?;
+    }
+    #[cfg(feature = "stats")]
+    {
+        let total = (0..BUCKETS).map(live_allocations).sum::<usize>();
+        writeln!(writer, "  live: {} bytes in {} allocations", live_bytes(), total)?;
+        for bucket in (0..BUCKETS).filter(|b| live_allocations(*b) != 0) {
+            writeln!(writer, "    <= {:>20} B: {}", 1u128 << bucket, live_allocations(bucket))?;
+        }
+        writeln!(writer, "  small size classes (class: blocks, bytes occupied):")?;
+        for (class, size) in SIZE_CLASSES.iter().enumerate().filter(|(class, _)| live_blocks(*class) != 0) {
+            writeln!(writer, "    {:>20} B: {}, {}", size, live_blocks(class), live_blocks(class) * size)?;
+        }
+    }
+    #[cfg(not(feature = "stats"))]
+    writeln!(writer, "  live: unavailable (enable the `stats` feature)")?;
+    #[cfg(feature = "debug")]
+    {
+        writeln!(writer, "  requests by alignment ({} with alignment > size):", misaligned_requests())?;
+        for shift in (0..usize::BITS).filter(|s| aligned_requests(1 << s) != 0) {
+            writeln!(writer, "    {:>20} B: {}", 1usize << shift, aligned_requests(1 << shift))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter(usize);
+
+    impl fmt::Write for Counter {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 += s.len();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_buckets_sizes() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 0);
+        assert_eq!(bucket(2), 1);
+        assert_eq!(bucket(16), 4);
+        assert_eq!(bucket(17), 5);
+        assert_eq!(bucket(usize::MAX), BUCKETS - 1);
+    }
+
+    #[cfg(feature = "stats-api")]
+    #[test]
+    fn it_splits_address_space() {
+        let ptr = unsafe { ffi::sn_rust_alloc(8, 1 << 20) };
+        let space = address_space();
+        assert!(space.committed >= 1 << 20);
+        assert!(space.reserved >= space.committed);
+        assert_eq!(space.live.is_some(), cfg!(feature = "stats"));
+        unsafe { ffi::sn_rust_dealloc(ptr, 8, 1 << 20) };
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn it_counts_misaligned_requests() {
+        use core::alloc::{GlobalAlloc, Layout};
+        let layout = Layout::from_size_align(8, 1 << 12).unwrap();
+        let (aligned, misaligned) = (aligned_requests(1 << 12), misaligned_requests());
+        for _ in 0..2 {
+            unsafe { crate::SnMalloc.dealloc(crate::SnMalloc.alloc(layout), layout) };
+        }
+        assert!(aligned_requests(1 << 12) >= aligned + 2);
+        assert!(misaligned_requests() >= misaligned + 2);
+        // The repeated call site and layout hold a single slot.
+        assert!(WARNED.iter().filter(|slot| slot.load(Ordering::Relaxed) != 0).count() >= 1);
+        let key = usize::MAX - 1;
+        assert_eq!(claim_warning(key), Some(true));
+        assert_eq!(claim_warning(key), Some(false));
+    }
+
+    #[test]
+    fn it_diffs_snapshots() {
+        use core::alloc::{GlobalAlloc, Layout};
+        use std::string::ToString;
+
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+        let before = Snapshot::take();
+        let ptrs: [_; 3] = core::array::from_fn(|_| unsafe { crate::SnMalloc.alloc(layout) });
+        let delta = Snapshot::take().diff(&before);
+        for ptr in ptrs {
+            unsafe { crate::SnMalloc.dealloc(ptr, layout) };
+        }
+        #[cfg(feature = "stats")]
+        {
+            assert!(delta.live_bytes.unwrap() >= 3000);
+            assert!(delta.live_allocations[bucket(1000)] >= 3);
+            if let Some(class) = size_class(1000) {
+                assert!(delta.live_blocks[class] >= 3);
+                assert!(delta.to_string().contains(&std::format!("{:>20} B: +", SIZE_CLASSES[class])));
+            }
+        }
+        #[cfg(not(feature = "stats"))]
+        assert_eq!(delta.live_bytes, None);
+        assert!(delta.to_string().starts_with("committed: "));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_reports_periodically() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let reporter = start_reporter(core::time::Duration::from_millis(5), move |snapshot| {
+            let _ = sender.send(snapshot.committed);
+        })
+        .unwrap();
+        for _ in 0..3 {
+            receiver.recv_timeout(core::time::Duration::from_secs(10)).unwrap();
+        }
+        reporter.stop();
+        // The sink went away with the thread.
+        while receiver.try_recv().is_ok() {}
+        assert!(receiver.recv().is_err());
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn it_counts_blocks_by_size_class() {
+        use core::alloc::{GlobalAlloc, Layout};
+        if SIZE_CLASSES.is_empty() {
+            return;
+        }
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let class = size_class(24).unwrap();
+        assert!(SIZE_CLASSES[class] >= 24 && (class == 0 || SIZE_CLASSES[class - 1] < 24));
+        assert_eq!(size_class(ffi::size_classes::MAX_SMALL_SIZE + 1), None);
+        let ptrs: [_; 3] = core::array::from_fn(|_| unsafe { crate::SnMalloc.alloc(layout) });
+        assert!(live_blocks(class) >= 3);
+        for ptr in ptrs {
+            unsafe { crate::SnMalloc.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test]
+    fn it_writes_a_report() {
+        let mut counter = Counter(0);
+        write_report(&mut counter).unwrap();
+        assert!(counter.0 > 0);
+    }
+}
@@ -0,0 +1,169 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ffi::c_char,
+    sync::atomic::{AtomicU8, Ordering},
+};
+use std::alloc::System;
+
+use crate::SnMalloc;
+
+/// Setting this environment variable to `1` makes [`SnMallocOrSystem`] use the system allocator.
+pub const DISABLE_ENV: &str = "SNMALLOC_DISABLE";
+
+const UNDECIDED: u8 = 0;
+const SNMALLOC: u8 = 1;
+const SYSTEM: u8 = 2;
+
+/// [`DISABLE_ENV`] as a C string.
+const DISABLE_ENV_C: [u8; DISABLE_ENV.len() + 1] = {
+    let mut name = [0; DISABLE_ENV.len() + 1];
+    let mut i = 0;
+    while i < DISABLE_ENV.len() {
+        name[i] = DISABLE_ENV.as_bytes()[i];
+        i += 1;
+    }
+    name
+};
+
+extern "C" {
+    fn getenv(name: *const c_char) -> *const c_char;
+}
+
+/// Reads [`DISABLE_ENV`] without allocating, since this runs inside the global allocator.
+fn disabled_by_env() -> bool {
+    let value = unsafe { getenv(DISABLE_ENV_C.as_ptr().cast()) };
+    disables(match value.is_null() {
+        true => None,
+        false => Some(unsafe { core::ffi::CStr::from_ptr(value) }.to_bytes()),
+    })
+}
+
+/// Whether the value of [`DISABLE_ENV`], if set, selects the system allocator.
+fn disables(value: Option<&[u8]>) -> bool {
+    value == Some(b"1")
+}
+
+/// A global allocator that serves everything from either [`SnMalloc`] or the system allocator.
+///
+/// The backend is chosen exactly once, at the first allocation: if the `SNMALLOC_DISABLE`
+/// environment variable is set to `1`, the system allocator is used for the whole lifetime of
+/// the process. This provides a kill switch to take snmalloc out of a deployed binary without
+/// rebuilding it.
+///
+/// ```rust
+/// #[global_allocator]
+/// static ALLOC: snmalloc_rs::SnMallocOrSystem = snmalloc_rs::SnMallocOrSystem::new();
+/// ```
+pub struct SnMallocOrSystem {
+    backend: AtomicU8,
+}
+
+impl Default for SnMallocOrSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SnMallocOrSystem {
+    pub const fn new() -> Self {
+        Self {
+            backend: AtomicU8::new(UNDECIDED),
+        }
+    }
+
+    #[inline(always)]
+    fn backend(&self) -> u8 {
+        self.decide(disabled_by_env)
+    }
+
+    /// Returns the backend, choosing it with `disabled` if it is undecided.
+    #[inline(always)]
+    fn decide(&self, disabled: impl FnOnce() -> bool) -> u8 {
+        match self.backend.load(Ordering::Acquire) {
+            UNDECIDED => {
+                let backend = if disabled() { SYSTEM } else { SNMALLOC };
+                match self.backend.compare_exchange(UNDECIDED, backend, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => backend,
+                    Err(decided) => decided,
+                }
+            }
+            decided => decided,
+        }
+    }
+
+    /// Returns `true` if the system allocator is in use, deciding the backend if needed.
+    pub fn is_disabled(&self) -> bool {
+        self.backend() == SYSTEM
+    }
+}
+
+unsafe impl GlobalAlloc for SnMallocOrSystem {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.backend() {
+            SYSTEM => System.alloc(layout),
+            _ => SnMalloc.alloc(layout),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match self.backend() {
+            SYSTEM => System.dealloc(ptr, layout),
+            _ => SnMalloc.dealloc(ptr, layout),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        match self.backend() {
+            SYSTEM => System.alloc_zeroed(layout),
+            _ => SnMalloc.alloc_zeroed(layout),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        match self.backend() {
+            SYSTEM => System.realloc(ptr, layout, new_size),
+            _ => SnMalloc.realloc(ptr, layout, new_size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe fn allocation_roundtrip(alloc: &SnMallocOrSystem) {
+        let layout = Layout::from_size_align(64, 16).unwrap();
+        let ptr = alloc.alloc(layout);
+        assert!(!ptr.is_null());
+        let ptr = alloc.realloc(ptr, layout, 128);
+        alloc.dealloc(ptr, Layout::from_size_align(128, 16).unwrap());
+    }
+
+    #[test]
+    fn it_parses_the_switch() {
+        assert_eq!(&DISABLE_ENV_C[..DISABLE_ENV.len()], DISABLE_ENV.as_bytes());
+        assert_eq!(DISABLE_ENV_C[DISABLE_ENV.len()], 0);
+        assert!(disables(Some(b"1")));
+        assert!(!disables(None) && !disables(Some(b"0")) && !disables(Some(b"")));
+    }
+
+    #[test]
+    fn it_selects_the_backend_once() {
+        // The environment is left alone: other tests read it from other threads.
+        let alloc = SnMallocOrSystem::new();
+        assert_eq!(alloc.decide(|| false), SNMALLOC);
+        unsafe { allocation_roundtrip(&alloc) };
+        assert!(!alloc.is_disabled());
+
+        let disabled = SnMallocOrSystem::new();
+        assert_eq!(disabled.decide(|| true), SYSTEM);
+        unsafe { allocation_roundtrip(&disabled) };
+        // The decision is cached.
+        assert_eq!(disabled.decide(|| false), SYSTEM);
+        assert!(disabled.is_disabled());
+    }
+}
@@ -0,0 +1,100 @@
+use core::{
+    cell::UnsafeCell,
+    hint,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// A minimal spin lock, usable from inside the global allocator where blocking primitives
+/// that may allocate are not an option.
+///
+/// Waiters spin on a plain load, for exponentially longer between tries, then yield the CPU
+/// (with the `std` feature) so that a preempted holder can run.
+pub(crate) struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn lock(&self) -> SpinLockGuard<'_, T> {
+        let mut round = 0;
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                back_off(&mut round);
+            }
+        }
+        SpinLockGuard { lock: self }
+    }
+}
+
+/// Rounds of spinning, doubling each time, before waiters start yielding.
+const SPIN_ROUNDS: u32 = 6;
+
+/// Waits a little longer after every `round` waiting for a lock.
+#[cold]
+fn back_off(round: &mut u32) {
+    if *round < SPIN_ROUNDS {
+        for _ in 0..1 << *round {
+            hint::spin_loop();
+        }
+        *round += 1;
+        return;
+    }
+    #[cfg(feature = "std")]
+    std::thread::yield_now();
+    #[cfg(not(feature = "std"))]
+    for _ in 0..1 << SPIN_ROUNDS {
+        hint::spin_loop();
+    }
+}
+
+pub(crate) struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// Runs `f` with snmalloc's internal state to itself.
+///
+/// With the `critical-section` feature, every call into snmalloc is made inside a critical
+/// section, so that an interrupt handler cannot enter the allocator while the code it
+/// interrupted holds one of snmalloc's spin locks. Otherwise this is a plain call.
+#[inline(always)]
+pub(crate) fn exclusive<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "critical-section")]
+    return critical_section::with(|_| f());
+    #[cfg(not(feature = "critical-section"))]
+    f()
+}
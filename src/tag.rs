@@ -0,0 +1,290 @@
+//! Heap attribution by subsystem (`tagging` feature).
+//!
+//! Code running inside [`with_tag`] has its allocations attributed to the given tag by
+//! [`SnMallocTagged`], which keeps live byte counts per tag. This gives a cheap breakdown of
+//! who holds the heap, without recording backtraces like `SnMallocDebug`.
+//! [`Tagged`] instead attributes every allocation of a global allocator to a tag fixed at compile
+//! time.
+//!
+//! ```rust,no_run
+//! #[global_allocator]
+//! static ALLOC: snmalloc_rs::SnMallocTagged = snmalloc_rs::SnMallocTagged::new();
+//!
+//! const PARSER: snmalloc_rs::tag::Tag = 1;
+//! let tokens = snmalloc_rs::tag::with_tag(PARSER, || vec![0u32; 1024]);
+//! assert!(snmalloc_rs::tag::live_bytes(PARSER) >= 4096);
+//! ```
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    fmt, mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::SnMalloc;
+
+/// Identifies a subsystem. Tag `0` is used for allocations made outside of [`with_tag`].
+pub type Tag = u8;
+
+/// Number of distinct tags.
+pub const TAGS: usize = Tag::MAX as usize + 1;
+
+static LIVE_BYTES: [AtomicUsize; TAGS] = [const { AtomicUsize::new(0) }; TAGS];
+
+std::thread_local! {
+    static CURRENT: Cell<Tag> = const { Cell::new(0) };
+}
+
+/// Restores the previous tag, even if the closure unwinds.
+struct Restore(Tag);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let _ = CURRENT.try_with(|current| current.set(self.0));
+    }
+}
+
+/// Runs `f` with allocations of the current thread attributed to `tag`.
+/// Calls can be nested; the innermost tag wins.
+pub fn with_tag<R>(tag: Tag, f: impl FnOnce() -> R) -> R {
+    let _restore = Restore(CURRENT.with(|current| current.replace(tag)));
+    f()
+}
+
+/// Returns the tag allocations of the current thread are attributed to.
+#[inline(always)]
+pub fn current() -> Tag {
+    CURRENT.try_with(Cell::get).unwrap_or(0)
+}
+
+/// Returns the bytes requested by live allocations attributed to `tag`.
+#[inline(always)]
+pub fn live_bytes(tag: Tag) -> usize {
+    LIVE_BYTES[tag as usize].load(Ordering::Relaxed)
+}
+
+/// Writes the live bytes of every tag holding memory to `writer`.
+pub fn write_report(writer: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(writer, "live bytes by tag")?;
+    for tag in (0..TAGS).filter(|tag| live_bytes(*tag as Tag) != 0) {
+        writeln!(writer, "  {:>3}: {}", tag, live_bytes(tag as Tag))?;
+    }
+    Ok(())
+}
+
+/// A wrapper around [`SnMalloc`] attributing every allocation to the tag set by [`with_tag`].
+///
+/// The tag is stored in a header in front of each allocation, so that memory freed by another
+/// thread, or under another tag, is still credited back to its owner.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SnMallocTagged;
+
+impl SnMallocTagged {
+    #[inline(always)]
+    pub const fn new() -> Self {
+        Self
+    }
+
+    /// Space in front of the user pointer: a multiple of the alignment, large enough for the tag.
+    #[inline(always)]
+    fn offset(layout: Layout) -> usize {
+        layout.align().max(mem::size_of::<usize>())
+    }
+
+    #[inline(always)]
+    fn outer(layout: Layout, size: usize) -> Option<Layout> {
+        let offset = Self::offset(layout);
+        Layout::from_size_align(size.checked_add(offset)?, offset).ok()
+    }
+
+    #[inline(always)]
+    unsafe fn tag_of(ptr: *mut u8) -> *mut usize {
+        ptr.cast::<usize>().sub(1)
+    }
+
+    #[inline(always)]
+    unsafe fn finish(base: *mut u8, layout: Layout) -> *mut u8 {
+        if base.is_null() {
+            return base;
+        }
+        let tag = current();
+        let ptr = base.add(Self::offset(layout));
+        Self::tag_of(ptr).write(tag as usize);
+        LIVE_BYTES[tag as usize].fetch_add(layout.size(), Ordering::Relaxed);
+        ptr
+    }
+}
+
+unsafe impl GlobalAlloc for SnMallocTagged {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match Self::outer(layout, layout.size()) {
+            Some(outer) => Self::finish(SnMalloc.alloc(outer), layout),
+            None => core::ptr::null_mut(),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        match Self::outer(layout, layout.size()) {
+            Some(outer) => Self::finish(SnMalloc.alloc_zeroed(outer), layout),
+            None => core::ptr::null_mut(),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let tag = *Self::tag_of(ptr);
+        LIVE_BYTES[tag].fetch_sub(layout.size(), Ordering::Relaxed);
+        let outer = Self::outer(layout, layout.size()).unwrap_unchecked();
+        SnMalloc.dealloc(ptr.sub(Self::offset(layout)), outer);
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let Some(new_outer) = Self::outer(layout, new_size) else {
+            return core::ptr::null_mut();
+        };
+        let offset = Self::offset(layout);
+        let outer = Self::outer(layout, layout.size()).unwrap_unchecked();
+        // The header moves with the block, so the memory stays attributed to its original tag.
+        let base = SnMalloc.realloc(ptr.sub(offset), outer, new_outer.size());
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base.add(offset);
+        let tag = *Self::tag_of(ptr);
+        LIVE_BYTES[tag].fetch_sub(layout.size(), Ordering::Relaxed);
+        LIVE_BYTES[tag].fetch_add(new_size, Ordering::Relaxed);
+        ptr
+    }
+}
+
+/// A wrapper around a global allocator attributing every allocation to the tag `TAG`, fixed at
+/// compile time, e.g. one per binary or subsystem of a workspace:
+///
+/// ```rust,no_run
+/// use snmalloc_rs::{SnMalloc, Tagged};
+///
+/// const NET: u32 = 2;
+/// #[global_allocator]
+/// static ALLOC: Tagged<SnMalloc, NET> = Tagged::new(SnMalloc);
+/// ```
+///
+/// The live bytes are folded into the counters of [`live_bytes`] and [`write_report`]. Unlike
+/// [`SnMallocTagged`], neither a header nor the thread-local tag is involved: the tag of a block
+/// is known when it is freed. `TAG` must be smaller than [`TAGS`].
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Tagged<A, const TAG: u32>(A);
+
+impl<A, const TAG: u32> Tagged<A, TAG> {
+    const VALID: () = assert!((TAG as usize) < TAGS, "the tag of `Tagged` must be smaller than `TAGS`");
+
+    #[inline(always)]
+    pub const fn new(inner: A) -> Self {
+        let () = Self::VALID;
+        Self(inner)
+    }
+
+    /// Returns the wrapped allocator.
+    #[inline(always)]
+    pub const fn inner(&self) -> &A {
+        &self.0
+    }
+
+    /// Returns the bytes requested by live allocations of this tag, including those of other
+    /// allocators attributed to it.
+    #[inline(always)]
+    pub fn live_bytes(&self) -> usize {
+        live_bytes(TAG as Tag)
+    }
+
+    #[inline(always)]
+    fn counter() -> &'static AtomicUsize {
+        &LIVE_BYTES[TAG as usize]
+    }
+
+    #[inline(always)]
+    fn on_alloc(ptr: *mut u8, size: usize) -> *mut u8 {
+        if !ptr.is_null() {
+            Self::counter().fetch_add(size, Ordering::Relaxed);
+        }
+        ptr
+    }
+}
+
+unsafe impl<A: GlobalAlloc, const TAG: u32> GlobalAlloc for Tagged<A, TAG> {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Self::on_alloc(self.0.alloc(layout), layout.size())
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Self::on_alloc(self.0.alloc_zeroed(layout), layout.size())
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Self::counter().fetch_sub(layout.size(), Ordering::Relaxed);
+        self.0.dealloc(ptr, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.0.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            Self::counter().fetch_sub(layout.size(), Ordering::Relaxed);
+            Self::counter().fetch_add(new_size, Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_attributes_allocations_to_tags() {
+        let alloc = SnMallocTagged::new();
+        let layout = Layout::from_size_align(100, 64).unwrap();
+        unsafe {
+            let ptr = with_tag(7, || alloc.alloc(layout));
+            assert_eq!(ptr as usize % 64, 0);
+            assert_eq!(live_bytes(7), 100);
+
+            // Freed under another tag, still credited back to the owner.
+            let ptr = with_tag(8, || alloc.realloc(ptr, layout, 300));
+            assert_eq!(live_bytes(7), 300);
+            assert_eq!(live_bytes(8), 0);
+            alloc.dealloc(ptr, Layout::from_size_align(300, 64).unwrap());
+            assert_eq!(live_bytes(7), 0);
+        }
+    }
+
+    #[test]
+    fn it_attributes_allocations_to_the_static_tag() {
+        let alloc: Tagged<SnMalloc, 42> = Tagged::new(SnMalloc);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            // The thread-local tag does not apply.
+            let ptr = with_tag(43, || alloc.alloc(layout));
+            assert_eq!(alloc.live_bytes(), 100);
+            assert_eq!(live_bytes(43), 0);
+            let ptr = alloc.realloc(ptr, layout, 1000);
+            assert_eq!(live_bytes(42), 1000);
+            alloc.dealloc(ptr, Layout::from_size_align(1000, 8).unwrap());
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+    }
+
+    #[test]
+    fn it_restores_the_previous_tag() {
+        with_tag(3, || {
+            with_tag(4, || assert_eq!(current(), 4));
+            assert_eq!(current(), 3);
+        });
+        assert_eq!(current(), 0);
+    }
+}
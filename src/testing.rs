@@ -0,0 +1,201 @@
+//! Allocation failure injection, for testing how code handles running out of memory.
+//!
+//! [`FailingAlloc`] serves allocations from [`SnMalloc`] unless the calling thread programmed it
+//! to fail them: the n-th allocation from now ([`fail_nth`]), every allocation above a size
+//! ([`fail_above`]), or each allocation with a probability ([`fail_with_probability`]). The
+//! controls are per thread, so that tests running in parallel do not see each other's failures,
+//! and only apply to `alloc`, `alloc_zeroed` and growing `realloc`: frees always succeed.
+//!
+//! ```rust
+//! use snmalloc_rs::testing::{self, FailingAlloc};
+//!
+//! #[global_allocator]
+//! static ALLOC: FailingAlloc = FailingAlloc::new();
+//!
+//! let mut buffer: Vec<u8> = Vec::new();
+//! testing::fail_above(1 << 20);
+//! assert!(buffer.try_reserve(4 << 20).is_err());
+//! testing::reset();
+//! assert!(buffer.try_reserve(4 << 20).is_ok());
+//! ```
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    ptr,
+};
+
+use crate::SnMalloc;
+
+/// A global allocator forwarding to [`SnMalloc`], failing the allocations programmed by the
+/// functions of this module on the calling thread.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FailingAlloc;
+
+struct Controls {
+    /// Allocations left until the programmed failure, 0 when none is.
+    nth: Cell<u64>,
+    above: Cell<usize>,
+    probability: Cell<f64>,
+    rng: Cell<u64>,
+    failures: Cell<u64>,
+}
+
+impl Controls {
+    const fn new() -> Self {
+        Self {
+            nth: Cell::new(0),
+            above: Cell::new(usize::MAX),
+            probability: Cell::new(0.0),
+            rng: Cell::new(1),
+            failures: Cell::new(0),
+        }
+    }
+
+    fn fails(&self, size: usize) -> bool {
+        let nth = match self.nth.get() {
+            0 => false,
+            n => {
+                self.nth.set(n - 1);
+                n == 1
+            }
+        };
+        let fails = nth || size > self.above.get() || self.draw() < self.probability.get();
+        if fails {
+            self.failures.set(self.failures.get() + 1);
+        }
+        fails
+    }
+
+    /// Returns a number in `[0, 1)` from a xorshift sequence.
+    fn draw(&self) -> f64 {
+        if self.probability.get() <= 0.0 {
+            return 1.0;
+        }
+        let mut x = self.rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+std::thread_local! {
+    static CONTROLS: Controls = const { Controls::new() };
+}
+
+/// Whether the calling thread programmed an allocation of `size` bytes to fail. Threads being
+/// torn down never fail.
+#[inline(always)]
+fn fails(size: usize) -> bool {
+    CONTROLS.try_with(|controls| controls.fails(size)).unwrap_or(false)
+}
+
+/// Makes the `n`-th allocation of the calling thread from now fail, 1 being the next one; the
+/// allocations after it succeed again. 0 cancels a pending failure.
+pub fn fail_nth(n: u64) {
+    CONTROLS.with(|controls| controls.nth.set(n));
+}
+
+/// Makes every allocation of more than `bytes` bytes of the calling thread fail.
+pub fn fail_above(bytes: usize) {
+    CONTROLS.with(|controls| controls.above.set(bytes));
+}
+
+/// Makes each allocation of the calling thread fail with probability `probability`, drawn from a
+/// sequence seeded with `seed`, so that a failing run can be replayed.
+pub fn fail_with_probability(probability: f64, seed: u64) {
+    CONTROLS.with(|controls| {
+        controls.probability.set(probability);
+        controls.rng.set(seed | 1);
+    });
+}
+
+/// Returns the failures injected on the calling thread since it started or [`reset`] was called.
+pub fn injected_failures() -> u64 {
+    CONTROLS.with(|controls| controls.failures.get())
+}
+
+/// Stops injecting failures on the calling thread, and resets [`injected_failures`].
+pub fn reset() {
+    CONTROLS.with(|controls| {
+        controls.nth.set(0);
+        controls.above.set(usize::MAX);
+        controls.probability.set(0.0);
+        controls.failures.set(0);
+    });
+}
+
+impl FailingAlloc {
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+unsafe impl GlobalAlloc for FailingAlloc {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match fails(layout.size()) {
+            true => ptr::null_mut(),
+            false => SnMalloc.alloc(layout),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        SnMalloc.dealloc(ptr, layout)
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        match fails(layout.size()) {
+            true => ptr::null_mut(),
+            false => SnMalloc.alloc_zeroed(layout),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        match new_size > layout.size() && fails(new_size) {
+            true => ptr::null_mut(),
+            false => SnMalloc.realloc(ptr, layout, new_size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_fails_as_programmed() {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let alloc = |size| unsafe {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let ptr = FailingAlloc.alloc(layout);
+            if !ptr.is_null() {
+                FailingAlloc.dealloc(ptr, layout);
+            }
+            !ptr.is_null()
+        };
+        fail_nth(3);
+        assert_eq!([alloc(64), alloc(64), alloc(64), alloc(64)], [true, true, false, true]);
+        fail_above(1024);
+        assert!(alloc(1024) && !alloc(1025));
+        // A failed growth leaves the block in place.
+        unsafe {
+            let ptr = FailingAlloc.alloc(layout);
+            assert!(FailingAlloc.realloc(ptr, layout, 4096).is_null());
+            FailingAlloc.dealloc(ptr, layout);
+        }
+        reset();
+        fail_with_probability(0.5, 42);
+        let first: std::vec::Vec<bool> = (0..64).map(|_| alloc(64)).collect();
+        fail_with_probability(0.5, 42);
+        let again: std::vec::Vec<bool> = (0..64).map(|_| alloc(64)).collect();
+        assert_eq!(first, again);
+        assert!(first.contains(&true) && first.contains(&false));
+        assert_eq!(injected_failures(), 2 * first.iter().filter(|ok| !**ok).count() as u64);
+        reset();
+    }
+}
@@ -0,0 +1,93 @@
+//! Allocator set-up and teardown for spawned threads (`std-thread-hook` feature).
+//!
+//! The allocator of a thread is initialised by its first allocation, which then takes much longer
+//! than the following ones, and released by the C++ thread-local destructors when it exits. The
+//! functions below wrap the body of a thread between [`thread_init`] and [`thread_teardown`], so
+//! that neither happens in the middle of the work of the thread:
+//!
+//! ```rust
+//! use snmalloc_rs::thread_hook::{self, BuilderExt};
+//!
+//! let worker = thread_hook::spawn(|| vec![0u8; 64].len());
+//! assert_eq!(worker.join().unwrap(), 64);
+//!
+//! let named = std::thread::Builder::new().name("io".into()).spawn_prewarmed(|| 1).unwrap();
+//! assert_eq!(named.join().unwrap(), 1);
+//! ```
+//!
+//! `std` has no stable hook run by every spawned thread: threads spawned by other crates, e.g.
+//! thread pools, are covered by passing their body through [`wrap`] where the pool lets it be
+//! customised (such as the start and exit handlers of a Tokio runtime or a Rayon pool, which can
+//! call [`thread_init`] and [`thread_teardown`] directly).
+use std::{
+    io,
+    thread::{self, Builder, JoinHandle},
+};
+
+use crate::{thread_init, thread_teardown};
+
+/// Tears the allocator down when the thread body returns or unwinds.
+struct Teardown;
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        thread_teardown();
+    }
+}
+
+/// Wraps the body of a thread between [`thread_init`] and [`thread_teardown`]. The teardown also
+/// runs if `f` panics.
+pub fn wrap<F, T>(f: F) -> impl FnOnce() -> T
+where
+    F: FnOnce() -> T,
+{
+    move || {
+        thread_init();
+        let _teardown = Teardown;
+        f()
+    }
+}
+
+/// Same as [`std::thread::spawn`], with the body wrapped by [`wrap`].
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::spawn(wrap(f))
+}
+
+/// Spawns threads of a [`std::thread::Builder`] with the body wrapped by [`wrap`].
+pub trait BuilderExt {
+    /// Same as [`Builder::spawn`], with the body wrapped by [`wrap`].
+    fn spawn_prewarmed<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static;
+}
+
+impl BuilderExt for Builder {
+    fn spawn_prewarmed<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn(wrap(f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_tears_down_panicking_threads() {
+        assert!(spawn(|| panic!("the teardown still runs")).join().is_err());
+        // Allocating after the teardown takes an allocator again.
+        let len = spawn(|| {
+            thread_teardown();
+            std::vec![1u64; 1000].len()
+        });
+        assert_eq!(len.join().unwrap(), 1000);
+    }
+}
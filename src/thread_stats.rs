@@ -0,0 +1,180 @@
+//! Per-thread allocation counters (`stats` and `std` features), reported by
+//! [`per_thread`](crate::stats::per_thread).
+//!
+//! A thread takes one of [`MAX_THREADS`] static slots at its first allocation and gives it back
+//! when it exits. The release is registered with `sn_rust_at_thread_exit` rather than a
+//! thread-local with `Drop`, which the standard library forbids in global allocators, and the
+//! name is read from the OS with `sn_rust_thread_name` rather than `std::thread::current`, so
+//! that registering never allocates. The counters of a slot are only written by its thread,
+//! which keeps them off the shared cache lines of the global counters.
+use core::{
+    cell::Cell,
+    ffi::c_void,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use std::{string::String, time::Instant, vec::Vec};
+
+use crate::{stats::ThreadStats, sync::SpinLock};
+
+/// Threads tracked at the same time; threads started while all slots are taken are not reported.
+pub(crate) const MAX_THREADS: usize = 256;
+const NAME_LEN: usize = 64;
+
+// States of a thread besides the index of its slot.
+const UNREGISTERED: usize = usize::MAX;
+const REGISTERING: usize = usize::MAX - 1;
+const UNTRACKED: usize = usize::MAX - 2;
+
+static CAPTURE_NAMES: AtomicBool = AtomicBool::new(false);
+static SLOTS: [Slot; MAX_THREADS] = [const { Slot::new() }; MAX_THREADS];
+
+std::thread_local! {
+    static STATE: Cell<usize> = const { Cell::new(UNREGISTERED) };
+}
+
+struct Slot {
+    used: AtomicBool,
+    allocated: AtomicUsize,
+    freed: AtomicUsize,
+    allocations: AtomicUsize,
+    name: SpinLock<([u8; NAME_LEN], usize)>,
+    /// Allocation count and time of the previous sample, for the rate.
+    sample: SpinLock<(usize, Option<Instant>)>,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            used: AtomicBool::new(false),
+            allocated: AtomicUsize::new(0),
+            freed: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            name: SpinLock::new(([0; NAME_LEN], 0)),
+            sample: SpinLock::new((0, None)),
+        }
+    }
+
+    /// Adds `value` to a counter only written by the thread owning the slot.
+    #[inline(always)]
+    fn add(counter: &AtomicUsize, value: usize) {
+        counter.store(counter.load(Ordering::Relaxed).wrapping_add(value), Ordering::Relaxed);
+    }
+}
+
+#[inline(always)]
+pub(crate) fn set_capture_names(enabled: bool) {
+    CAPTURE_NAMES.store(enabled, Ordering::Relaxed);
+}
+
+#[inline(always)]
+pub(crate) fn on_alloc(size: usize) {
+    with_slot(|slot| {
+        Slot::add(&slot.allocated, size);
+        Slot::add(&slot.allocations, 1);
+    });
+}
+
+#[inline(always)]
+pub(crate) fn on_dealloc(size: usize) {
+    with_slot(|slot| Slot::add(&slot.freed, size));
+}
+
+#[inline(always)]
+fn with_slot(f: impl FnOnce(&Slot)) {
+    let _ = STATE.try_with(|state| {
+        let index = match state.get() {
+            UNREGISTERED => register(state),
+            index => index,
+        };
+        if let Some(slot) = SLOTS.get(index) {
+            f(slot);
+        }
+    });
+}
+
+/// Takes a slot for the calling thread. Allocations made meanwhile, e.g. by the C runtime
+/// registering the exit callback, find the thread `REGISTERING` and are not counted.
+#[cold]
+fn register(state: &Cell<usize>) -> usize {
+    state.set(REGISTERING);
+    let free = SLOTS.iter().position(|slot| {
+        slot.used.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    });
+    let Some(index) = free else {
+        state.set(UNTRACKED);
+        return UNTRACKED;
+    };
+    let slot = &SLOTS[index];
+    for counter in [&slot.allocated, &slot.freed, &slot.allocations] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    *slot.sample.lock() = (0, Some(Instant::now()));
+    let mut name = slot.name.lock();
+    name.1 = match CAPTURE_NAMES.load(Ordering::Relaxed) {
+        true => unsafe { ffi::sn_rust_thread_name(name.0.as_mut_ptr().cast(), NAME_LEN) },
+        false => 0,
+    };
+    drop(name);
+    if !unsafe { ffi::sn_rust_at_thread_exit(Some(release), index as *mut c_void) } {
+        slot.used.store(false, Ordering::Release);
+        state.set(UNTRACKED);
+        return UNTRACKED;
+    }
+    state.set(index);
+    index
+}
+
+/// Gives the slot back when its thread exits. Allocations made later by the exiting thread,
+/// from other destructors, are not counted.
+unsafe extern "C" fn release(context: *mut c_void) {
+    let _ = STATE.try_with(|state| state.set(UNTRACKED));
+    SLOTS[context as usize].used.store(false, Ordering::Release);
+}
+
+pub(crate) fn per_thread() -> Vec<ThreadStats> {
+    let now = Instant::now();
+    let mut threads = Vec::new();
+    for slot in SLOTS.iter().filter(|slot| slot.used.load(Ordering::Acquire)) {
+        let allocations = slot.allocations.load(Ordering::Relaxed);
+        let live_bytes = slot.allocated.load(Ordering::Relaxed).wrapping_sub(slot.freed.load(Ordering::Relaxed));
+        let previous = core::mem::replace(&mut *slot.sample.lock(), (allocations, Some(now)));
+        let elapsed = previous.1.map_or(0.0, |at| now.saturating_duration_since(at).as_secs_f64());
+        let (bytes, len) = *slot.name.lock();
+        threads.push(ThreadStats {
+            name: (len != 0).then(|| String::from_utf8_lossy(&bytes[..len]).into_owned()),
+            live_bytes: live_bytes as isize,
+            allocs_per_sec: match elapsed > 0.0 {
+                true => allocations.wrapping_sub(previous.0) as f64 / elapsed,
+                false => 0.0,
+            },
+        });
+    }
+    threads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::{GlobalAlloc, Layout};
+
+    #[test]
+    fn it_counts_per_thread() {
+        set_capture_names(true);
+        let layout = Layout::from_size_align(1 << 16, 8).unwrap();
+        let worker = std::thread::Builder::new().name("sn-stats-test".into()).spawn(move || {
+            let ptr = unsafe { crate::SnMalloc.alloc(layout) };
+            let threads = per_thread();
+            unsafe { crate::SnMalloc.dealloc(ptr, layout) };
+            threads
+        });
+        let threads = worker.unwrap().join().unwrap();
+        assert!(threads.iter().any(|thread| thread.live_bytes >= 1 << 16));
+        // Only some platforms name their threads.
+        if cfg!(any(target_env = "gnu", target_os = "macos", windows)) {
+            let worker = threads.iter().find(|thread| thread.name.as_deref() == Some("sn-stats-test")).unwrap();
+            assert!(worker.live_bytes >= 1 << 16);
+            // The slot was given back when the thread exited.
+            assert!(per_thread().iter().all(|thread| thread.name.as_deref() != Some("sn-stats-test")));
+        }
+    }
+}
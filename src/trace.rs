@@ -0,0 +1,99 @@
+//! `tracing` events for large allocations.
+//!
+//! With the `tracing` feature, every request through [`SnMalloc`](crate::SnMalloc) of at least
+//! [`trace_threshold`] bytes emits an `INFO` event with target `snmalloc`, carrying the size,
+//! the alignment and the thread, so that surprise large allocations show up in existing
+//! observability pipelines. Allocations made while the event is being recorded are not traced.
+#[cfg(feature = "tracing")]
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[cfg(feature = "tracing")]
+static THRESHOLD: AtomicUsize = AtomicUsize::new(1 << 20);
+
+#[cfg(feature = "tracing")]
+std::thread_local! {
+    static EMITTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sets the size from which allocations are traced (1MiB by default).
+#[cfg(feature = "tracing")]
+#[inline(always)]
+pub fn set_trace_threshold(bytes: usize) {
+    THRESHOLD.store(bytes, Ordering::Relaxed);
+}
+
+/// Returns the size from which allocations are traced.
+#[cfg(feature = "tracing")]
+#[inline(always)]
+pub fn trace_threshold() -> usize {
+    THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Traces an allocation request if it is large enough; a no-op without the `tracing` feature.
+#[inline(always)]
+pub(crate) fn on_request(size: usize, align: usize) {
+    #[cfg(feature = "tracing")]
+    if size >= trace_threshold() {
+        emit(size, align);
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = (size, align);
+}
+
+#[cfg(feature = "tracing")]
+#[cold]
+fn emit(size: usize, align: usize) {
+    // Subscribers may allocate: the nested requests must not be traced.
+    let _ = EMITTING.try_with(|emitting| {
+        if !emitting.replace(true) {
+            tracing::info!(target: "snmalloc", size, align, thread = ?std::thread::current().id(), "large allocation");
+            emitting.set(false);
+        }
+    });
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use core::alloc::{GlobalAlloc, Layout};
+    use std::sync::atomic::AtomicUsize;
+
+    use tracing::{span, Event, Metadata, Subscriber};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl Subscriber for &'static Counter {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "snmalloc"
+        }
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, _: &Event<'_>) {
+            // Allocating here must not trace again.
+            drop(std::vec![0u8; 2 << 20]);
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn it_traces_large_allocations() {
+        static COUNTER: Counter = Counter(AtomicUsize::new(0));
+        tracing::subscriber::with_default(&COUNTER, || unsafe {
+            let small = Layout::from_size_align(64, 8).unwrap();
+            crate::SnMalloc.dealloc(crate::SnMalloc.alloc(small), small);
+            let large = Layout::from_size_align(2 << 20, 8).unwrap();
+            crate::SnMalloc.dealloc(crate::SnMalloc.alloc(large), large);
+        });
+        assert_eq!(COUNTER.0.load(Ordering::Relaxed), 1);
+    }
+}
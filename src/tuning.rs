@@ -0,0 +1,227 @@
+//! Process-wide allocator tunables.
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::sync::SpinLock;
+
+/// The runtime knobs of [`SnMalloc`](crate::SnMalloc) set away from their defaults, one bit per
+/// [`Knobs`] constant, so that its paths load a single word to skip all of them.
+static KNOBS: AtomicUsize = AtomicUsize::new(0);
+
+/// Serialises the setters, so that the bit of a knob always follows its last value.
+static SETTING: SpinLock<()> = SpinLock::new(());
+
+/// The runtime knobs set when an operation of [`SnMalloc`](crate::SnMalloc) started, see
+/// [`knobs`].
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Knobs(usize);
+
+impl Knobs {
+    /// [`set_max_alloc_size`](crate::set_max_alloc_size) set a limit.
+    pub(crate) const MAX_ALLOC_SIZE: usize = 1 << 0;
+    /// [`set_large_cache`](crate::set_large_cache) enabled the large-object cache.
+    pub(crate) const LARGE_CACHE: usize = 1 << 1;
+    /// `set_cache_decay` set a decay.
+    pub(crate) const CACHE_DECAY: usize = 1 << 2;
+    /// [`set_remote_batch_size`] set a batch size.
+    pub(crate) const REMOTE_BATCH: usize = 1 << 3;
+    /// [`set_lazy_zero`] set a threshold.
+    pub(crate) const LAZY_ZERO: usize = 1 << 4;
+
+    #[inline(always)]
+    pub(crate) fn has(self, knob: usize) -> bool {
+        self.0 & knob != 0
+    }
+}
+
+/// Returns the runtime knobs currently set, for an operation to check them all with one load.
+#[inline(always)]
+pub(crate) fn knobs() -> Knobs {
+    Knobs(KNOBS.load(Ordering::Relaxed))
+}
+
+/// Runs `store`, which updates the value of `knob` and returns whether it differs from its
+/// default, and records the result in the knobs.
+pub(crate) fn set_knob(knob: usize, store: impl FnOnce() -> bool) {
+    let _guard = SETTING.lock();
+    match store() {
+        true => KNOBS.fetch_or(knob, Ordering::Relaxed),
+        false => KNOBS.fetch_and(!knob, Ordering::Relaxed),
+    };
+}
+
+/// `0` keeps snmalloc's compile-time default.
+static REMOTE_BATCH_SIZE: AtomicUsize = AtomicUsize::new(0);
+static LAZY_ZERO_THRESHOLD: AtomicUsize = AtomicUsize::new(usize::MAX);
+static LAZY_ZERO_STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Sets how many bytes of memory owned by other threads a thread buffers before sending the
+/// frees back to their owners.
+///
+/// Smaller batches return memory to producer threads sooner, at the cost of more messages;
+/// `0` restores snmalloc's default. Only batches smaller than the default have an effect, and
+/// only for memory freed through [`SnMalloc`](crate::SnMalloc).
+///
+/// The shim clamps a field of the remote-free cache of snmalloc, which is not part of its API:
+/// it is checked when the shim is compiled, and with a snmalloc that no longer has it this
+/// returns `false`, leaving the default in place.
+pub fn set_remote_batch_size(bytes: usize) -> bool {
+    if bytes != 0 && !unsafe { ffi::sn_rust_remote_batch_supported() } {
+        return false;
+    }
+    set_knob(Knobs::REMOTE_BATCH, || {
+        REMOTE_BATCH_SIZE.store(bytes, Ordering::Relaxed);
+        bytes != 0
+    });
+    true
+}
+
+/// Returns the batch size set by [`set_remote_batch_size`], `0` meaning snmalloc's default.
+#[inline(always)]
+pub fn remote_batch_size() -> usize {
+    REMOTE_BATCH_SIZE.load(Ordering::Relaxed)
+}
+
+/// What a lazily zeroed allocation does when its pages cannot be replaced by fresh ones, see
+/// [`set_lazy_zero`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LazyZeroFallback {
+    /// Writes the zeros, making every page resident.
+    Memset,
+    /// Fails the allocation, returning null (or `None`), so that callers relying on untouched
+    /// pages, e.g. for sparse tables, can fall back to another strategy.
+    Fail,
+}
+
+/// Serves the zeroed allocations of at least `threshold` bytes made through
+/// [`SnMalloc`](crate::SnMalloc) with fresh pages of the OS instead of writing zeros: the pages
+/// read as zero and only become resident when written, so that a large zeroed buffer costs no
+/// memory until it is used. `usize::MAX` (the default) disables it.
+///
+/// Only the pages of large objects (above the largest size class) are replaced, and only on Linux
+/// and Windows; smaller allocations, and every allocation on other platforms, take the fallback. Lazily zeroed allocations bypass the
+/// [large-object cache](crate::set_large_cache), whose objects would have to be written.
+///
+/// ```rust
+/// use core::alloc::{GlobalAlloc, Layout};
+/// use snmalloc_rs::LazyZeroFallback;
+/// snmalloc_rs::set_lazy_zero(64 << 20, LazyZeroFallback::Memset);
+/// let layout = Layout::from_size_align(1 << 30, 4096).unwrap();
+/// let table = unsafe { snmalloc_rs::SnMalloc.alloc_zeroed(layout) };
+/// assert!(!table.is_null());
+/// unsafe { snmalloc_rs::SnMalloc.dealloc(table, layout) };
+/// snmalloc_rs::set_lazy_zero(usize::MAX, LazyZeroFallback::Memset);
+/// ```
+pub fn set_lazy_zero(threshold: usize, fallback: LazyZeroFallback) {
+    set_knob(Knobs::LAZY_ZERO, || {
+        LAZY_ZERO_STRICT.store(fallback == LazyZeroFallback::Fail, Ordering::Relaxed);
+        LAZY_ZERO_THRESHOLD.store(threshold, Ordering::Relaxed);
+        threshold != usize::MAX
+    });
+}
+
+/// Returns the threshold set by [`set_lazy_zero`].
+#[inline(always)]
+pub fn lazy_zero_threshold() -> usize {
+    LAZY_ZERO_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Returns the fallback set by [`set_lazy_zero`].
+#[inline(always)]
+pub fn lazy_zero_fallback() -> LazyZeroFallback {
+    match LAZY_ZERO_STRICT.load(Ordering::Relaxed) {
+        true => LazyZeroFallback::Fail,
+        false => LazyZeroFallback::Memset,
+    }
+}
+
+/// Returns whether a zeroed allocation of `size` bytes is served by [`alloc_zeroed_fresh`]. The
+/// threshold is only loaded when `knobs` say that one is set.
+#[inline(always)]
+pub(crate) fn zeroes_lazily(knobs: Knobs, size: usize) -> bool {
+    knobs.has(Knobs::LAZY_ZERO) && size >= lazy_zero_threshold()
+}
+
+/// Allocates a zeroed block of `layout` on fresh pages, see [`set_lazy_zero`].
+#[inline(always)]
+pub(crate) fn alloc_zeroed_fresh(layout: core::alloc::Layout) -> *mut u8 {
+    let strict = LAZY_ZERO_STRICT.load(Ordering::Relaxed);
+    crate::sync::exclusive(|| unsafe { ffi::sn_rust_alloc_zeroed_fresh(layout.align(), layout.size(), strict) }).cast()
+}
+
+/// Returns the number of bytes at the start of freed objects that snmalloc leaves untouched, so
+/// that the next user of an object finds its first cache line undisturbed by the free lists.
+///
+/// This is fixed at build time: 64 with the `cache-friendly` feature, `0` otherwise.
+#[inline(always)]
+pub fn cache_friendly_offset() -> usize {
+    unsafe { ffi::sn_rust_cache_friendly_offset() }
+}
+
+/// Returns the page size of the running kernel, e.g. 16KiB on Apple Silicon or 64KiB on some
+/// aarch64 Linux kernels.
+///
+/// snmalloc is compiled for a fixed page size, which the build script raises for such targets
+/// (see `SNMALLOC_SYS_PAGE_SIZE`); the page-granular operations of this crate, such as guard
+/// pages, [locking](crate::SnAllocator::new_locked) and protection, round to the larger of both.
+#[inline(always)]
+pub fn os_page_size() -> usize {
+    unsafe { ffi::sn_rust_os_page_size() }
+}
+
+/// Serialises the tests changing the process-wide tunables.
+#[cfg(test)]
+pub(crate) static TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reports_the_cache_friendly_offset() {
+        assert_eq!(cache_friendly_offset(), if cfg!(feature = "cache-friendly") { 64 } else { 0 });
+    }
+
+    /// Resident pages of `len` bytes at `ptr`, from `mincore`.
+    #[cfg(target_os = "linux")]
+    fn resident_pages(ptr: *mut u8, len: usize) -> usize {
+        extern "C" {
+            fn mincore(addr: *mut core::ffi::c_void, length: usize, vec: *mut u8) -> i32;
+        }
+        let mut pages = std::vec![0u8; len.div_ceil(os_page_size())];
+        assert_eq!(unsafe { mincore(ptr.cast(), len, pages.as_mut_ptr()) }, 0);
+        pages.iter().filter(|page| **page & 1 != 0).count()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn it_zeroes_large_allocations_lazily() {
+        use core::alloc::{GlobalAlloc, Layout};
+
+        const SIZE: usize = 64 << 20;
+        let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_lazy_zero(SIZE, LazyZeroFallback::Fail);
+        let layout = Layout::from_size_align(SIZE, 4096).unwrap();
+        let ptr = unsafe { crate::SnMalloc.alloc_zeroed(layout) };
+        set_lazy_zero(usize::MAX, LazyZeroFallback::Memset);
+        assert!(!ptr.is_null());
+        assert_eq!(resident_pages(ptr, SIZE), 0);
+        unsafe {
+            let bytes = core::slice::from_raw_parts_mut(ptr, SIZE);
+            for page in bytes.chunks_mut(os_page_size()) {
+                assert_eq!(page[0], 0);
+                page[0] = 1;
+            }
+            assert_eq!(resident_pages(ptr, SIZE), SIZE / os_page_size());
+            crate::SnMalloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn it_reports_the_os_page_size() {
+        let page = os_page_size();
+        assert!(page.is_power_of_two() && page >= 4096);
+        if cfg!(all(target_arch = "aarch64", target_vendor = "apple")) {
+            assert_eq!(page, 16384);
+        }
+    }
+}
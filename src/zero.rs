@@ -0,0 +1,100 @@
+//! Zeroing of freed memory (`zero-on-free` feature).
+//!
+//! Deployments that must not leave data in freed memory can have every block freed through
+//! [`SnMalloc`](crate::SnMalloc) zeroed before it is handed back, from the start of the
+//! allocation to the end of its block, so that data left past the end of a block shrunk in
+//! place is cleared too. Whole pages are zeroed by the OS, which is cheaper than writing them.
+//! Guarded allocations and allocations with redzones only have the bytes of the allocation
+//! zeroed, the pages and canaries around them being checked by their own paths.
+//!
+//! Zeroing is enabled as soon as the feature is, and can be switched off at run time with
+//! [`set_zero_on_free`], e.g. for a batch job sharing the binary of a service. The bytes zeroed
+//! are reported by [`zeroed_memory`](crate::stats::zeroed_memory).
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static ZEROED_BYTES: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "std")]
+static SAMPLE: crate::sync::SpinLock<(usize, Option<std::time::Instant>)> = crate::sync::SpinLock::new((0, None));
+
+/// Returns whether freed memory is zeroed.
+#[inline(always)]
+pub fn zero_on_free() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enables or disables the zeroing of freed memory. Memory freed while disabled is not zeroed
+/// later.
+#[inline(always)]
+pub fn set_zero_on_free(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Zeroes an allocation of `size` bytes about to be released.
+#[inline(always)]
+pub(crate) unsafe fn on_free(ptr: *mut u8, size: usize) {
+    if size == 0 || !zero_on_free() {
+        return;
+    }
+    #[allow(unused_mut)]
+    let mut bounded = false;
+    #[cfg(feature = "guard-large-allocs")]
+    {
+        bounded |= crate::guard::may_be_guarded(size);
+    }
+    #[cfg(feature = "redzones")]
+    {
+        bounded |= crate::redzone::covers(size);
+    }
+    let span = match bounded {
+        true => size,
+        false => ffi::sn_rust_remaining_bytes(ptr.cast()),
+    };
+    ffi::sn_rust_zero(ptr.cast(), span);
+    ZEROED_BYTES.fetch_add(span, Ordering::Relaxed);
+}
+
+#[inline(always)]
+pub(crate) fn zeroed_bytes() -> usize {
+    ZEROED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Returns the rate of zeroing since the previous call, or since the first zeroing for the
+/// first call.
+#[cfg(feature = "std")]
+pub(crate) fn zeroed_bytes_per_sec(bytes: usize) -> f64 {
+    let now = std::time::Instant::now();
+    let previous = core::mem::replace(&mut *SAMPLE.lock(), (bytes, Some(now)));
+    match previous.1.map(|at| now.saturating_duration_since(at).as_secs_f64()) {
+        Some(elapsed) if elapsed > 0.0 => bytes.wrapping_sub(previous.0) as f64 / elapsed,
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SnMalloc;
+    use core::alloc::{GlobalAlloc, Layout};
+
+    #[test]
+    fn it_zeroes_freed_blocks() {
+        for size in [48, 1 << 20] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            unsafe {
+                let ptr = SnMalloc.alloc(layout);
+                ptr.write_bytes(0xAA, size);
+                let before = zeroed_bytes();
+                SnMalloc.dealloc(ptr, layout);
+                assert!(zeroed_bytes() - before >= size);
+                // Served the same block again, without asking for zeroed memory. The free list
+                // of snmalloc links freed blocks through their first bytes.
+                let again = SnMalloc.alloc(layout);
+                if again == ptr {
+                    assert!(core::slice::from_raw_parts(again, size)[16..].iter().all(|byte| *byte == 0));
+                }
+                SnMalloc.dealloc(again, layout);
+            }
+        }
+    }
+}
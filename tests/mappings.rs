@@ -0,0 +1,107 @@
+//! Tests showing that a path never maps memory, by counting the OS mappings made by a thread.
+//!
+//! `mmap` of the C library is interposed for this test binary only: the shim is linked
+//! statically, so its calls bind to the definition here, which forwards them to the kernel.
+#![cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+
+use core::{
+    alloc::Layout,
+    cell::Cell,
+    ffi::{c_int, c_long, c_void},
+};
+
+
+const SYS_MMAP: c_long = if cfg!(target_arch = "x86_64") { 9 } else { 222 };
+
+std::thread_local! {
+    static MAPPINGS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+extern "C" {
+    fn syscall(number: c_long, ...) -> c_long;
+}
+
+/// Threads inside [`count`] count their calls.
+#[no_mangle]
+unsafe extern "C" fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void {
+    let _ = MAPPINGS.try_with(|count| count.set(count.get().map(|count| count + 1)));
+    syscall(SYS_MMAP, addr, len, prot, flags, fd, offset) as *mut c_void
+}
+
+/// Runs `f`, returning its result and the number of `mmap` calls the current thread made meanwhile.
+fn count<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    MAPPINGS.with(|count| count.set(Some(0)));
+    let result = f();
+    (result, MAPPINGS.with(|count| count.take()).unwrap_or(0))
+}
+
+#[cfg(feature = "handle-api")]
+#[test]
+fn preallocated_pool_never_maps() {
+    use snmalloc_rs::SnAllocator;
+
+    std::thread::spawn(|| {
+        let alloc = SnAllocator::with_preallocated(1 << 20).unwrap();
+        let layouts = [16, 1000, 4096, 1 << 16].map(|size| Layout::from_size_align(size, 8).unwrap());
+        let ((), mappings) = count(|| unsafe {
+            for _ in 0..100 {
+                for layout in layouts {
+                    let ptr = alloc.allocate(layout).unwrap();
+                    ptr.as_ptr().write_bytes(0x5A, layout.size());
+                    alloc.deallocate(ptr, layout);
+                }
+            }
+            // Requests beyond the pool fail rather than map more memory.
+            assert!(alloc.allocate(Layout::from_size_align(2 << 20, 8).unwrap()).is_none());
+        });
+        assert_eq!(mappings, 0);
+    })
+    .join()
+    .unwrap();
+}
+
+#[cfg(feature = "no-alloc-on-free")]
+#[test]
+fn frees_without_mapping() {
+    use core::alloc::GlobalAlloc;
+
+    use snmalloc_rs::SnMalloc;
+
+    let layout = Layout::from_size_align(48, 8).unwrap();
+    // Allocated by another thread, so that half of the frees are remote.
+    let remote: Vec<usize> = std::thread::spawn(move || {
+        (0..10_000).map(|_| unsafe { SnMalloc.alloc(layout) } as usize).collect()
+    })
+    .join()
+    .unwrap();
+    std::thread::spawn(move || {
+        let local: Vec<usize> = (0..10_000).map(|_| unsafe { SnMalloc.alloc(layout) } as usize).collect();
+        let ((), mappings) = count(|| {
+            for ptr in local.into_iter().chain(remote) {
+                unsafe { SnMalloc.dealloc(ptr as *mut u8, layout) };
+            }
+        });
+        assert_eq!(mappings, 0);
+    })
+    .join()
+    .unwrap();
+}
+
+#[cfg(feature = "no-alloc-on-free")]
+#[test]
+fn frees_first_after_thread_init() {
+    use core::alloc::GlobalAlloc;
+
+    use snmalloc_rs::SnMalloc;
+
+    let layout = Layout::from_size_align(48, 8).unwrap();
+    let ptr = unsafe { SnMalloc.alloc(layout) } as usize;
+    std::thread::spawn(move || {
+        // Initialised without allocating through `SnMalloc`: the audit must not abort.
+        snmalloc_rs::thread_init();
+        let ((), mappings) = count(|| unsafe { SnMalloc.dealloc(ptr as *mut u8, layout) });
+        assert_eq!(mappings, 0);
+    })
+    .join()
+    .unwrap();
+}